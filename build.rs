@@ -0,0 +1,56 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+}
+
+// The commit changes with HEAD when switching branches, and with the
+// branch HEAD points to on every commit. That branch can be a loose ref
+// or only listed in packed-refs, so both are watched. Without a loose
+// ref, its directory is watched instead, for when one is written.
+fn watch_git_refs() {
+    let mut watched = vec![
+        git(&["rev-parse", "--git-path", "HEAD"]),
+        git(&["rev-parse", "--git-path", "packed-refs"]),
+    ];
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        watched.push(git(&["rev-parse", "--git-path", &branch]).map(|path| {
+            match Path::new(&path).parent() {
+                Some(dir) if !Path::new(&path).exists() => dir.display().to_string(),
+                _ => path,
+            }
+        }));
+    }
+
+    for path in watched.into_iter().flatten() {
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+// Bakes the git commit and the enabled cargo features into the binary
+// so that a running tracker can report exactly what it was built from.
+fn main() {
+    let commit = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|f| f.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=TYTO_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=TYTO_FEATURES={}", features.join(","));
+    watch_git_refs();
+}
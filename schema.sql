@@ -4,5 +4,47 @@ CREATE TABLE IF NOT EXISTS torrents (
         downloaded INT NOT NULL,
         incomplete INT NOT NULL,
         balance BIGINT NOT NULL,
+        bytes_uploaded BIGINT NOT NULL DEFAULT 0,
+        bytes_downloaded BIGINT NOT NULL DEFAULT 0,
+        category VARCHAR(64) NULL,
+        hidden BOOLEAN NOT NULL DEFAULT FALSE,
         PRIMARY KEY (info_hash)
 ) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS stats_history (
+        period_start BIGINT UNSIGNED NOT NULL,
+        period_end BIGINT UNSIGNED NOT NULL,
+        announce_requests INT UNSIGNED NOT NULL,
+        succ_announces INT UNSIGNED NOT NULL,
+        scrapes INT UNSIGNED NOT NULL,
+        uploaded BIGINT UNSIGNED NOT NULL,
+        downloaded BIGINT UNSIGNED NOT NULL,
+        PRIMARY KEY (period_start)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS peers (
+        info_hash VARCHAR(50) NOT NULL,
+        peer_id VARCHAR(50) NOT NULL,
+        ip VARCHAR(45) NOT NULL,
+        port INT UNSIGNED NOT NULL,
+        uploaded BIGINT UNSIGNED NOT NULL,
+        downloaded BIGINT UNSIGNED NOT NULL,
+        role VARCHAR(16) NOT NULL,
+        last_announced BIGINT UNSIGNED NOT NULL
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS users (
+        id INT UNSIGNED NOT NULL,
+        passkey CHAR(32) NOT NULL UNIQUE,
+        uploaded BIGINT UNSIGNED NOT NULL DEFAULT 0,
+        downloaded BIGINT UNSIGNED NOT NULL DEFAULT 0,
+        PRIMARY KEY (id)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS snatches (
+        user_id INT UNSIGNED NOT NULL,
+        info_hash VARCHAR(50) NOT NULL,
+        completed_at BIGINT UNSIGNED NOT NULL,
+        uploaded BIGINT UNSIGNED NOT NULL,
+        downloaded BIGINT UNSIGNED NOT NULL
+) ENGINE = InnoDB;
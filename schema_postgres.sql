@@ -0,0 +1,50 @@
+CREATE TABLE IF NOT EXISTS torrents (
+        info_hash VARCHAR(50) NOT NULL UNIQUE,
+        complete INT NOT NULL,
+        downloaded INT NOT NULL,
+        incomplete INT NOT NULL,
+        balance BIGINT NOT NULL,
+        bytes_uploaded BIGINT NOT NULL DEFAULT 0,
+        bytes_downloaded BIGINT NOT NULL DEFAULT 0,
+        category VARCHAR(64) NULL,
+        hidden BOOLEAN NOT NULL DEFAULT FALSE,
+        PRIMARY KEY (info_hash)
+);
+
+CREATE TABLE IF NOT EXISTS stats_history (
+        period_start BIGINT NOT NULL,
+        period_end BIGINT NOT NULL,
+        announce_requests INT NOT NULL,
+        succ_announces INT NOT NULL,
+        scrapes INT NOT NULL,
+        uploaded BIGINT NOT NULL,
+        downloaded BIGINT NOT NULL,
+        PRIMARY KEY (period_start)
+);
+
+CREATE TABLE IF NOT EXISTS peers (
+        info_hash VARCHAR(50) NOT NULL,
+        peer_id VARCHAR(50) NOT NULL,
+        ip VARCHAR(45) NOT NULL,
+        port INT NOT NULL,
+        uploaded BIGINT NOT NULL,
+        downloaded BIGINT NOT NULL,
+        role VARCHAR(16) NOT NULL,
+        last_announced BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS users (
+        id INT NOT NULL,
+        passkey CHAR(32) NOT NULL UNIQUE,
+        uploaded BIGINT NOT NULL DEFAULT 0,
+        downloaded BIGINT NOT NULL DEFAULT 0,
+        PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS snatches (
+        user_id INT NOT NULL,
+        info_hash VARCHAR(50) NOT NULL,
+        completed_at BIGINT NOT NULL,
+        uploaded BIGINT NOT NULL,
+        downloaded BIGINT NOT NULL
+);
@@ -1,20 +1,47 @@
-use crate::bittorrent::{AnnounceResponse, ScrapeFile, ScrapeResponse};
-use bendy::encoding::{Error, SingleItemEncoder, ToBencode};
+use std::convert::TryInto;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::bittorrent::{
+    AnnounceResponse, CompactPeerv4, CompactPeerv6, InfoHash, ScrapeFile, ScrapeResponse,
+    UpstreamCounts,
+};
+use bendy::decoding::{self, Decoder, Object};
+use bendy::encoding::{AsString, Encoder, Error, SingleItemEncoder, SortedDictEncoder, ToBencode};
+
+impl ToBencode for UpstreamCounts {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"complete", self.complete)?;
+            e.emit_pair(b"incomplete", self.incomplete)?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
 
 impl ToBencode for ScrapeFile {
-    const MAX_DEPTH: usize = 1;
+    const MAX_DEPTH: usize = 2;
 
     // bendy's emit methods return a result, which isn't immediately clear
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
         encoder.emit_dict(|mut e| {
-            e.emit_pair(b"complete", &self.complete)?;
-            e.emit_pair(b"downloaded", &self.downloaded)?;
-            e.emit_pair(b"incomplete", &self.incomplete)?;
+            e.emit_pair(b"complete", self.complete)?;
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"incomplete", self.incomplete)?;
 
             if let Some(name) = &self.name {
                 e.emit_pair(b"name", name)?;
             }
 
+            if let Some(upstream) = &self.upstream {
+                e.emit_pair(b"upstream", upstream)?;
+            }
+
             Ok(())
         })?;
 
@@ -22,42 +49,89 @@ impl ToBencode for ScrapeFile {
     }
 }
 
-impl ToBencode for AnnounceResponse {
-    const MAX_DEPTH: usize = 5;
+impl AnnounceResponse {
+    // If there is a failure reason present, then nothing
+    // else gets encoded. The key has a space, per BEP 3.
+    fn encode_failure(&self, reason: &str, mut e: SortedDictEncoder) -> Result<(), Error> {
+        e.emit_pair(b"failure reason", reason)?;
 
-    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
-        // If there is a failure reason present,
-        // then nothing else gets encoded
-        match &self.failure_reason {
-            Some(reason) => {
-                encoder.emit_dict(|mut e| {
-                    e.emit_pair(b"failure_reason", reason)?;
+        if let Some(retry_in) = &self.retry_in {
+            e.emit_pair(b"retry in", retry_in)?;
+        }
 
-                    Ok(())
-                })?;
-            }
+        Ok(())
+    }
 
-            None => {
-                encoder.emit_dict(|mut e| {
-                    e.emit_pair(b"complete", &self.complete)?;
-                    e.emit_pair(b"incomplete", &self.incomplete)?;
-                    e.emit_pair(b"interval", &self.interval)?;
+    // The counts and everything else that sorts ahead of the peers,
+    // which is written anew for each client
+    fn encode_counts(&self, mut e: SortedDictEncoder) -> Result<(), Error> {
+        e.emit_pair(b"complete", self.complete)?;
 
-                    if let Some(min_interval) = &self.min_interval {
-                        e.emit_pair(b"min_interval", min_interval)?;
-                    }
+        if let Some(downloaded) = &self.downloaded {
+            e.emit_pair(b"downloaded", downloaded)?;
+        }
 
-                    e.emit_pair(b"peers", &self.peersv4_as_compact())?;
-                    e.emit_pair(b"peers6", &self.peersv6_as_compact())?;
-                    e.emit_pair(b"tracker_id", &self.tracker_id)?;
+        // BEP 24: the address is packed the same way compact peers are
+        if let Some(ip) = &self.external_ip {
+            let packed = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            e.emit_pair(b"external ip", AsString(packed))?;
+        }
 
-                    Ok(())
-                })?;
-            }
+        e.emit_pair(b"incomplete", self.incomplete)?;
+        e.emit_pair(b"interval", self.interval)?;
+
+        if let Some(min_interval) = &self.min_interval {
+            e.emit_pair(b"min interval", min_interval)?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_peers(&self, mut e: SortedDictEncoder) -> Result<(), Error> {
+        // BEP 23 and BEP 7: compact peers are packed into
+        // byte strings, not emitted as lists of integers
+        if self.compact {
+            e.emit_pair(b"peers", AsString(self.peersv4_as_compact()))?;
+            e.emit_pair(b"peers6", AsString(self.peersv6_as_compact()))?;
+        } else {
+            e.emit_pair_with(b"peers", |e| self.encode_peer_dicts(e))?;
         }
+        e.emit_pair(b"tracker_id", &self.tracker_id)?;
 
         Ok(())
     }
+
+    // The dictionary model carries both address families in one list
+    fn encode_peer_dicts(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        let addrs = self
+            .peers
+            .iter()
+            .map(|p| SocketAddr::new(IpAddr::V4(p.ip), p.port))
+            .chain(
+                self.peers6
+                    .iter()
+                    .map(|p| SocketAddr::new(IpAddr::V6(p.ip), p.port)),
+            );
+
+        encoder.emit_list(|e| {
+            for addr in addrs {
+                e.emit_dict(|mut d| {
+                    d.emit_pair(b"ip", addr.ip().to_string())?;
+
+                    if let Some(peer_id) = self.peer_ids.get(&addr) {
+                        d.emit_pair(b"peer id", AsString(&peer_id.0[..]))?;
+                    }
+
+                    d.emit_pair(b"port", addr.port())
+                })?;
+            }
+
+            Ok(())
+        })
+    }
 }
 
 impl ToBencode for ScrapeResponse {
@@ -69,7 +143,11 @@ impl ToBencode for ScrapeResponse {
         match &self.failure_reason {
             Some(reason) => {
                 encoder.emit_dict(|mut e| {
-                    e.emit_pair(b"failure_reason", reason)?;
+                    e.emit_pair(b"failure reason", reason)?;
+
+                    if let Some(retry_in) = &self.retry_in {
+                        e.emit_pair(b"retry in", retry_in)?;
+                    }
 
                     Ok(())
                 })?;
@@ -88,8 +166,200 @@ impl ToBencode for ScrapeResponse {
     }
 }
 
+// A dictionary's pairs without the delimiters around them. Runs of
+// pairs can be joined into one dictionary as long as their keys stay
+// in order, which is how responses are put together around cached peers.
+fn encode_pairs<F>(pairs: F) -> Vec<u8>
+where
+    F: FnOnce(SortedDictEncoder) -> Result<(), Error>,
+{
+    let mut encoder = Encoder::new();
+    encoder.emit_dict(pairs).unwrap();
+    let mut bencoded = encoder.get_output().unwrap();
+    bencoded.pop();
+    bencoded.remove(0);
+    bencoded
+}
+
+// The peers as pairs of the response dictionary, which is the part the
+// response cache keeps. It's the same for every client in the swarm.
+pub fn encode_announce_peers(response: &AnnounceResponse) -> Vec<u8> {
+    encode_pairs(|e| response.encode_peers(e))
+}
+
 pub fn encode_announce_response(response: AnnounceResponse) -> Vec<u8> {
-    response.to_bencode().ok().unwrap()
+    let mut bencoded = vec![b'd'];
+
+    if let Some(reason) = &response.failure_reason {
+        bencoded.extend(encode_pairs(|e| response.encode_failure(reason, e)));
+        bencoded.push(b'e');
+        return bencoded;
+    }
+
+    bencoded.extend(encode_pairs(|e| response.encode_counts(e)));
+    match &response.encoded_peers {
+        Some(peers) => bencoded.extend_from_slice(peers),
+        None => bencoded.extend(encode_announce_peers(&response)),
+    }
+
+    if let Some(warning) = &response.warning_message {
+        bencoded.extend(encode_pairs(|mut e| {
+            e.emit_pair(b"warning message", warning)
+        }));
+    }
+
+    bencoded.push(b'e');
+    bencoded
+}
+
+// Pulls the counts out of another tracker's scrape response, which may
+// be a full scrape. Anything other than the counts themselves is ignored.
+pub fn decode_scrape_files(bytes: &[u8]) -> Result<Vec<ScrapeFile>, decoding::Error> {
+    let mut scrape_files = Vec::new();
+    let mut decoder = Decoder::new(bytes).with_max_depth(4);
+
+    if let Some(Object::Dict(mut response)) = decoder.next_object()? {
+        while let Some((key, value)) = response.next_pair()? {
+            if key != b"files" {
+                continue;
+            }
+
+            let mut files = value.try_into_dictionary()?;
+            while let Some((info_hash, file)) = files.next_pair()? {
+                let mut file = file.try_into_dictionary()?;
+                let mut scrape_file = ScrapeFile {
+                    info_hash: info_hash.to_vec(),
+                    ..ScrapeFile::default()
+                };
+
+                while let Some((field, value)) = file.next_pair()? {
+                    let count = match field {
+                        b"complete" => &mut scrape_file.complete,
+                        b"downloaded" => &mut scrape_file.downloaded,
+                        b"incomplete" => &mut scrape_file.incomplete,
+                        _ => continue,
+                    };
+                    *count = value.try_into_integer()?.parse().unwrap_or(0);
+                }
+
+                scrape_files.push(scrape_file);
+            }
+        }
+    }
+
+    Ok(scrape_files)
+}
+
+// Just the seeder and leecher counts, for torrents with valid info hashes
+pub fn decode_scrape_counts(
+    bytes: &[u8],
+) -> Result<Vec<(InfoHash, UpstreamCounts)>, decoding::Error> {
+    let counts = decode_scrape_files(bytes)?
+        .into_iter()
+        .filter_map(|file| {
+            let info_hash = InfoHash::from_bytes(&file.info_hash)?;
+            let counts = UpstreamCounts {
+                complete: file.complete,
+                incomplete: file.incomplete,
+            };
+            Some((info_hash, counts))
+        })
+        .collect();
+
+    Ok(counts)
+}
+
+// Pulls the peers out of another tracker's announce response. Both
+// compact and dictionary peer models are understood, as the origin
+// tracker may not honor the compact flag.
+pub fn decode_announce_peers(
+    bytes: &[u8],
+) -> Result<(Vec<CompactPeerv4>, Vec<CompactPeerv6>), decoding::Error> {
+    let mut peers = Vec::new();
+    let mut peers6 = Vec::new();
+    let mut decoder = Decoder::new(bytes).with_max_depth(4);
+
+    if let Some(Object::Dict(mut response)) = decoder.next_object()? {
+        while let Some((key, value)) = response.next_pair()? {
+            match (key, value) {
+                (b"peers", Object::Bytes(compact)) => {
+                    for chunk in compact.chunks_exact(6) {
+                        let ip: [u8; 4] = chunk[..4].try_into().unwrap();
+                        peers.push(CompactPeerv4 {
+                            ip: Ipv4Addr::from(ip),
+                            port: u16::from_be_bytes([chunk[4], chunk[5]]),
+                        });
+                    }
+                }
+                (b"peers", Object::List(mut list)) => {
+                    while let Some(peer) = list.next_object()? {
+                        let mut peer = peer.try_into_dictionary()?;
+                        let mut ip = None;
+                        let mut port = None;
+
+                        while let Some((field, value)) = peer.next_pair()? {
+                            match field {
+                                b"ip" => {
+                                    ip = std::str::from_utf8(value.try_into_bytes()?)
+                                        .ok()
+                                        .and_then(|s| s.parse::<IpAddr>().ok())
+                                }
+                                b"port" => port = value.try_into_integer()?.parse::<u16>().ok(),
+                                _ => {}
+                            }
+                        }
+
+                        match (ip, port) {
+                            (Some(IpAddr::V4(ip)), Some(port)) => {
+                                peers.push(CompactPeerv4 { ip, port })
+                            }
+                            (Some(IpAddr::V6(ip)), Some(port)) => {
+                                peers6.push(CompactPeerv6 { ip, port })
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                (b"peers6", Object::Bytes(compact)) => {
+                    for chunk in compact.chunks_exact(18) {
+                        let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+                        peers6.push(CompactPeerv6 {
+                            ip: Ipv6Addr::from(ip),
+                            port: u16::from_be_bytes([chunk[16], chunk[17]]),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok((peers, peers6))
+}
+
+// Number of files bencoded together into each chunk of a full scrape
+const FULL_SCRAPE_CHUNK_SIZE: usize = 1000;
+
+// Bencodes a full scrape lazily, a chunk of files at a time. The
+// files must already be sorted by info hash, as dictionary keys are.
+pub fn encode_full_scrape(files: Vec<ScrapeFile>) -> impl Iterator<Item = Vec<u8>> {
+    let mut files = files.into_iter().peekable();
+    let body = iter::from_fn(move || {
+        files.peek()?;
+
+        let mut chunk = Vec::new();
+        for file in files.by_ref().take(FULL_SCRAPE_CHUNK_SIZE) {
+            chunk.extend_from_slice(file.info_hash.len().to_string().as_bytes());
+            chunk.push(b':');
+            chunk.extend_from_slice(&file.info_hash);
+            chunk.extend(file.to_bencode().unwrap());
+        }
+        Some(chunk)
+    });
+
+    iter::once(b"d5:filesd".to_vec())
+        .chain(body)
+        .chain(iter::once(b"ee".to_vec()))
 }
 
 pub fn encode_scrape_response(response: ScrapeResponse) -> Vec<u8> {
@@ -99,9 +369,10 @@ pub fn encode_scrape_response(response: ScrapeResponse) -> Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::bittorrent::{AnnounceResponse, CompactPeerv4, CompactPeerv6, ScrapeResponse};
+    use crate::bittorrent::{
+        AnnounceResponse, CompactPeerv4, CompactPeerv6, PeerId, ScrapeResponse,
+    };
     use std::net::{Ipv4Addr, Ipv6Addr};
-    use std::time::Instant;
 
     #[test]
     fn announce_response_encoding() {
@@ -114,9 +385,7 @@ mod tests {
             port: 6894,
         };
 
-        let mut peers: Vec<CompactPeerv4> = Vec::new();
-        peers.push(peerv4_1);
-        peers.push(peerv4_2);
+        let peers: Vec<CompactPeerv4> = vec![peerv4_1, peerv4_2];
 
         let peerv6_1 = CompactPeerv6 {
             ip: Ipv6Addr::new(
@@ -131,15 +400,140 @@ mod tests {
             port: 6699,
         };
 
-        let mut peers6: Vec<CompactPeerv6> = Vec::new();
-        peers6.push(peerv6_1);
-        peers6.push(peerv6_2);
+        let peers6: Vec<CompactPeerv6> = vec![peerv6_1, peerv6_2];
 
         let response = AnnounceResponse::new(60, 100, 23, peers, peers6).unwrap();
 
         let encoded = encode_announce_response(response);
 
-        assert_eq!(encoded.as_slice(), &b"d8:completei100e10:incompletei23e8:intervali60e5:peersli127ei0ei0ei1ei26ei237ei255ei255ei255ei255ei26ei238ee6:peers6li32ei1ei13ei184ei133ei163ei0ei0ei0ei0ei138ei46ei3ei112ei115ei52ei26ei25ei254ei128ei0ei0ei0ei0ei0ei0ei2ei2ei179ei255ei254ei30ei131ei41ei26ei43ee10:tracker_id0:e"[..]);
+        assert_eq!(encoded.as_slice(), &b"d8:completei100e10:incompletei23e8:intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xed\xff\xff\xff\xff\x1a\xee6:peers636:\x20\x01\x0d\xb8\x85\xa3\x00\x00\x00\x00\x8a\x2e\x03\x70\x73\x34\x1a\x19\xfe\x80\x00\x00\x00\x00\x00\x00\x02\x02\xb3\xff\xfe\x1e\x83\x29\x1a\x2b10:tracker_id0:e"[..]);
+    }
+
+    #[test]
+    fn announce_response_dictionary_encoding() {
+        let peers = vec![CompactPeerv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+        }];
+        let peers6 = vec![CompactPeerv6 {
+            ip: Ipv6Addr::LOCALHOST,
+            port: 6894,
+        }];
+
+        let mut response = AnnounceResponse::new(60, 1, 1, peers, peers6).unwrap();
+        response.compact = false;
+        response.peer_ids.insert(
+            "127.0.0.1:6893".parse().unwrap(),
+            PeerId(*b"-DE9824-143964258012"),
+        );
+
+        let encoded = encode_announce_response(response);
+
+        // Peers without a known ID (e.g. permanent seeders) go without one
+        assert_eq!(encoded.as_slice(), &b"d8:completei1e10:incompletei1e8:intervali60e5:peersld2:ip9:127.0.0.17:peer id20:-DE9824-1439642580124:porti6893eed2:ip3:::14:porti6894eee10:tracker_id0:e"[..]);
+    }
+
+    #[test]
+    fn announce_response_external_ip_encoding() {
+        let mut response = AnnounceResponse::new(60, 100, 23, Vec::new(), Vec::new()).unwrap();
+        response.external_ip = Some("198.51.100.7".parse().unwrap());
+
+        let encoded = encode_announce_response(response);
+
+        assert_eq!(encoded.as_slice(), &b"d8:completei100e11:external ip4:\xc6\x33\x64\x0710:incompletei23e8:intervali60e5:peers0:6:peers60:10:tracker_id0:e"[..]);
+    }
+
+    #[test]
+    fn announce_response_downloaded_encoding() {
+        let mut response = AnnounceResponse::new(60, 100, 23, Vec::new(), Vec::new()).unwrap();
+        response.downloaded = Some(412);
+
+        let encoded = encode_announce_response(response);
+
+        assert_eq!(encoded.as_slice(), &b"d8:completei100e10:downloadedi412e10:incompletei23e8:intervali60e5:peers0:6:peers60:10:tracker_id0:e"[..]);
+    }
+
+    #[test]
+    fn announce_response_warning_encoding() {
+        let mut response = AnnounceResponse::new(60, 100, 23, Vec::new(), Vec::new()).unwrap();
+        response.warning_message = Some("Slow down".to_string());
+
+        let encoded = encode_announce_response(response);
+
+        assert_eq!(encoded.as_slice(), &b"d8:completei100e10:incompletei23e8:intervali60e5:peers0:6:peers60:10:tracker_id0:15:warning message9:Slow downe"[..]);
+    }
+
+    #[test]
+    fn announce_response_cached_peers_encoding() {
+        let peers = vec![CompactPeerv4 {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+        }];
+        let cached = AnnounceResponse::new(60, 1, 1, peers, Vec::new()).unwrap();
+
+        // The counts around the cached peers are the response's own
+        let mut response = AnnounceResponse::new(60, 100, 23, Vec::new(), Vec::new()).unwrap();
+        response.encoded_peers = Some(encode_announce_peers(&cached).into());
+        response.warning_message = Some("Slow down".to_string());
+
+        let encoded = encode_announce_response(response);
+
+        assert_eq!(encoded.as_slice(), &b"d8:completei100e10:incompletei23e8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xed6:peers60:10:tracker_id0:15:warning message9:Slow downe"[..]);
+    }
+
+    #[test]
+    fn announce_failure_retry_encoding() {
+        let failure = AnnounceResponse::failure_retry("Please upgrade".to_string(), Some(86400));
+        let encoded = encode_announce_response(failure);
+
+        assert_eq!(
+            encoded.as_slice(),
+            &b"d14:failure reason14:Please upgrade8:retry ini86400ee"[..]
+        );
+    }
+
+    #[test]
+    fn full_scrape_chunked_encoding() {
+        let files: Vec<ScrapeFile> = (0..FULL_SCRAPE_CHUNK_SIZE as u32 + 1)
+            .map(|n| ScrapeFile {
+                info_hash: format!("{:020}", n).into_bytes(),
+                complete: n,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut response = ScrapeResponse::new().unwrap();
+        for file in files.clone() {
+            response.add_file(file.info_hash.clone(), file);
+        }
+
+        let chunks: Vec<Vec<u8>> = encode_full_scrape(files).collect();
+
+        // Header, two chunks of files and the closing delimiters
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.concat(), encode_scrape_response(response));
+    }
+
+    #[test]
+    fn scrape_failure_encoding() {
+        let failure = ScrapeResponse::failure("Malformed scrape request".to_string());
+        let encoded = encode_scrape_response(failure);
+
+        assert_eq!(
+            encoded.as_slice(),
+            &b"d14:failure reason24:Malformed scrape requeste"[..]
+        );
+    }
+
+    #[test]
+    fn scrape_failure_retry_encoding() {
+        let failure = ScrapeResponse::failure_retry("Slow down".to_string(), Some(600));
+        let encoded = encode_scrape_response(failure);
+
+        assert_eq!(
+            encoded.as_slice(),
+            &b"d14:failure reason9:Slow down8:retry ini600ee"[..]
+        );
     }
 
     #[test]
@@ -149,33 +543,177 @@ mod tests {
 
         let encoded = encode_announce_response(failure);
 
-        assert_eq!(encoded.as_slice(), b"d14:failure_reason4:ouche");
+        assert_eq!(encoded.as_slice(), b"d14:failure reason4:ouche");
     }
 
     #[test]
     fn scrape_response_encoding() {
         let file1 = ScrapeFile {
-            info_hash: "blah".to_string(),
+            info_hash: b"blah".to_vec(),
             complete: 1,
             downloaded: 2,
             incomplete: 3,
             name: Some("test".to_string()),
+            upstream: None,
         };
 
         let file2 = ScrapeFile {
-            info_hash: "test".to_string(),
+            info_hash: b"test".to_vec(),
             complete: 4000,
             downloaded: 5678,
             incomplete: 785,
             name: Some("Reflections".to_string()),
+            upstream: None,
         };
 
         let mut scrape_response = ScrapeResponse::new().unwrap();
-        scrape_response.add_file("ABCDEFGHIJKLMNOPQRST".to_string(), file1);
-        scrape_response.add_file("TSRQPONMLKJIHGFEDCBA".to_string(), file2);
+        scrape_response.add_file(b"ABCDEFGHIJKLMNOPQRST".to_vec(), file1);
+        scrape_response.add_file(b"TSRQPONMLKJIHGFEDCBA".to_vec(), file2);
 
         let encoded = encode_scrape_response(scrape_response);
 
         assert_eq!(encoded.as_slice(), &b"d5:filesd20:ABCDEFGHIJKLMNOPQRSTd8:completei1e10:downloadedi2e10:incompletei3e4:name4:teste20:TSRQPONMLKJIHGFEDCBAd8:completei4000e10:downloadedi5678e10:incompletei785e4:name11:Reflectionseee"[..]);
     }
+
+    #[test]
+    fn scrape_response_upstream_encoding() {
+        let file = ScrapeFile {
+            info_hash: b"blah".to_vec(),
+            complete: 1,
+            downloaded: 2,
+            incomplete: 3,
+            name: None,
+            upstream: Some(UpstreamCounts {
+                complete: 10,
+                incomplete: 20,
+            }),
+        };
+
+        let mut scrape_response = ScrapeResponse::new().unwrap();
+        scrape_response.add_file(b"ABCDEFGHIJKLMNOPQRST".to_vec(), file);
+
+        let encoded = encode_scrape_response(scrape_response);
+
+        assert_eq!(encoded.as_slice(), &b"d5:filesd20:ABCDEFGHIJKLMNOPQRSTd8:completei1e10:downloadedi2e10:incompletei3e8:upstreamd8:completei10e10:incompletei20eeeee"[..]);
+    }
+
+    #[test]
+    fn scrape_counts_decoding() {
+        let response = b"d5:filesd20:ABCDEFGHIJKLMNOPQRSTd8:completei4e10:downloadedi9e10:incompletei2e4:name4:teste20:TSRQPONMLKJIHGFEDCBAd8:completei0e10:downloadedi0e10:incompletei7eee5:flagsd20:min_request_intervali900eee";
+
+        let counts = decode_scrape_counts(response).unwrap();
+
+        assert_eq!(
+            counts,
+            vec![
+                (
+                    InfoHash(*b"ABCDEFGHIJKLMNOPQRST"),
+                    UpstreamCounts {
+                        complete: 4,
+                        incomplete: 2
+                    }
+                ),
+                (
+                    InfoHash(*b"TSRQPONMLKJIHGFEDCBA"),
+                    UpstreamCounts {
+                        complete: 0,
+                        incomplete: 7
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_scrape_decoding() {
+        let files = vec![
+            ScrapeFile {
+                info_hash: b"ABCDEFGHIJKLMNOPQRST".to_vec(),
+                complete: 4,
+                downloaded: 9,
+                incomplete: 2,
+                name: None,
+                upstream: None,
+            },
+            ScrapeFile {
+                info_hash: b"TSRQPONMLKJIHGFEDCBA".to_vec(),
+                complete: 0,
+                downloaded: 3,
+                incomplete: 7,
+                name: None,
+                upstream: None,
+            },
+        ];
+        let encoded: Vec<u8> = encode_full_scrape(files).flatten().collect();
+
+        let decoded = decode_scrape_files(&encoded).unwrap();
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].info_hash, b"ABCDEFGHIJKLMNOPQRST");
+        assert_eq!(
+            (
+                decoded[0].complete,
+                decoded[0].downloaded,
+                decoded[0].incomplete
+            ),
+            (4, 9, 2)
+        );
+        assert_eq!(
+            (
+                decoded[1].complete,
+                decoded[1].downloaded,
+                decoded[1].incomplete
+            ),
+            (0, 3, 7)
+        );
+    }
+
+    #[test]
+    fn announce_peers_decoding() {
+        let compact = b"d8:completei1e10:incompletei0e8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e";
+        let (peers, peers6) = decode_announce_peers(compact).unwrap();
+
+        assert_eq!(
+            peers,
+            vec![CompactPeerv4 {
+                ip: Ipv4Addr::LOCALHOST,
+                port: 6881
+            }]
+        );
+        assert_eq!(
+            peers6,
+            vec![CompactPeerv6 {
+                ip: Ipv6Addr::new(0x2001, 0x0db8, 0, 0, 0, 0, 0, 1),
+                port: 6882
+            }]
+        );
+
+        let dictionary = b"d8:intervali1800e5:peersld2:ip9:10.0.0.127:peer id20:ABCDEFGHIJKLMNOPQRST4:porti51413eeee";
+        let (peers, peers6) = decode_announce_peers(dictionary).unwrap();
+
+        assert_eq!(
+            peers,
+            vec![CompactPeerv4 {
+                ip: Ipv4Addr::new(10, 0, 0, 12),
+                port: 51413
+            }]
+        );
+        assert!(peers6.is_empty());
+    }
+
+    #[test]
+    fn announce_compact_peers_round_trip() {
+        let peers = vec![CompactPeerv4 {
+            ip: Ipv4Addr::new(10, 0, 0, 12),
+            port: 51413,
+        }];
+        let peers6 = vec![CompactPeerv6 {
+            ip: Ipv6Addr::new(0x2001, 0x0db8, 0, 0, 0, 0, 0, 1),
+            port: 6882,
+        }];
+        let response = AnnounceResponse::new(1800, 1, 1, peers.clone(), peers6.clone()).unwrap();
+        let encoded = encode_announce_response(response);
+
+        assert_eq!(decode_announce_peers(&encoded).unwrap(), (peers, peers6));
+    }
 }
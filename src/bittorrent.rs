@@ -2,15 +2,18 @@
 // Most of the information is coming from the following link:
 // https://wiki.theory.org/index.php/BitTorrentSpecification
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
 
 use bytes::BufMut;
 use percent_encoding;
-use regex::Regex;
-use url::form_urlencoded;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::errors::ClientError;
 use crate::util::{string_to_event, Event};
@@ -19,21 +22,129 @@ trait Compact {
     fn compact(&self) -> Vec<u8>;
 }
 
+pub const V1_HASH_LEN: usize = 20;
+pub const V2_HASH_LEN: usize = 32;
+
+// Info hashes and peer IDs are raw 20-byte values. Clients send them
+// percent-encoded and they are rarely valid UTF-8, so they are kept as
+// bytes from parsing to storage, and only rendered as hex for people.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash(pub [u8; V1_HASH_LEN]);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(pub [u8; 20]);
+
+impl InfoHash {
+    // BEP 52: v2 torrents are identified by a 32-byte SHA-256 hash, which is
+    // truncated to 20 bytes wherever only a v1-sized hash fits, trackers
+    // included. Full hashes are truncated the same way, so that clients
+    // sending either form of the hash end up in the same swarm.
+    pub fn from_bytes(bytes: &[u8]) -> Option<InfoHash> {
+        match bytes.len() {
+            V1_HASH_LEN | V2_HASH_LEN => {
+                let mut hash = [0; V1_HASH_LEN];
+                hash.copy_from_slice(&bytes[..V1_HASH_LEN]);
+                Some(InfoHash(hash))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl PeerId {
+    pub fn from_bytes(bytes: &[u8]) -> Option<PeerId> {
+        let mut id = [0; 20];
+        if bytes.len() != id.len() {
+            return None;
+        }
+        id.copy_from_slice(bytes);
+        Some(PeerId(id))
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some(digit(*hi)? << 4 | digit(*lo)?),
+            _ => None,
+        })
+        .collect()
+}
+
+// Both types are written as lowercase hex in logs, config, JSON and the database
+macro_rules! hex_id {
+    ($id:ident) => {
+        impl $id {
+            pub fn to_hex(&self) -> String {
+                self.to_string()
+            }
+        }
+
+        impl fmt::Display for $id {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                for byte in &self.0 {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+
+        impl fmt::Debug for $id {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}({})", stringify!($id), self)
+            }
+        }
+
+        impl FromStr for $id {
+            type Err = &'static str;
+
+            fn from_str(s: &str) -> Result<$id, Self::Err> {
+                decode_hex(s)
+                    .and_then(|bytes| $id::from_bytes(&bytes))
+                    .ok_or(concat!("invalid hex ", stringify!($id)))
+            }
+        }
+
+        impl Serialize for $id {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $id {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$id, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+hex_id!(InfoHash);
+hex_id!(PeerId);
+
 // These two peer types could probably be implemented more elegantly
 // with a trait, but there's only two types right now, so it's not a lot of work
 #[derive(Clone, Eq, Ord, PartialOrd, Debug)]
 pub struct Peerv4 {
-    pub peer_id: String,
+    pub peer_id: PeerId,
     pub ip: Ipv4Addr,
     pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
     pub last_announced: Instant,
 }
 
 #[derive(Clone, Eq, Ord, PartialOrd, Debug)]
 pub struct Peerv6 {
-    pub peer_id: String,
+    pub peer_id: PeerId,
     pub ip: Ipv6Addr,
     pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
     pub last_announced: Instant,
 }
 
@@ -147,6 +258,73 @@ pub enum Peer {
     V6(Peerv6),
 }
 
+impl Peer {
+    pub fn new(peer_id: PeerId, ip: IpAddr, port: u16, uploaded: u64, downloaded: u64) -> Peer {
+        match ip {
+            IpAddr::V4(ip) => Peer::V4(Peerv4 {
+                peer_id,
+                ip,
+                port,
+                uploaded,
+                downloaded,
+                last_announced: Instant::now(),
+            }),
+            IpAddr::V6(ip) => Peer::V6(Peerv6 {
+                peer_id,
+                ip,
+                port,
+                uploaded,
+                downloaded,
+                last_announced: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        match self {
+            Peer::V4(p) => p.peer_id,
+            Peer::V6(p) => p.peer_id,
+        }
+    }
+
+    // Clients report totals for the current session, so these
+    // are only meaningful when compared against a previous announce
+    pub fn traffic(&self) -> (u64, u64) {
+        match self {
+            Peer::V4(p) => (p.uploaded, p.downloaded),
+            Peer::V6(p) => (p.uploaded, p.downloaded),
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Peer::V4(p) => IpAddr::V4(p.ip),
+            Peer::V6(p) => IpAddr::V6(p.ip),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Peer::V4(p) => p.port,
+            Peer::V6(p) => p.port,
+        }
+    }
+
+    pub fn last_announced(&self) -> Instant {
+        match self {
+            Peer::V4(p) => p.last_announced,
+            Peer::V6(p) => p.last_announced,
+        }
+    }
+
+    pub fn set_last_announced(&mut self, time: Instant) {
+        match self {
+            Peer::V4(p) => p.last_announced = time,
+            Peer::V6(p) => p.last_announced = time,
+        }
+    }
+}
+
 impl Compact for Peer {
     fn compact(&self) -> Vec<u8> {
         match self {
@@ -171,14 +349,17 @@ impl Compact for CompactPeer {
     }
 }
 
+// Used when a client doesn't say how many peers it wants
+pub const DEFAULT_NUMWANT: u32 = 50;
+
 #[derive(Debug)]
 pub struct AnnounceRequest {
-    pub info_hash: String,
+    pub info_hash: InfoHash,
     pub peer: Peer,
     pub port: u16,
-    pub uploaded: u32,
-    pub downloaded: u32,
-    pub left: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
     pub compact: bool,
     pub no_peer_id: bool,
     pub event: Event,
@@ -186,17 +367,83 @@ pub struct AnnounceRequest {
     pub numwant: Option<u32>,
     pub key: Option<String>,
     pub trackerid: Option<String>,
+    // Taken from the announce URL's path rather than the query
+    pub passkey: Option<String>,
+}
+
+// Decodes a single query component, handling both '+' and percent-escapes.
+// Nothing is allocated unless the component actually contains a '+'.
+fn decode_component(input: &[u8]) -> Cow<'_, [u8]> {
+    if input.contains(&b'+') {
+        let replaced: Vec<u8> = input
+            .iter()
+            .map(|&b| if b == b'+' { b' ' } else { b })
+            .collect();
+        Cow::Owned(percent_encoding::percent_decode(&replaced).collect())
+    } else {
+        percent_encoding::percent_decode(input).into()
+    }
+}
+
+// Splits a raw query string into its keys and still-encoded values
+fn query_pairs(url_string: &str) -> impl Iterator<Item = (&[u8], &[u8])> {
+    url_string
+        .as_bytes()
+        .split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.iter().position(|&b| b == b'=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, &pair[pair.len()..]),
+        })
+}
+
+// Returns the decoded bytes of the first value for a key, if present
+pub fn query_value(url_string: &str, key: &[u8]) -> Option<Vec<u8>> {
+    query_pairs(url_string)
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| decode_component(value).into_owned())
+}
+
+// Returns the decoded bytes of every value for a key, in order
+pub fn query_values<'a>(
+    url_string: &'a str,
+    key: &'a [u8],
+) -> impl Iterator<Item = Cow<'a, [u8]>> + 'a {
+    query_pairs(url_string)
+        .filter(move |(k, _)| *k == key)
+        .map(|(_, value)| decode_component(value))
+}
+
+fn parse_value<T: FromStr>(input: &[u8]) -> Option<T> {
+    std::str::from_utf8(&decode_component(input))
+        .ok()?
+        .parse()
+        .ok()
+}
+
+// Connection addresses come in as "ip:port", "[ipv6]:port", or as a bare
+// address when forwarded by a proxy, so the port is stripped off by hand.
+pub fn parse_remote_addr(addr: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = addr.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+
+    if let Ok(ip) = addr.parse() {
+        return Some(ip);
+    }
+
+    addr.rsplit_once(':')?.0.parse().ok()
 }
 
 impl AnnounceRequest {
+    // Failures are returned as the response to send back
+    #[allow(clippy::result_large_err)]
     pub fn new(
         url_string: &str,
         req_ip: Option<&str>,
     ) -> Result<AnnounceRequest, AnnounceResponse> {
-        let request_kv_pairs = form_urlencoded::parse(url_string.as_bytes()).into_owned();
-
-        let mut info_hash: String = "".to_string();
-        let mut peer_string: String = "".to_string();
+        let mut info_hash = None;
+        let mut peer_id = None;
         let mut port = 0;
         let mut uploaded = 0;
         let mut downloaded = 0;
@@ -209,130 +456,85 @@ impl AnnounceRequest {
         let mut key = None;
         let mut trackerid = None;
 
+        let malformed = || AnnounceResponse::failure(ClientError::MalformedAnnounce.text());
+
+        // This is a single pass over the raw query bytes; values are only
+        // decoded in place and the only owned data is what ends up in the request.
         // If any request does not properly encode these paramters,
         // return an AnnounceFailure to be sent to the client
-        for (k, value) in request_kv_pairs {
-            match k.as_str() {
-                "info_hash" => {
-                    match percent_encoding::percent_decode(value.as_bytes()).decode_utf8() {
-                        Ok(s) => info_hash = s.to_string(),
-                        _ => {
-                            return Err(AnnounceResponse::failure(
-                                ClientError::MalformedAnnounce.text(),
-                            ))
-                        }
-                    }
-                }
-                "peer_id" => peer_string = value,
-                "port" => match value.parse::<u16>() {
-                    Ok(n) => port = n,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+        for (k, value) in query_pairs(url_string) {
+            match k {
+                b"info_hash" => match InfoHash::from_bytes(&decode_component(value)) {
+                    Some(hash) => info_hash = Some(hash),
+                    _ => return Err(malformed()),
                 },
-                "uploaded" => match value.parse::<u32>() {
-                    Ok(n) => uploaded = n,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"peer_id" => match PeerId::from_bytes(&decode_component(value)) {
+                    Some(id) => peer_id = Some(id),
+                    _ => return Err(malformed()),
                 },
-                "downloaded" => match value.parse::<u32>() {
-                    Ok(n) => downloaded = n,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"port" => match parse_value::<u16>(value) {
+                    Some(n) => port = n,
+                    _ => return Err(malformed()),
                 },
-                "left" => match value.parse::<u32>() {
-                    Ok(n) => left = n,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"uploaded" => match parse_value::<u64>(value) {
+                    Some(n) => uploaded = n,
+                    _ => return Err(malformed()),
                 },
-                "compact" => match value.parse::<u32>() {
-                    Ok(n) => compact = n != 0,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"downloaded" => match parse_value::<u64>(value) {
+                    Some(n) => downloaded = n,
+                    _ => return Err(malformed()),
                 },
-                "no_peer_id" => match value.parse::<u32>() {
-                    Ok(n) => no_peer_id = n != 0,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"left" => match parse_value::<u64>(value) {
+                    Some(n) => left = n,
+                    _ => return Err(malformed()),
                 },
-                "event" => match string_to_event(value) {
-                    Ok(ev) => event = ev,
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"compact" => match parse_value::<u32>(value) {
+                    Some(n) => compact = n != 0,
+                    _ => return Err(malformed()),
                 },
-                "ip" => match value.parse::<IpAddr>() {
-                    Ok(addr) => ip = Some(addr),
-                    _ => {
-                        return Err(AnnounceResponse::failure(
-                            ClientError::MalformedAnnounce.text(),
-                        ))
-                    }
+                b"no_peer_id" => match parse_value::<u32>(value) {
+                    Some(n) => no_peer_id = n != 0,
+                    _ => return Err(malformed()),
                 },
-                "numwant" => match value.parse::<u32>() {
-                    Ok(n) => numwant = Some(n),
-                    _ => numwant = Some(50),
+                b"event" => match std::str::from_utf8(&decode_component(value)) {
+                    Ok(s) => match string_to_event(s) {
+                        Ok(ev) => event = ev,
+                        _ => return Err(malformed()),
+                    },
+                    _ => return Err(malformed()),
                 },
-                "key" => key = Some(value),
-                "trackerid" => trackerid = Some(value),
+                b"ip" => match parse_value::<IpAddr>(value) {
+                    Some(addr) => ip = Some(addr),
+                    _ => return Err(malformed()),
+                },
+                b"numwant" => match parse_value::<u32>(value) {
+                    Some(n) => numwant = Some(n),
+                    _ => numwant = None,
+                },
+                b"key" => {
+                    key = Some(String::from_utf8_lossy(&decode_component(value)).into_owned())
+                }
+                b"trackerid" => {
+                    trackerid = Some(String::from_utf8_lossy(&decode_component(value)).into_owned())
+                }
                 _ => {}
             }
         }
 
-        // This should not be the default value
-        if info_hash == "" {
-            return Err(AnnounceResponse::failure(
-                ClientError::MalformedAnnounce.text(),
-            ));
-        }
+        // Both of these are required, and have no sensible default
+        let (info_hash, peer_id) = match (info_hash, peer_id) {
+            (Some(info_hash), Some(peer_id)) => (info_hash, peer_id),
+            _ => return Err(malformed()),
+        };
 
-        // Digusting unwrap sequence, but whatever.
         if ip.is_none() {
-            if let Some(addr) = req_ip {
-                if addr.starts_with('[') {
-                    let re = Regex::new(r"\[(.*)\]").unwrap();
-                    let caps = re.captures(addr).unwrap();
-                    let ip_string = &caps[0];
-                    ip = Some(ip_string.parse().unwrap());
-                } else {
-                    let ip_string: Vec<&str> = addr.split(':').collect();
-                    ip = Some(ip_string[0].parse().unwrap());
-                }
-            }
+            ip = req_ip.and_then(parse_remote_addr);
         }
 
-        let peer = match ip.unwrap() {
-            IpAddr::V4(i) => Peer::V4(Peerv4 {
-                peer_id: peer_string,
-                ip: i,
-                port,
-                last_announced: Instant::now(),
-            }),
-            IpAddr::V6(i) => Peer::V6(Peerv6 {
-                peer_id: peer_string,
-                ip: i,
-                port,
-                last_announced: Instant::now(),
-            }),
+        // Without an address, the peer is of no use to anyone
+        let peer = match ip {
+            Some(addr) => Peer::new(peer_id, addr, port, uploaded, downloaded),
+            None => return Err(malformed()),
         };
 
         Ok(AnnounceRequest {
@@ -349,6 +551,7 @@ impl AnnounceRequest {
             numwant,
             key,
             trackerid,
+            passkey: None,
         })
     }
 }
@@ -358,13 +561,48 @@ impl AnnounceRequest {
 #[derive(Default, Debug)]
 pub struct AnnounceResponse {
     pub failure_reason: Option<String>,
+    pub retry_in: Option<u64>,
+    pub warning_message: Option<String>,
     pub interval: u32,
     pub min_interval: Option<u32>,
     pub tracker_id: String,
     pub complete: u32,
+    pub downloaded: Option<u32>,
     pub incomplete: u32,
     pub peers: Vec<CompactPeerv4>,
     pub peers6: Vec<CompactPeerv6>,
+    // Peers are sent as dictionaries unless the client asked for compact
+    // ones. IDs are only known for peers from the local swarm.
+    pub compact: bool,
+    pub peer_ids: HashMap<SocketAddr, PeerId>,
+    pub external_ip: Option<IpAddr>,
+    // The peers already encoded for the client, as the response cache
+    // holds them. When set, these are sent in place of the peer lists.
+    pub encoded_peers: Option<Arc<[u8]>>,
+}
+
+// How the peers in an announce response are laid out for the client.
+// Each takes its own encoding, so cached responses are kept per format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerFormat {
+    // BEP 23 byte strings
+    Compact,
+    // Dictionaries, with peer IDs unless the client opted out
+    Dictionary { peer_ids: bool },
+    // The packed peers of one address family, as UDP responses carry them
+    Datagram { v6: bool },
+}
+
+impl PeerFormat {
+    pub fn of(request: &AnnounceRequest) -> PeerFormat {
+        if request.compact {
+            PeerFormat::Compact
+        } else {
+            PeerFormat::Dictionary {
+                peer_ids: !request.no_peer_id,
+            }
+        }
+    }
 }
 
 impl AnnounceResponse {
@@ -377,13 +615,20 @@ impl AnnounceResponse {
     ) -> Result<AnnounceResponse, &'static str> {
         Ok(AnnounceResponse {
             failure_reason: None,
+            retry_in: None,
+            warning_message: None,
             interval,
             min_interval: None,
             tracker_id: "".to_string(),
             complete,
+            downloaded: None,
             incomplete,
             peers,
             peers6,
+            compact: true,
+            peer_ids: HashMap::new(),
+            external_ip: None,
+            encoded_peers: None,
         })
     }
 
@@ -394,6 +639,15 @@ impl AnnounceResponse {
         }
     }
 
+    // BEP 31: tells the client how long to wait before trying again
+    pub fn failure_retry(reason: String, retry_in: Option<u64>) -> AnnounceResponse {
+        AnnounceResponse {
+            failure_reason: Some(reason),
+            retry_in,
+            ..Default::default()
+        }
+    }
+
     pub fn peersv4_as_compact(&self) -> Vec<u8> {
         let mut compact_peers = Vec::new();
         for peer in &self.peers {
@@ -411,27 +665,37 @@ impl AnnounceResponse {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct ScrapeFile {
-    pub info_hash: String,
+    pub info_hash: Vec<u8>,
     pub complete: u32,
     pub downloaded: u32,
     pub incomplete: u32,
     pub name: Option<String>,
+    pub upstream: Option<UpstreamCounts>,
+}
+
+// Seeder and leecher counts for a torrent as reported by other trackers.
+// These are kept apart from local counts so that they are clearly flagged.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct UpstreamCounts {
+    pub complete: u32,
+    pub incomplete: u32,
 }
 
+// Hashes are kept exactly as requested, since that is
+// how they have to be reported back to the client
 pub struct ScrapeRequest {
-    pub info_hashes: Vec<String>,
+    pub info_hashes: Vec<Vec<u8>>,
 }
 
 impl ScrapeRequest {
     pub fn new(url_string: &str) -> Result<ScrapeRequest, ScrapeResponse> {
-        let request_kv_pairs = form_urlencoded::parse(url_string.as_bytes()).into_owned();
         let mut info_hashes = Vec::new();
 
-        for (key, value) in request_kv_pairs {
-            match key.as_str() {
-                "info_hash" => info_hashes.push(value),
+        for (key, value) in query_pairs(url_string) {
+            match key {
+                b"info_hash" => info_hashes.push(decode_component(value).into_owned()),
                 _ => {
                     return Err(ScrapeResponse::failure(
                         "Malformed scrape request".to_string(),
@@ -447,13 +711,16 @@ impl ScrapeRequest {
 #[derive(Default, Debug)]
 pub struct ScrapeResponse {
     pub failure_reason: Option<String>,
-    pub files: HashMap<String, ScrapeFile>,
+    pub retry_in: Option<u64>,
+    pub files: HashMap<Vec<u8>, ScrapeFile>,
 }
 
 impl ScrapeResponse {
+    #[allow(clippy::result_unit_err)]
     pub fn new() -> Result<ScrapeResponse, ()> {
         Ok(ScrapeResponse {
             failure_reason: None,
+            retry_in: None,
             files: HashMap::new(),
         })
     }
@@ -465,7 +732,16 @@ impl ScrapeResponse {
         }
     }
 
-    pub fn add_file(&mut self, info_hash: String, scrape_file: ScrapeFile) {
+    // BEP 31: scrapes can be told to back off the same way announces are
+    pub fn failure_retry(reason: String, retry_in: Option<u64>) -> ScrapeResponse {
+        ScrapeResponse {
+            failure_reason: Some(reason),
+            retry_in,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_file(&mut self, info_hash: Vec<u8>, scrape_file: ScrapeFile) {
         self.files.insert(info_hash, scrape_file);
     }
 }
@@ -491,6 +767,99 @@ mod tests {
         );
     }
 
+    #[test]
+    fn announce_good_request_creation() {
+        let url_string = "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+             &port=6881&uploaded=9000&downloaded=1000&left=727955456&event=started\
+             &numwant=30&compact=1&key=ab%2Bc+d";
+
+        let request = AnnounceRequest::new(url_string, Some("127.0.0.1:51413")).unwrap();
+
+        assert_eq!(request.info_hash, InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"));
+        assert_eq!(request.peer.peer_id(), PeerId(*b"-DE9824-143964258012"));
+        assert_eq!(request.port, 6881);
+        assert_eq!(request.left, 727955456);
+        assert_eq!(request.event, Event::Started);
+        assert_eq!(request.ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(request.key, Some("ab+c d".to_string()));
+        assert!(request.compact);
+    }
+
+    #[test]
+    fn announce_remote_addr_forms() {
+        let url_string = "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+             &port=6881&uploaded=0&downloaded=0&left=0";
+        let ip = |remote| {
+            AnnounceRequest::new(url_string, remote)
+                .ok()
+                .map(|r| r.peer.ip())
+        };
+
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(ip(Some("[2001:db8::1]:51413")).unwrap(), v6);
+        assert_eq!(ip(Some("[2001:db8::1]")).unwrap(), v6);
+        assert_eq!(ip(Some("2001:db8::1")).unwrap(), v6);
+        assert_eq!(
+            ip(Some("127.0.0.1")).unwrap(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+
+        // Announces whose address can't be made out are refused rather than panicking
+        assert!(ip(Some("unknown")).is_none());
+        assert!(ip(Some("[]:51413")).is_none());
+        assert!(ip(Some("")).is_none());
+        assert!(ip(None).is_none());
+    }
+
+    #[test]
+    fn announce_v2_hash_truncated() {
+        let url_string = "info_hash=A1B2C3D4E5F6G7H8I9J0K1L2M3N4O5P6\
+             &peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=0";
+
+        let request = AnnounceRequest::new(url_string, Some("127.0.0.1:51413")).unwrap();
+
+        assert_eq!(request.info_hash, InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"));
+    }
+
+    #[test]
+    fn announce_binary_hash() {
+        let url_string = "info_hash=%90%28%9F%D3M%FC%1C%F8%F3%16%A2h%AD%D85L%853DX\
+             &peer_id=%2DDE9824%2D%FF%FE%FD%FC%FB%FA%F9%F8%F7%F6%F5%F4&port=6881\
+             &uploaded=0&downloaded=0&left=0";
+
+        let request = AnnounceRequest::new(url_string, Some("127.0.0.1:51413")).unwrap();
+
+        assert_eq!(
+            request.info_hash.to_hex(),
+            "90289fd34dfc1cf8f316a268add8354c85334458"
+        );
+        assert_eq!(
+            request.peer.peer_id().to_hex(),
+            "2d4445393832342dfffefdfcfbfaf9f8f7f6f5f4"
+        );
+    }
+
+    #[test]
+    fn announce_hash_length_validated() {
+        let url_string = "info_hash=A1B2C3D4&peer_id=-DE9824-143964258012\
+             &port=6881&uploaded=0&downloaded=0&left=0";
+        assert!(AnnounceRequest::new(url_string, Some("127.0.0.1:51413")).is_err());
+
+        let url_string = "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-\
+             &port=6881&uploaded=0&downloaded=0&left=0";
+        assert!(AnnounceRequest::new(url_string, Some("127.0.0.1:51413")).is_err());
+    }
+
+    #[test]
+    fn info_hash_hex_round_trip() {
+        let hash: InfoHash = "90289FD34DFC1CF8F316A268ADD8354C85334458".parse().unwrap();
+        assert_eq!(hash.to_hex(), "90289fd34dfc1cf8f316a268add8354c85334458");
+        assert!("90289fd3".parse::<InfoHash>().is_err());
+        assert!("zz289fd34dfc1cf8f316a268add8354c85334458"
+            .parse::<InfoHash>()
+            .is_err());
+    }
+
     #[test]
     fn announce_failure_return() {
         let failure_reason = "It's not you...no, it's just you".to_string();
@@ -512,9 +881,7 @@ mod tests {
             port: 6894,
         };
 
-        let mut peers: Vec<CompactPeerv4> = Vec::new();
-        peers.push(peerv4_1);
-        peers.push(peerv4_2);
+        let peers: Vec<CompactPeerv4> = vec![peerv4_1, peerv4_2];
 
         let peerv6_1 = CompactPeerv6 {
             ip: Ipv6Addr::new(
@@ -529,9 +896,7 @@ mod tests {
             port: 6699,
         };
 
-        let mut peers6: Vec<CompactPeerv6> = Vec::new();
-        peers6.push(peerv6_1);
-        peers6.push(peerv6_2);
+        let peers6: Vec<CompactPeerv6> = vec![peerv6_1, peerv6_2];
 
         let response = AnnounceResponse::new(60, 100, 23, peers, peers6);
 
@@ -541,9 +906,11 @@ mod tests {
     #[test]
     fn peerv4_compact_transform() {
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6681,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
@@ -559,17 +926,19 @@ mod tests {
     #[test]
     fn peerv6_compact_transform() {
         let peer = Peer::V6(Peerv6 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv6Addr::new(
                 0x2001, 0x0db8, 0x85a3, 0x0000, 0x0000, 0x8a2e, 0x0370, 0x7334,
             ),
             port: 6681,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
         let mut localhost_port_byte_string = vec![];
-        let localhost_decimal = 42540766452641154071740215577757643572 as u128;
-        let port = 6681 as u16;
+        let localhost_decimal = 42540766452641154071740215577757643572_u128;
+        let port = 6681_u16;
         localhost_port_byte_string.put_slice(&localhost_decimal.to_be_bytes());
         localhost_port_byte_string.put_slice(&port.to_be_bytes());
 
@@ -595,9 +964,9 @@ mod tests {
         assert_eq!(
             scrape.info_hashes,
             vec![
-                "aaaaaaaaaaaaaaaaaaaa",
-                "bbbbbbbbbbbbbbbbbbbb",
-                "cccccccccccccccccccc"
+                b"aaaaaaaaaaaaaaaaaaaa".to_vec(),
+                b"bbbbbbbbbbbbbbbbbbbb".to_vec(),
+                b"cccccccccccccccccccc".to_vec()
             ]
         );
     }
@@ -616,7 +985,7 @@ mod tests {
     fn scrape_response_add_file() {
         let file = ScrapeFile::default();
         let mut scrape_response = ScrapeResponse::new().unwrap();
-        scrape_response.add_file("test".to_string(), file);
+        scrape_response.add_file(b"test".to_vec(), file);
 
         assert_eq!(scrape_response.files.len(), 1);
     }
@@ -0,0 +1,276 @@
+// Address blocklists, compiled into sorted and merged ranges so that
+// lookups are a binary search no matter how many entries a list has.
+// Both P2P-format ("description:1.2.3.0-1.2.3.255") and CIDR entries
+// are understood, and may be mixed within a single file.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "actix")]
+use awc::ClientBuilder;
+
+use crate::config::BlocklistSource;
+
+// Published lists run to several megabytes
+#[cfg(feature = "actix")]
+const DOWNLOAD_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct RangeSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => {
+                if end > last.1 {
+                    last.1 = end;
+                }
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], addr: T) -> bool {
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if end < addr {
+                std::cmp::Ordering::Less
+            } else if start > addr {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+fn cidr_v4(ip: Ipv4Addr, prefix: u32) -> Option<(u32, u32)> {
+    let mask = match prefix {
+        0 => 0,
+        1..=32 => u32::MAX << (32 - prefix),
+        _ => return None,
+    };
+    let start = u32::from(ip) & mask;
+    Some((start, start | !mask))
+}
+
+fn cidr_v6(ip: Ipv6Addr, prefix: u32) -> Option<(u128, u128)> {
+    let mask = match prefix {
+        0 => 0,
+        1..=128 => u128::MAX << (128 - prefix),
+        _ => return None,
+    };
+    let start = u128::from(ip) & mask;
+    Some((start, start | !mask))
+}
+
+impl RangeSet {
+    // Lines that can't be understood are skipped and counted
+    // so that the caller can report on the quality of a list
+    pub fn parse(contents: &str) -> (RangeSet, usize) {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        let mut skipped = 0;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parsed = if let Some((ip, prefix)) = line.split_once('/') {
+                match (ip.parse::<IpAddr>(), prefix.parse::<u32>()) {
+                    (Ok(IpAddr::V4(ip)), Ok(prefix)) => cidr_v4(ip, prefix).map(|r| v4.push(r)),
+                    (Ok(IpAddr::V6(ip)), Ok(prefix)) => cidr_v6(ip, prefix).map(|r| v6.push(r)),
+                    _ => None,
+                }
+            } else if line.contains('-') {
+                // P2P lines only carry IPv4 ranges, after the description
+                let range = line.rsplit_once(':').map_or(line, |(_, r)| r);
+                range.split_once('-').and_then(|(start, end)| {
+                    match (
+                        start.trim().parse::<Ipv4Addr>(),
+                        end.trim().parse::<Ipv4Addr>(),
+                    ) {
+                        (Ok(start), Ok(end)) if start <= end => {
+                            v4.push((start.into(), end.into()));
+                            Some(())
+                        }
+                        _ => None,
+                    }
+                })
+            } else {
+                match line.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(ip)) => {
+                        v4.push((ip.into(), ip.into()));
+                        Some(())
+                    }
+                    Ok(IpAddr::V6(ip)) => {
+                        v6.push((ip.into(), ip.into()));
+                        Some(())
+                    }
+                    Err(_) => None,
+                }
+            };
+
+            if parsed.is_none() {
+                skipped += 1;
+            }
+        }
+
+        (
+            RangeSet {
+                v4: merge(v4),
+                v6: merge(v6),
+            },
+            skipped,
+        )
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4() {
+                // Mapped addresses are checked against the v4 ranges too
+                Some(mapped) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                    contains(&self.v4, u32::from(mapped))
+                }
+                _ => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Ranges taken from the config, which are swapped out when it's reloaded
+#[derive(Debug, Clone, Default)]
+pub struct SharedRanges(Arc<RwLock<RangeSet>>);
+
+impl SharedRanges {
+    pub fn parse(entries: &[String]) -> SharedRanges {
+        let ranges = SharedRanges::default();
+        ranges.replace(entries);
+        ranges
+    }
+
+    pub fn replace(&self, entries: &[String]) {
+        let (ranges, skipped) = RangeSet::parse(&entries.join("\n"));
+        if skipped > 0 {
+            warn!("Skipped {} invalid address ranges", skipped);
+        }
+        *self.0.write().unwrap() = ranges;
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.read().unwrap().contains(ip)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    pub name: String,
+    pub ranges: RangeSet,
+}
+
+#[cfg(feature = "actix")]
+async fn download(url: &str) -> Result<String, String> {
+    let client = ClientBuilder::new().finish();
+    let mut response = client.get(url).send().await.map_err(|e| e.to_string())?;
+    let body = response
+        .body()
+        .limit(DOWNLOAD_SIZE_LIMIT)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+// Without an HTTP client, lists can only be read from files
+#[cfg(not(feature = "actix"))]
+async fn download(_url: &str) -> Result<String, String> {
+    Err("tyto was built without the actix feature".to_string())
+}
+
+impl Blocklist {
+    // Sources starting with a URL scheme are downloaded,
+    // and anything else is read from the local filesystem
+    pub async fn load(source: &BlocklistSource) -> Result<Blocklist, String> {
+        let contents =
+            if source.location.starts_with("http://") || source.location.starts_with("https://") {
+                download(&source.location).await?
+            } else {
+                fs::read_to_string(&source.location).map_err(|e| e.to_string())?
+            };
+
+        let (ranges, skipped) = RangeSet::parse(&contents);
+        if skipped > 0 {
+            warn!(
+                "Skipped {} unreadable entries in blocklist {}",
+                skipped, &source.name
+            );
+        }
+
+        Ok(Blocklist {
+            name: source.name.clone(),
+            ranges,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklist_parse_mixed_formats() {
+        let contents = "\
+# Comment lines and blank lines are ignored
+
+Some Bad Range:1.2.3.0-1.2.3.255
+Another: Range:10.0.0.5-10.0.0.9
+192.168.0.0/16
+2001:db8::/32
+203.0.113.7
+this is not an entry
+";
+        let (ranges, skipped) = RangeSet::parse(contents);
+
+        assert_eq!(skipped, 1);
+        assert!(ranges.contains("1.2.3.128".parse().unwrap()));
+        assert!(ranges.contains("10.0.0.9".parse().unwrap()));
+        assert!(!ranges.contains("10.0.0.10".parse().unwrap()));
+        assert!(ranges.contains("192.168.44.1".parse().unwrap()));
+        assert!(ranges.contains("203.0.113.7".parse().unwrap()));
+        assert!(ranges.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!ranges.contains("2001:db9::1".parse().unwrap()));
+        assert!(ranges.contains("::ffff:1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn blocklist_ranges_merged() {
+        let (ranges, _) = RangeSet::parse("1.0.0.0/24\n1.0.0.128/25\n1.0.0.100-1.0.1.5\n");
+
+        assert_eq!(ranges.len(), 1);
+        assert!(ranges.contains("1.0.1.5".parse().unwrap()));
+        assert!(!ranges.contains("1.0.1.6".parse().unwrap()));
+    }
+}
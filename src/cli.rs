@@ -0,0 +1,201 @@
+use std::fs;
+use std::io;
+#[cfg(feature = "actix")]
+use std::time::Duration;
+
+#[cfg(feature = "actix")]
+use awc::ClientBuilder;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use crate::config::Config;
+use crate::errors::InternalError;
+use crate::network::admin::ImportRequest;
+use crate::storage::backend::Backend;
+use crate::storage::{Torrent, TorrentStore};
+
+// Full scrapes of large trackers take a while to come through
+#[cfg(feature = "actix")]
+const SCRAPE_FETCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub fn app() -> App<'static, 'static> {
+    App::new("tyto")
+        .version("0.5.5")
+        .author("Alexander Decurnou. <ad@alx.xyz>")
+        .about("A BitTorrent tracker that aims to be distributed, fast, and fault-tolerant.")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .alias("configuration")
+                .value_name("CONFIG_FILE")
+                .help("Use this configuration instead of config.toml")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Log at this level (error, warn, info, debug, trace) instead of RUST_LOG")
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Start the tracker (the default when no command is given)")
+                .arg(
+                    Arg::with_name("bind")
+                        .long("bind")
+                        .value_name("ADDRESS")
+                        .help("Listen on this address instead of network.binding")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Check that the configuration parses, then exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Write torrents from a JSON file into the storage backend")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("File in the same format as the admin import route")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-scrape")
+                .about(
+                    "Write the torrents in another tracker's full scrape into the storage backend",
+                )
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .help("The tracker's scrape URL, or a file holding its bencoded output")
+                        .required(true),
+                ),
+        )
+}
+
+// Global args can be given before or after the subcommand
+pub fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    matches
+        .subcommand()
+        .1
+        .and_then(|sub| sub.value_of(name))
+        .or_else(|| matches.value_of(name))
+}
+
+fn invalid_config(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid configuration at {}", path),
+    )
+}
+
+// Parses the config the same strict way a reload does,
+// so that mistakes are caught before a tracker uses it
+pub fn check_config(path: &str) -> io::Result<()> {
+    let config = Config::from_file(path).map_err(|_| invalid_config(path))?;
+    config.log_settings();
+    info!("Configuration at {} is valid.", path);
+    Ok(())
+}
+
+async fn connect_backend(config: &Config) -> io::Result<Backend> {
+    Backend::connect(&config.storage).await.map_err(|e| {
+        error!("{} ({})", InternalError::StorageUnreachable.text(), e);
+        io::Error::from(io::ErrorKind::NotConnected)
+    })
+}
+
+async fn flush_torrents(backend: &Backend, torrents: Vec<Torrent>) -> io::Result<()> {
+    backend.flush_torrents(torrents).await.map_err(|e| {
+        error!("{} ({})", InternalError::StorageTorrentFlush.text(), e);
+        io::Error::from(io::ErrorKind::Other)
+    })
+}
+
+// Seeds the storage backend with torrents, e.g. when moving to Tyto from
+// another tracker. Peers only exist in a running tracker, so any in the
+// file are skipped; they can be sent to the admin import route instead.
+pub async fn import(config_path: &str, file: &str) -> io::Result<()> {
+    let config = Config::from_file(config_path).map_err(|_| invalid_config(config_path))?;
+    let request: ImportRequest = serde_json::from_slice(&fs::read(file)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if !request.peers.is_empty() {
+        warn!(
+            "Skipping {} peers, which can only be imported into a running tracker.",
+            request.peers.len()
+        );
+    }
+
+    let num_torrents = request.torrents.len();
+    let backend = connect_backend(&config).await?;
+    flush_torrents(&backend, request.torrents).await?;
+
+    info!("Imported {} torrents from {}.", num_torrents, file);
+    Ok(())
+}
+
+#[cfg(feature = "actix")]
+async fn fetch_scrape(url: &str, max_size: usize) -> io::Result<Vec<u8>> {
+    let client = ClientBuilder::new().timeout(SCRAPE_FETCH_TIMEOUT).finish();
+    let mut response = client.get(url).send().await.map_err(|e| {
+        error!("{} ({})", InternalError::UpstreamScrape.text(), e);
+        io::Error::from(io::ErrorKind::NotConnected)
+    })?;
+    let body = response.body().limit(max_size).await.map_err(|e| {
+        error!("{} ({})", InternalError::UpstreamScrape.text(), e);
+        io::Error::from(io::ErrorKind::InvalidData)
+    })?;
+    Ok(body.to_vec())
+}
+
+// Without an HTTP client, scrapes can only be read from files
+#[cfg(not(feature = "actix"))]
+async fn fetch_scrape(_url: &str, _max_size: usize) -> io::Result<Vec<u8>> {
+    error!(
+        "{} (tyto was built without the actix feature)",
+        InternalError::UpstreamScrape.text()
+    );
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Seeds the storage backend from the full scrape of the tracker being
+// moved away from (opentracker, chihaya and the like), so that counts
+// don't start over at the cutover. Torrents already in the backend take
+// the larger of each count, as a merge would. Swarms only exist in a
+// running tracker, so the scrape has to be sent to the admin
+// import-scrape route for those to be created ahead of the clients.
+pub async fn import_scrape(config_path: &str, source: &str) -> io::Result<()> {
+    let config = Config::from_file(config_path).map_err(|_| invalid_config(config_path))?;
+    let bytes = if source.starts_with("http://") || source.starts_with("https://") {
+        fetch_scrape(source, config.upstream.max_scrape_size).await?
+    } else {
+        fs::read(source)?
+    };
+
+    let torrents = ImportRequest::from_scrape(&bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+        .torrents;
+
+    let num_torrents = torrents.len();
+    let backend = connect_backend(&config).await?;
+    let existing = backend.get_torrents().await.map_err(|e| {
+        error!("{} ({})", InternalError::StorageTorrentLoad.text(), e);
+        io::Error::from(io::ErrorKind::Other)
+    })?;
+    let torrent_store = TorrentStore::new(existing);
+    let new_torrents = torrent_store.merge(torrents).await;
+    flush_torrents(&backend, torrent_store.take_dirty().await).await?;
+
+    info!("Swarms are only created by a running tracker, from scrapes POSTed to /admin/import-scrape.");
+    info!(
+        "Imported {} torrents from {}, {} of them new.",
+        num_torrents, source, new_torrents
+    );
+    Ok(())
+}
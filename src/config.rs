@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
 
 use serde::Deserialize;
 use toml;
+use url::Url;
 
+use crate::bittorrent::{InfoHash, PeerId, DEFAULT_NUMWANT};
 use crate::errors::InternalError;
 
 #[derive(Default, Deserialize, Clone)]
@@ -12,11 +17,63 @@ pub struct Config {
     pub storage: Storage,
     pub bt: BitTorrent,
     pub client_approval: ClientApproval,
+    #[serde(default)]
+    pub torrent_approval: TorrentApproval,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub categories: HashMap<String, CategoryPolicy>,
+    #[serde(default)]
+    pub permaseeds: Vec<Permaseed>,
+    #[serde(default)]
+    pub upstream: Upstream,
+    #[serde(default)]
+    pub retracker: Retracker,
+    #[serde(default)]
+    pub dnsbl: Dnsbl,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub blocklists: Blocklists,
+    #[serde(default)]
+    pub scripting: Scripting,
+    #[serde(default)]
+    pub peer_snapshot: PeerSnapshot,
+    #[serde(default)]
+    pub private: Private,
+    #[serde(default)]
+    pub cheaters: Cheaters,
+    #[serde(default)]
+    pub ip_filter: IpFilter,
+    #[serde(default)]
+    pub ip_param: IpParam,
+    #[serde(default)]
+    pub statsd: Statsd,
+    #[serde(default)]
+    pub geoip: Geoip,
+    #[serde(default)]
+    pub cluster: Cluster,
+    #[serde(default)]
+    pub sharding: Sharding,
+    #[serde(default)]
+    pub replication: Replication,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Network {
     pub binding: String,
+    // BEP 15 listener; empty means disabled
+    #[serde(default)]
+    pub udp_binding: String,
+    // More paths that announces and scrapes are served at
+    #[serde(default)]
+    pub announce_aliases: Vec<String>,
+    #[serde(default)]
+    pub scrape_aliases: Vec<String>,
+    // Reverse proxies whose forwarded headers name the client. Requests
+    // from anywhere else are taken to come from the connection's address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -24,6 +81,15 @@ pub struct Storage {
     pub backend: String,
     pub path: String,
     pub password: Option<String>,
+    // Save swarms to the database on every flush and reload them at startup
+    #[serde(default)]
+    pub persist_peers: bool,
+    // Keep torrents in a RocksDB tier at this path, with only
+    // cached_torrents of them held in memory at once
+    #[serde(default)]
+    pub rocksdb_path: String,
+    #[serde(default)]
+    pub cached_torrents: usize,
 }
 
 #[derive(Deserialize, Clone)]
@@ -32,20 +98,423 @@ pub struct BitTorrent {
     pub peer_timeout: u64,
     pub reap_interval: u64,
     pub flush_interval: u64,
+    #[serde(default)]
+    pub snapshot_interval: u64,
+    #[serde(default)]
+    pub announce_downloaded: bool,
+    #[serde(default)]
+    pub announce_external_ip: bool,
+    #[serde(default)]
+    pub allow_full_scrape: bool,
+    #[serde(default)]
+    pub reap_strategy: ReapStrategy,
+    #[serde(default)]
+    pub stop_delay: u64,
+    #[serde(default)]
+    pub reap_overrides: HashMap<InfoHash, ReapStrategy>,
+    #[serde(default)]
+    pub stats_rollover: StatsRollover,
+    #[serde(default)]
+    pub announce_history: usize,
+    #[serde(default)]
+    pub peer_shards: usize,
+    #[serde(default)]
+    pub swarm_writers: bool,
+    #[serde(default)]
+    pub reap_chunk_size: usize,
+    #[serde(default)]
+    pub reap_chunk_interval: u64,
+    #[serde(default)]
+    pub reap_concurrency: usize,
+    #[serde(default)]
+    pub max_swarms: usize,
+    #[serde(default)]
+    pub max_peers: usize,
+    #[serde(default)]
+    pub response_cache_ttl: u64,
+    #[serde(default)]
+    pub response_cache_min_peers: usize,
+    #[serde(default)]
+    pub duplicate_peer_ids: DuplicatePeerIds,
+    #[serde(default)]
+    pub min_port: u16,
+    #[serde(default)]
+    pub denied_ports: Vec<u16>,
+    #[serde(default)]
+    pub compact_only: bool,
+    #[serde(default)]
+    pub default_numwant: u32,
+    #[serde(default)]
+    pub seeder_numwant: u32,
+    #[serde(default)]
+    pub max_numwant: u32,
+    #[serde(default)]
+    pub min_interval: u64,
 }
 
-#[derive(Deserialize, Clone)]
+// How often global statistics are archived into the history
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsRollover {
+    #[default]
+    Off,
+    Daily,
+    Weekly,
+}
+
+// What to do when a peer ID that's already in a swarm
+// announces to that swarm from another address
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePeerIds {
+    // Every address is its own peer, and nothing is checked
+    #[default]
+    Allow,
+    // Every address is its own peer, but duplicates are counted
+    Flag,
+    // The newest address replaces the others
+    Merge,
+    // The newcomer is turned away
+    Reject,
+}
+
+// Different communities want different semantics for "this peer is gone".
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReapStrategy {
+    // Peers are cleared as soon as they exceed the peer timeout
+    #[default]
+    Hard,
+    // Peers are given one extra announce interval past the peer timeout
+    Soft,
+    // Like hard, but stopped peers linger for stop_delay secs before removal
+    DelayedStop,
+}
+
+#[derive(Deserialize, Clone, Default)]
 pub struct ClientApproval {
     pub enabled: bool,
     pub blacklist_style: bool,
     pub versioned: bool,
     pub client_list: Vec<String>,
+    #[serde(default)]
+    pub rules: HashMap<String, ClientRule>,
+    // More clients, one per line, reread every refresh_interval
+    // secs (if nonzero) and whenever the config is reloaded
+    #[serde(default)]
+    pub list_file: String,
+    #[serde(default)]
+    pub refresh_interval: u64,
+    #[serde(default)]
+    pub patterns: Vec<ClientPattern>,
+    #[serde(default)]
+    pub user_agent_patterns: Vec<ClientPattern>,
+}
+
+// Patterns are matched against the whole peer ID ('*' for any run of
+// characters, '?' for any one, '[0-3]' for one of a range), in order,
+// before the client list. The first matching pattern decides.
+//
+// User agent patterns are matched against the User-Agent header of HTTP
+// announces in the same way, on top of the peer ID checks. If any of them
+// allow, user agents matching none of them are rejected.
+#[derive(Deserialize, Clone)]
+pub struct ClientPattern {
+    pub pattern: String,
+    pub allow: bool,
+}
+
+// A rule gives a particular client its own failure message and retry
+// policy, which is sent back instead of the generic rejection.
+#[derive(Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientRule {
+    pub message: Option<String>,
+    // Minutes, as sent in the BEP 31 "retry in" key
+    pub retry_in: Option<u64>,
+}
+
+// Clients needn't be told to wait longer than a week
+pub const MAX_RETRY_IN_MINUTES: u64 = 7 * 24 * 60;
+
+#[derive(Deserialize, Clone)]
+pub struct TorrentApproval {
+    pub enabled: bool,
+    pub blacklist_style: bool,
+    pub torrent_list: Vec<InfoHash>,
+    // Same as for client approval, with a hex info hash per line
+    #[serde(default)]
+    pub list_file: String,
+    #[serde(default)]
+    pub refresh_interval: u64,
+}
+
+// Torrents can be assigned a category when they are registered, and each
+// category can override the global announce policy for its torrents.
+#[derive(Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct CategoryPolicy {
+    pub interval: Option<u64>,
+    pub numwant_cap: Option<u32>,
+    pub allowed_clients: Vec<String>,
+}
+
+// Peers that are handed out for their torrents regardless of announce
+// state, such as an operator's seedbox, so that new swarms can bootstrap
+// before the first organic seeder shows up. No torrents means all torrents.
+#[derive(Deserialize, Clone)]
+pub struct Permaseed {
+    pub ip: IpAddr,
+    pub port: u16,
+    #[serde(default)]
+    pub torrents: Vec<InfoHash>,
+}
+
+// Other trackers whose seeder and leecher counts for our registered
+// torrents are shown alongside our own. No trackers means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Upstream {
+    pub trackers: Vec<String>,
+    pub interval: u64,
+    pub timeout: u64,
+    // Adds their counts to ours in scrapes and announces, for while
+    // peers are still moving over from the upstream trackers
+    pub merge_counts: bool,
+    // The most of another tracker's full scrape that is read, in bytes
+    pub max_scrape_size: usize,
+}
+
+// Announces for the selected torrents are forwarded to an origin tracker
+// and its peers are merged with the local swarm. No announce URL means
+// disabled, and no torrents means every torrent is forwarded.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Retracker {
+    pub announce_url: String,
+    pub torrents: Vec<InfoHash>,
+    pub timeout: u64,
+}
+
+// DNS blocklists that announcing addresses are checked against.
+// No zones means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Dnsbl {
+    pub zones: Vec<String>,
+    pub action: DnsblAction,
+    pub timeout_ms: u64,
+    pub cache_ttl: u64,
+}
+
+// A private tracker only serves clients that announce and scrape with
+// the passkey of a user in the database, which is reloaded periodically
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Private {
+    pub enabled: bool,
+    pub refresh_interval: u64,
+    // Downloads aren't charged to users on all torrents,
+    // or only on those listed, while uploads still count
+    pub freeleech: bool,
+    pub freeleech_torrents: Vec<InfoHash>,
+    // How many peers a user may have on one torrent and on all
+    // of them together, to deter account sharing. 0 means no limit.
+    pub max_peers_per_torrent: u32,
+    pub max_peers: u32,
+}
+
+// Announces and scrapes are limited to requests_per_sec per address, with
+// bursts of up to burst requests. In private mode, announces are also
+// limited per passkey, however many addresses they come from. A rate of
+// zero means no limit.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimit {
+    pub requests_per_sec: f64,
+    pub burst: u32,
+    pub passkey_requests_per_sec: f64,
+    pub passkey_burst: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsblAction {
+    // Listed addresses are turned away
+    Reject,
+    // Listed addresses are let through, but logged and counted
+    Flag,
+}
+
+// Peers reporting more uploaded per second between announces than
+// max_upload_rate bytes are flagged, or banned by address for
+// ban_duration secs. A rate of zero means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Cheaters {
+    pub max_upload_rate: u64,
+    pub action: CheaterAction,
+    pub ban_duration: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheaterAction {
+    // Cheaters are logged and counted, and their traffic still counts
+    Flag,
+    // Cheaters are turned away, and their reported traffic ignored
+    Ban,
+}
+
+// Addresses or CIDR ranges whose announces and scrapes are turned away
+// before anything else is done with them. If allow has any entries,
+// only addresses within them are served at all.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct IpFilter {
+    pub deny: Vec<String>,
+    pub allow: Vec<String>,
+}
+
+// Whether peers are sent to the address a client names in its announce
+// rather than the one it connects from. Addresses no peer could reach
+// are never taken. No trusted sources means any client may name one.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct IpParam {
+    pub honor: bool,
+    pub trusted: Vec<String>,
+}
+
+// Address blocklists in P2P or CIDR format, read from files or URLs
+// and reloaded on an interval. No sources means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Blocklists {
+    pub sources: Vec<BlocklistSource>,
+    pub refresh_interval: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BlocklistSource {
+    pub name: String,
+    pub location: String,
+}
+
+// Path to a Lua script deciding on announces; empty means disabled.
+// Only honoured when built with the scripting feature.
+#[derive(Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct Scripting {
+    pub announce_policy: String,
+}
+
+// Swarms are saved to this file every interval secs and reloaded
+// on startup, so a restart doesn't drop every peer; empty means disabled
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PeerSnapshot {
+    pub path: String,
+    pub interval: u64,
+}
+
+// Metrics are pushed to a StatsD server every interval secs. Tags are
+// only understood by DogStatsD; an empty address means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Statsd {
+    pub address: String,
+    pub prefix: String,
+    pub interval: u64,
+    pub tags: Vec<String>,
+}
+
+// Range database that peers are looked up in for the per-country
+// statistics route; empty means disabled. Only honoured when built with
+// the geoip feature.
+#[derive(Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct Geoip {
+    pub database: String,
+}
+
+// Swarm changes are gossiped to the other trackers in the cluster every
+// interval secs, and swarm digests compared every digest_interval secs.
+// Messages are signed with the key, and only members at the seeds'
+// addresses or in the allowed ranges are gossiped with. An empty bind
+// address means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Cluster {
+    pub bind: String,
+    pub seeds: Vec<String>,
+    pub allow: Vec<String>,
+    pub key: String,
+    pub interval: u64,
+    pub digest_interval: u64,
+}
+
+// Cluster keys shorter than this are refused
+pub const MIN_CLUSTER_KEY_LENGTH: usize = 16;
+
+// The info hash space is split among the trackers in nodes (their base
+// URLs), each owning the torrents that land in its part of a hash ring.
+// Announces and scrapes for torrents owned elsewhere are redirected or
+// proxied there. No nodes means disabled.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Sharding {
+    pub nodes: Vec<String>,
+    // This tracker's own entry in nodes
+    pub this_node: String,
+    pub forward: ShardForward,
+    pub timeout: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardForward {
+    // Clients are sent a 307 to the owning tracker
+    Redirect,
+    // Requests are made to the owning tracker on the client's behalf
+    Proxy,
+}
+
+// A primary takes announces and keeps a log of the last log_size changes
+// they made to the swarms. Replicas follow it through its admin routes,
+// authenticating with token, and only serve scrapes themselves.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Replication {
+    pub role: ReplicationRole,
+    // The primary's base URL, for replicas
+    pub primary: String,
+    pub token: String,
+    pub poll_interval: u64,
+    pub log_size: usize,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationRole {
+    Standalone,
+    Primary,
+    Replica,
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct Admin {
+    pub tokens: Vec<String>,
 }
 
 impl Default for Network {
     fn default() -> Self {
         Network {
             binding: "0.0.0.0:8585".to_string(),
+            udp_binding: "".to_string(),
+            announce_aliases: Vec::new(),
+            scrape_aliases: Vec::new(),
+            trusted_proxies: Vec::new(),
         }
     }
 }
@@ -56,6 +525,9 @@ impl Default for Storage {
             backend: "memory".to_string(),
             path: "".to_string(),
             password: None,
+            persist_peers: false,
+            rocksdb_path: String::new(),
+            cached_torrents: 0,
         }
     }
 }
@@ -67,62 +539,839 @@ impl Default for BitTorrent {
             peer_timeout: 7200,
             reap_interval: 1800,
             flush_interval: 900,
+            snapshot_interval: 0,
+            announce_downloaded: false,
+            announce_external_ip: false,
+            allow_full_scrape: false,
+            reap_strategy: ReapStrategy::Hard,
+            stop_delay: 0,
+            reap_overrides: HashMap::new(),
+            stats_rollover: StatsRollover::Off,
+            announce_history: 0,
+            peer_shards: 1,
+            swarm_writers: false,
+            reap_chunk_size: 0,
+            reap_chunk_interval: 0,
+            reap_concurrency: 1,
+            max_swarms: 0,
+            max_peers: 0,
+            response_cache_ttl: 0,
+            response_cache_min_peers: 1000,
+            duplicate_peer_ids: DuplicatePeerIds::Allow,
+            min_port: 0,
+            denied_ports: Vec::new(),
+            compact_only: false,
+            default_numwant: DEFAULT_NUMWANT,
+            seeder_numwant: 0,
+            max_numwant: 200,
+            min_interval: 0,
+        }
+    }
+}
+
+impl Default for PeerSnapshot {
+    fn default() -> Self {
+        PeerSnapshot {
+            path: "".to_string(),
+            interval: 300,
+        }
+    }
+}
+
+impl Default for Statsd {
+    fn default() -> Self {
+        Statsd {
+            address: "".to_string(),
+            prefix: "tyto".to_string(),
+            interval: 10,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Cluster {
+            bind: "".to_string(),
+            seeds: Vec::new(),
+            allow: Vec::new(),
+            key: "".to_string(),
+            interval: 2,
+            digest_interval: 30,
+        }
+    }
+}
+
+impl Default for Sharding {
+    fn default() -> Self {
+        Sharding {
+            nodes: Vec::new(),
+            this_node: "".to_string(),
+            forward: ShardForward::Redirect,
+            timeout: 5,
+        }
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            role: ReplicationRole::Standalone,
+            primary: "".to_string(),
+            token: "".to_string(),
+            poll_interval: 1,
+            log_size: 100_000,
+        }
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Upstream {
+            trackers: Vec::new(),
+            interval: 1800,
+            timeout: 10,
+            merge_counts: false,
+            max_scrape_size: 64 * 1024 * 1024,
         }
     }
 }
 
-impl Default for ClientApproval {
-    fn default() -> ClientApproval {
-        ClientApproval {
+impl Default for Retracker {
+    fn default() -> Self {
+        Retracker {
+            announce_url: "".to_string(),
+            torrents: Vec::new(),
+            timeout: 5,
+        }
+    }
+}
+
+impl Retracker {
+    pub fn forwards(&self, info_hash: &InfoHash) -> bool {
+        !self.announce_url.is_empty()
+            && (self.torrents.is_empty() || self.torrents.contains(info_hash))
+    }
+}
+
+impl Default for Dnsbl {
+    fn default() -> Self {
+        Dnsbl {
+            zones: Vec::new(),
+            action: DnsblAction::Reject,
+            timeout_ms: 500,
+            cache_ttl: 3600,
+        }
+    }
+}
+
+impl Default for IpParam {
+    fn default() -> Self {
+        IpParam {
+            honor: true,
+            trusted: Vec::new(),
+        }
+    }
+}
+
+impl Default for Cheaters {
+    fn default() -> Self {
+        Cheaters {
+            max_upload_rate: 0,
+            action: CheaterAction::Flag,
+            ban_duration: 86400,
+        }
+    }
+}
+
+impl Default for Private {
+    fn default() -> Self {
+        Private {
             enabled: false,
-            blacklist_style: false,
-            versioned: false,
-            client_list: Vec::new(),
+            refresh_interval: 300,
+            freeleech: false,
+            freeleech_torrents: Vec::new(),
+            max_peers_per_torrent: 0,
+            max_peers: 0,
         }
     }
 }
 
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            requests_per_sec: 0.0,
+            burst: 10,
+            passkey_requests_per_sec: 0.0,
+            passkey_burst: 10,
+        }
+    }
+}
+
+impl Default for Blocklists {
+    fn default() -> Self {
+        Blocklists {
+            sources: Vec::new(),
+            refresh_interval: 86400,
+        }
+    }
+}
+
+impl StatsRollover {
+    pub fn interval(self) -> Option<u64> {
+        match self {
+            StatsRollover::Off => None,
+            StatsRollover::Daily => Some(86_400),
+            StatsRollover::Weekly => Some(604_800),
+        }
+    }
+}
+
+impl Default for TorrentApproval {
+    fn default() -> TorrentApproval {
+        TorrentApproval {
+            enabled: false,
+            blacklist_style: true,
+            torrent_list: Vec::new(),
+            list_file: String::new(),
+            refresh_interval: 0,
+        }
+    }
+}
+
+impl Permaseed {
+    pub fn serves(&self, info_hash: &InfoHash) -> bool {
+        self.torrents.is_empty() || self.torrents.contains(info_hash)
+    }
+}
+
+impl Storage {
+    // Keeping peers is what the Redis backend is there for
+    pub fn persists_peers(&self) -> bool {
+        self.persist_peers || self.backend == "redis"
+    }
+}
+
+impl BitTorrent {
+    // Individual torrents can override the tracker-wide strategy
+    pub fn reap_strategy(&self, info_hash: &InfoHash) -> ReapStrategy {
+        match self.reap_overrides.get(info_hash) {
+            Some(strategy) => *strategy,
+            None => self.reap_strategy,
+        }
+    }
+
+    // How long a peer may go without announcing under a given strategy
+    pub fn peer_timeout(&self, strategy: ReapStrategy) -> u64 {
+        match strategy {
+            ReapStrategy::Soft => self.peer_timeout + self.announce_rate,
+            _ => self.peer_timeout,
+        }
+    }
+}
+
+impl CategoryPolicy {
+    // Clients are matched by the start of their Azureus-style peer ID,
+    // e.g. "qB" or "qB4250". An empty list lets every client through.
+    pub fn allows_client(&self, peer_id: &PeerId) -> bool {
+        if self.allowed_clients.is_empty() {
+            return true;
+        }
+
+        self.allowed_clients
+            .iter()
+            .any(|client| peer_id.0[1..].starts_with(client.as_bytes()))
+    }
+}
+
 impl Config {
-    pub fn load_config(path: String) -> Config {
+    // Unlike load_config, this never falls back to the default config,
+    // so that a broken file can't replace a running configuration
+    pub fn from_file(path: &str) -> Result<Config, InternalError> {
         let mut config_toml = String::new();
 
-        let mut file = match File::open(&path) {
-            Ok(file) => file,
-            _ => {
-                error!("{}", InternalError::ConfigFileOpen.text());
-                return Config::default();
-            }
-        };
+        let mut file = File::open(path).map_err(|e| {
+            warn!("{}: {}", path, e);
+            InternalError::ConfigFileOpen
+        })?;
+        file.read_to_string(&mut config_toml).map_err(|e| {
+            warn!("{}: {}", path, e);
+            InternalError::ConfigFileRead
+        })?;
 
-        if file.read_to_string(&mut config_toml).is_err() {
-            error!("{}", InternalError::ConfigFileRead.text());
-            return Config::default();
-        };
+        // Parse errors say at which line and column they happened
+        let config: Config = toml::from_str(&config_toml).map_err(|e| {
+            error!("{}: {}", path, e);
+            InternalError::ConfigParse
+        })?;
+
+        let problems = config.validate();
+        for problem in &problems {
+            error!("{}: {}", path, problem);
+        }
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(InternalError::ConfigInvalid)
+        }
+    }
 
-        let config: Config = match toml::from_str(&config_toml) {
-            Ok(t) => t,
-            _ => {
-                error!("{}", InternalError::ConfigParse.text());
+    // Only a missing file falls back to the default config. A file
+    // that is there but broken stops the tracker from starting.
+    pub fn load_config(path: String) -> Result<Config, InternalError> {
+        let config = match Config::from_file(&path) {
+            Ok(config) => config,
+            Err(InternalError::ConfigFileOpen) => {
+                warn!("{}", InternalError::ConfigFileOpen.text());
                 Config::default()
             }
+            Err(e) => return Err(e),
         };
 
-        info!("Binding to address: {}", &config.network.binding);
+        config.log_settings();
+        Ok(config)
+    }
+
+    // Settings that parse but can't work, each naming the setting at fault
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.network.binding.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "network.binding: '{}' is not an address and port, e.g. 0.0.0.0:8585",
+                self.network.binding
+            ));
+        }
+        if !self.network.udp_binding.is_empty()
+            && self.network.udp_binding.parse::<SocketAddr>().is_err()
+        {
+            problems.push(format!(
+                "network.udp_binding: '{}' is not an address and port, e.g. 0.0.0.0:8585",
+                self.network.udp_binding
+            ));
+        }
+
+        if !self.cluster.bind.is_empty() && self.cluster.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "cluster.bind: '{}' is not an address and port, e.g. 0.0.0.0:6667",
+                self.cluster.bind
+            ));
+        }
+        if !self.cluster.bind.is_empty() && self.cluster.key.len() < MIN_CLUSTER_KEY_LENGTH {
+            problems.push(format!(
+                "cluster.key: needs to be at least {} characters long",
+                MIN_CLUSTER_KEY_LENGTH
+            ));
+        }
+
+        for node in &self.sharding.nodes {
+            match Url::parse(node) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+                _ => problems.push(format!(
+                    "sharding.nodes: '{}' is not a base URL, e.g. http://tracker-1.example:6666",
+                    node
+                )),
+            }
+        }
+        if !self.sharding.nodes.is_empty()
+            && !self.sharding.nodes.contains(&self.sharding.this_node)
+        {
+            problems.push(format!(
+                "sharding.this_node: '{}' is not one of sharding.nodes",
+                self.sharding.this_node
+            ));
+        }
+
+        if self.replication.role == ReplicationRole::Replica {
+            match Url::parse(&self.replication.primary) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+                _ => problems.push(format!(
+                    "replication.primary: '{}' is not a base URL, e.g. http://primary.example:6666",
+                    self.replication.primary
+                )),
+            }
+        }
+        if self.replication.role != ReplicationRole::Standalone && self.replication.log_size == 0 {
+            problems.push("replication.log_size: must be at least 1".to_string());
+        }
+
+        let aliases = self
+            .network
+            .announce_aliases
+            .iter()
+            .map(|a| ("network.announce_aliases", a))
+            .chain(
+                self.network
+                    .scrape_aliases
+                    .iter()
+                    .map(|a| ("network.scrape_aliases", a)),
+            );
+        for (name, alias) in aliases {
+            if !alias.starts_with('/') || alias.len() < 2 {
+                problems.push(format!(
+                    "{}: '{}' should be a path other than /, e.g. /announce.php",
+                    name, alias
+                ));
+            }
+        }
+
+        match self.storage.backend.as_str() {
+            "memory" => {}
+            "mysql" if cfg!(feature = "mysql") => {}
+            "postgres" if cfg!(feature = "postgres") => {}
+            "sqlite" if cfg!(feature = "sqlite") => {}
+            "redis" if cfg!(feature = "redis") => {}
+            "mysql" | "postgres" | "sqlite" | "redis" => problems.push(format!(
+                "storage.backend: tyto was built without the {} feature",
+                self.storage.backend
+            )),
+            other => problems.push(format!(
+                "storage.backend: '{}' is not one of memory, mysql, postgres, sqlite or redis",
+                other
+            )),
+        }
+        if !self.storage.rocksdb_path.is_empty() {
+            if !cfg!(feature = "rocksdb") {
+                problems.push(
+                    "storage.rocksdb_path: tyto was built without the rocksdb feature".to_string(),
+                );
+            } else if self.storage.cached_torrents == 0 {
+                problems.push("storage.cached_torrents: must be at least 1".to_string());
+            }
+        }
+
+        // Builds without the actix feature only have the UDP tracker, and
+        // nothing to reach other trackers or download lists with
+        if !cfg!(feature = "actix") {
+            if self.network.udp_binding.is_empty() {
+                problems.push(
+                    "network.udp_binding: must be set, as tyto was built without the actix feature"
+                        .to_string(),
+                );
+            }
+            let downloads_lists = self.blocklists.sources.iter().any(|source| {
+                source.location.starts_with("http://") || source.location.starts_with("https://")
+            });
+            let needs_http = [
+                ("upstream.trackers", !self.upstream.trackers.is_empty()),
+                (
+                    "retracker.announce_url",
+                    !self.retracker.announce_url.is_empty(),
+                ),
+                ("sharding.nodes", !self.sharding.nodes.is_empty()),
+                (
+                    "replication.role",
+                    self.replication.role != ReplicationRole::Standalone,
+                ),
+                ("blocklists.sources", downloads_lists),
+            ];
+            for (name, set) in needs_http.iter() {
+                if *set {
+                    problems.push(format!(
+                        "{}: tyto was built without the actix feature",
+                        name
+                    ));
+                }
+            }
+        }
+        if !cfg!(feature = "metrics") && !self.statsd.address.is_empty() {
+            problems.push("statsd.address: tyto was built without the metrics feature".to_string());
+        }
+        if !cfg!(feature = "geoip") && !self.geoip.database.is_empty() {
+            problems.push("geoip.database: tyto was built without the geoip feature".to_string());
+        }
+
+        // Background jobs would run back to back with no interval
+        let mut intervals = vec![
+            ("bt.announce_rate", self.bt.announce_rate),
+            ("bt.peer_timeout", self.bt.peer_timeout),
+            ("bt.reap_interval", self.bt.reap_interval),
+            ("bt.flush_interval", self.bt.flush_interval),
+        ];
+        if !self.peer_snapshot.path.is_empty() {
+            intervals.push(("peer_snapshot.interval", self.peer_snapshot.interval));
+        }
+        if !self.upstream.trackers.is_empty() {
+            intervals.push(("upstream.interval", self.upstream.interval));
+        }
+        if !self.blocklists.sources.is_empty() {
+            intervals.push((
+                "blocklists.refresh_interval",
+                self.blocklists.refresh_interval,
+            ));
+        }
+        if self.private.enabled {
+            intervals.push(("private.refresh_interval", self.private.refresh_interval));
+        }
+        if !self.statsd.address.is_empty() {
+            intervals.push(("statsd.interval", self.statsd.interval));
+        }
+        if self.replication.role == ReplicationRole::Replica {
+            intervals.push(("replication.poll_interval", self.replication.poll_interval));
+        }
+        if !self.cluster.bind.is_empty() {
+            intervals.push(("cluster.interval", self.cluster.interval));
+            intervals.push(("cluster.digest_interval", self.cluster.digest_interval));
+        }
+        for (name, interval) in intervals {
+            if interval == 0 {
+                problems.push(format!("{}: must be at least 1 sec", name));
+            }
+        }
+
+        // Entries are compared with the client part of peer IDs, which
+        // is two characters long, or six along with the version
+        let entry_len = if self.client_approval.versioned { 6 } else { 2 };
+        for client in &self.client_approval.client_list {
+            if client.len() != entry_len || !client.is_ascii() {
+                problems.push(format!(
+                    "client_approval.client_list: '{}' should be {} characters long{}",
+                    client,
+                    entry_len,
+                    if self.client_approval.versioned {
+                        ", e.g. 'DE1234'"
+                    } else {
+                        ", e.g. 'DE'"
+                    }
+                ));
+            }
+        }
+        let mut rules: Vec<_> = self.client_approval.rules.iter().collect();
+        rules.sort_by(|a, b| a.0.cmp(b.0));
+        for (client, rule) in rules {
+            if rule.retry_in.is_some_and(|r| r > MAX_RETRY_IN_MINUTES) {
+                problems.push(format!(
+                    "client_approval.rules.{}.retry_in: must be at most {} min",
+                    client, MAX_RETRY_IN_MINUTES
+                ));
+            }
+        }
+
+        problems
+    }
+
+    pub fn log_settings(&self) {
+        if cfg!(feature = "actix") {
+            info!("Binding to address: {}", &self.network.binding);
+        }
+        if !self.network.udp_binding.is_empty() {
+            info!(
+                "Binding UDP tracker to address: {}",
+                &self.network.udp_binding
+            );
+        }
+        if !self.network.announce_aliases.is_empty() || !self.network.scrape_aliases.is_empty() {
+            info!(
+                "Also serving announces at {:?} and scrapes at {:?}",
+                &self.network.announce_aliases, &self.network.scrape_aliases
+            );
+        }
         info!(
             "Utilizing {} storage backend located at {}",
-            &config.storage.backend, &config.storage.path
+            &self.storage.backend, &self.storage.path
         );
-        info!("Announce interval: {} secs", &config.bt.announce_rate);
+        if self.storage.persists_peers() {
+            info!("Saving swarms to the storage backend on every flush");
+        }
+        if !self.storage.rocksdb_path.is_empty() {
+            info!(
+                "Keeping torrents in RocksDB at {}, with up to {} in memory",
+                &self.storage.rocksdb_path, &self.storage.cached_torrents
+            );
+        }
+        info!("Announce interval: {} secs", &self.bt.announce_rate);
+        if self.bt.min_interval > 0 {
+            info!("Minimum announce interval: {} secs", &self.bt.min_interval);
+        }
         info!(
             "Clearing peers older than {} secs at {}-sec interval",
-            &config.bt.peer_timeout, &config.bt.reap_interval
+            &self.bt.peer_timeout, &self.bt.reap_interval
+        );
+        info!(
+            "Reaping strategy: {:?} ({} per-torrent overrides)",
+            &self.bt.reap_strategy,
+            self.bt.reap_overrides.len()
         );
         info!(
             "Flushing torrents to disk every {} secs",
-            &config.bt.flush_interval
+            &self.bt.flush_interval
         );
-        info!("Client list: {:?}", &config.client_approval.client_list);
+        if self.bt.snapshot_interval > 0 {
+            info!(
+                "Serving scrapes from snapshots taken every {} secs",
+                &self.bt.snapshot_interval
+            );
+        }
+        if self.bt.max_swarms > 0 || self.bt.max_peers > 0 {
+            info!(
+                "Evicting idle swarms beyond {} swarms or {} peers (0 is unlimited)",
+                &self.bt.max_swarms, &self.bt.max_peers
+            );
+        }
+        if self.bt.response_cache_ttl > 0 {
+            info!(
+                "Caching announce responses for {} secs in swarms of {} or more peers",
+                &self.bt.response_cache_ttl, &self.bt.response_cache_min_peers
+            );
+        }
+        if self.bt.allow_full_scrape {
+            info!("Full scrapes are allowed");
+        }
+        if self.bt.duplicate_peer_ids != DuplicatePeerIds::Allow {
+            info!(
+                "Duplicate peer IDs in a swarm: {:?}",
+                &self.bt.duplicate_peer_ids
+            );
+        }
+        if self.bt.default_numwant > 0 {
+            info!("Handing out {} peers by default", &self.bt.default_numwant);
+        }
+        if self.bt.seeder_numwant > 0 {
+            info!(
+                "Handing out {} peers to seeders by default",
+                &self.bt.seeder_numwant
+            );
+        }
+        if self.bt.max_numwant > 0 {
+            info!("Handing out at most {} peers", &self.bt.max_numwant);
+        }
+        if self.bt.compact_only {
+            info!("Only compact announces are served");
+        }
+        if self.bt.min_port > 0 {
+            info!("Rejecting announces for ports below {}", &self.bt.min_port);
+        }
+        if !self.bt.denied_ports.is_empty() {
+            info!("Denied ports: {:?}", &self.bt.denied_ports);
+        }
+        if self.bt.stats_rollover != StatsRollover::Off {
+            info!("Archiving statistics {:?}", &self.bt.stats_rollover);
+        }
+        if !self.peer_snapshot.path.is_empty() {
+            info!(
+                "Saving swarms to {} every {} secs",
+                &self.peer_snapshot.path, &self.peer_snapshot.interval
+            );
+        }
+        if !self.statsd.address.is_empty() {
+            info!(
+                "Pushing metrics to StatsD at {} every {} secs",
+                &self.statsd.address, &self.statsd.interval
+            );
+        }
+        if !self.cluster.bind.is_empty() {
+            info!(
+                "Gossiping with the cluster from {} every {} secs, with {} seeds",
+                &self.cluster.bind,
+                &self.cluster.interval,
+                self.cluster.seeds.len()
+            );
+        }
+        match self.replication.role {
+            ReplicationRole::Standalone => {}
+            ReplicationRole::Primary => info!(
+                "Replicating as a primary, keeping the last {} changes",
+                &self.replication.log_size
+            ),
+            ReplicationRole::Replica => {
+                info!("Replicating from primary {}", &self.replication.primary)
+            }
+        }
+        if !self.sharding.nodes.is_empty() {
+            let forward = match self.sharding.forward {
+                ShardForward::Redirect => "redirecting",
+                ShardForward::Proxy => "proxying",
+            };
+            info!(
+                "Owning a share of torrents as {} among {} trackers, {} the rest",
+                &self.sharding.this_node,
+                self.sharding.nodes.len(),
+                forward
+            );
+        }
+        if !self.upstream.trackers.is_empty() {
+            info!(
+                "Scraping {} upstream trackers every {} secs",
+                self.upstream.trackers.len(),
+                &self.upstream.interval
+            );
+            if self.upstream.merge_counts {
+                info!("Adding upstream counts to our own");
+            }
+        }
+        if !self.retracker.announce_url.is_empty() {
+            info!("Forwarding announces to {}", &self.retracker.announce_url);
+        }
+        if !self.dnsbl.zones.is_empty() {
+            info!(
+                "Checking announcing addresses against {:?} ({:?} on hit)",
+                &self.dnsbl.zones, &self.dnsbl.action
+            );
+        }
+        if self.private.enabled {
+            info!(
+                "Private tracker, reloading users every {} secs",
+                &self.private.refresh_interval
+            );
+            if self.private.freeleech {
+                info!("Freeleech on all torrents");
+            } else if !self.private.freeleech_torrents.is_empty() {
+                info!(
+                    "Freeleech on {} torrents",
+                    self.private.freeleech_torrents.len()
+                );
+            }
+            if self.private.max_peers_per_torrent > 0 || self.private.max_peers > 0 {
+                info!(
+                    "Limiting users to {} peers per torrent and {} in total (0 is unlimited)",
+                    &self.private.max_peers_per_torrent, &self.private.max_peers
+                );
+            }
+        }
+        if self.cheaters.max_upload_rate > 0 {
+            info!(
+                "Catching peers uploading over {} bytes/sec ({:?} on catch)",
+                &self.cheaters.max_upload_rate, &self.cheaters.action
+            );
+        }
+        if !self.network.trusted_proxies.is_empty() {
+            info!(
+                "Taking client addresses forwarded by {:?}",
+                &self.network.trusted_proxies
+            );
+        }
+        if !self.ip_param.honor {
+            info!("Ignoring addresses named by clients");
+        } else if !self.ip_param.trusted.is_empty() {
+            info!(
+                "Only taking addresses named by clients from {:?}",
+                &self.ip_param.trusted
+            );
+        }
+        if !self.ip_filter.deny.is_empty() || !self.ip_filter.allow.is_empty() {
+            info!(
+                "Filtering addresses: denying {:?}, only allowing {:?}",
+                &self.ip_filter.deny, &self.ip_filter.allow
+            );
+        }
+        if self.rate_limit.requests_per_sec > 0.0 {
+            info!(
+                "Limiting each address to {} requests/sec (bursts of {})",
+                &self.rate_limit.requests_per_sec, &self.rate_limit.burst
+            );
+        }
+        if self.private.enabled && self.rate_limit.passkey_requests_per_sec > 0.0 {
+            info!(
+                "Limiting each passkey to {} announces/sec (bursts of {})",
+                &self.rate_limit.passkey_requests_per_sec, &self.rate_limit.passkey_burst
+            );
+        }
+        if !self.scripting.announce_policy.is_empty() {
+            info!(
+                "Deciding announces with policy script {}",
+                &self.scripting.announce_policy
+            );
+        }
+        info!("Client list: {:?}", &self.client_approval.client_list);
+        if !self.client_approval.patterns.is_empty() {
+            info!(
+                "Client patterns: {:?}",
+                self.client_approval
+                    .patterns
+                    .iter()
+                    .map(|p| &p.pattern)
+                    .collect::<Vec<_>>()
+            );
+        }
+        if !self.client_approval.user_agent_patterns.is_empty() {
+            info!(
+                "User agent patterns: {:?}",
+                self.client_approval
+                    .user_agent_patterns
+                    .iter()
+                    .map(|p| &p.pattern)
+                    .collect::<Vec<_>>()
+            );
+        }
+        if !self.client_approval.list_file.is_empty() {
+            info!(
+                "Reading more clients from {}",
+                &self.client_approval.list_file
+            );
+        }
+        if self.torrent_approval.enabled {
+            info!(
+                "Torrent {}: {} entries",
+                if self.torrent_approval.blacklist_style {
+                    "blacklist"
+                } else {
+                    "whitelist"
+                },
+                self.torrent_approval.torrent_list.len()
+            );
+            if !self.torrent_approval.list_file.is_empty() {
+                info!(
+                    "Reading more torrents from {}",
+                    &self.torrent_approval.list_file
+                );
+            }
+        }
+    }
+}
+
+// The running configuration, which can be swapped out on reload. Readers
+// take a snapshot, so settings never change halfway through a request.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        config
+    #[test]
+    fn config_validation() {
+        let mut config = Config::default();
+        config.network.udp_binding = "0.0.0.0:6969".to_string();
+        config.storage.backend = "memory".to_string();
+        assert!(config.validate().is_empty());
+
+        config.network.binding = "localhost".to_string();
+        config.storage.backend = "oracle".to_string();
+        config.bt.reap_interval = 0;
+        config.client_approval.client_list = vec!["DE".to_string(), "DE1234".to_string()];
+        config.network.announce_aliases = vec!["/a".to_string(), "announce.php".to_string()];
+        config.client_approval.rules.insert(
+            "DE".to_string(),
+            ClientRule {
+                message: None,
+                retry_in: Some(86400),
+            },
+        );
+
+        let problems = config.validate();
+        assert_eq!(problems.len(), 6);
+        assert!(problems[0].starts_with("network.binding: 'localhost'"));
+        assert!(problems[1].starts_with("network.announce_aliases: 'announce.php'"));
+        assert!(problems[2].starts_with("storage.backend: 'oracle'"));
+        assert_eq!(problems[3], "bt.reap_interval: must be at least 1 sec");
+        assert!(problems[4].starts_with("client_approval.client_list: 'DE1234'"));
+        assert_eq!(
+            problems[5],
+            "client_approval.rules.DE.retry_in: must be at most 10080 min"
+        );
     }
 }
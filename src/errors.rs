@@ -3,10 +3,25 @@
 pub enum ClientError {
     MalformedAnnounce,
     MalformedScrape,
+    BlockedAddress,
     NotCompact,
     ResourceDoesNotExist,
     UnapprovedClient,
     UnapprovedTorrent,
+    InvalidConnectionId,
+    RateLimited,
+    MissingPasskey,
+    UnknownPasskey,
+    RevokedPasskey,
+    TooManyPeers,
+    BannedForCheating,
+    DuplicatePeerId,
+    InvalidPort,
+    PortTooLow,
+    DeniedPort,
+    OwnerUnreachable,
+    NotPrimary,
+    InternalFailure,
 }
 
 // This is a list of errors that are internal to the tracker,
@@ -15,10 +30,36 @@ pub enum InternalError {
     ConfigFileOpen,
     ConfigFileRead,
     ConfigParse,
+    ConfigInvalid,
     ConfigReload,
     StorageTorrentFetchNew,
     StorageTorrentFlush,
     StorageTorrentLoad,
+    StorageStatsArchive,
+    StorageStatsLoad,
+    StoragePeerSave,
+    StoragePeerLoad,
+    StorageUnreachable,
+    StorageUserLoad,
+    ApprovalListLoad,
+    StorageUserFlush,
+    StorageSnatchSave,
+    StorageTierOpen,
+    BlocklistLoad,
+    UpstreamAnnounce,
+    UpstreamScrape,
+    ScriptLoad,
+    ScriptEvaluate,
+    UdpSocket,
+    HandlerPanic,
+    StatsdConnect,
+    StatsdSend,
+    GeoipLoad,
+    ClusterSocket,
+    ClusterSeed,
+    ReplicationFetch,
+    SwarmPublish,
+    SwarmSubscribe,
 }
 
 impl ClientError {
@@ -26,10 +67,31 @@ impl ClientError {
         match *self {
             ClientError::MalformedAnnounce => "Malformed announce request".to_string(),
             ClientError::MalformedScrape => "Malformed scrape request".to_string(),
+            ClientError::BlockedAddress => "Announcing address is blocklisted".to_string(),
             ClientError::NotCompact => "Announces must be in compact format".to_string(),
             ClientError::ResourceDoesNotExist => "Resource does not exist".to_string(),
             ClientError::UnapprovedClient => "Unapproved client".to_string(),
             ClientError::UnapprovedTorrent => "Unapproved torrent".to_string(),
+            ClientError::InvalidConnectionId => "Invalid or expired connection ID".to_string(),
+            ClientError::RateLimited => "Too many requests".to_string(),
+            ClientError::MissingPasskey => "Passkey required".to_string(),
+            ClientError::UnknownPasskey => "Unknown passkey".to_string(),
+            ClientError::RevokedPasskey => "Passkey revoked".to_string(),
+            ClientError::TooManyPeers => "Too many peers for this account".to_string(),
+            ClientError::BannedForCheating => {
+                "Banned for reporting impossible transfers".to_string()
+            }
+            ClientError::DuplicatePeerId => {
+                "Peer ID already in use from another address".to_string()
+            }
+            ClientError::InvalidPort => "Port 0 is not a valid port".to_string(),
+            ClientError::PortTooLow => "Port is below the allowed minimum".to_string(),
+            ClientError::DeniedPort => "Port is not allowed".to_string(),
+            ClientError::OwnerUnreachable => {
+                "Tracker responsible for this torrent is unreachable".to_string()
+            }
+            ClientError::NotPrimary => "Announces go to the primary tracker".to_string(),
+            ClientError::InternalFailure => "Internal tracker error".to_string(),
         }
     }
 }
@@ -40,14 +102,38 @@ impl InternalError {
             InternalError::ConfigFileOpen => {
                 "Could not find config file! Loading default config..."
             }
-            InternalError::ConfigFileRead => {
-                "Could not read config file! Loading default config..."
-            }
-            InternalError::ConfigParse => "Could not parse config file! Loading default config...",
+            InternalError::ConfigFileRead => "Could not read config file!",
+            InternalError::ConfigParse => "Could not parse config file!",
+            InternalError::ConfigInvalid => "Config file has invalid settings!",
             InternalError::ConfigReload => "Could not reload configuration! Keeping old config...",
             InternalError::StorageTorrentFetchNew => "Could not fetch new torrents from disk!",
             InternalError::StorageTorrentFlush => "Could not flush torrents to disk!",
             InternalError::StorageTorrentLoad => "Could not load torrents from disk!",
+            InternalError::StorageStatsArchive => "Could not archive statistics to disk!",
+            InternalError::StorageStatsLoad => "Could not load statistics history from disk!",
+            InternalError::StoragePeerSave => "Could not save swarms to disk!",
+            InternalError::StoragePeerLoad => "Could not load swarms from disk!",
+            InternalError::StorageUnreachable => "Storage backend is unreachable!",
+            InternalError::StorageUserLoad => "Could not load users from disk!",
+            InternalError::ApprovalListLoad => "Could not load approval list file!",
+            InternalError::StorageUserFlush => "Could not flush user traffic to disk!",
+            InternalError::StorageSnatchSave => "Could not save snatches to disk!",
+            InternalError::StorageTierOpen => "Could not open RocksDB torrent tier!",
+            InternalError::BlocklistLoad => "Could not load blocklist!",
+            InternalError::UpstreamAnnounce => "Could not forward announce upstream!",
+            InternalError::UpstreamScrape => "Could not scrape upstream tracker!",
+            InternalError::ScriptLoad => "Could not load announce policy script!",
+            InternalError::ScriptEvaluate => "Announce policy script failed! Allowing announce...",
+            InternalError::UdpSocket => "Could not use UDP tracker socket!",
+            InternalError::HandlerPanic => "Request handler panicked! Sent a failure instead...",
+            InternalError::StatsdConnect => "Could not set up StatsD exporter!",
+            InternalError::StatsdSend => "Could not push metrics to StatsD!",
+            InternalError::GeoipLoad => "Could not load GeoIP database!",
+            InternalError::ClusterSocket => "Could not use cluster gossip socket!",
+            InternalError::ClusterSeed => "Could not resolve cluster seed! Skipping it...",
+            InternalError::ReplicationFetch => "Could not fetch changes from primary!",
+            InternalError::SwarmPublish => "Could not publish swarm changes to Redis!",
+            InternalError::SwarmSubscribe => "Could not subscribe to swarm changes in Redis!",
         }
     }
 }
@@ -0,0 +1,210 @@
+// Country lookups for the per-country swarm statistics, from a range
+// database in CSV form: the first and last address of each range and the
+// country code, one range to a line, as in the DB-IP and IP2Location Lite
+// country CSVs. Ranges are kept sorted so that lookups are a binary search.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::storage::{PeerRole, PeerStore};
+
+#[derive(Debug, Default)]
+pub struct CountryDb {
+    v4: Vec<(u32, u32, String)>,
+    v6: Vec<(u128, u128, String)>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CountryCounts {
+    pub seeders: u32,
+    pub leechers: u32,
+}
+
+fn find<T: Ord + Copy>(ranges: &[(T, T, String)], addr: T) -> Option<&str> {
+    ranges
+        .binary_search_by(|(start, end, _)| {
+            if *end < addr {
+                Ordering::Less
+            } else if *start > addr {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        })
+        .ok()
+        .map(|i| ranges[i].2.as_str())
+}
+
+impl CountryDb {
+    pub fn load(path: &str) -> Result<CountryDb, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let (db, skipped) = CountryDb::parse(&contents);
+        if skipped > 0 {
+            warn!("Skipped {} unreadable ranges in {}", skipped, path);
+        }
+        Ok(db)
+    }
+
+    // Lines that can't be understood, including ranges that overlap
+    // one already read, are skipped and counted
+    pub fn parse(contents: &str) -> (CountryDb, usize) {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        let mut skipped = 0;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line
+                .split(',')
+                .map(|f| f.trim().trim_matches('"'))
+                .collect();
+            let parsed = match fields.as_slice() {
+                [start, end, country, ..] if !country.is_empty() => {
+                    match (start.parse::<IpAddr>(), end.parse::<IpAddr>()) {
+                        (Ok(IpAddr::V4(start)), Ok(IpAddr::V4(end))) if start <= end => {
+                            v4.push((start.into(), end.into(), country.to_uppercase()));
+                            true
+                        }
+                        (Ok(IpAddr::V6(start)), Ok(IpAddr::V6(end))) if start <= end => {
+                            v6.push((start.into(), end.into(), country.to_uppercase()));
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+
+            if !parsed {
+                skipped += 1;
+            }
+        }
+
+        skipped += sort_ranges(&mut v4) + sort_ranges(&mut v6);
+        (CountryDb { v4, v6 }, skipped)
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        match ip {
+            IpAddr::V4(ip) => find(&self.v4, ip.into()),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => find(&self.v4, ip.into()),
+                None => find(&self.v6, ip.into()),
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Returns how many overlapping ranges were dropped
+fn sort_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T, String)>) -> usize {
+    ranges.sort_unstable_by_key(|&(start, end, _)| (start, end));
+    let before = ranges.len();
+    let mut last_end = None;
+    ranges.retain(|&(start, end, _)| {
+        if last_end.is_some_and(|last| start <= last) {
+            return false;
+        }
+        last_end = Some(end);
+        true
+    });
+    before - ranges.len()
+}
+
+// Every peer in every swarm, by the country it announced from. Partial
+// seeds are counted as leechers, as in scrapes. Peers outside any range
+// in the database are counted under "--".
+pub async fn country_counts(
+    db: &CountryDb,
+    peer_store: &PeerStore,
+) -> BTreeMap<String, CountryCounts> {
+    let mut counts: BTreeMap<String, CountryCounts> = BTreeMap::new();
+    for (_, peer, role) in peer_store.dump().await {
+        let country = db.country(peer.ip()).unwrap_or("--");
+        let entry = counts.entry(country.to_string()).or_default();
+        if role == PeerRole::Seeder {
+            entry.seeders += 1;
+        } else {
+            entry.leechers += 1;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::{InfoHash, Peer, PeerId};
+
+    const DATABASE: &str = "\
+        1.0.0.0,1.0.0.255,au\n\
+        \"8.8.8.0\",\"8.8.8.255\",\"US\"\n\
+        8.8.8.128,8.8.9.0,CA\n\
+        2001:db8::,2001:db8::ffff,NL\n\
+        not,a,range\n";
+
+    #[test]
+    fn country_lookup() {
+        let (db, skipped) = CountryDb::parse(DATABASE);
+        assert_eq!(skipped, 2);
+        assert_eq!(db.len(), 3);
+
+        assert_eq!(db.country("1.0.0.7".parse().unwrap()), Some("AU"));
+        assert_eq!(db.country("8.8.8.200".parse().unwrap()), Some("US"));
+        assert_eq!(db.country("::ffff:8.8.8.8".parse().unwrap()), Some("US"));
+        assert_eq!(db.country("2001:db8::12".parse().unwrap()), Some("NL"));
+        assert_eq!(db.country("9.9.9.9".parse().unwrap()), None);
+    }
+
+    #[actix_rt::test]
+    async fn counts_by_country() {
+        let (db, _) = CountryDb::parse(DATABASE);
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer =
+            |id: &[u8; 20], ip: &str| Peer::new(PeerId(*id), ip.parse().unwrap(), 6881, 0, 0);
+
+        peer_store
+            .put_seeder(info_hash, peer(b"-DE9824-143964258012", "1.0.0.1"))
+            .await;
+        peer_store
+            .put_leecher(info_hash, peer(b"-DE9824-143964258013", "1.0.0.2"))
+            .await;
+        peer_store
+            .put_leecher(info_hash, peer(b"-DE9824-143964258014", "9.9.9.9"))
+            .await;
+
+        let counts = country_counts(&db, &peer_store).await;
+        assert_eq!(counts.len(), 2);
+        assert_eq!(
+            counts["AU"],
+            CountryCounts {
+                seeders: 1,
+                leechers: 1
+            }
+        );
+        assert_eq!(
+            counts["--"],
+            CountryCounts {
+                seeders: 0,
+                leechers: 1
+            }
+        );
+    }
+}
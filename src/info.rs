@@ -0,0 +1,31 @@
+use serde::Serialize;
+
+use crate::state::State;
+
+// These are filled in by the build script at compile time.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_COMMIT: &str = env!("TYTO_GIT_COMMIT");
+const FEATURES: &str = env!("TYTO_FEATURES");
+
+// This is returned through the version handler so that operators
+// can audit what is actually running on each node of a deployment.
+#[derive(Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub features: Vec<&'static str>,
+    pub backend: String,
+    pub listeners: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn new(state: &State) -> BuildInfo {
+        BuildInfo {
+            version: VERSION,
+            commit: GIT_COMMIT,
+            features: FEATURES.split(',').filter(|f| !f.is_empty()).collect(),
+            backend: state.config().storage.backend.clone(),
+            listeners: state.listeners.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
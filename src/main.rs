@@ -1,95 +1,312 @@
 pub mod bencode;
 pub mod bittorrent;
+pub mod blocklist;
+pub mod cli;
 pub mod config;
 pub mod errors;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod info;
 pub mod network;
+pub mod scripting;
 pub mod state;
 pub mod statistics;
+#[cfg(feature = "metrics")]
+pub mod statsd;
 pub mod storage;
 pub mod util;
 
 use actix::prelude::*;
-use actix_rt;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer};
-use clap::{App as ClapApp, Arg};
+#[cfg(feature = "actix")]
+use actix_web::{web, App, HttpResponse, HttpServer};
 use config::Config;
-use mysql;
-use pretty_env_logger;
+#[cfg(feature = "actix")]
+use network::middleware::TokenAuth;
 use state::State;
 use storage::janitor::Janitor;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 #[macro_use]
 extern crate log;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
+    let matches = cli::app().get_matches();
+
+    // A level given on the command line wins over RUST_LOG
+    if let Some(level) = cli::global_value(&matches, "log-level") {
+        std::env::set_var("RUST_LOG", level);
+    } else if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "INFO");
     }
     pretty_env_logger::init_timed();
 
-    let matches = ClapApp::new("tyto")
-        .version("0.5.5")
-        .author("Alexander Decurnou. <ad@alx.xyz>")
-        .about("A BitTorrent tracker that aims to be distributed, fast, and fault-tolerant.")
-        .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("configuration")
-                .value_name("CONFIG_FILE")
-                .help("Start the tracker using this configuration")
-                .takes_value(true),
-        )
-        .get_matches();
+    let config_path = cli::global_value(&matches, "config")
+        .unwrap_or("config.toml")
+        .to_string();
 
-    // Parse arguments and attempt to parse configuration file
-    let config_path = matches.value_of("config");
-    let config = match config_path {
-        Some(path) => Config::load_config(path.to_string()),
-        None => Config::load_config("config.toml".to_string()),
-    };
+    // Starting the tracker is the default for backwards compatibility
+    match matches.subcommand() {
+        ("check-config", _) => cli::check_config(&config_path),
+        ("import", Some(args)) => cli::import(&config_path, args.value_of("FILE").unwrap()).await,
+        ("import-scrape", Some(args)) => {
+            cli::import_scrape(&config_path, args.value_of("SOURCE").unwrap()).await
+        }
+        ("serve", Some(args)) => serve(config_path, args.value_of("bind")).await,
+        _ => serve(config_path, None).await,
+    }
+}
+
+async fn serve(config_path: String, bind: Option<&str>) -> std::io::Result<()> {
+    let mut config = Config::load_config(config_path.clone()).map_err(|e| {
+        error!("{}", e.text());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.text())
+    })?;
+    if let Some(bind) = bind {
+        info!("Binding to address {} instead", bind);
+        config.network.binding = bind.to_string();
+    }
 
     // Copy and cloning up here to avoid errors for moved values
+    #[cfg(feature = "actix")]
     let binding = config.network.binding.clone();
+    #[cfg(feature = "actix")]
+    let network_config = config.network.clone();
 
     // TODO: abstract into a general loading function
     // TODO: add support to pass mysql password
     // Collect torrents from desired storage
     // backend and instantiate data stores.
-    let pool = mysql::Pool::new(&config.storage.path).unwrap();
-    let torrents = storage::mysql::get_torrents(pool.clone()).unwrap();
+    let backend = storage::backend::Backend::connect(&config.storage)
+        .await
+        .unwrap();
+    let torrents = backend.get_torrents().await.unwrap();
     info!("Number of torrents loaded: {}", torrents.len());
 
+    // Bind the listener up front so the actual
+    // addresses can be reported by the version handler
+    #[cfg(feature = "actix")]
+    let listener = std::net::TcpListener::bind(&binding)?;
+    let udp_socket = if config.network.udp_binding.is_empty() {
+        None
+    } else {
+        Some(std::net::UdpSocket::bind(&config.network.udp_binding)?)
+    };
+    let cluster_socket = if config.cluster.bind.is_empty() {
+        None
+    } else {
+        Some(std::net::UdpSocket::bind(&config.cluster.bind)?)
+    };
+
+    // With a RocksDB tier, torrents are only held in memory while in use
+    #[cfg(feature = "rocksdb")]
+    let torrent_records = if config.storage.rocksdb_path.is_empty() {
+        storage::TorrentStore::new(torrents)
+    } else {
+        storage::rocksdb::TorrentTier::open(
+            &config.storage.rocksdb_path,
+            config.storage.cached_torrents,
+        )
+        .and_then(|tier| storage::TorrentStore::with_tier(torrents, tier))
+        .map_err(|e| {
+            error!("{} ({})", errors::InternalError::StorageTierOpen.text(), e);
+            std::io::Error::other(e)
+        })?
+    };
+    #[cfg(not(feature = "rocksdb"))]
     let torrent_records = storage::TorrentStore::new(torrents);
-    let state = web::Data::new(State::new(config.clone(), torrent_records));
-    let janitor_state_clone = state.clone();
-
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(state.clone())
-            // Log all requests to stdout
-            //.wrap(middleware::Logger::default())
-            // If enabled, filter requests
-            // by client ID and reject or accept
-            .wrap(middleware::Condition::new(
-                config.client_approval.enabled,
-                network::middleware::ClientApproval::new(
-                    config.client_approval.blacklist_style,
-                    config.client_approval.versioned,
-                    config.client_approval.client_list.clone(),
-                ),
-            ))
-            .service(web::scope("announce").route("", web::get().to(network::parse_announce)))
-            .service(web::scope("scrape").route("", web::get().to(network::parse_scrape)))
-            .service(web::scope("stats").route("", web::get().to(network::get_stats)))
-            .service(web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())))
-    })
-    .bind(binding)?
-    .run();
+    let mut state = State::new(config.clone(), torrent_records);
+    state.backend = Some(backend.clone());
+    state.config_path = config_path;
+    if config.bt.stats_rollover != config::StatsRollover::Off {
+        match backend.get_stats_history().await {
+            Ok(history) => *state.stats_history.write().await = history,
+            Err(_) => error!("{}", errors::InternalError::StorageStatsLoad.text()),
+        }
+    }
+    if config.storage.persists_peers() {
+        match backend.get_peers().await {
+            Ok(saved) => {
+                let peers = storage::peer_snapshot::from_saved(saved, config.bt.peer_timeout);
+                let num_peers = state.peer_store.restore(peers).await;
+                info!("Number of peers loaded: {}", num_peers);
+            }
+            Err(e) => error!("{} ({})", errors::InternalError::StoragePeerLoad.text(), e),
+        }
+    }
+    if !config.peer_snapshot.path.is_empty() {
+        let path = &config.peer_snapshot.path;
+        match storage::peer_snapshot::load(path, &state.peer_store, config.bt.peer_timeout).await {
+            Ok(num_peers) => info!("Number of peers restored: {}", num_peers),
+            Err(e) => error!("{} ({})", errors::InternalError::StoragePeerLoad.text(), e),
+        }
+    }
+    if config.private.enabled {
+        match backend.get_users().await {
+            Ok(users) => {
+                info!("Number of users loaded: {}", users.len());
+                state.user_store.replace(users).await;
+            }
+            Err(e) => error!("{} ({})", errors::InternalError::StorageUserLoad.text(), e),
+        }
+    }
+    #[cfg(feature = "geoip")]
+    if !config.geoip.database.is_empty() {
+        match geoip::CountryDb::load(&config.geoip.database) {
+            Ok(db) => {
+                info!("Number of GeoIP ranges loaded: {}", db.len());
+                state.countries = Some(std::sync::Arc::new(db));
+            }
+            Err(e) => error!("{} ({})", errors::InternalError::GeoipLoad.text(), e),
+        }
+    }
+    if !config.scripting.announce_policy.is_empty() {
+        match scripting::AnnouncePolicy::load(&config.scripting.announce_policy) {
+            Ok(policy) => state.announce_policy = Some(policy),
+            Err(e) => error!("{} ({})", errors::InternalError::ScriptLoad.text(), e),
+        }
+    }
+    #[cfg(feature = "actix")]
+    state.listeners.push(listener.local_addr()?);
+    if let Some(socket) = &udp_socket {
+        state.listeners.push(socket.local_addr()?);
+    }
+
+    #[cfg(feature = "actix")]
+    let server = {
+        let state = web::Data::new(state.clone());
+        HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                // Log all requests to stdout
+                //.wrap(middleware::Logger::default())
+                // Announces and scrapes, along with the
+                // middleware that filters and limits them
+                .configure(|cfg| network::tracker_routes(cfg, state.get_ref(), &network_config))
+                // Statistics stay public until API tokens are configured,
+                // while admin routes are closed without any
+                .service({
+                    let stats = web::scope("stats")
+                        .wrap(TokenAuth::new(state.get_ref().clone(), true))
+                        .route("", web::get().to(network::get_stats))
+                        .route("history", web::get().to(network::get_stats_history))
+                        .route("cheaters", web::get().to(network::get_cheaters));
+                    // Swarms by country, with a GeoIP database
+                    #[cfg(feature = "geoip")]
+                    let stats = stats.route("countries", web::get().to(network::get_countries));
+                    stats
+                })
+                .service(web::scope("version").route("", web::get().to(network::get_version)))
+                .service(web::scope("healthz").route("", web::get().to(network::get_health)))
+                .service(web::scope("readyz").route("", web::get().to(network::get_ready)))
+                .service(
+                    web::scope("admin")
+                        .wrap(TokenAuth::new(state.get_ref().clone(), false))
+                        .app_data(
+                            web::JsonConfig::default().limit(network::admin::IMPORT_SIZE_LIMIT),
+                        )
+                        .app_data(
+                            web::PayloadConfig::default().limit(network::admin::IMPORT_SIZE_LIMIT),
+                        )
+                        .route("import", web::post().to(network::admin::import))
+                        .route(
+                            "import-scrape",
+                            web::post().to(network::admin::import_scrape),
+                        )
+                        .route("merge", web::post().to(network::admin::merge))
+                        .route("export", web::get().to(network::admin::export))
+                        .route("peer", web::get().to(network::admin::get_peer))
+                        .route("top", web::get().to(network::admin::get_top))
+                        .route("changes", web::get().to(network::admin::get_changes))
+                        .route("promote", web::post().to(network::admin::promote))
+                        .route("reload", web::post().to(network::admin::reload))
+                        .route("freeleech", web::get().to(network::admin::get_freeleech))
+                        .route("freeleech", web::post().to(network::admin::set_freeleech))
+                        .route(
+                            "revocations",
+                            web::get().to(network::admin::get_revocations),
+                        )
+                        .route(
+                            "revocations",
+                            web::post().to(network::admin::set_revocations),
+                        ),
+                )
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed)))
+        })
+        .listen(listener)?
+        .run()
+    };
+
+    // UDP clients are served on the same runtime, against the same state
+    if let Some(socket) = udp_socket {
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let tracker = network::udp::UdpTracker::new(state.clone());
+        actix_rt::spawn(tracker.serve(socket));
+    }
+
+    // Cluster members gossip over their own socket, against the same state
+    if let Some(socket) = cluster_socket {
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let node = network::gossip::GossipNode::new(state.clone(), &config.cluster);
+        actix_rt::spawn(node.serve(socket));
+    }
+
+    // Replicas follow their primary until they're promoted
+    #[cfg(feature = "actix")]
+    if config.replication.role == config::ReplicationRole::Replica {
+        let replica = network::replication::Replica::new(state.clone(), &config.replication);
+        actix_rt::spawn(replica.follow());
+    }
+
+    // Trackers sharing a Redis database share their swarms through it too
+    #[cfg(feature = "redis")]
+    {
+        if config.storage.backend == "redis" {
+            let state = state.clone();
+            match network::pubsub::SwarmChannel::new(state, &config.storage.path) {
+                Ok(channel) => {
+                    actix_rt::spawn(channel.serve());
+                }
+                Err(e) => error!("{} ({})", errors::InternalError::SwarmSubscribe.text(), e),
+            }
+        }
+    }
+
+    // Reload the config whenever the process is sent a SIGHUP
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+        actix_rt::spawn(async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    warn!(
+                        "Could not listen for SIGHUP, config reloads are admin-only ({})",
+                        e
+                    );
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                match state.reload_config() {
+                    Ok(()) => info!("Reloaded configuration from {}", &state.config_path),
+                    Err(e) => error!("{}", e.text()),
+                }
+            }
+        });
+    }
 
     // Start janitor in its own thread
-    Janitor::create(|_ctx: &mut Context<Janitor>| Janitor::new(janitor_state_clone, pool));
+    Janitor::create(|_ctx: &mut Context<Janitor>| Janitor::new(state, backend));
 
     // Start server
-    server.await
+    #[cfg(feature = "actix")]
+    server.await?;
+    // Without it, the UDP tracker and the janitor run until interrupted
+    #[cfg(not(feature = "actix"))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
 }
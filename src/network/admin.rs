@@ -0,0 +1,918 @@
+use std::net::IpAddr;
+
+use bendy::decoding;
+use serde::{Deserialize, Serialize};
+
+use crate::bencode;
+use crate::bittorrent::{InfoHash, PeerId};
+use crate::storage::history::AnnounceRecord;
+use crate::storage::users::Passkey;
+use crate::storage::Torrent;
+
+// Only the import format is needed without the HTTP server, by the CLI
+#[cfg(feature = "actix")]
+use {
+    crate::bittorrent::Peer,
+    crate::network::replication,
+    crate::state::State,
+    crate::storage::{ImportedPeer, PeerRole},
+    actix_web::{web, HttpResponse, Responder},
+    std::time::Duration,
+};
+
+// Imports can carry thousands of records, so the
+// default JSON payload limit is far too small here.
+pub const IMPORT_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportPeer {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub seeder: bool,
+}
+
+// Also the format of exports, so that one tracker's
+// state can be merged into another's as it is
+#[derive(Serialize, Deserialize)]
+pub struct ImportRequest {
+    #[serde(default)]
+    pub torrents: Vec<Torrent>,
+    #[serde(default)]
+    pub peers: Vec<ImportPeer>,
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    pub torrents: usize,
+    pub peers: usize,
+}
+
+#[derive(Serialize)]
+pub struct ScrapeImportResponse {
+    pub torrents: usize,
+    pub swarms: usize,
+}
+
+#[derive(Deserialize)]
+pub struct PeerQuery {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+#[derive(Serialize)]
+pub struct PeerResponse {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub history: Vec<AnnounceRecord>,
+}
+
+// How to rank torrents for the top route
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TopOrder {
+    #[default]
+    Peers,
+    Announces,
+}
+
+// At most this many torrents are listed, however many are asked for
+pub const MAX_TOP_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct TopQuery {
+    #[serde(default)]
+    pub by: TopOrder,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct TopTorrent {
+    pub info_hash: InfoHash,
+    pub seeders: u32,
+    pub leechers: u32,
+    // Announces over the last minute
+    pub recent_announces: f64,
+}
+
+// Omitted fields leave that part of freeleech as it is
+#[derive(Deserialize)]
+pub struct FreeleechRequest {
+    #[serde(default)]
+    pub global: Option<bool>,
+    #[serde(default)]
+    pub add: Vec<InfoHash>,
+    #[serde(default)]
+    pub remove: Vec<InfoHash>,
+}
+
+// Passkeys to revoke, and revoked ones to let back in
+#[derive(Deserialize)]
+pub struct RevocationRequest {
+    #[serde(default)]
+    pub add: Vec<Passkey>,
+    #[serde(default)]
+    pub remove: Vec<Passkey>,
+}
+
+#[derive(Serialize)]
+pub struct RevocationResponse {
+    pub passkeys: Vec<Passkey>,
+}
+
+#[derive(Serialize)]
+pub struct FreeleechResponse {
+    pub global: bool,
+    pub torrents: Vec<InfoHash>,
+}
+
+#[cfg(feature = "actix")]
+impl FreeleechResponse {
+    fn new(data: &State) -> FreeleechResponse {
+        FreeleechResponse {
+            global: data.freeleech.is_global(),
+            torrents: data.freeleech.torrents(),
+        }
+    }
+}
+
+impl ImportRequest {
+    // The torrents in another tracker's full scrape, with its counts.
+    // A scrape has no peers, so there are none to import.
+    pub fn from_scrape(bytes: &[u8]) -> Result<ImportRequest, decoding::Error> {
+        let torrents = bencode::decode_scrape_files(bytes)?
+            .into_iter()
+            .filter_map(|file| {
+                let info_hash = InfoHash::from_bytes(&file.info_hash)?;
+                Some(Torrent::new(
+                    info_hash,
+                    file.complete,
+                    file.downloaded,
+                    file.incomplete,
+                    0,
+                ))
+            })
+            .collect();
+
+        Ok(ImportRequest {
+            torrents,
+            peers: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "actix")]
+impl ImportPeer {
+    fn into_imported(self) -> ImportedPeer {
+        ImportedPeer {
+            info_hash: self.info_hash,
+            peer: Peer::new(self.peer_id, self.ip, self.port, 0, 0),
+            seeder: self.seeder,
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
+impl ImportRequest {
+    // Partial seeds are exported as leechers, which is what an import
+    // would have made of them before they announced as partial seeds
+    async fn export(data: &State) -> ImportRequest {
+        let torrents = data.torrent_store.records().await.into_values().collect();
+        let peers = data
+            .peer_store
+            .dump()
+            .await
+            .into_iter()
+            .map(|(info_hash, peer, role)| ImportPeer {
+                info_hash,
+                peer_id: peer.peer_id(),
+                ip: peer.ip(),
+                port: peer.port(),
+                seeder: role == PeerRole::Seeder,
+            })
+            .collect();
+
+        ImportRequest { torrents, peers }
+    }
+}
+
+// Admin routes are guarded by the TokenAuth middleware
+#[cfg(feature = "actix")]
+pub async fn import(data: web::Data<State>, payload: web::Json<ImportRequest>) -> impl Responder {
+    let ImportRequest { torrents, peers } = payload.into_inner();
+
+    let num_torrents = data.torrent_store.bulk_insert(torrents).await;
+    let (new_seeds, new_leeches) = data
+        .peer_store
+        .bulk_insert(peers.into_iter().map(ImportPeer::into_imported).collect())
+        .await;
+
+    {
+        let mut stats = data.stats.write().await;
+        stats.total_seeders += new_seeds;
+        stats.total_leechers += new_leeches;
+    }
+
+    info!(
+        "Imported {} torrents, {} seeders and {} leechers.",
+        num_torrents, new_seeds, new_leeches
+    );
+
+    HttpResponse::Ok().json(ImportResponse {
+        torrents: num_torrents,
+        peers: (new_seeds + new_leeches) as usize,
+    })
+}
+
+// Like import, but torrents that are already here keep the larger of
+// each count rather than being replaced, which makes it safe to merge
+// in the export of a tracker that has been running alongside this one
+#[cfg(feature = "actix")]
+pub async fn merge(data: web::Data<State>, payload: web::Json<ImportRequest>) -> impl Responder {
+    let ImportRequest { torrents, peers } = payload.into_inner();
+
+    let new_torrents = data.torrent_store.merge(torrents).await;
+    let (new_seeds, new_leeches) = data
+        .peer_store
+        .bulk_insert(peers.into_iter().map(ImportPeer::into_imported).collect())
+        .await;
+
+    {
+        let mut stats = data.stats.write().await;
+        stats.total_seeders += new_seeds;
+        stats.total_leechers += new_leeches;
+    }
+
+    info!(
+        "Merged in {} new torrents, {} seeders and {} leechers.",
+        new_torrents, new_seeds, new_leeches
+    );
+
+    HttpResponse::Ok().json(ImportResponse {
+        torrents: new_torrents,
+        peers: (new_seeds + new_leeches) as usize,
+    })
+}
+
+// Merges in the counts from another tracker's full scrape, as the
+// import-scrape command does, and creates a swarm for each torrent in it.
+// The swarms are kept for a reap interval even while empty, so that they
+// are still there as clients move over from the other tracker.
+#[cfg(feature = "actix")]
+pub async fn import_scrape(data: web::Data<State>, body: web::Bytes) -> impl Responder {
+    let torrents = match ImportRequest::from_scrape(&body) {
+        Ok(request) => request.torrents,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let info_hashes = torrents.iter().map(|t| t.info_hash).collect();
+
+    let new_torrents = data.torrent_store.merge(torrents).await;
+    let keep_for = Duration::from_secs(data.config().bt.reap_interval);
+    let new_swarms = data.peer_store.create_swarms(info_hashes, keep_for).await;
+
+    info!(
+        "Merged in {} new torrents from a scrape, and created {} swarms.",
+        new_torrents, new_swarms
+    );
+
+    HttpResponse::Ok().json(ScrapeImportResponse {
+        torrents: new_torrents,
+        swarms: new_swarms,
+    })
+}
+
+// Every torrent and peer, in the format import and merge take
+#[cfg(feature = "actix")]
+pub async fn export(data: web::Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(ImportRequest::export(&data).await)
+}
+
+// Shows what the tracker has recently seen from a single peer
+#[cfg(feature = "actix")]
+pub async fn get_peer(data: web::Data<State>, query: web::Query<PeerQuery>) -> impl Responder {
+    let PeerQuery { info_hash, peer_id } = query.into_inner();
+    let history = data.announce_history.get(info_hash, peer_id).await;
+
+    HttpResponse::Ok().json(PeerResponse {
+        info_hash,
+        peer_id,
+        history,
+    })
+}
+
+// Lists the busiest torrents, either by the size of their swarms or by
+// how often they were announced to over the last minute
+#[cfg(feature = "actix")]
+pub async fn get_top(data: web::Data<State>, query: web::Query<TopQuery>) -> impl Responder {
+    let limit = query.limit.unwrap_or(10).min(MAX_TOP_LIMIT);
+    let mut top = Vec::new();
+
+    match query.by {
+        TopOrder::Peers => {
+            for (info_hash, seeders, leechers) in data.peer_store.largest_swarms(limit).await {
+                top.push(TopTorrent {
+                    info_hash,
+                    seeders,
+                    leechers,
+                    recent_announces: data.leaderboard.recent(&info_hash),
+                });
+            }
+        }
+        TopOrder::Announces => {
+            for (info_hash, recent_announces) in data.leaderboard.top(limit) {
+                let (seeders, leechers) = data.peer_store.swarm_size(&info_hash).await;
+                top.push(TopTorrent {
+                    info_hash,
+                    seeders,
+                    leechers,
+                    recent_announces,
+                });
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(top)
+}
+
+#[cfg(feature = "actix")]
+pub async fn get_freeleech(data: web::Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(FreeleechResponse::new(&data))
+}
+
+// Changes last until the config is reloaded
+#[cfg(feature = "actix")]
+pub async fn set_freeleech(
+    data: web::Data<State>,
+    payload: web::Json<FreeleechRequest>,
+) -> impl Responder {
+    let FreeleechRequest {
+        global,
+        add,
+        remove,
+    } = payload.into_inner();
+
+    if let Some(global) = global {
+        data.freeleech.set_global(global);
+    }
+    for info_hash in add {
+        data.freeleech.set_torrent(info_hash, true);
+    }
+    for info_hash in remove {
+        data.freeleech.set_torrent(info_hash, false);
+    }
+
+    HttpResponse::Ok().json(FreeleechResponse::new(&data))
+}
+
+#[cfg(feature = "actix")]
+pub async fn get_revocations(data: web::Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(RevocationResponse {
+        passkeys: data.user_store.revoked(),
+    })
+}
+
+// Revoked passkeys are turned away before they're even looked up. A
+// revocation lasts until the passkey is gone from the users the tracker
+// reloads from the database, or until it's removed here.
+#[cfg(feature = "actix")]
+pub async fn set_revocations(
+    data: web::Data<State>,
+    payload: web::Json<RevocationRequest>,
+) -> impl Responder {
+    let RevocationRequest { add, remove } = payload.into_inner();
+
+    for passkey in add {
+        data.user_store.revoke(passkey);
+    }
+    for passkey in remove {
+        data.user_store.reinstate(&passkey);
+    }
+
+    HttpResponse::Ok().json(RevocationResponse {
+        passkeys: data.user_store.revoked(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    pub epoch: Option<u64>,
+    pub since: Option<u64>,
+}
+
+// Replicas follow the primary through here. Batches are bincoded, as
+// full ones hold every peer.
+#[cfg(feature = "actix")]
+pub async fn get_changes(
+    data: web::Data<State>,
+    query: web::Query<ChangesQuery>,
+) -> impl Responder {
+    let position = match (query.epoch, query.since) {
+        (Some(epoch), Some(since)) => Some((epoch, since)),
+        _ => None,
+    };
+
+    match replication::changes(&data, position).await {
+        Some(batch) => match bincode::serialize(&batch) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(body),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// Fails a replica over: it stops following its primary and takes
+// announces itself. Nothing changes on trackers that aren't replicas.
+#[cfg(feature = "actix")]
+pub async fn promote(data: web::Data<State>) -> impl Responder {
+    if data.primary.promote() {
+        info!("Promoted from replica to primary");
+    }
+    HttpResponse::Ok().finish()
+}
+
+// Same as sending the process a SIGHUP
+#[cfg(feature = "actix")]
+pub async fn reload(data: web::Data<State>) -> impl Responder {
+    match data.reload_config() {
+        Ok(()) => {
+            info!("Reloaded configuration from {}", &data.config_path);
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => {
+            error!("{}", e.text());
+            HttpResponse::InternalServerError().body(e.text())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "actix"))]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    use crate::config::Config;
+    use crate::errors::ClientError;
+    use crate::network::middleware::TokenAuth;
+    use crate::storage::users::{User, UserRecords, UserTraffic};
+    use crate::storage::{TorrentRecords, TorrentStore};
+
+    const IMPORT_BODY: &str = r#"{
+        "torrents": [{"info_hash": "4131423243334434453546364737483849394a30", "complete": 1,
+                      "downloaded": 4, "incomplete": 0, "balance": 0}],
+        "peers": [{"info_hash": "4131423243334434453546364737483849394a30",
+                   "peer_id": "2d4445393832342d313433393634323538303132",
+                   "ip": "127.0.0.1", "port": 6881, "seeder": true}]
+    }"#;
+
+    fn admin_state() -> web::Data<State> {
+        let mut config = Config::default();
+        config.admin.tokens = vec!["secret".to_string()];
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        web::Data::new(State::new(config, torrent_store))
+    }
+
+    #[actix_rt::test]
+    async fn import_unauthorized() {
+        let stores = admin_state();
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("admin")
+                    .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                    .app_data(stores.clone())
+                    .route("import", web::post().to(import)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/import")
+            .header("Authorization", "Bearer wrong")
+            .header("Content-Type", "application/json")
+            .set_payload(IMPORT_BODY)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(stores.torrent_store.torrents.read().await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn import_success() {
+        let stores = admin_state();
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("admin")
+                    .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                    .app_data(stores.clone())
+                    .route("import", web::post().to(import)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/import")
+            .header("Authorization", "Bearer secret")
+            .header("Content-Type", "application/json")
+            .set_payload(IMPORT_BODY)
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, r#"{"torrents":1,"peers":1}"#.as_bytes());
+        assert_eq!(stores.torrent_store.torrents.read().await.len(), 1);
+        assert_eq!(stores.stats.read().await.total_seeders, 1);
+    }
+
+    #[actix_rt::test]
+    async fn export_merges_into_another_tracker() {
+        let source = admin_state();
+        let target = admin_state();
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("source")
+                        .app_data(source.clone())
+                        .route("import", web::post().to(import))
+                        .route("export", web::get().to(export)),
+                )
+                .service(
+                    web::scope("target")
+                        .app_data(target.clone())
+                        .route("merge", web::post().to(merge)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/source/import")
+            .header("Content-Type", "application/json")
+            .set_payload(IMPORT_BODY)
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let req = test::TestRequest::get().uri("/source/export").to_request();
+        let exported = test::read_response(&mut app, req).await;
+
+        // The target has seen more snatches, which it keeps
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut known = Torrent::new(info_hash, 0, 9, 0, 0);
+        known.category = Some("music".to_string());
+        target.torrent_store.bulk_insert(vec![known]).await;
+
+        for expected in &[r#"{"torrents":0,"peers":1}"#, r#"{"torrents":0,"peers":0}"#] {
+            let req = test::TestRequest::post()
+                .uri("/target/merge")
+                .header("Content-Type", "application/json")
+                .set_payload(exported.clone())
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert_eq!(resp, expected.as_bytes());
+        }
+
+        let torrents = target.torrent_store.torrents.read().await;
+        let merged = &torrents[&info_hash];
+        assert_eq!((merged.complete, merged.downloaded), (1, 9));
+        assert_eq!(merged.category.as_deref(), Some("music"));
+        assert_eq!(target.peer_store.swarm_size(&info_hash).await, (1, 0));
+        assert_eq!(target.stats.read().await.total_seeders, 1);
+    }
+
+    #[actix_rt::test]
+    async fn import_scrape_creates_swarms() {
+        let stores = admin_state();
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("admin")
+                    .app_data(stores.clone())
+                    .route("import-scrape", web::post().to(import_scrape)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/import-scrape")
+            .set_payload(
+                &b"d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei5e10:downloadedi9e10:incompletei2eeee"[..],
+            )
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, r#"{"torrents":1,"swarms":1}"#.as_bytes());
+
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let stats = stores.torrent_store.get_announce_stats(info_hash).await;
+        assert_eq!(stats, (5, 9, 2));
+        let shard = stores.peer_store.shard(&info_hash).read().await;
+        assert!(shard[&info_hash].is_kept());
+    }
+
+    #[actix_rt::test]
+    async fn peer_history() {
+        let mut config = Config::default();
+        config.admin.tokens = vec!["secret".to_string()];
+        config.bt.announce_history = 2;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(crate::network::parse_announce)),
+                )
+                .service(
+                    web::scope("admin")
+                        .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                        .app_data(stores.clone())
+                        .route("peer", web::get().to(get_peer)),
+                ),
+        )
+        .await;
+
+        for (uploaded, event) in &[(0, "started"), (100, ""), (200, "")] {
+            let req = test::TestRequest::with_uri(&format!("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded={}&downloaded=0&left=727955456&compact=1&event={}", uploaded, event))
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+
+        let req = test::TestRequest::with_uri(
+            "/admin/peer?info_hash=4131423243334434453546364737483849394a30&peer_id=2d4445393832342d313433393634323538303132",
+        )
+        .header("Authorization", "Bearer secret")
+        .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+
+        // Only the two most recent announces are kept
+        assert!(!body.contains("\"started\""));
+        assert!(body.contains("\"uploaded\":100"));
+        assert!(body.contains("\"uploaded\":200"));
+    }
+
+    #[actix_rt::test]
+    async fn top_torrents() {
+        let stores = admin_state();
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(crate::network::parse_announce)),
+                )
+                .service(
+                    web::scope("admin")
+                        .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                        .app_data(stores.clone())
+                        .route("top", web::get().to(get_top)),
+                ),
+        )
+        .await;
+
+        // Two peers on the first torrent, and three announces from one peer on the second
+        let announces = [
+            (
+                "A1B2C3D4E5F6G7H8I9J0",
+                "-DE9824-143964258012",
+                6881,
+                "started",
+            ),
+            (
+                "A1B2C3D4E5F6G7H8I9J0",
+                "-DE9824-143964258013",
+                6882,
+                "started",
+            ),
+            (
+                "B2C3D4E5F6G7H8I9J0K1",
+                "-DE9824-143964258012",
+                6881,
+                "started",
+            ),
+            ("B2C3D4E5F6G7H8I9J0K1", "-DE9824-143964258012", 6881, ""),
+            ("B2C3D4E5F6G7H8I9J0K1", "-DE9824-143964258012", 6881, ""),
+        ];
+        for (info_hash, peer_id, port, event) in announces.iter() {
+            let req = test::TestRequest::with_uri(&format!("/announce?info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left=727955456&compact=1&event={}", info_hash, peer_id, port, event))
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+
+        let top = |uri: &'static str| {
+            test::TestRequest::with_uri(uri)
+                .header("Authorization", "Bearer secret")
+                .to_request()
+        };
+        let by_peers: serde_json::Value =
+            test::read_response_json(&mut app, top("/admin/top")).await;
+        assert_eq!(by_peers.as_array().unwrap().len(), 2);
+        assert_eq!(
+            by_peers[0]["info_hash"],
+            "4131423243334434453546364737483849394a30"
+        );
+        assert_eq!(by_peers[0]["leechers"], 2);
+        assert_eq!(by_peers[0]["recent_announces"], 2.0);
+
+        let by_announces: serde_json::Value =
+            test::read_response_json(&mut app, top("/admin/top?by=announces&limit=1")).await;
+        assert_eq!(by_announces.as_array().unwrap().len(), 1);
+        assert_eq!(
+            by_announces[0]["info_hash"],
+            "423243334434453546364737483849394a304b31"
+        );
+        assert_eq!(by_announces[0]["recent_announces"], 3.0);
+    }
+
+    #[actix_rt::test]
+    async fn freeleech_skips_download_charges() {
+        let mut config = Config::default();
+        config.admin.tokens = vec!["secret".to_string()];
+        config.private.enabled = true;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut users = UserRecords::new();
+        users.insert(
+            "alice".to_string(),
+            User {
+                id: 1,
+                passkey: "alice".to_string(),
+            },
+        );
+        stores.user_store.replace(users).await;
+        let mut app = test::init_service(
+            App::new()
+                .service(web::scope("announce").app_data(stores.clone()).route(
+                    "/{passkey}",
+                    web::get().to(crate::network::parse_private_announce),
+                ))
+                .service(
+                    web::scope("admin")
+                        .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                        .app_data(stores.clone())
+                        .route("freeleech", web::post().to(set_freeleech)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/freeleech")
+            .header("Authorization", "Bearer secret")
+            .header("Content-Type", "application/json")
+            .set_payload(r#"{"add": ["4131423243334434453546364737483849394a30"]}"#)
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(
+            resp,
+            r#"{"global":false,"torrents":["4131423243334434453546364737483849394a30"]}"#
+                .as_bytes()
+        );
+
+        for (transferred, event) in &[(0, "started"), (100, "")] {
+            let req = test::TestRequest::with_uri(&format!("/announce/alice?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded={0}&downloaded={0}&left=727955456&compact=1&event={1}", transferred, event))
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+
+        assert_eq!(
+            stores.user_store.take_traffic(),
+            vec![(
+                1,
+                UserTraffic {
+                    uploaded: 100,
+                    downloaded: 0,
+                }
+            )]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn revoked_passkeys_cut_off() {
+        let mut config = Config::default();
+        config.admin.tokens = vec!["secret".to_string()];
+        config.private.enabled = true;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut users = UserRecords::new();
+        users.insert(
+            "alice".to_string(),
+            User {
+                id: 1,
+                passkey: "alice".to_string(),
+            },
+        );
+        stores.user_store.replace(users.clone()).await;
+        let mut app = test::init_service(
+            App::new()
+                .service(web::scope("announce").app_data(stores.clone()).route(
+                    "/{passkey}",
+                    web::get().to(crate::network::parse_private_announce),
+                ))
+                .service(
+                    web::scope("admin")
+                        .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                        .app_data(stores.clone())
+                        .route("revocations", web::post().to(set_revocations)),
+                ),
+        )
+        .await;
+        let announce = || {
+            test::TestRequest::with_uri("/announce/alice?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=727955456&compact=1&event=started")
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request()
+        };
+        let revocations = |body: &'static str| {
+            test::TestRequest::post()
+                .uri("/admin/revocations")
+                .header("Authorization", "Bearer secret")
+                .header("Content-Type", "application/json")
+                .set_payload(body)
+                .to_request()
+        };
+        let revoked = |resp: &[u8]| {
+            String::from_utf8_lossy(resp).contains(&ClientError::RevokedPasskey.text())
+        };
+
+        let resp = test::read_response(&mut app, revocations(r#"{"add": ["alice"]}"#)).await;
+        assert_eq!(resp, r#"{"passkeys":["alice"]}"#.as_bytes());
+        assert!(revoked(&test::read_response(&mut app, announce()).await));
+
+        // Still revoked after a reload that hasn't caught up yet
+        stores.user_store.replace(users.clone()).await;
+        assert!(revoked(&test::read_response(&mut app, announce()).await));
+
+        test::read_response(&mut app, revocations(r#"{"remove": ["alice"]}"#)).await;
+        assert!(!revoked(&test::read_response(&mut app, announce()).await));
+
+        // Dropped once the passkey is gone from the database
+        test::read_response(&mut app, revocations(r#"{"add": ["alice"]}"#)).await;
+        stores.user_store.replace(UserRecords::new()).await;
+        assert!(stores.user_store.revoked().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn reload_applies_new_config() {
+        let path = std::env::temp_dir().join("tyto_reload_test.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [network]
+            binding = "0.0.0.0:8585"
+            [storage]
+            backend = "mysql"
+            path = "mysql://root:@localhost:3306/tyto"
+            [bt]
+            announce_rate = 900
+            peer_timeout = 7200
+            reap_interval = 1800
+            flush_interval = 900
+            [client_approval]
+            enabled = true
+            blacklist_style = false
+            versioned = false
+            client_list = ["TR"]
+            [admin]
+            tokens = ["secret"]
+            "#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config.admin.tokens = vec!["secret".to_string()];
+        let mut state = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        state.config_path = path.to_string_lossy().to_string();
+        let stores = web::Data::new(state);
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("admin")
+                    .wrap(TokenAuth::new(stores.get_ref().clone(), false))
+                    .app_data(stores.clone())
+                    .route("reload", web::post().to(reload)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/reload")
+            .header("Authorization", "Bearer secret")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(stores.config().bt.announce_rate, 900);
+        assert!(stores.client_approval.is_enabled());
+
+        // A file that doesn't parse leaves the running config alone
+        std::fs::write(&path, "[bt]\nannounce_rate = \"soon\"").unwrap();
+        let req = test::TestRequest::post()
+            .uri("/admin/reload")
+            .header("Authorization", "Bearer secret")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(stores.config().bt.announce_rate, 900);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use serde::Serialize;
+
+use crate::bittorrent::{InfoHash, PeerId};
+
+// Only the most recent catches are kept for the stats API
+const MAX_FLAGGED: usize = 1000;
+
+// A peer that claimed to upload faster than any real connection could
+#[derive(Debug, Clone, Serialize)]
+pub struct FlaggedPeer {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub ip: IpAddr,
+    // Bytes per second since the peer's previous announce
+    pub upload_rate: u64,
+    pub timestamp: u64,
+}
+
+// Recently caught cheaters, and the addresses that are banned along with
+// when their bans run out. Bans only live as long as the process does.
+#[derive(Debug, Clone, Default)]
+pub struct Cheaters {
+    flagged: Arc<Mutex<VecDeque<FlaggedPeer>>>,
+    bans: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+}
+
+impl Cheaters {
+    pub fn new() -> Cheaters {
+        Cheaters::default()
+    }
+
+    pub fn flag(&self, peer: FlaggedPeer) {
+        let mut flagged = self.flagged.lock().unwrap();
+        if flagged.len() == MAX_FLAGGED {
+            flagged.pop_front();
+        }
+        flagged.push_back(peer);
+    }
+
+    pub fn flagged(&self) -> Vec<FlaggedPeer> {
+        self.flagged.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.bans
+            .lock()
+            .unwrap()
+            .insert(ip, Instant::now() + duration);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cheater_bans_expire() {
+        let cheaters = Cheaters::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        cheaters.ban(ip, Duration::from_secs(60));
+        cheaters.ban(other, Duration::new(0, 0));
+
+        assert!(cheaters.is_banned(ip));
+        assert!(!cheaters.is_banned(other));
+        assert!(!cheaters.is_banned("192.0.2.3".parse().unwrap()));
+    }
+}
@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use tokio::net::lookup_host;
+use tokio::sync::RwLock;
+use tokio::time::timeout;
+
+use crate::config;
+
+// Expired results are only swept out once the cache grows this large
+const CACHE_SWEEP_SIZE: usize = 100_000;
+
+// Checks announcing addresses against DNS blocklists. A listed address
+// resolves to something under the blocklist's zone, while an unlisted
+// one does not resolve at all. Results are cached, and lookups that
+// don't finish in time are treated as unlisted so announces never stall.
+#[derive(Debug, Clone)]
+pub struct Dnsbl {
+    zones: Vec<String>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Arc<RwLock<HashMap<IpAddr, (bool, Instant)>>>,
+}
+
+// Blocklists are queried with the address reversed, nibble by nibble for v6
+pub fn query_name(ip: IpAddr, zone: &str) -> String {
+    let reversed: Vec<String> = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().rev().map(|o| o.to_string()).collect(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|o| vec![o & 0xf, o >> 4])
+            .map(|n| format!("{:x}", n))
+            .collect(),
+    };
+
+    format!("{}.{}", reversed.join("."), zone)
+}
+
+impl Dnsbl {
+    pub fn new(config: &config::Dnsbl) -> Dnsbl {
+        Dnsbl {
+            zones: config.zones.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            cache_ttl: Duration::new(config.cache_ttl, 0),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.zones.is_empty()
+    }
+
+    pub async fn is_listed(&self, ip: IpAddr) -> bool {
+        if let Some((listed, checked)) = self.cache.read().await.get(&ip) {
+            if checked.elapsed() < self.cache_ttl {
+                return *listed;
+            }
+        }
+
+        let mut listed = false;
+        for zone in &self.zones {
+            match timeout(
+                self.timeout,
+                lookup_host((query_name(ip, zone).as_str(), 0)),
+            )
+            .await
+            {
+                Ok(Ok(mut addrs)) => {
+                    if addrs.next().is_some() {
+                        listed = true;
+                        break;
+                    }
+                }
+                Ok(Err(_)) => {}
+                // Don't cache anything if a blocklist couldn't answer
+                Err(_) => return false,
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        if cache.len() >= CACHE_SWEEP_SIZE {
+            let ttl = self.cache_ttl;
+            cache.retain(|_, (_, checked)| checked.elapsed() < ttl);
+        }
+        cache.insert(ip, (listed, Instant::now()));
+
+        listed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dnsbl_query_name() {
+        assert_eq!(
+            query_name("192.0.2.99".parse().unwrap(), "dnsbl.example.org"),
+            "99.2.0.192.dnsbl.example.org"
+        );
+        assert_eq!(
+            query_name("2001:db8::567:89ab".parse().unwrap(), "dnsbl.example.org"),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.dnsbl.example.org"
+        );
+    }
+
+    #[tokio::test]
+    async fn dnsbl_disabled_without_zones() {
+        let dnsbl = Dnsbl::new(&config::Dnsbl::default());
+
+        assert!(!dnsbl.is_enabled());
+        assert!(!dnsbl.is_listed("192.0.2.99".parse().unwrap()).await);
+    }
+}
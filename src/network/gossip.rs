@@ -0,0 +1,880 @@
+// Cluster mode: trackers share their swarms without a shared database by
+// gossiping over UDP. Each one sends the changes its own announces made to
+// every other member, and every so often compares digests of its swarms
+// with one of them so that changes lost on the way are caught up on. The
+// rate limit tokens each member took for an address are sent the same way.
+// Changes are applied peer by peer, keeping whichever announce is newest,
+// so it doesn't matter in what order they arrive. Every message is signed
+// with the cluster key, and anything not signed with it, or not sent from
+// a seed's address or the allowed ranges, is dropped unanswered. So is any
+// message that has already been received once.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hashbrown::hash_map::Entry;
+use hashbrown::{HashMap, HashSet};
+use hmac::{Hmac, Mac, NewMac};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+
+use crate::bittorrent::{InfoHash, Peer, PeerId};
+use crate::blocklist::RangeSet;
+use crate::config::Cluster;
+use crate::errors::InternalError;
+use crate::state::State;
+use crate::storage::{PeerRole, Swarm};
+
+// Large enough for any UDP datagram
+const RECEIVE_BUFFER_SIZE: usize = 65_536;
+
+// These keep messages to around 16 KB
+const DELTAS_PER_MESSAGE: usize = 200;
+const DIGESTS_PER_MESSAGE: usize = 500;
+const RATE_COUNTS_PER_MESSAGE: usize = 500;
+
+// Messages start with an HMAC-SHA256 of the rest
+const TAG_SIZE: usize = 32;
+
+// Messages sent longer ago than this (or as far in the future) are
+// dropped, so that captured ones can't be replayed later on
+const MAX_MESSAGE_AGE_MILLIS: u64 = 60_000;
+
+// How far behind the newest message from a member one can arrive and
+// still be taken. Any older ones are treated as replays, which does
+// no harm to genuine ones as digests catch up on what they carried.
+const REPLAY_WINDOW: u64 = 128;
+
+// Members that haven't been heard from for this many rounds are
+// forgotten, apart from the seeds, which are always gossiped with
+const MEMBER_TIMEOUT_ROUNDS: u32 = 10;
+
+// A peer as it stands after a change, or None for the role once it has
+// left the swarm. Instants can't be sent, so the time of its last
+// announce is sent as its age in millis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerDelta {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub role: Option<PeerRole>,
+    pub age: u64,
+}
+
+impl PeerDelta {
+    pub fn new(
+        info_hash: InfoHash,
+        peer: &Peer,
+        role: Option<PeerRole>,
+        now: Instant,
+    ) -> PeerDelta {
+        let (uploaded, downloaded) = peer.traffic();
+        PeerDelta {
+            info_hash,
+            peer_id: peer.peer_id(),
+            ip: peer.ip(),
+            port: peer.port(),
+            uploaded,
+            downloaded,
+            role,
+            age: now
+                .saturating_duration_since(peer.last_announced())
+                .as_millis() as u64,
+        }
+    }
+
+    pub fn peer(&self, now: Instant) -> Peer {
+        let mut peer = Peer::new(
+            self.peer_id,
+            self.ip,
+            self.port,
+            self.uploaded,
+            self.downloaded,
+        );
+        let age = Duration::from_millis(self.age);
+        peer.set_last_announced(now.checked_sub(age).unwrap_or(now));
+        peer
+    }
+}
+
+type PendingChanges = HashMap<(InfoHash, Peer), Option<PeerRole>>;
+
+// Changes made by this tracker's own announces, held until the next
+// round. Only the latest change to each peer is kept.
+#[derive(Clone)]
+pub struct GossipLog {
+    pending: Option<Arc<Mutex<PendingChanges>>>,
+}
+
+impl GossipLog {
+    pub fn new(enabled: bool) -> GossipLog {
+        GossipLog {
+            pending: if enabled {
+                Some(Arc::new(Mutex::new(HashMap::new())))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn record(&self, info_hash: InfoHash, peer: Peer, role: Option<PeerRole>) {
+        if let Some(pending) = &self.pending {
+            // Peers are keyed by ID and address alone,
+            // so the stored one has to be replaced outright
+            let mut pending = pending.lock().unwrap();
+            let key = (info_hash, peer);
+            pending.remove(&key);
+            pending.insert(key, role);
+        }
+    }
+
+    pub fn take(&self) -> Vec<PeerDelta> {
+        let pending = match &self.pending {
+            Some(pending) => std::mem::take(&mut *pending.lock().unwrap()),
+            None => return Vec::new(),
+        };
+
+        let now = Instant::now();
+        pending
+            .into_iter()
+            .map(|((info_hash, peer), role)| PeerDelta::new(info_hash, &peer, role, now))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Body {
+    Deltas(Vec<PeerDelta>),
+    // A hash of each swarm's peers and their roles. Replies only carry
+    // the swarms that differed, and aren't replied to in turn.
+    Digest {
+        swarms: Vec<(InfoHash, u64)>,
+        reply: bool,
+    },
+    // How many rate limit tokens were taken for each address
+    RateCounts(Vec<(IpAddr, u32)>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+    // Unix time in millis
+    sent: u64,
+    // Picked at random on startup, so a tracker can tell
+    // when it has been sent its own messages
+    node: u64,
+    // One more than the last message the node sent
+    seq: u64,
+    members: Vec<SocketAddr>,
+    body: Body,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn swarm_peers(sw: &Swarm) -> impl Iterator<Item = (&Peer, PeerRole)> {
+    sw.seeders
+        .iter()
+        .map(|p| (p, PeerRole::Seeder))
+        .chain(sw.leechers.iter().map(|p| (p, PeerRole::Leecher)))
+        .chain(sw.partial_seeds.iter().map(|p| (p, PeerRole::PartialSeed)))
+}
+
+// Peers hash by ID and address, so announce times and traffic don't
+// count. DefaultHasher::new() always uses the same keys, so every member
+// built from the same release comes to the same digest for the same swarm.
+fn swarm_digest(sw: &Swarm) -> u64 {
+    swarm_peers(sw).fold(0, |digest, (peer, role)| {
+        let mut hasher = DefaultHasher::new();
+        peer.hash(&mut hasher);
+        role.as_str().hash(&mut hasher);
+        digest ^ hasher.finish()
+    })
+}
+
+// Applies a change made elsewhere, and keeps the torrent counts and
+// statistics in step with it the same way an announce would
+pub async fn apply(state: &State, delta: PeerDelta) {
+    let info_hash = delta.info_hash;
+    let peer = delta.peer(Instant::now());
+    let previous = match state.peer_store.set_role(info_hash, peer, delta.role).await {
+        Some(previous) => previous,
+        None => return,
+    };
+
+    let torrents = &state.torrent_store;
+    match (previous, delta.role) {
+        (None, Some(PeerRole::Seeder)) => {
+            torrents.add_seed(info_hash).await;
+            state.stats.write().await.add_seed();
+        }
+        (None, Some(PeerRole::Leecher)) => {
+            torrents.new_leech(info_hash).await;
+            state.stats.write().await.add_leech();
+        }
+        (None, Some(PeerRole::PartialSeed)) => {
+            torrents.new_partial_seed(info_hash, false).await;
+            state.stats.write().await.add_leech();
+        }
+        (Some(PeerRole::Leecher), Some(PeerRole::Seeder))
+        | (Some(PeerRole::PartialSeed), Some(PeerRole::Seeder)) => {
+            torrents.new_seed(info_hash).await;
+            state.stats.write().await.promote_leech();
+        }
+        (Some(PeerRole::Leecher), Some(PeerRole::PartialSeed)) => {
+            torrents.new_partial_seed(info_hash, true).await;
+        }
+        (Some(PeerRole::PartialSeed), Some(PeerRole::Leecher)) => {
+            torrents.resume_partial_seed(info_hash).await;
+            torrents.new_leech(info_hash).await;
+        }
+        // Seeders only become leechers again by starting over,
+        // so they're counted as having left and come back
+        (Some(PeerRole::Seeder), Some(PeerRole::Leecher)) => {
+            torrents.new_leech(info_hash).await;
+            let mut stats = state.stats.write().await;
+            stats.sub_seed();
+            stats.add_leech();
+        }
+        (Some(PeerRole::Seeder), Some(PeerRole::PartialSeed)) => {
+            let mut stats = state.stats.write().await;
+            stats.sub_seed();
+            stats.add_leech();
+        }
+        (Some(PeerRole::Seeder), None) => state.stats.write().await.sub_seed(),
+        (Some(_), None) => state.stats.write().await.sub_leech(),
+        _ => {}
+    }
+}
+
+// The messages received from a node, which are only taken once each.
+// Like IPsec's anti-replay window, it keeps the newest number along
+// with a bit for each of the REPLAY_WINDOW numbers before it.
+struct Received {
+    newest: u64,
+    window: u128,
+    heard: Instant,
+}
+
+impl Received {
+    fn new(seq: u64) -> Received {
+        Received {
+            newest: seq,
+            window: 1,
+            heard: Instant::now(),
+        }
+    }
+
+    // Returns whether the number is new, marking it as received if it is
+    fn receive(&mut self, seq: u64) -> bool {
+        if seq > self.newest {
+            let ahead = seq - self.newest;
+            self.window = if ahead < REPLAY_WINDOW {
+                self.window << ahead
+            } else {
+                0
+            };
+            self.window |= 1;
+            self.newest = seq;
+        } else {
+            let behind = self.newest - seq;
+            if behind >= REPLAY_WINDOW || self.window & (1 << behind) != 0 {
+                return false;
+            }
+            self.window |= 1 << behind;
+        }
+
+        self.heard = Instant::now();
+        true
+    }
+}
+
+struct Members {
+    // When each member was last heard from
+    heard: HashMap<SocketAddr, Instant>,
+    // Addresses this tracker was found to have sent messages from
+    own: HashSet<SocketAddr>,
+    // The messages received from each node
+    received: HashMap<u64, Received>,
+}
+
+#[derive(Clone)]
+pub struct GossipNode {
+    state: State,
+    key: Arc<Vec<u8>>,
+    node: u64,
+    seq: Arc<AtomicU64>,
+    seeds: Vec<SocketAddr>,
+    // Members outside of these and the seeds' addresses aren't gossiped with
+    allowed: Arc<RangeSet>,
+    members: Arc<Mutex<Members>>,
+    interval: Duration,
+    digest_interval: Duration,
+}
+
+impl GossipNode {
+    // Seeds are only resolved once; any that can't be are skipped
+    pub fn new(state: State, config: &Cluster) -> GossipNode {
+        let seeds = config
+            .seeds
+            .iter()
+            .filter_map(|seed| match seed.to_socket_addrs() {
+                Ok(mut addrs) => addrs.next(),
+                Err(e) => {
+                    warn!("{} ({}: {})", InternalError::ClusterSeed.text(), seed, e);
+                    None
+                }
+            })
+            .collect();
+
+        let (allowed, skipped) = RangeSet::parse(&config.allow.join("\n"));
+        if skipped > 0 {
+            warn!("Skipped {} invalid entries in cluster.allow", skipped);
+        }
+
+        GossipNode {
+            state,
+            key: Arc::new(config.key.as_bytes().to_vec()),
+            node: rand::random(),
+            seq: Arc::new(AtomicU64::new(0)),
+            seeds,
+            allowed: Arc::new(allowed),
+            members: Arc::new(Mutex::new(Members {
+                heard: HashMap::new(),
+                own: HashSet::new(),
+                received: HashMap::new(),
+            })),
+            interval: Duration::from_secs(config.interval),
+            digest_interval: Duration::from_secs(config.digest_interval),
+        }
+    }
+
+    pub async fn serve(self, socket: UdpSocket) {
+        let (mut recv, send) = socket.split();
+        let send = Arc::new(tokio::sync::Mutex::new(send));
+        actix_rt::spawn(self.clone().gossip(send.clone()));
+
+        let mut buf = vec![0u8; RECEIVE_BUFFER_SIZE];
+        loop {
+            let (len, src) = match recv.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("{} ({})", InternalError::ClusterSocket.text(), e);
+                    continue;
+                }
+            };
+
+            // Messages are handled one at a time, in the order they came in
+            let replies = self.handle_packet(&buf[..len], src).await;
+            send_all(&send, &replies, &[src]).await;
+        }
+    }
+
+    // Every round, this tracker's own changes go out to every member,
+    // even when there are none so that members know it's still there
+    async fn gossip(self, send: Arc<tokio::sync::Mutex<SendHalf>>) {
+        let mut rounds = tokio::time::interval(self.interval);
+        let mut last_digest = Instant::now();
+
+        loop {
+            rounds.tick().await;
+            let targets = self.targets();
+            send_all(&send, &self.round_messages(), &targets).await;
+
+            if last_digest.elapsed() >= self.digest_interval {
+                last_digest = Instant::now();
+                if let Some(target) = targets.choose(&mut rand::thread_rng()) {
+                    send_all(&send, &self.digest_messages().await, &[*target]).await;
+                }
+            }
+        }
+    }
+
+    // The seeds and every member heard from recently, other than this tracker
+    fn targets(&self) -> Vec<SocketAddr> {
+        let timeout = self.interval * MEMBER_TIMEOUT_ROUNDS;
+        let mut members = self.members.lock().unwrap();
+        members.heard.retain(|_, heard| heard.elapsed() < timeout);
+
+        // Replays are only caught while the node is remembered, so
+        // it's remembered for as long as its messages are taken
+        let forget = timeout.max(Duration::from_millis(MAX_MESSAGE_AGE_MILLIS));
+        members
+            .received
+            .retain(|_, received| received.heard.elapsed() < forget);
+
+        let mut targets: Vec<SocketAddr> = members.heard.keys().copied().collect();
+        for seed in &self.seeds {
+            if !members.own.contains(seed) && !members.heard.contains_key(seed) {
+                targets.push(*seed);
+            }
+        }
+        targets
+    }
+
+    fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        self.seeds.iter().any(|seed| seed.ip() == addr.ip()) || self.allowed.contains(addr.ip())
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        // HMAC takes keys of any length
+        HmacSha256::new_varkey(&self.key).unwrap()
+    }
+
+    fn encode(&self, body: Body) -> Option<Vec<u8>> {
+        let members = self.members.lock().unwrap().heard.keys().copied().collect();
+        let message = Message {
+            sent: unix_millis(),
+            node: self.node,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1,
+            members,
+            body,
+        };
+        let payload = bincode::serialize(&message).ok()?;
+
+        let mut mac = self.mac();
+        mac.update(&payload);
+        let mut packet = mac.finalize().into_bytes().to_vec();
+        packet.extend(payload);
+        Some(packet)
+    }
+
+    // The message in the packet if it's signed with the key and recent
+    fn decode(&self, packet: &[u8]) -> Option<Message> {
+        if packet.len() < TAG_SIZE {
+            return None;
+        }
+        let (tag, payload) = packet.split_at(TAG_SIZE);
+        let mut mac = self.mac();
+        mac.update(payload);
+        mac.verify(tag).ok()?;
+
+        let message: Message = bincode::deserialize(payload).ok()?;
+        let now = unix_millis();
+        if message.sent.max(now) - message.sent.min(now) > MAX_MESSAGE_AGE_MILLIS {
+            return None;
+        }
+        Some(message)
+    }
+
+    fn encode_deltas(&self, deltas: Vec<PeerDelta>) -> Vec<Vec<u8>> {
+        deltas
+            .chunks(DELTAS_PER_MESSAGE)
+            .filter_map(|chunk| self.encode(Body::Deltas(chunk.to_vec())))
+            .collect()
+    }
+
+    fn encode_digests(&self, swarms: Vec<(InfoHash, u64)>, reply: bool) -> Vec<Vec<u8>> {
+        swarms
+            .chunks(DIGESTS_PER_MESSAGE)
+            .filter_map(|chunk| {
+                self.encode(Body::Digest {
+                    swarms: chunk.to_vec(),
+                    reply,
+                })
+            })
+            .collect()
+    }
+
+    fn round_messages(&self) -> Vec<Vec<u8>> {
+        let deltas = self.state.gossip.take();
+        let mut messages = if deltas.is_empty() {
+            self.encode(Body::Deltas(Vec::new())).into_iter().collect()
+        } else {
+            self.encode_deltas(deltas)
+        };
+
+        let counts = self.state.rate_limiter.take_counts();
+        messages.extend(
+            counts
+                .chunks(RATE_COUNTS_PER_MESSAGE)
+                .filter_map(|chunk| self.encode(Body::RateCounts(chunk.to_vec()))),
+        );
+        messages
+    }
+
+    async fn digest_messages(&self) -> Vec<Vec<u8>> {
+        let mut swarms = Vec::new();
+        for shard in self.state.peer_store.shards() {
+            for (info_hash, sw) in shard.read().await.iter() {
+                swarms.push((*info_hash, swarm_digest(sw)));
+            }
+        }
+        self.encode_digests(swarms, false)
+    }
+
+    // Returns the messages to send back to the member, if any. Anything
+    // from outside the cluster's addresses, or that isn't signed with its
+    // key, is dropped before it's even decoded.
+    pub async fn handle_packet(&self, packet: &[u8], src: SocketAddr) -> Vec<Vec<u8>> {
+        if !self.is_allowed(&src) {
+            return Vec::new();
+        }
+        let message = match self.decode(packet) {
+            Some(message) => message,
+            None => return Vec::new(),
+        };
+
+        {
+            let mut members = self.members.lock().unwrap();
+            if message.node == self.node {
+                members.own.insert(src);
+                members.heard.remove(&src);
+                return Vec::new();
+            }
+
+            match members.received.entry(message.node) {
+                Entry::Occupied(mut received) => {
+                    if !received.get_mut().receive(message.seq) {
+                        return Vec::new();
+                    }
+                }
+                Entry::Vacant(received) => {
+                    received.insert(Received::new(message.seq));
+                }
+            }
+
+            let now = Instant::now();
+            members.heard.insert(src, now);
+            for member in message.members {
+                if self.is_allowed(&member)
+                    && !members.own.contains(&member)
+                    && !members.heard.contains_key(&member)
+                {
+                    members.heard.insert(member, now);
+                }
+            }
+        }
+
+        match message.body {
+            Body::Deltas(deltas) => {
+                for delta in deltas {
+                    apply(&self.state, delta).await;
+                }
+                Vec::new()
+            }
+            Body::Digest { swarms, reply } => self.compare(swarms, reply).await,
+            Body::RateCounts(counts) => {
+                let limit = &self.state.config().rate_limit;
+                if limit.requests_per_sec > 0.0 {
+                    for (ip, count) in counts {
+                        self.state.rate_limiter.consume(
+                            ip,
+                            count,
+                            limit.requests_per_sec,
+                            limit.burst,
+                        );
+                    }
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    // Sends back every peer in the swarms that differ. Swarms this tracker
+    // doesn't have also differ, so unless this is a reply already, the
+    // digests it came to are sent back for the member to send its own.
+    async fn compare(&self, swarms: Vec<(InfoHash, u64)>, reply: bool) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut deltas = Vec::new();
+        let mut differing = Vec::new();
+
+        for (info_hash, theirs) in swarms {
+            let store = self.state.peer_store.shard(&info_hash).read().await;
+            let ours = store.get(&info_hash).map_or(0, swarm_digest);
+            if ours == theirs {
+                continue;
+            }
+
+            differing.push((info_hash, ours));
+            if let Some(sw) = store.get(&info_hash) {
+                deltas.extend(
+                    swarm_peers(sw)
+                        .map(|(peer, role)| PeerDelta::new(info_hash, peer, Some(role), now)),
+                );
+            }
+        }
+
+        let mut messages = self.encode_deltas(deltas);
+        if !reply {
+            messages.extend(self.encode_digests(differing, true));
+        }
+        messages
+    }
+}
+
+async fn send_all(
+    send: &tokio::sync::Mutex<SendHalf>,
+    messages: &[Vec<u8>],
+    targets: &[SocketAddr],
+) {
+    let mut send = send.lock().await;
+    for target in targets {
+        for message in messages {
+            if let Err(e) = send.send_to(message, target).await {
+                warn!("{} ({})", InternalError::ClusterSocket.text(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::{AnnounceRequest, PeerFormat};
+    use crate::config::Config;
+    use crate::network::handle_announce;
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    const INFO_HASH: InfoHash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+    const KEY: &str = "correct horse battery staple";
+
+    fn node(key: &str) -> GossipNode {
+        let mut config = Config::default();
+        config.cluster.bind = "127.0.0.1:0".to_string();
+        config.cluster.allow = vec!["192.0.2.0/24".to_string()];
+        config.cluster.key = key.to_string();
+        config.rate_limit.requests_per_sec = 0.01;
+        config.rate_limit.burst = 2;
+
+        let mut records = TorrentRecords::new();
+        records.insert(INFO_HASH, Torrent::new(INFO_HASH, 0, 0, 0, 0));
+        let state = State::new(config.clone(), TorrentStore::new(records));
+        GossipNode::new(state, &config.cluster)
+    }
+
+    async fn announce(node: &GossipNode, peer_id: &str, left: u64, event: &str) {
+        let query = format!(
+            "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id={}&port=6881&uploaded=0&downloaded=0&left={}&event={}",
+            peer_id, left, event
+        );
+        let request = AnnounceRequest::new(&query, Some("8.8.4.4:51413")).unwrap();
+        let format = PeerFormat::of(&request);
+        handle_announce(&node.state, request, format).await.unwrap();
+    }
+
+    async fn deliver(messages: Vec<Vec<u8>>, to: &GossipNode, from: SocketAddr) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        for message in messages {
+            replies.extend(to.handle_packet(&message, from).await);
+        }
+        replies
+    }
+
+    #[actix_rt::test]
+    async fn gossip_shares_announces() {
+        let first = node(KEY);
+        let second = node(KEY);
+        let first_addr: SocketAddr = "192.0.2.1:6667".parse().unwrap();
+
+        announce(&first, "-DE9824-143964258012", 1000, "started").await;
+        announce(&first, "-qB4250-143964258012", 0, "started").await;
+        announce(&first, "-qB4250-143964258012", 0, "completed").await;
+        assert!(deliver(first.round_messages(), &second, first_addr)
+            .await
+            .is_empty());
+
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (1, 1));
+        assert_eq!(
+            second
+                .state
+                .torrent_store
+                .get_announce_stats(INFO_HASH)
+                .await,
+            (1, 0, 1)
+        );
+        let stats = second.state.stats.read().await;
+        assert_eq!((stats.total_seeders, stats.total_leechers), (1, 1));
+        drop(stats);
+
+        // Peers that stop are taken out everywhere
+        announce(&first, "-DE9824-143964258012", 1000, "stopped").await;
+        deliver(first.round_messages(), &second, first_addr).await;
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (1, 0));
+        assert_eq!(second.state.stats.read().await.total_leechers, 0);
+
+        // Messages from other clusters are ignored, and their senders
+        // don't become members
+        let other = node("another cluster's key");
+        let other_addr: SocketAddr = "192.0.2.3:6667".parse().unwrap();
+        announce(&other, "-DE9824-143964258012", 1000, "started").await;
+        deliver(other.round_messages(), &second, other_addr).await;
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (1, 0));
+        assert_eq!(second.targets(), vec![first_addr]);
+    }
+
+    #[actix_rt::test]
+    async fn gossip_is_authenticated() {
+        let first = node(KEY);
+        let second = node(KEY);
+        let first_addr: SocketAddr = "192.0.2.1:6667".parse().unwrap();
+        announce(&first, "-DE9824-143964258012", 1000, "started").await;
+        let messages = first.round_messages();
+
+        // Tampered messages and unsigned ones are dropped
+        let mut tampered = messages[0].clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let payload = messages[0][TAG_SIZE..].to_vec();
+        assert!(deliver(vec![tampered, payload], &second, first_addr)
+            .await
+            .is_empty());
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (0, 0));
+
+        // So are signed ones from outside the allowed addresses, even digests
+        let outsider: SocketAddr = "198.51.100.1:6667".parse().unwrap();
+        deliver(messages.clone(), &second, outsider).await;
+        assert!(deliver(first.digest_messages().await, &second, outsider)
+            .await
+            .is_empty());
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (0, 0));
+        assert!(second.targets().is_empty());
+
+        // and stale ones, which could have been captured and replayed
+        let stale = Message {
+            sent: unix_millis() - 2 * MAX_MESSAGE_AGE_MILLIS,
+            node: first.node,
+            seq: 1_000,
+            members: Vec::new(),
+            body: Body::Deltas(Vec::new()),
+        };
+        let payload = bincode::serialize(&stale).unwrap();
+        let mut mac = first.mac();
+        mac.update(&payload);
+        let mut packet = mac.finalize().into_bytes().to_vec();
+        packet.extend(payload);
+        deliver(vec![packet], &second, first_addr).await;
+        assert!(second.targets().is_empty());
+
+        deliver(messages, &second, first_addr).await;
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (0, 1));
+
+        // Members passed along from outside the allowed addresses aren't taken in
+        first
+            .members
+            .lock()
+            .unwrap()
+            .heard
+            .insert(outsider, Instant::now());
+        deliver(first.round_messages(), &second, first_addr).await;
+        assert_eq!(second.targets(), vec![first_addr]);
+    }
+
+    #[actix_rt::test]
+    async fn gossip_drops_replays() {
+        let first = node(KEY);
+        let second = node(KEY);
+        let first_addr: SocketAddr = "192.0.2.1:6667".parse().unwrap();
+
+        announce(&first, "-DE9824-143964258012", 1000, "started").await;
+        let started = first.round_messages();
+        deliver(started.clone(), &second, first_addr).await;
+        announce(&first, "-DE9824-143964258012", 1000, "stopped").await;
+        deliver(first.round_messages(), &second, first_addr).await;
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (0, 0));
+
+        // Sent again, the started announce would bring the peer back
+        deliver(started, &second, first_addr).await;
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (0, 0));
+
+        // Messages may still arrive out of order, but only once each
+        let mut received = Received::new(10);
+        assert!(received.receive(12));
+        assert!(received.receive(11));
+        assert!(!received.receive(11));
+        assert!(!received.receive(10));
+        assert!(received.receive(12 + REPLAY_WINDOW));
+        assert!(!received.receive(12));
+    }
+
+    #[actix_rt::test]
+    async fn gossip_counts_match_announces() {
+        let first = node(KEY);
+        let second = node(KEY);
+        let first_addr: SocketAddr = "192.0.2.1:6667".parse().unwrap();
+
+        // A partial seed that starts leeching again
+        for event in &["started", "paused", "started"] {
+            announce(&first, "-DE9824-143964258012", 1000, event).await;
+            deliver(first.round_messages(), &second, first_addr).await;
+        }
+        assert_eq!(second.state.peer_store.swarm_size(&INFO_HASH).await, (0, 1));
+        assert_eq!(
+            second
+                .state
+                .torrent_store
+                .get_announce_stats(INFO_HASH)
+                .await,
+            first
+                .state
+                .torrent_store
+                .get_announce_stats(INFO_HASH)
+                .await
+        );
+    }
+
+    #[actix_rt::test]
+    async fn gossip_shares_rate_limits() {
+        let first = node(KEY);
+        let second = node(KEY);
+        let first_addr: SocketAddr = "192.0.2.1:6667".parse().unwrap();
+        let client: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // A client that used up part of its allowance on one tracker
+        // only has what's left of it on the others
+        assert!(first.state.rate_limiter.check(client, 0.01, 2).is_ok());
+        deliver(first.round_messages(), &second, first_addr).await;
+        assert!(second.state.rate_limiter.check(client, 0.01, 2).is_ok());
+        assert!(second.state.rate_limiter.check(client, 0.01, 2).is_err());
+
+        // Counts are only sent once
+        assert!(first.state.rate_limiter.take_counts().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn gossip_digests_catch_up() {
+        let first = node(KEY);
+        let second = node(KEY);
+        let first_addr: SocketAddr = "192.0.2.1:6667".parse().unwrap();
+        let second_addr: SocketAddr = "192.0.2.2:6667".parse().unwrap();
+
+        // The changes from these announces are lost on the way
+        announce(&first, "-DE9824-143964258012", 1000, "started").await;
+        first.state.gossip.take();
+        announce(&second, "-qB4250-143964258012", 0, "started").await;
+        second.state.gossip.take();
+
+        // Each side ends up with what it was missing from the other
+        let replies = deliver(first.digest_messages().await, &second, first_addr).await;
+        assert_eq!(replies.len(), 2);
+        let replies = deliver(replies, &first, second_addr).await;
+        assert_eq!(replies.len(), 1);
+        assert!(deliver(replies, &second, first_addr).await.is_empty());
+
+        for node in [&first, &second].iter() {
+            assert_eq!(node.state.peer_store.swarm_size(&INFO_HASH).await, (0, 2));
+        }
+        assert!(deliver(first.digest_messages().await, &second, first_addr)
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,516 @@
+// The actix-web side of the middleware: the filters and lists in the
+// parent module wrapped around the tracker routes, and the middleware
+// that only ever guards HTTP requests.
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::{Error, HttpResponse, ResponseError};
+use futures::future::{ok, Either, FutureExt, LocalBoxFuture, Ready};
+use subtle::ConstantTimeEq;
+
+use super::{ClientApproval, IpFilter, Route, TorrentApproval};
+use crate::bencode;
+use crate::bittorrent::{
+    query_value, query_values, AnnounceResponse, InfoHash, PeerId, ScrapeResponse,
+};
+use crate::errors::{ClientError, InternalError};
+use crate::network::client_ip;
+use crate::state::State;
+
+impl Route {
+    pub(super) fn failure(self, reason: String, retry_in: Option<u64>) -> Vec<u8> {
+        match self {
+            Route::Announce => {
+                bencode::encode_announce_response(AnnounceResponse::failure_retry(reason, retry_in))
+            }
+            Route::Scrape => {
+                bencode::encode_scrape_response(ScrapeResponse::failure_retry(reason, retry_in))
+            }
+        }
+    }
+}
+
+fn reject<B>(req: ServiceRequest, failure: AnnounceResponse) -> ServiceResponse<B> {
+    let bencoded = bencode::encode_announce_response(failure);
+    req.into_response(
+        HttpResponse::Ok()
+            .content_type("text/plain")
+            .body(bencoded)
+            .into_body(),
+    )
+}
+
+impl<S, B> Transform<S> for ClientApproval
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ClientApprovalMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ClientApprovalMiddleware {
+            service,
+            approval: self.clone(),
+        })
+    }
+}
+pub struct ClientApprovalMiddleware<S> {
+    service: S,
+    approval: ClientApproval,
+}
+
+impl<S, B> Service for ClientApprovalMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !self.approval.is_enabled() {
+            return Either::Left(self.service.call(req));
+        }
+
+        // If a client's peer ID is missing or malformed, this is a Bad Thing
+        let failure = match query_value(req.query_string(), b"peer_id")
+            .and_then(|value| PeerId::from_bytes(&value))
+        {
+            Some(peer_id) => self.approval.check(&peer_id),
+            None => Some(AnnounceResponse::failure(
+                ClientError::UnapprovedClient.text(),
+            )),
+        }
+        .or_else(|| {
+            let user_agent = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            self.approval.check_user_agent(user_agent)
+        });
+
+        match failure {
+            Some(failure) => Either::Right(ok(reject(req, failure))),
+            None => Either::Left(self.service.call(req)),
+        }
+    }
+}
+impl<S, B> Transform<S> for TorrentApproval
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TorrentApprovalMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TorrentApprovalMiddleware {
+            service,
+            approval: self.clone(),
+        })
+    }
+}
+pub struct TorrentApprovalMiddleware<S> {
+    service: S,
+    approval: TorrentApproval,
+}
+
+impl<S, B> Service for TorrentApprovalMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !self.approval.is_enabled() {
+            return Either::Left(self.service.call(req));
+        }
+
+        // Scrapes can ask for several torrents, and every one of them has
+        // to be approved. Those without any are full scrapes, which only
+        // list torrents the tracker already has.
+        let approved = query_values(req.query_string(), b"info_hash").all(|value| {
+            InfoHash::from_bytes(&value).is_some_and(|info_hash| self.approval.approves(&info_hash))
+        });
+
+        if approved {
+            Either::Left(self.service.call(req))
+        } else {
+            let failure = AnnounceResponse::failure(ClientError::UnapprovedTorrent.text());
+            Either::Right(ok(reject(req, failure)))
+        }
+    }
+}
+impl IpFilter {
+    // Requests whose address can't be made out are let through
+    fn check(&self, req: &ServiceRequest) -> Option<Vec<u8>> {
+        let ip = client_ip(req.peer_addr(), req.headers(), &self.proxies)?;
+        if self.permits(ip) {
+            return None;
+        }
+
+        Some(self.route.failure(ClientError::BlockedAddress.text(), None))
+    }
+}
+
+impl<S, B> Transform<S> for IpFilter
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IpFilterMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpFilterMiddleware {
+            service,
+            filter: self.clone(),
+        })
+    }
+}
+pub struct IpFilterMiddleware<S> {
+    service: S,
+    filter: IpFilter,
+}
+
+impl<S, B> Service for IpFilterMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match self.filter.check(&req) {
+            Some(bencoded) => {
+                let response = HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .body(bencoded)
+                    .into_body();
+                Either::Right(ok(req.into_response(response)))
+            }
+            None => Either::Left(self.service.call(req)),
+        }
+    }
+}
+// Guards the statistics, admin and debugging routes. Requests need an
+// "Authorization: Bearer <token>" header matching one of the tokens in the
+// admin config. Routes that were public before tokens existed can be left
+// open for trackers that haven't configured any; the rest are closed then.
+#[derive(Clone)]
+pub struct TokenAuth {
+    state: State,
+    open_without_tokens: bool,
+}
+
+impl TokenAuth {
+    pub fn new(state: State, open_without_tokens: bool) -> Self {
+        TokenAuth {
+            state,
+            open_without_tokens,
+        }
+    }
+
+    // Tokens are compared in constant time, and every one of them is
+    // compared, so that how long it takes gives nothing away
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let config = self.state.config();
+        if config.admin.tokens.is_empty() {
+            return self.open_without_tokens;
+        }
+
+        headers
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| {
+                config.admin.tokens.iter().fold(false, |found, t| {
+                    found | bool::from(t.as_bytes().ct_eq(token.as_bytes()))
+                })
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl<S, B> Transform<S> for TokenAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TokenAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TokenAuthMiddleware {
+            service,
+            auth: self.clone(),
+        })
+    }
+}
+pub struct TokenAuthMiddleware<S> {
+    service: S,
+    auth: TokenAuth,
+}
+
+impl<S, B> Service for TokenAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.auth.is_authorized(req.headers()) {
+            Either::Left(self.service.call(req))
+        } else {
+            let response = HttpResponse::Unauthorized().finish().into_body();
+            Either::Right(ok(req.into_response(response)))
+        }
+    }
+}
+
+// Limits how often each address can announce and scrape. Clients over
+// the limit get a bencoded failure with a BEP 31 retry hint rather than
+// an HTTP error, as most clients only back off for the former. Like the
+// IP filter, addresses are only taken from trusted proxies' headers.
+#[derive(Clone)]
+pub struct RateLimit {
+    state: State,
+    route: Route,
+}
+
+impl RateLimit {
+    pub fn new(state: State, route: Route) -> Self {
+        RateLimit { state, route }
+    }
+
+    // Returns the failure to send back if the request is over the limit
+    fn check(&self, req: &ServiceRequest) -> Option<Vec<u8>> {
+        let config = self.state.config();
+        let limit = &config.rate_limit;
+        if limit.requests_per_sec <= 0.0 {
+            return None;
+        }
+
+        let ip = client_ip(req.peer_addr(), req.headers(), &self.state.trusted_proxies)?;
+        let wait = self
+            .state
+            .rate_limiter
+            .check(ip, limit.requests_per_sec, limit.burst)
+            .err()?;
+
+        // The retry hint is in whole minutes, rounded up
+        let retry_in = Some(wait.as_secs() / 60 + 1);
+        Some(
+            self.route
+                .failure(ClientError::RateLimited.text(), retry_in),
+        )
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service,
+            limit: self.clone(),
+        })
+    }
+}
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limit: RateLimit,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match self.limit.check(&req) {
+            Some(bencoded) => {
+                let response = HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .body(bencoded)
+                    .into_body();
+                Either::Right(ok(req.into_response(response)))
+            }
+            None => Either::Left(self.service.call(req)),
+        }
+    }
+}
+
+// A panic while handling a request would otherwise drop the connection,
+// which clients take to mean the tracker is down. Instead they're sent a
+// failure they can understand, and the panic is logged. This goes around
+// everything else so that the other middleware is covered too.
+#[derive(Clone)]
+pub struct PanicRecovery {
+    route: Route,
+}
+
+impl PanicRecovery {
+    pub fn new(route: Route) -> Self {
+        PanicRecovery { route }
+    }
+}
+
+// Returned in place of the response, so that the server
+// sends the failure without needing the request back
+#[derive(Debug)]
+struct RecoveredPanic {
+    route: Route,
+}
+
+impl fmt::Display for RecoveredPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(InternalError::HandlerPanic.text())
+    }
+}
+
+impl ResponseError for RecoveredPanic {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let bencoded = self
+            .route
+            .failure(ClientError::InternalFailure.text(), None);
+        HttpResponse::Ok().content_type("text/plain").body(bencoded)
+    }
+}
+
+fn recovered(route: Route, path: &str) -> Error {
+    error!("{} ({})", InternalError::HandlerPanic.text(), path);
+    RecoveredPanic { route }.into()
+}
+
+impl<S, B> Transform<S> for PanicRecovery
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = PanicRecoveryMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(PanicRecoveryMiddleware {
+            service,
+            route: self.route,
+        })
+    }
+}
+pub struct PanicRecoveryMiddleware<S> {
+    service: S,
+    route: Route,
+}
+
+impl<S, B> Service for PanicRecoveryMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let route = self.route;
+        let path = req.path().to_string();
+        let service = &mut self.service;
+        let fut = match panic::catch_unwind(AssertUnwindSafe(|| service.call(req))) {
+            Ok(fut) => fut,
+            Err(_) => return Box::pin(async move { Err(recovered(route, &path)) }),
+        };
+
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(res) => res,
+                Err(_) => Err(recovered(route, &path)),
+            }
+        })
+    }
+}
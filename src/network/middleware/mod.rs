@@ -1,226 +1,409 @@
-use std::task::{Context, Poll};
+#[cfg(feature = "actix")]
+mod http;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 
-use actix_service::{Service, Transform};
-use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::{Error, HttpResponse};
-use futures::future::{ok, Either, Ready};
 use hashbrown::HashSet;
-use url::form_urlencoded;
 
-use crate::bencode;
-use crate::bittorrent::AnnounceResponse;
-use crate::errors::ClientError;
+use crate::bittorrent::{AnnounceResponse, InfoHash, PeerId};
+use crate::blocklist::{RangeSet, SharedRanges};
+use crate::config::{self, ClientPattern, ClientRule};
+use crate::errors::{ClientError, InternalError};
+use crate::storage::TorrentStore;
 
-pub struct ClientApproval {
+#[cfg(feature = "actix")]
+pub use self::http::{PanicRecovery, RateLimit, TokenAuth};
+
+struct ClientList {
+    enabled: bool,
     blacklist_style: bool,
     versioned: bool,
     list: HashSet<String>,
+    rules: HashMap<String, ClientRule>,
+    patterns: Vec<ClientPattern>,
+    user_agent_patterns: Vec<ClientPattern>,
 }
 
-impl ClientApproval {
-    pub fn new(blacklist_style: bool, versioned: bool, client_list: Vec<String>) -> Self {
-        ClientApproval {
-            blacklist_style,
-            versioned,
-            list: client_list.into_iter().collect(),
+impl From<&config::ClientApproval> for ClientList {
+    fn from(config: &config::ClientApproval) -> Self {
+        ClientList {
+            enabled: config.enabled,
+            blacklist_style: config.blacklist_style,
+            versioned: config.versioned,
+            list: config.client_list.iter().cloned().collect(),
+            rules: config.rules.clone(),
+            patterns: config.patterns.clone(),
+            user_agent_patterns: config.user_agent_patterns.clone(),
         }
     }
 }
 
-impl<S, B> Transform<S> for ClientApproval
-where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = ClientApprovalMiddleware<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-    fn new_transform(&self, service: S) -> Self::Future {
-        ok(ClientApprovalMiddleware {
-            service,
-            blacklist_style: self.blacklist_style,
-            versioned: self.versioned,
-            list: self.list.clone(),
-        })
+// Shell-style matching: '*' matches any run of characters, '?' any single
+// character, and '[0-3]' or '[abc]' any one character of a range or set
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| glob_matches(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_matches(rest, &text[1..]),
+        Some((b'[', rest)) if rest.contains(&b']') => {
+            let end = rest.iter().position(|&c| c == b']').unwrap();
+            match text.split_first() {
+                Some((&c, text_rest)) => {
+                    class_contains(&rest[..end], c) && glob_matches(&rest[end + 1..], text_rest)
+                }
+                None => false,
+            }
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_matches(rest, &text[1..]),
     }
 }
-pub struct ClientApprovalMiddleware<S> {
-    service: S,
-    blacklist_style: bool,
-    versioned: bool,
-    list: HashSet<String>,
+
+fn class_contains(class: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            if class[i] <= c && c <= class[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+// List files hold an entry per line. Blank lines
+// and anything after a '#' are skipped.
+fn read_list_file(path: &str) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split('#').next())
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+impl ClientList {
+    // The config's clients, along with any in its list file
+    fn load(config: &config::ClientApproval) -> io::Result<ClientList> {
+        let mut client_list = ClientList::from(config);
+        if !config.list_file.is_empty() {
+            client_list.list.extend(read_list_file(&config.list_file)?);
+        }
+        Ok(client_list)
+    }
 }
 
-impl<S, B> Service for ClientApprovalMiddleware<S>
-where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+// The list is shared by every worker and the UDP tracker, so that
+// a config reload swaps it out for all of them at once. Approval can
+// be switched on or off by a reload too, so the middleware is always
+// installed and lets everything through while it's disabled.
+#[derive(Clone)]
+pub struct ClientApproval {
+    inner: Arc<RwLock<ClientList>>,
+}
+
+impl ClientApproval {
+    pub fn new(
+        blacklist_style: bool,
+        versioned: bool,
+        client_list: Vec<String>,
+        rules: HashMap<String, ClientRule>,
+    ) -> Self {
+        ClientApproval {
+            inner: Arc::new(RwLock::new(ClientList {
+                enabled: true,
+                blacklist_style,
+                versioned,
+                list: client_list.into_iter().collect(),
+                rules,
+                patterns: Vec::new(),
+                user_agent_patterns: Vec::new(),
+            })),
+        }
+    }
+
+    // If the list file can't be read, only the config's clients are used
+    pub fn from_config(config: &config::ClientApproval) -> Self {
+        let client_list = ClientList::load(config).unwrap_or_else(|e| {
+            error!("{} ({})", InternalError::ApprovalListLoad.text(), e);
+            ClientList::from(config)
+        });
+        ClientApproval {
+            inner: Arc::new(RwLock::new(client_list)),
+        }
+    }
+
+    // If the list file can't be read, the list in use is kept
+    pub fn reload(&self, config: &config::ClientApproval) {
+        match ClientList::load(config) {
+            Ok(client_list) => *self.inner.write().unwrap() = client_list,
+            Err(e) => error!("{} ({})", InternalError::ApprovalListLoad.text(), e),
+        }
+    }
 
-    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+    pub fn is_enabled(&self) -> bool {
+        self.inner.read().unwrap().enabled
     }
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let request_kv_pairs = form_urlencoded::parse(req.query_string().as_bytes()).into_owned();
-        let mut peer_string: String = "".to_string();
+    // Returns the failure to send back if the client isn't approved.
+    // Rejected clients get the message and retry policy of their
+    // rule if there is one, and the generic failure otherwise.
+    pub fn check(&self, peer_id: &PeerId) -> Option<AnnounceResponse> {
+        let inner = self.inner.read().unwrap();
+        if !inner.enabled {
+            return None;
+        }
 
-        for (k, value) in request_kv_pairs {
-            if let "peer_id" = k.as_str() {
-                peer_string = value
+        // Most clients do Azureus-style encoding which
+        // looks like '-AZ1234-' followed by a random string
+        let client_end = if inner.versioned { 7 } else { 3 };
+        let client_check = std::str::from_utf8(&peer_id.0[1..client_end]).ok();
+
+        let pattern = inner
+            .patterns
+            .iter()
+            .find(|p| glob_matches(p.pattern.as_bytes(), &peer_id.0));
+        let approved = match pattern {
+            Some(pattern) => pattern.allow,
+            // A blacklist rejects listed clients, while
+            // a whitelist only lets listed clients through
+            None => {
+                let listed = match client_check {
+                    Some(client) => inner.list.contains(client),
+                    None => false,
+                };
+                listed != inner.blacklist_style
             }
+        };
+        if approved {
+            return None;
         }
 
-        // If a client's peer string is empty, this is a Bad Thing
-        if peer_string.is_empty() {
-            let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
-            let bencoded = bencode::encode_announce_response(failure);
-            Either::Right(ok(req.into_response(
-                HttpResponse::Ok()
-                    .content_type("text/plain")
-                    .body(bencoded)
-                    .into_body(),
-            )))
+        let failure = match client_check.and_then(|c| inner.rules.get(c)) {
+            Some(rule) => AnnounceResponse::failure_retry(
+                rule.message
+                    .clone()
+                    .unwrap_or_else(|| ClientError::UnapprovedClient.text()),
+                rule.retry_in,
+            ),
+            None => AnnounceResponse::failure(ClientError::UnapprovedClient.text()),
+        };
+        Some(failure)
+    }
+
+    // Missing user agents are matched as empty ones
+    pub fn check_user_agent(&self, user_agent: &str) -> Option<AnnounceResponse> {
+        let inner = self.inner.read().unwrap();
+        if !inner.enabled || inner.user_agent_patterns.is_empty() {
+            return None;
+        }
+
+        let approved = match inner
+            .user_agent_patterns
+            .iter()
+            .find(|p| glob_matches(p.pattern.as_bytes(), user_agent.as_bytes()))
+        {
+            Some(pattern) => pattern.allow,
+            None => !inner.user_agent_patterns.iter().any(|p| p.allow),
+        };
+        if approved {
+            None
         } else {
-            // Most clients do Azureus-style encoding which
-            // looks like '-AZ1234-' followed by a random string
-            let client_check = if self.versioned {
-                &peer_string[1..7]
-            } else {
-                &peer_string[1..3]
-            };
-
-            if self.blacklist_style {
-                // Check that client isn't part of blacklist.
-                // If so, reject with same error as above.
-                // If not, let the request pass through.
-                if self.list.contains(&client_check.to_string()) {
-                    let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
-                    let bencoded = bencode::encode_announce_response(failure);
-                    Either::Right(ok(req.into_response(
-                        HttpResponse::Ok()
-                            .content_type("text/plain")
-                            .body(bencoded)
-                            .into_body(),
-                    )))
-                } else {
-                    Either::Left(self.service.call(req))
-                }
-            } else {
-                // Check that client is part of whitelist.
-                // If so, let the request pass through.
-                // If not, reject with same error as above.
-                if self.list.contains(&client_check.to_string()) {
-                    Either::Left(self.service.call(req))
-                } else {
-                    let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
-                    let bencoded = bencode::encode_announce_response(failure);
-                    Either::Right(ok(req.into_response(
-                        HttpResponse::Ok()
-                            .content_type("text/plain")
-                            .body(bencoded)
-                            .into_body(),
-                    )))
-                }
-            }
+            Some(AnnounceResponse::failure(
+                ClientError::UnapprovedClient.text(),
+            ))
         }
     }
 }
 
+// In whitelist style, any torrent registered in the store is also
+// approved, so torrents added at runtime can be announced immediately.
+#[derive(Clone)]
 pub struct TorrentApproval {
-    prohibited_list: HashSet<String>,
+    inner: Arc<RwLock<TorrentList>>,
+    torrent_store: TorrentStore,
 }
 
-impl TorrentApproval {
-    pub fn new(prohibited_list: Vec<String>) -> Self {
-        TorrentApproval {
-            prohibited_list: prohibited_list.into_iter().collect(),
+struct TorrentList {
+    enabled: bool,
+    blacklist_style: bool,
+    list: HashSet<InfoHash>,
+}
+
+impl From<&config::TorrentApproval> for TorrentList {
+    fn from(config: &config::TorrentApproval) -> Self {
+        TorrentList {
+            enabled: config.enabled,
+            blacklist_style: config.blacklist_style,
+            list: config.torrent_list.iter().cloned().collect(),
         }
     }
 }
 
-impl<S, B> Transform<S> for TorrentApproval
-where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = TorrentApprovalMiddleware<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-    fn new_transform(&self, service: S) -> Self::Future {
-        ok(TorrentApprovalMiddleware {
-            service,
-            prohibited_list: self.prohibited_list.clone(),
-        })
+impl TorrentList {
+    // The config's torrents, along with any in its list file
+    fn load(config: &config::TorrentApproval) -> io::Result<TorrentList> {
+        let mut torrent_list = TorrentList::from(config);
+        if !config.list_file.is_empty() {
+            for entry in read_list_file(&config.list_file)? {
+                match entry.parse() {
+                    Ok(info_hash) => {
+                        torrent_list.list.insert(info_hash);
+                    }
+                    Err(_) => warn!("Skipping invalid info hash {} in list file", entry),
+                }
+            }
+        }
+        Ok(torrent_list)
     }
 }
-pub struct TorrentApprovalMiddleware<S> {
-    service: S,
-    prohibited_list: HashSet<String>,
+
+impl TorrentApproval {
+    pub fn new(
+        blacklist_style: bool,
+        torrent_list: Vec<InfoHash>,
+        torrent_store: TorrentStore,
+    ) -> Self {
+        TorrentApproval {
+            inner: Arc::new(RwLock::new(TorrentList {
+                enabled: true,
+                blacklist_style,
+                list: torrent_list.into_iter().collect(),
+            })),
+            torrent_store,
+        }
+    }
+
+    pub fn from_config(config: &config::TorrentApproval, torrent_store: TorrentStore) -> Self {
+        let torrent_list = TorrentList::load(config).unwrap_or_else(|e| {
+            error!("{} ({})", InternalError::ApprovalListLoad.text(), e);
+            TorrentList::from(config)
+        });
+        TorrentApproval {
+            inner: Arc::new(RwLock::new(torrent_list)),
+            torrent_store,
+        }
+    }
+
+    pub fn reload(&self, config: &config::TorrentApproval) {
+        match TorrentList::load(config) {
+            Ok(torrent_list) => *self.inner.write().unwrap() = torrent_list,
+            Err(e) => error!("{} ({})", InternalError::ApprovalListLoad.text(), e),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.read().unwrap().enabled
+    }
+
+    // A blacklist rejects listed torrents, while a whitelist only lets
+    // listed or registered torrents through. Everything is approved
+    // while approval is disabled.
+    pub fn approves(&self, info_hash: &InfoHash) -> bool {
+        let inner = self.inner.read().unwrap();
+        if !inner.enabled {
+            true
+        } else if inner.blacklist_style {
+            !inner.list.contains(info_hash)
+        } else {
+            inner.list.contains(info_hash) || self.torrent_store.is_registered(info_hash)
+        }
+    }
 }
 
-impl<S, B> Service for TorrentApprovalMiddleware<S>
-where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+struct IpRanges {
+    deny: RangeSet,
+    // Everything is allowed while this is empty
+    allow: RangeSet,
+}
 
-    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+impl From<&config::IpFilter> for IpRanges {
+    fn from(config: &config::IpFilter) -> Self {
+        let (deny, skipped_deny) = RangeSet::parse(&config.deny.join("\n"));
+        let (allow, skipped_allow) = RangeSet::parse(&config.allow.join("\n"));
+        if skipped_deny + skipped_allow > 0 {
+            warn!(
+                "Skipped {} invalid entries in the IP filter",
+                skipped_deny + skipped_allow
+            );
+        }
+        IpRanges { deny, allow }
     }
+}
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let request_kv_pairs = form_urlencoded::parse(req.query_string().as_bytes()).into_owned();
-        let mut info_hash: String = "".to_string();
+// The tracker route a middleware wraps, so that its failures
+// take the shape clients expect from that route
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    Announce,
+    Scrape,
+}
 
-        for (k, value) in request_kv_pairs {
-            if let "info_hash" = k.as_str() {
-                info_hash = value
-            }
+// Turns away announces and scrapes from filtered addresses before any
+// other middleware looks at them. Like the approval lists, the ranges
+// are shared with the UDP tracker and swapped out on a config reload.
+// Addresses forwarded by anything but a trusted proxy are never filtered
+// on, or a blocked client could get in just by naming another address.
+#[derive(Clone)]
+pub struct IpFilter {
+    inner: Arc<RwLock<IpRanges>>,
+    proxies: SharedRanges,
+    #[cfg_attr(not(feature = "actix"), allow(dead_code))]
+    route: Route,
+}
+
+impl IpFilter {
+    pub fn from_config(config: &config::IpFilter, proxies: SharedRanges) -> Self {
+        IpFilter {
+            inner: Arc::new(RwLock::new(IpRanges::from(config))),
+            proxies,
+            route: Route::Announce,
         }
+    }
 
-        // If a client's peer string is empty, this is a Bad Thing
-        if self.prohibited_list.contains(&info_hash) {
-            let failure = AnnounceResponse::failure(ClientError::UnapprovedTorrent.text());
-            let bencoded = bencode::encode_announce_response(failure);
-            Either::Right(ok(req.into_response(
-                HttpResponse::Ok()
-                    .content_type("text/plain")
-                    .body(bencoded)
-                    .into_body(),
-            )))
-        } else {
-            Either::Left(self.service.call(req))
+    // The same filter, answering in the shape of the given route
+    pub fn on(&self, route: Route) -> Self {
+        IpFilter {
+            inner: self.inner.clone(),
+            proxies: self.proxies.clone(),
+            route,
         }
     }
+
+    pub fn reload(&self, config: &config::IpFilter) {
+        *self.inner.write().unwrap() = IpRanges::from(config);
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let inner = self.inner.read().unwrap();
+        !inner.deny.contains(ip) && (inner.allow.is_empty() || inner.allow.contains(ip))
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "actix"))]
 mod tests {
     use super::*;
 
-    use actix_web::{test, web, App};
+    use actix_service::Service;
+    use actix_web::body::Body;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
 
     use crate::config::Config;
-    use crate::network::parse_announce;
+    use crate::network::{get_stats, parse_announce, tracker_routes};
     use crate::state::State;
-    use crate::storage::{TorrentRecords, TorrentStore};
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
 
     #[actix_rt::test]
     async fn client_blacklist_non_versioned() {
@@ -239,7 +422,12 @@ mod tests {
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    blacklist_style,
+                    versioned,
+                    client_list,
+                    HashMap::new(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -248,8 +436,8 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
@@ -272,7 +460,12 @@ mod tests {
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    blacklist_style,
+                    versioned,
+                    client_list,
+                    HashMap::new(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -281,8 +474,8 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
@@ -305,7 +498,12 @@ mod tests {
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    blacklist_style,
+                    versioned,
+                    client_list,
+                    HashMap::new(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -314,8 +512,8 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-AZ9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-AZ9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
@@ -338,7 +536,12 @@ mod tests {
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    blacklist_style,
+                    versioned,
+                    client_list,
+                    HashMap::new(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -347,27 +550,61 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
     }
 
     #[actix_rt::test]
-    async fn torrent_blacklist() {
+    async fn client_blacklist_rule_message() {
         let config = Config::default();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
         let stores = web::Data::new(State::new(config, torrent_store));
+        let client_list = vec!["qB4300".to_string()];
+        let mut rules = HashMap::new();
+        rules.insert(
+            "qB4300".to_string(),
+            ClientRule {
+                message: Some("Known bug, please upgrade".to_string()),
+                retry_in: Some(1440),
+            },
+        );
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(ClientApproval::new(true, true, client_list, rules))
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                ),
+        )
+        .await;
+
+        let proper_resp =
+            "d14:failure reason25:Known bug, please upgrade8:retry ini1440ee".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-qB4300-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn torrent_blacklist() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store.clone()));
         let prohibited_list = vec![
-            "2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f".to_string(),
-            "3bbc36a0bcae854bd40c4deec639d4afadf65deb".to_string(),
-            "8a541fa2db56003884b0acf9c059f6652d5f611c".to_string(),
+            "2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f".parse().unwrap(),
+            "3bbc36a0bcae854bd40c4deec639d4afadf65deb".parse().unwrap(),
+            "8a541fa2db56003884b0acf9c059f6652d5f611c".parse().unwrap(),
         ];
 
         let mut app = test::init_service(
             App::new()
-                .wrap(TorrentApproval::new(prohibited_list))
+                .wrap(TorrentApproval::new(true, prohibited_list, torrent_store))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -376,10 +613,447 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason18:Unapproved torrente".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let proper_resp = "d14:failure reason18:Unapproved torrente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
     }
+
+    #[actix_rt::test]
+    async fn torrent_whitelist() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store.clone()));
+        let approved_list = vec![
+            "3bbc36a0bcae854bd40c4deec639d4afadf65deb".parse().unwrap(),
+            "8a541fa2db56003884b0acf9c059f6652d5f611c".parse().unwrap(),
+        ];
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(TorrentApproval::new(false, approved_list, torrent_store))
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                ),
+        )
+        .await;
+
+        let proper_resp = "d14:failure reason18:Unapproved torrente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn torrent_whitelist_registered_at_runtime() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store.clone()));
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(TorrentApproval::new(
+                    false,
+                    Vec::new(),
+                    torrent_store.clone(),
+                ))
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                ),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1";
+        let rejected_resp = "d14:failure reason18:Unapproved torrente".as_bytes();
+
+        let req = test::TestRequest::with_uri(uri)
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, rejected_resp);
+
+        torrent_store
+            .bulk_insert(vec![Torrent::new(
+                "2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f".parse().unwrap(),
+                0,
+                0,
+                0,
+                0,
+            )])
+            .await;
+
+        let req = test::TestRequest::with_uri(uri)
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_ne!(resp, rejected_resp);
+    }
+
+    #[actix_rt::test]
+    async fn torrent_whitelist_only_filters_tracker_routes() {
+        let mut config = Config::default();
+        config.torrent_approval.enabled = true;
+        config.torrent_approval.blacklist_style = false;
+        config.torrent_approval.torrent_list =
+            vec!["2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f".parse().unwrap()];
+        let network = config.network.clone();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .configure(|cfg| tracker_routes(cfg, stores.get_ref(), &network))
+                .service(web::scope("stats").route("", web::get().to(get_stats))),
+        )
+        .await;
+
+        let rejected_resp = "d14:failure reason18:Unapproved torrente".as_bytes();
+
+        // Every torrent in a scrape has to be approved, not just the first
+        let req = test::TestRequest::with_uri("/scrape?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&info_hash=%3b%bc%36%a0%bc%ae%85%4b%d4%0c%36%a0%bc%ae%85%4b%d4%0c%36%a0").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, rejected_resp);
+
+        let req = test::TestRequest::with_uri(
+            "/scrape?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f",
+        )
+        .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_ne!(resp, rejected_resp);
+
+        // Routes without an info hash are left alone
+        let req = test::TestRequest::with_uri("/stats").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn client_patterns() {
+        assert!(glob_matches(b"-DE1[0-3]*", b"-DE1300-143964258012"));
+        assert!(!glob_matches(b"-DE1[0-3]*", b"-DE2000-143964258012"));
+        assert!(glob_matches(b"S58B*", b"S58B-----abcdefghijk"));
+        assert!(glob_matches(b"-??????-*", b"-qB4300-143964258012"));
+        assert!(!glob_matches(b"-qB", b"-qB4300-143964258012"));
+
+        let config = config::ClientApproval {
+            enabled: true,
+            blacklist_style: false,
+            client_list: vec!["DE".to_string()],
+            patterns: vec![
+                ClientPattern {
+                    pattern: "-DE13*".to_string(),
+                    allow: false,
+                },
+                ClientPattern {
+                    pattern: "S58B*".to_string(),
+                    allow: true,
+                },
+            ],
+            ..Default::default()
+        };
+        let approval = ClientApproval::from_config(&config);
+
+        // Patterns come before the list, and anything else falls through to it
+        assert!(approval.check(&PeerId(*b"-DE1300-143964258012")).is_some());
+        assert!(approval.check(&PeerId(*b"-DE2000-143964258012")).is_none());
+        assert!(approval.check(&PeerId(*b"S58B-----abcdefghijk")).is_none());
+        assert!(approval.check(&PeerId(*b"-TR2940-143964258012")).is_some());
+    }
+
+    #[test]
+    fn user_agent_patterns() {
+        let pattern = |pattern: &str, allow| ClientPattern {
+            pattern: pattern.to_string(),
+            allow,
+        };
+        let mut config = config::ClientApproval {
+            enabled: true,
+            blacklist_style: true,
+            user_agent_patterns: vec![pattern("curl/*", false)],
+            ..Default::default()
+        };
+        let approval = ClientApproval::from_config(&config);
+        assert!(approval.check_user_agent("curl/7.68.0").is_some());
+        assert!(approval.check_user_agent("Deluge 2.0.3").is_none());
+        assert!(approval.check_user_agent("").is_none());
+
+        // Once anything is allowed, everything else is rejected
+        config.user_agent_patterns = vec![
+            pattern("Transmission/1*", false),
+            pattern("Transmission/*", true),
+        ];
+        approval.reload(&config);
+        assert!(approval.check_user_agent("Transmission/2.94").is_none());
+        assert!(approval.check_user_agent("Transmission/1.93").is_some());
+        assert!(approval.check_user_agent("Deluge 2.0.3").is_some());
+        assert!(approval.check_user_agent("").is_some());
+    }
+
+    #[test]
+    fn approval_list_files_reload() {
+        let path = std::env::temp_dir().join("tyto_client_list_test.txt");
+        std::fs::write(&path, "# Transmission\nTR\n\n").unwrap();
+        let config = config::ClientApproval {
+            enabled: true,
+            client_list: vec!["DE".to_string()],
+            list_file: path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+
+        let approval = ClientApproval::from_config(&config);
+        let transmission = PeerId(*b"-TR2940-143964258012");
+        let qbittorrent = PeerId(*b"-qB4300-143964258012");
+        assert!(approval.check(&PeerId(*b"-DE9824-143964258012")).is_none());
+        assert!(approval.check(&transmission).is_none());
+        assert!(approval.check(&qbittorrent).is_some());
+
+        std::fs::write(&path, "qB  # added later\n").unwrap();
+        approval.reload(&config);
+        assert!(approval.check(&transmission).is_some());
+        assert!(approval.check(&qbittorrent).is_none());
+
+        // A missing file leaves the list as it was
+        std::fs::remove_file(&path).unwrap();
+        approval.reload(&config);
+        assert!(approval.check(&qbittorrent).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn ip_filter_deny_and_allow() {
+        let mut config = Config::default();
+        config.ip_filter = config::IpFilter {
+            deny: vec!["127.0.0.2".to_string()],
+            allow: vec!["127.0.0.0/8".to_string()],
+        };
+        config.network.scrape_aliases = vec!["stats.php".to_string()];
+        let network = config.network.clone();
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let filter = stores.ip_filter.clone();
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .configure(|cfg| tracker_routes(cfg, stores.get_ref(), &network))
+                .service(
+                    web::scope("healthz").route("", web::get().to(crate::network::get_health)),
+                ),
+        )
+        .await;
+
+        let blocked = "d14:failure reason33:Announcing address is blocklistede".as_bytes();
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started";
+        for (addr, permitted) in &[
+            ("127.0.0.1:51413", true),
+            ("127.0.0.2:51413", false),
+            ("192.0.2.1:51413", false),
+        ] {
+            let req = test::TestRequest::with_uri(uri)
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert_eq!(resp != blocked, *permitted);
+        }
+
+        // Aliases are filtered too, and scrapes get a scrape failure
+        let req = test::TestRequest::with_uri("/stats.php?info_hash=A1B2C3D4E5F6G7H8I9J0")
+            .peer_addr("192.0.2.1:51413".parse().unwrap())
+            .to_request();
+        assert_eq!(
+            test::read_response(&mut app, req).await,
+            Route::Scrape.failure(ClientError::BlockedAddress.text(), None)
+        );
+
+        // Only tracker requests are filtered
+        let req = test::TestRequest::with_uri("/healthz")
+            .peer_addr("192.0.2.1:51413".parse().unwrap())
+            .to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "OK".as_bytes());
+
+        filter.reload(&config::IpFilter::default());
+        assert!(filter.permits("192.0.2.1".parse().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn ip_filter_ignores_spoofed_forwarded_for() {
+        let mut config = Config::default();
+        config.ip_filter.deny = vec!["192.0.2.0/24".to_string()];
+        config.network.trusted_proxies = vec!["10.0.0.1".to_string()];
+        let network = config.network.clone();
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .configure(|cfg| tracker_routes(cfg, stores.get_ref(), &network)),
+        )
+        .await;
+
+        let blocked = "d14:failure reason33:Announcing address is blocklistede".as_bytes();
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started";
+        for (addr, header, value, permitted) in &[
+            // Only a trusted proxy can say who it's forwarding for
+            ("192.0.2.1:51413", "X-Forwarded-For", "1.1.1.1", false),
+            ("192.0.2.1:51413", "Forwarded", "for=1.1.1.1", false),
+            ("10.0.0.1:51413", "X-Forwarded-For", "192.0.2.1", false),
+            ("10.0.0.1:51413", "X-Forwarded-For", "1.1.1.1", true),
+            // Whatever the client put before the proxy's entry is ignored
+            (
+                "10.0.0.1:51413",
+                "X-Forwarded-For",
+                "1.1.1.1, 192.0.2.1",
+                false,
+            ),
+            (
+                "10.0.0.1:51413",
+                "Forwarded",
+                "for=\"192.0.2.1:4711\"",
+                false,
+            ),
+        ] {
+            let req = test::TestRequest::with_uri(uri)
+                .peer_addr(addr.parse().unwrap())
+                .header(*header, *value)
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert_eq!(resp != blocked, *permitted, "{} {}", header, value);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn token_auth_stats() {
+        let mut config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let open = web::Data::new(State::new(config.clone(), torrent_store.clone()));
+        config.admin.tokens = vec!["secret".to_string()];
+        let guarded = web::Data::new(State::new(config, torrent_store));
+
+        for (stores, token, status) in &[
+            (&open, None, StatusCode::OK),
+            (&guarded, None, StatusCode::UNAUTHORIZED),
+            (&guarded, Some("Bearer wrong"), StatusCode::UNAUTHORIZED),
+            (&guarded, Some("Bearer secreT"), StatusCode::UNAUTHORIZED),
+            (&guarded, Some("Bearer secret"), StatusCode::OK),
+        ] {
+            let mut app = test::init_service(
+                App::new().service(
+                    web::scope("stats")
+                        .wrap(TokenAuth::new(stores.get_ref().clone(), true))
+                        .app_data((*stores).clone())
+                        .route("", web::get().to(get_stats)),
+                ),
+            )
+            .await;
+
+            let mut req = test::TestRequest::with_uri("/stats");
+            if let Some(token) = token {
+                req = req.header("Authorization", *token);
+            }
+            let resp = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(resp.status(), *status);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn token_auth_closed_without_tokens() {
+        let config = Config::default();
+        let state = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        let auth = TokenAuth::new(state, false);
+
+        let req = test::TestRequest::default()
+            .header("Authorization", "Bearer ")
+            .to_http_request();
+        assert!(!auth.is_authorized(req.headers()));
+    }
+
+    #[actix_rt::test]
+    async fn rate_limit_announces() {
+        let mut config = Config::default();
+        config.rate_limit.requests_per_sec = 0.01;
+        config.rate_limit.burst = 1;
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .wrap(RateLimit::new(stores.get_ref().clone(), Route::Announce))
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=%2f%a9%0c%59%c8%07%2c%5a%4c%54%c1%f1%30%7d%ac%ae%b4%c8%2f%0f&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=0&compact=1";
+        let responses = [
+            test::read_response(
+                &mut app,
+                test::TestRequest::with_uri(uri)
+                    .peer_addr("192.0.2.1:51413".parse().unwrap())
+                    .to_request(),
+            )
+            .await,
+            test::read_response(
+                &mut app,
+                test::TestRequest::with_uri(uri)
+                    .peer_addr("192.0.2.1:51413".parse().unwrap())
+                    .to_request(),
+            )
+            .await,
+            test::read_response(
+                &mut app,
+                test::TestRequest::with_uri(uri)
+                    .peer_addr("192.0.2.2:51413".parse().unwrap())
+                    .to_request(),
+            )
+            .await,
+            // Naming another address doesn't get a client a fresh bucket
+            test::read_response(
+                &mut app,
+                test::TestRequest::with_uri(uri)
+                    .peer_addr("192.0.2.1:51413".parse().unwrap())
+                    .header("X-Forwarded-For", "203.0.113.9")
+                    .to_request(),
+            )
+            .await,
+        ];
+
+        // Only the later requests from the same address are over the limit,
+        // and they're told to come back once a token has been refilled
+        let limited = "d14:failure reason17:Too many requests8:retry ini2ee".as_bytes();
+        assert!(!responses[0].starts_with(b"d14:failure reason"));
+        assert_eq!(responses[1], limited);
+        assert!(!responses[2].starts_with(b"d14:failure reason"));
+        assert_eq!(responses[3], limited);
+    }
+
+    async fn panicking() -> HttpResponse {
+        panic!("Response could not be encoded")
+    }
+
+    #[actix_rt::test]
+    async fn panics_become_failures() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(PanicRecovery::new(Route::Announce))
+                .service(web::scope("announce").route("", web::get().to(panicking))),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/announce").to_request();
+        let err = app.call(req).await.err().unwrap();
+        let resp = HttpResponse::from_error(err);
+        assert_eq!(resp.status(), StatusCode::OK);
+        match resp.body().as_ref() {
+            Some(Body::Bytes(body)) => assert_eq!(
+                body,
+                "d14:failure reason22:Internal tracker errore".as_bytes()
+            ),
+            _ => panic!("Failure was not sent"),
+        }
+    }
 }
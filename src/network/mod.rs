@@ -1,352 +1,2458 @@
+pub mod admin;
+pub mod cheaters;
+pub mod dnsbl;
+pub mod gossip;
 pub mod middleware;
+#[cfg(feature = "redis")]
+pub mod pubsub;
+pub mod rate_limit;
+pub mod replication;
+pub mod sharding;
+pub mod udp;
+pub mod upstream;
 
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use crate::bencode;
-use crate::bittorrent::{AnnounceRequest, AnnounceResponse, ScrapeRequest, ScrapeResponse};
+use crate::bittorrent::{
+    AnnounceRequest, AnnounceResponse, CompactPeerv4, CompactPeerv6, InfoHash, Peer, PeerFormat,
+    ScrapeFile, DEFAULT_NUMWANT,
+};
+use crate::config::{
+    CategoryPolicy, CheaterAction, Config, DnsblAction, DuplicatePeerIds, ReapStrategy,
+};
+use crate::errors::{ClientError, InternalError};
+use crate::network::cheaters::FlaggedPeer;
+use crate::scripting::{AnnounceContext, AnnouncePolicy, Decision};
 use crate::state::State;
-use crate::statistics::ReturnedStatistics;
-use crate::util::Event;
+use crate::storage::history::AnnounceRecord;
+use crate::storage::users::UserTraffic;
+use crate::storage::PeerRole;
+use crate::util::{event_to_string, is_bogon, unix_secs, Event};
 
+// Only the UDP tracker is left in builds without the actix feature
+#[cfg(feature = "actix")]
+use {
+    crate::bittorrent::{parse_remote_addr, ScrapeRequest, ScrapeResponse},
+    crate::blocklist::SharedRanges,
+    crate::config::Network,
+    crate::info::BuildInfo,
+    crate::network::middleware::{PanicRecovery, RateLimit, Route},
+    crate::statistics::ReturnedStatistics,
+    actix_web::http::{header, HeaderMap},
+    actix_web::{web, HttpRequest, HttpResponse, Responder},
+    futures::stream,
+    std::net::SocketAddr,
+    std::time::Instant,
+};
+
+// Announces and scrapes are served at their usual paths and at any
+// aliases, each with a passkey after it for private trackers. Every
+// path is rate limited on its own. The filtering middleware only wraps
+// these, so the other routes are never filtered, and each knows which
+// route it guards. The last one wrapped runs first. Scrapes carry no
+// peer ID, so like the UDP tracker, only announces go through client
+// approval.
+#[cfg(feature = "actix")]
+pub fn tracker_routes(cfg: &mut web::ServiceConfig, state: &State, network: &Network) {
+    let announce_paths =
+        std::iter::once("announce").chain(network.announce_aliases.iter().map(String::as_str));
+    for path in announce_paths {
+        cfg.service(
+            web::scope(path)
+                .wrap(RateLimit::new(state.clone(), Route::Announce))
+                .wrap(state.client_approval.clone())
+                .wrap(state.torrent_approval.clone())
+                .wrap(state.ip_filter.on(Route::Announce))
+                .wrap(PanicRecovery::new(Route::Announce))
+                .route("", web::get().to(parse_announce))
+                .route("/{passkey}", web::get().to(parse_private_announce)),
+        );
+    }
+
+    let scrape_paths =
+        std::iter::once("scrape").chain(network.scrape_aliases.iter().map(String::as_str));
+    for path in scrape_paths {
+        cfg.service(
+            web::scope(path)
+                .wrap(RateLimit::new(state.clone(), Route::Scrape))
+                .wrap(state.torrent_approval.clone())
+                .wrap(state.ip_filter.on(Route::Scrape))
+                .wrap(PanicRecovery::new(Route::Scrape))
+                .route("", web::get().to(parse_scrape))
+                .route("/{passkey}", web::get().to(parse_private_scrape)),
+        );
+    }
+}
+
+#[cfg(feature = "actix")]
 pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Responder {
-    let announce_request = AnnounceRequest::new(req.query_string(), req.connection_info().remote());
+    announce(data, req, None).await
+}
+
+// Private trackers give every user an announce URL of their own
+#[cfg(feature = "actix")]
+pub async fn parse_private_announce(
+    data: web::Data<State>,
+    req: HttpRequest,
+    passkey: web::Path<String>,
+) -> impl Responder {
+    announce(data, req, Some(passkey.into_inner())).await
+}
+
+#[cfg(feature = "actix")]
+async fn announce(
+    data: web::Data<State>,
+    req: HttpRequest,
+    passkey: Option<String>,
+) -> HttpResponse {
+    let started = Instant::now();
+    let response = serve_announce(&data, &req, passkey).await;
+    data.stats
+        .write()
+        .await
+        .announce_latency
+        .record(started.elapsed());
+    response
+}
+
+#[cfg(feature = "actix")]
+async fn serve_announce(
+    data: &web::Data<State>,
+    req: &HttpRequest,
+    passkey: Option<String>,
+) -> HttpResponse {
+    // Replicas only serve scrapes, and send announces on to their primary
+    if let Some(primary) = data.primary.primary() {
+        let url = format!("{}{}?{}", primary, req.path(), req.query_string());
+        return HttpResponse::TemporaryRedirect()
+            .header(header::LOCATION, url)
+            .finish();
+    }
+
+    // Whether the client may name its own address is decided by the one
+    // it connected from, which only a trusted proxy can speak for
+    let remote_ip = client_ip(req.peer_addr(), req.headers(), &data.trusted_proxies);
+    let remote_addr = remote_ip.map(|ip| ip.to_string());
+    let announce_request = AnnounceRequest::new(req.query_string(), remote_addr.as_deref());
 
     match announce_request {
-        Ok(parsed_req) => {
-            // There are only three types of events that lead to
-            // actual change between swarms on the storage layer
-            match parsed_req.event {
-                // Started should be sent whenever a client
-                // starts or resumes the leeching process
-                Event::Started => {
-                    data.peer_store
-                        .put_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
+        Ok(mut parsed_req) => {
+            parsed_req.passkey = passkey;
+            if let Some(remote_ip) = remote_ip {
+                resolve_claimed_ip(data, &mut parsed_req, remote_ip);
+            }
+            let client_ip = parsed_req.peer.ip();
+
+            // Torrents owned by another tracker in the ring are announced there
+            if let Some(owner) = shard_owner(data, req, &[parsed_req.info_hash]) {
+                let config = data.config();
+                let forwarded =
+                    sharding::forward(&config.sharding, &owner, req, Some(client_ip)).await;
+                return match forwarded {
+                    Ok(response) => response,
+                    Err(e) => {
+                        data.stats.write().await.fail_announce();
+                        let bencoded =
+                            bencode::encode_announce_response(AnnounceResponse::failure(e.text()));
+                        HttpResponse::Ok().content_type("text/plain").body(bencoded)
+                    }
+                };
+            }
+
+            let forwarded = data.config().retracker.forwards(&parsed_req.info_hash);
+
+            let format = PeerFormat::of(&parsed_req);
+            let (mut response, numwant) = match handle_announce(data, parsed_req, format).await {
+                Ok(accepted) => accepted,
+                Err(failure) => {
+                    let bencoded = bencode::encode_announce_response(failure);
+                    return HttpResponse::Ok().content_type("text/plain").body(bencoded);
+                }
+            };
+
+            // Peers known to the origin tracker fill out whatever room
+            // is left after the local swarm has been sampled
+            if forwarded {
+                let origin_peers = upstream::forward_announce(
+                    &data.config().retracker,
+                    req.query_string(),
+                    client_ip,
+                )
+                .await;
+                if let Some((peers, peers6)) = origin_peers {
+                    merge_peers(&mut response, peers, peers6, numwant);
+                }
+            }
+
+            // Unlike the peer's address, this is never taken from the ip parameter
+            if data.config().bt.announce_external_ip {
+                response.external_ip = remote_ip;
+            }
+
+            let bencoded = bencode::encode_announce_response(response);
+            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        }
+
+        // If the request is not parse-able, short-circuit and respond with failure
+        Err(failure) => {
+            let bencoded = bencode::encode_announce_response(failure);
+            data.stats.write().await.fail_announce();
+            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        }
+    }
+}
+
+// The tracker a request should go to instead, if any. Requests another
+// tracker proxied here are always handled here.
+#[cfg(feature = "actix")]
+fn shard_owner(data: &State, req: &HttpRequest, info_hashes: &[InfoHash]) -> Option<String> {
+    let ring = data.hash_ring.as_ref()?;
+    if req.headers().contains_key(sharding::FORWARDED_HEADER) {
+        return None;
+    }
+    ring.sole_owner(info_hashes).map(str::to_string)
+}
+
+// The address a request came from. Forwarded headers are only believed
+// on connections from a trusted proxy, as any client can send them.
+// Proxies add the address they were connected from at the end, so the
+// client is the last one that isn't another trusted proxy.
+#[cfg(feature = "actix")]
+pub fn client_ip(
+    peer_addr: Option<SocketAddr>,
+    headers: &HeaderMap,
+    proxies: &SharedRanges,
+) -> Option<IpAddr> {
+    let peer_ip = peer_addr?.ip();
+    if !proxies.contains(peer_ip) {
+        return Some(peer_ip);
+    }
+
+    let forwarded = forwarded_for(headers);
+    let client = forwarded
+        .iter()
+        .rev()
+        .find(|ip| !proxies.contains(**ip))
+        .or_else(|| forwarded.first());
+    Some(client.copied().unwrap_or(peer_ip))
+}
+
+// Forwarded (RFC 7239) is read if it's there, otherwise X-Forwarded-For
+#[cfg(feature = "actix")]
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let forwarded: Vec<IpAddr> = headers
+        .get_all(header::FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if key.eq_ignore_ascii_case("for") {
+                    parse_remote_addr(value.trim_matches('"'))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|addr| parse_remote_addr(addr.trim()))
+        .collect()
+}
+
+// A client naming an address other than the one it connects from only
+// gets it if the address is reachable and the client is trusted to name
+// one. Otherwise the peer is put at the address it connected from.
+pub fn resolve_claimed_ip(data: &State, announce: &mut AnnounceRequest, remote_ip: IpAddr) {
+    let claimed = announce.peer.ip();
+    if claimed == remote_ip {
+        return;
+    }
+
+    let config = data.config();
+    let trusted = data.ip_param_trusted.is_empty() || data.ip_param_trusted.contains(remote_ip);
+    if config.ip_param.honor && trusted && !is_bogon(claimed) {
+        return;
+    }
+
+    let (uploaded, downloaded) = announce.peer.traffic();
+    announce.peer = Peer::new(
+        announce.peer.peer_id(),
+        remote_ip,
+        announce.port,
+        uploaded,
+        downloaded,
+    );
+    announce.ip = Some(remote_ip);
+}
+
+// Everything an announce does to the swarms, regardless of the protocol it
+// arrived over. Accepted announces come back with their response and the
+// number of peers the client ended up being allowed, and rejected ones with
+// the failure to send to the client.
+pub async fn handle_announce(
+    data: &State,
+    mut parsed_req: AnnounceRequest,
+    format: PeerFormat,
+) -> Result<(AnnounceResponse, u32), AnnounceResponse> {
+    // One snapshot of the config for the whole announce
+    let config = data.config();
+    let mut interval = config.bt.announce_rate as u32;
+    let client_ip = parsed_req.peer.ip();
+    parsed_req.numwant = Some(numwant(&config, parsed_req.numwant, parsed_req.left == 0));
+
+    if let Err(e) = check_passkey(data, &config, parsed_req.passkey.as_deref()).await {
+        data.stats.write().await.fail_announce();
+        return Err(AnnounceResponse::failure(e.text()));
+    }
+
+    // Users are limited however many addresses they announce from
+    if let Some(wait) = passkey_rate_limited(data, &config, parsed_req.passkey.as_deref()) {
+        data.stats.write().await.fail_announce();
+        // The retry hint is in whole minutes, rounded up
+        let retry_in = Some(wait.as_secs() / 60 + 1);
+        return Err(AnnounceResponse::failure_retry(
+            ClientError::RateLimited.text(),
+            retry_in,
+        ));
+    }
+
+    if let Err(e) = check_port(&config, parsed_req.port) {
+        data.stats.write().await.fail_announce();
+        return Err(AnnounceResponse::failure(e.text()));
+    }
+
+    if config.bt.compact_only && !parsed_req.compact {
+        data.stats.write().await.fail_announce();
+        return Err(AnnounceResponse::failure(ClientError::NotCompact.text()));
+    }
+
+    if data.cheaters.is_banned(client_ip) {
+        let failure = AnnounceResponse::failure(ClientError::BannedForCheating.text());
+        data.stats.write().await.fail_announce();
+        return Err(failure);
+    }
+
+    if data.dnsbl.is_enabled() && data.dnsbl.is_listed(client_ip).await {
+        data.stats.write().await.dnsbl_hits += 1;
+
+        if config.dnsbl.action == DnsblAction::Reject {
+            let failure = AnnounceResponse::failure(ClientError::BlockedAddress.text());
+            data.stats.write().await.fail_announce();
+            return Err(failure);
+        }
+
+        warn!("Announce from blocklisted address {}", client_ip);
+    }
+
+    if let Some(name) = blocked_by(data, client_ip).await {
+        let failure = AnnounceResponse::failure(ClientError::BlockedAddress.text());
+
+        let mut stats = data.stats.write().await;
+        stats.blocklist_hit(&name);
+        stats.fail_announce();
+        return Err(failure);
+    }
+
+    // Torrents in a category are subject to that category's policy
+    if let Some(policy) = category_policy(data, &config, &parsed_req.info_hash).await {
+        if !policy.allows_client(&parsed_req.peer.peer_id()) {
+            let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
+            data.stats.write().await.fail_announce();
+            return Err(failure);
+        }
+
+        if let Some(category_interval) = policy.interval {
+            interval = category_interval as u32;
+        }
+
+        if let Some(cap) = policy.numwant_cap {
+            parsed_req.numwant = parsed_req.numwant.map(|n| n.min(cap));
+        }
+    }
+
+    // The operator's policy script has the final say
+    let mut warning = None;
+    if let Some(policy) = &data.announce_policy {
+        let decision = evaluate_policy(data, policy, &parsed_req).await;
+
+        if let Some(reason) = decision.deny {
+            let failure = AnnounceResponse::failure(reason);
+            data.stats.write().await.fail_announce();
+            return Err(failure);
+        }
+
+        if let Some(policy_interval) = decision.interval {
+            interval = policy_interval;
+        }
+
+        warning = decision.warning;
+    }
+
+    let mut duplicates = Vec::new();
+    if parsed_req.event != Event::Stopped {
+        match check_duplicates(data, &config, &parsed_req).await {
+            Ok(merged) => duplicates = merged,
+            Err(e) => {
+                data.stats.write().await.fail_announce();
+                return Err(AnnounceResponse::failure(e.text()));
+            }
+        }
+    }
+
+    // Started begins a new session, so there is nothing to compare
+    // against; every other event may carry transferred data
+    if parsed_req.event != Event::Started {
+        if let Err(e) = check_upload_rate(data, &config, &parsed_req).await {
+            data.stats.write().await.fail_announce();
+            return Err(AnnounceResponse::failure(e.text()));
+        }
+    }
+
+    // Users may only run so many peers at once on private trackers. This
+    // comes after every other rejection, so that rejected announces never
+    // hold on to a slot.
+    if let Some(passkey) = &parsed_req.passkey {
+        let limits = (
+            config.private.max_peers_per_torrent,
+            config.private.max_peers,
+        );
+        if parsed_req.event == Event::Stopped {
+            data.user_store
+                .release_slot(passkey, parsed_req.info_hash, &parsed_req.peer)
+                .await;
+        } else if !data
+            .user_store
+            .claim_slot(
+                passkey,
+                parsed_req.info_hash,
+                &parsed_req.peer,
+                limits,
+                Duration::new(config.bt.peer_timeout, 0),
+            )
+            .await
+        {
+            let failure = AnnounceResponse::failure(ClientError::TooManyPeers.text());
+            data.stats.write().await.fail_announce();
+            return Err(failure);
+        }
+    }
+
+    data.leaderboard.record(parsed_req.info_hash);
+    data.stats
+        .write()
+        .await
+        .uniques
+        .record(&parsed_req.peer.peer_id(), &parsed_req.peer.ip());
+
+    if data.announce_history.is_enabled() {
+        let (uploaded, downloaded) = parsed_req.peer.traffic();
+        let record = AnnounceRecord {
+            timestamp: unix_secs(SystemTime::now()),
+            event: event_to_string(parsed_req.event),
+            uploaded,
+            downloaded,
+            left: parsed_req.left,
+            ip: parsed_req.peer.ip(),
+            port: parsed_req.peer.port(),
+        };
+        data.announce_history
+            .record(parsed_req.info_hash, parsed_req.peer.peer_id(), record)
+            .await;
+    }
+
+    if parsed_req.event != Event::Started {
+        record_traffic(
+            data,
+            &parsed_req.info_hash,
+            &parsed_req.peer,
+            parsed_req.passkey.as_deref(),
+        )
+        .await;
+    }
+
+    // The rest of the cluster, any replicas and the trackers sharing
+    // a Redis database are told where the peer stands once it's handled
+    let announced = if data.gossip.is_enabled()
+        || data.change_log.is_enabled()
+        || data.swarm_events.is_enabled()
+    {
+        Some(parsed_req.peer.clone())
+    } else {
+        None
+    };
+
+    // Duplicates are only merged away once nothing can turn the announce
+    // down, so that a rejected one never costs the peer its other address
+    merge_duplicates(data, parsed_req.info_hash, duplicates).await;
+
+    // There are only three types of events that lead to
+    // actual change between swarms on the storage layer
+    match parsed_req.event {
+        // Started should be sent whenever a client
+        // starts or resumes the leeching process
+        Event::Started => {
+            let resumed = data
+                .peer_store
+                .put_leecher(parsed_req.info_hash, parsed_req.peer)
+                .await;
+            if resumed {
+                data.torrent_store
+                    .resume_partial_seed(parsed_req.info_hash)
+                    .await;
+            }
+            data.torrent_store.new_leech(parsed_req.info_hash).await;
+
+            // Partial seeds were never taken out of the leecher statistics
+            let mut stats = data.stats.write().await;
+            if !resumed {
+                stats.add_leech();
+            }
+            stats.succ_announce();
+        }
+
+        // Stopped should be sent when a client stops seed or leeching
+        Event::Stopped => {
+            // If the peer is present in one set, then it
+            // cannot be present in the other.
+            let mut stats = data.stats.write().await;
+            let bt = &config.bt;
+
+            // Under a delayed-stop strategy, the peer is left for
+            // the reaper, which also takes care of the statistics
+            if bt.reap_strategy(&parsed_req.info_hash) == ReapStrategy::DelayedStop {
+                data.peer_store
+                    .expire_peer(
+                        parsed_req.info_hash,
+                        parsed_req.peer,
+                        Duration::new(bt.peer_timeout, 0),
+                        Duration::new(bt.stop_delay, 0),
+                    )
+                    .await;
+            } else if data
+                .peer_store
+                .remove_seeder(parsed_req.info_hash, parsed_req.peer.clone())
+                .await
+            {
+                stats.sub_seed();
+            } else if data
+                .peer_store
+                .remove_leecher(parsed_req.info_hash, parsed_req.peer)
+                .await
+            {
+                stats.sub_leech();
+            }
+
+            stats.succ_announce();
+        }
+
+        // Completed should be sent when a peer receives 100%
+        // of the data associated with a particular torrent
+        Event::Completed => {
+            if let Some(passkey) = &parsed_req.passkey {
+                let (uploaded, downloaded) = parsed_req.peer.traffic();
+                data.user_store
+                    .record_snatch(passkey, parsed_req.info_hash, uploaded, downloaded)
+                    .await;
+            }
+
+            let was_leecher = data
+                .peer_store
+                .promote_leecher(parsed_req.info_hash, parsed_req.peer)
+                .await;
+            data.torrent_store.new_seed(parsed_req.info_hash).await;
+
+            let mut stats = data.stats.write().await;
+            if was_leecher {
+                stats.promote_leech();
+            } else {
+                stats.add_seed();
+            }
+            stats.succ_announce();
+        }
+
+        // Paused is sent by leechers that have all they want of a torrent
+        // (BEP 21), and which now only upload the pieces they have
+        Event::Paused => {
+            let previous = data
+                .peer_store
+                .pause_peer(parsed_req.info_hash, parsed_req.peer)
+                .await;
+            match previous {
+                Some(PeerRole::Leecher) => {
+                    data.torrent_store
+                        .new_partial_seed(parsed_req.info_hash, true)
                         .await;
+                }
+                None => {
                     data.torrent_store
-                        .new_leech(parsed_req.info_hash.clone())
+                        .new_partial_seed(parsed_req.info_hash, false)
                         .await;
+                    data.stats.write().await.add_leech();
+                }
+                Some(PeerRole::Seeder) | Some(PeerRole::PartialSeed) => {}
+            }
 
-                    // Get randomized peer list
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
+            data.stats.write().await.succ_announce();
+        }
+
+        // None should only be sent if
+        // there is no change in snatch state
+        Event::None => {
+            // This updates a peer if it is present in either swarm. Clients
+            // whose started event never arrived (or that were reaped) are
+            // added as a seeder or a leecher, going by what they have left.
+            let seeding = parsed_req.left == 0;
+            let present = data
+                .peer_store
+                .update_peer(parsed_req.info_hash, parsed_req.peer, seeding)
+                .await;
+            if !present && seeding {
+                data.torrent_store.add_seed(parsed_req.info_hash).await;
+            } else if !present {
+                data.torrent_store.new_leech(parsed_req.info_hash).await;
+            }
+
+            let mut stats = data.stats.write().await;
+            if !present && seeding {
+                stats.add_seed();
+            } else if !present {
+                stats.add_leech();
+            }
+            stats.succ_announce();
+        }
+    }
+
+    if let Some(peer) = announced {
+        let info_hash = parsed_req.info_hash;
+        let (peer, role) = match data.peer_store.lookup(&info_hash, &peer).await {
+            Some((stored, role)) => (stored, Some(role)),
+            None => (peer, None),
+        };
+        data.change_log.record(info_hash, peer.clone(), role);
+        data.gossip.record(info_hash, peer.clone(), role);
+        data.swarm_events.record(info_hash, peer, role);
+    }
+
+    // Associate all the requisite data together
+    let numwant = parsed_req.numwant.unwrap_or(DEFAULT_NUMWANT);
+    let mut response =
+        announce_response(data, parsed_req.info_hash, numwant, interval, format).await;
+    response.warning_message = warning;
+    if config.bt.min_interval > 0 {
+        response.min_interval = Some((config.bt.min_interval as u32).min(interval));
+    }
+
+    Ok((response, numwant))
+}
+
+// Runs the policy script over an announce, letting it through if the script fails
+async fn evaluate_policy(
+    data: &State,
+    policy: &AnnouncePolicy,
+    announce: &AnnounceRequest,
+) -> Decision {
+    let (uploaded, downloaded) = announce.peer.traffic();
+    let peer_id = announce.peer.peer_id();
+    let (complete, snatches, incomplete) = data
+        .torrent_store
+        .get_announce_stats(announce.info_hash)
+        .await;
+
+    let context = AnnounceContext {
+        info_hash: announce.info_hash.to_hex(),
+        peer_id: &peer_id.0,
+        ip: announce.peer.ip(),
+        port: announce.peer.port(),
+        uploaded,
+        downloaded,
+        left: announce.left,
+        event: event_to_string(announce.event),
+        numwant: announce.numwant,
+        category: data.torrent_store.get_category(&announce.info_hash).await,
+        complete,
+        incomplete,
+        snatches,
+    };
+
+    match policy.evaluate(&context) {
+        Ok(decision) => decision,
+        Err(e) => {
+            error!("{} ({})", InternalError::ScriptEvaluate.text(), e);
+            Decision::default()
+        }
+    }
+}
+
+// Returns the name of the first blocklist containing the address
+async fn blocked_by(data: &State, ip: IpAddr) -> Option<String> {
+    let blocklists = data.blocklists.read().await;
+    blocklists
+        .iter()
+        .find(|list| list.ranges.contains(ip))
+        .map(|list| list.name.clone())
+}
+
+// Adds peers from elsewhere to a response without exceeding numwant
+#[cfg(feature = "actix")]
+fn merge_peers(
+    response: &mut AnnounceResponse,
+    peers: Vec<CompactPeerv4>,
+    peers6: Vec<CompactPeerv6>,
+    numwant: u32,
+) {
+    let mut room = (numwant as usize).saturating_sub(response.peers.len() + response.peers6.len());
+
+    for peer in peers {
+        if room == 0 {
+            return;
+        }
+        if !response.peers.contains(&peer) {
+            response.peers.push(peer);
+            room -= 1;
+        }
+    }
+
+    for peer in peers6 {
+        if room == 0 {
+            return;
+        }
+        if !response.peers6.contains(&peer) {
+            response.peers6.push(peer);
+            room -= 1;
+        }
+    }
+}
+
+// Deals with a peer ID announcing from an address other than the one(s)
+// it's already in the swarm with, as bt.duplicate_peer_ids says to.
+// Returns the peers to merge away, which is none unless merging.
+async fn check_duplicates(
+    data: &State,
+    config: &Config,
+    announce: &AnnounceRequest,
+) -> Result<Vec<Peer>, ClientError> {
+    let policy = config.bt.duplicate_peer_ids;
+    if policy == DuplicatePeerIds::Allow {
+        return Ok(Vec::new());
+    }
+
+    let duplicates = data
+        .peer_store
+        .duplicates(&announce.info_hash, &announce.peer)
+        .await;
+    if duplicates.is_empty() {
+        return Ok(duplicates);
+    }
+
+    data.stats.write().await.duplicate_peer_ids += 1;
+
+    match policy {
+        DuplicatePeerIds::Allow | DuplicatePeerIds::Flag => Ok(Vec::new()),
+        DuplicatePeerIds::Reject => Err(ClientError::DuplicatePeerId),
+        DuplicatePeerIds::Merge => Ok(duplicates),
+    }
+}
+
+async fn merge_duplicates(data: &State, info_hash: InfoHash, duplicates: Vec<Peer>) {
+    if duplicates.is_empty() {
+        return;
+    }
+
+    let mut stats = data.stats.write().await;
+    for duplicate in duplicates {
+        if data
+            .peer_store
+            .remove_seeder(info_hash, duplicate.clone())
+            .await
+        {
+            stats.sub_seed();
+        } else if data.peer_store.remove_leecher(info_hash, duplicate).await {
+            stats.sub_leech();
+        }
+    }
+}
+
+// Catches peers claiming to have uploaded faster since their last announce
+// than max_upload_rate allows. Caught peers are banned if so configured.
+async fn check_upload_rate(
+    data: &State,
+    config: &Config,
+    announce: &AnnounceRequest,
+) -> Result<(), ClientError> {
+    let max_rate = config.cheaters.max_upload_rate;
+    if max_rate == 0 {
+        return Ok(());
+    }
+
+    let previous = match data
+        .peer_store
+        .previous(&announce.info_hash, &announce.peer)
+        .await
+    {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+    let (prev_up, _) = previous.traffic();
+    let (up, _) = announce.peer.traffic();
+    let uploaded = up.checked_sub(prev_up).unwrap_or(up);
+
+    // Announces moments apart would make any upload look impossible
+    let elapsed = announce
+        .peer
+        .last_announced()
+        .saturating_duration_since(previous.last_announced());
+    let rate = (uploaded as f64 / elapsed.as_secs_f64().max(1.0)) as u64;
+    if rate <= max_rate {
+        return Ok(());
+    }
+
+    let ip = announce.peer.ip();
+    warn!(
+        "Peer {} at {} reported uploading {} bytes/sec",
+        announce.peer.peer_id(),
+        ip,
+        rate
+    );
+    data.cheaters.flag(FlaggedPeer {
+        info_hash: announce.info_hash,
+        peer_id: announce.peer.peer_id(),
+        ip,
+        upload_rate: rate,
+        timestamp: unix_secs(SystemTime::now()),
+    });
+    data.stats.write().await.cheaters_caught += 1;
+
+    match config.cheaters.action {
+        CheaterAction::Flag => Ok(()),
+        CheaterAction::Ban => {
+            data.cheaters
+                .ban(ip, Duration::new(config.cheaters.ban_duration, 0));
+            Err(ClientError::BannedForCheating)
+        }
+    }
+}
+
+// Accumulates whatever a peer transferred since its last announce,
+// crediting the peer's owner as well on private trackers
+async fn record_traffic(data: &State, info_hash: &InfoHash, peer: &Peer, passkey: Option<&str>) {
+    let (uploaded, downloaded) = data.peer_store.traffic_delta(info_hash, peer).await;
+    if uploaded == 0 && downloaded == 0 {
+        return;
+    }
+
+    data.torrent_store
+        .add_traffic(info_hash, uploaded, downloaded)
+        .await;
+    data.stats.write().await.add_traffic(uploaded, downloaded);
+
+    if let Some(passkey) = passkey {
+        let traffic = UserTraffic {
+            uploaded,
+            downloaded: if data.freeleech.is_free(info_hash) {
+                0
+            } else {
+                downloaded
+            },
+        };
+        data.user_store.add_traffic(passkey, traffic).await;
+    }
+}
+
+// Every successful announce is answered in the same
+// way, regardless of which event changed the swarm
+async fn announce_response(
+    data: &State,
+    info_hash: InfoHash,
+    numwant: u32,
+    interval: u32,
+    format: PeerFormat,
+) -> AnnounceResponse {
+    let (mut complete, downloaded, mut incomplete) =
+        data.torrent_store.get_announce_stats(info_hash).await;
+    if data.config().upstream.merge_counts {
+        if let Some(counts) = data.upstream.read().await.get(&info_hash) {
+            complete = complete.saturating_add(counts.complete);
+            incomplete = incomplete.saturating_add(counts.incomplete);
+        }
+    }
+
+    let mut response =
+        AnnounceResponse::new(interval, complete, incomplete, Vec::new(), Vec::new()).unwrap();
+
+    // Several clients display the snatch count, and some site UIs rely on it
+    if data.config().bt.announce_downloaded {
+        response.downloaded = Some(downloaded);
+    }
+
+    // Legacy clients get dictionary peers, with IDs unless they opted out
+    response.compact = !matches!(format, PeerFormat::Dictionary { .. });
+
+    // The client only wanted to update its state,
+    // so the swarm isn't sampled at all
+    if numwant == 0 {
+        return response;
+    }
+
+    // Torrents forwarded to an origin tracker have its peers merged
+    // in for each request, so their peers are never cached
+    let cacheable = !data.config().retracker.forwards(&info_hash);
+    if cacheable {
+        if let Some(encoded) = data.response_cache.get(info_hash, numwant, format).await {
+            response.encoded_peers = Some(encoded);
+            return response;
+        }
+    }
+
+    // Get randomized peer list
+    let (mut peers, mut peers6, swarm_size) =
+        data.peer_store.sample_peers(info_hash, numwant).await;
+
+    // Permanent seeders are added on top of the sampled peers
+    for seed in data
+        .config()
+        .permaseeds
+        .iter()
+        .filter(|s| s.serves(&info_hash))
+    {
+        match seed.ip {
+            IpAddr::V4(ip) => {
+                let peer = CompactPeerv4 {
+                    ip,
+                    port: seed.port,
+                };
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+            IpAddr::V6(ip) => {
+                let peer = CompactPeerv6 {
+                    ip,
+                    port: seed.port,
+                };
+                if !peers6.contains(&peer) {
+                    peers6.push(peer);
+                }
+            }
+        }
+    }
+
+    response.peers = peers;
+    response.peers6 = peers6;
+    if format == (PeerFormat::Dictionary { peer_ids: true }) {
+        response.peer_ids = data
+            .peer_store
+            .get_peer_ids(info_hash)
+            .await
+            .into_iter()
+            .collect();
+    }
+
+    if cacheable && data.response_cache.caches(swarm_size) {
+        let encoded: Arc<[u8]> = match format {
+            PeerFormat::Datagram { v6: false } => response.peersv4_as_compact().into(),
+            PeerFormat::Datagram { v6: true } => response.peersv6_as_compact().into(),
+            _ => bencode::encode_announce_peers(&response).into(),
+        };
+        data.response_cache
+            .insert(info_hash, numwant, format, encoded.clone())
+            .await;
+        response.encoded_peers = Some(encoded);
+    }
+
+    response
+}
+
+// Private trackers only serve requests carrying the passkey of a known
+// user. Public trackers ignore passkeys altogether.
+async fn check_passkey(
+    data: &State,
+    config: &Config,
+    passkey: Option<&str>,
+) -> Result<(), ClientError> {
+    if !config.private.enabled {
+        return Ok(());
+    }
+
+    match passkey {
+        Some(passkey) if data.user_store.is_revoked(passkey) => Err(ClientError::RevokedPasskey),
+        Some(passkey) if data.user_store.get(passkey).await.is_some() => Ok(()),
+        Some(_) => Err(ClientError::UnknownPasskey),
+        None => Err(ClientError::MissingPasskey),
+    }
+}
+
+// How long the passkey's owner has to wait if they're over the limit
+fn passkey_rate_limited(data: &State, config: &Config, passkey: Option<&str>) -> Option<Duration> {
+    let limit = &config.rate_limit;
+    if !config.private.enabled || limit.passkey_requests_per_sec <= 0.0 {
+        return None;
+    }
+
+    data.passkey_rate_limiter
+        .check(
+            passkey?.to_string(),
+            limit.passkey_requests_per_sec,
+            limit.passkey_burst,
+        )
+        .err()
+}
+
+// Clients that don't say how many peers they want get the default,
+// or the seeders' default if they're seeding, and nobody gets more
+// than the cap (0 is no cap)
+fn numwant(config: &Config, requested: Option<u32>, seeding: bool) -> u32 {
+    let bt = &config.bt;
+    let default = if seeding && bt.seeder_numwant > 0 {
+        bt.seeder_numwant
+    } else {
+        bt.default_numwant
+    };
+    let numwant = match (requested, default) {
+        (Some(n), _) => n,
+        (None, 0) => DEFAULT_NUMWANT,
+        (None, n) => n,
+    };
+    if bt.max_numwant > 0 {
+        numwant.min(bt.max_numwant)
+    } else {
+        numwant
+    }
+}
+
+// Nobody can connect to a peer on port 0, and the operator may
+// not want peers sent to low or otherwise unsuitable ports
+fn check_port(config: &Config, port: u16) -> Result<(), ClientError> {
+    if port == 0 {
+        Err(ClientError::InvalidPort)
+    } else if port < config.bt.min_port {
+        Err(ClientError::PortTooLow)
+    } else if config.bt.denied_ports.contains(&port) {
+        Err(ClientError::DeniedPort)
+    } else {
+        Ok(())
+    }
+}
+
+// Looks up the announce policy for the category a torrent belongs to, if any
+async fn category_policy<'a>(
+    data: &State,
+    config: &'a Config,
+    info_hash: &InfoHash,
+) -> Option<&'a CategoryPolicy> {
+    if config.categories.is_empty() {
+        return None;
+    }
+
+    let category = data.torrent_store.get_category(info_hash).await?;
+    config.categories.get(&category)
+}
+
+// Looks up the local counts for a scrape, regardless of the protocol it
+// arrived over. Unknown and hidden torrents are left out.
+pub async fn handle_scrape(data: &State, info_hashes: Vec<Vec<u8>>) -> Vec<ScrapeFile> {
+    // Heavy scrape traffic can be kept off of the live store's locks
+    let scrape_files = if data.config().bt.snapshot_interval > 0 {
+        data.snapshot.get_scrapes(info_hashes)
+    } else {
+        data.torrent_store.get_scrapes(info_hashes).await
+    };
+
+    data.stats.write().await.incr_scrapes();
+    scrape_files
+}
+
+pub async fn handle_full_scrape(data: &State) -> Vec<ScrapeFile> {
+    let scrape_files = if data.config().bt.snapshot_interval > 0 {
+        data.snapshot.get_all_scrapes()
+    } else {
+        data.torrent_store.get_all_scrapes().await
+    };
+
+    data.stats.write().await.incr_scrapes();
+    scrape_files
+}
+
+#[cfg(feature = "actix")]
+pub async fn parse_scrape(data: web::Data<State>, req: HttpRequest) -> impl Responder {
+    scrape(data, req, None).await
+}
+
+#[cfg(feature = "actix")]
+pub async fn parse_private_scrape(
+    data: web::Data<State>,
+    req: HttpRequest,
+    passkey: web::Path<String>,
+) -> impl Responder {
+    scrape(data, req, Some(passkey.into_inner())).await
+}
+
+#[cfg(feature = "actix")]
+async fn scrape(data: web::Data<State>, req: HttpRequest, passkey: Option<String>) -> HttpResponse {
+    if let Err(e) = check_passkey(&data, &data.config(), passkey.as_deref()).await {
+        let bencoded = bencode::encode_scrape_response(ScrapeResponse::failure(e.text()));
+        return HttpResponse::Ok().content_type("text/plain").body(bencoded);
+    }
+
+    let scrape_request = ScrapeRequest::new(req.query_string());
+    match scrape_request {
+        Ok(parsed_req) => {
+            let info_hashes: Vec<InfoHash> = parsed_req
+                .info_hashes
+                .iter()
+                .filter_map(|h| InfoHash::from_bytes(h))
+                .collect();
+            if let Some(owner) = shard_owner(&data, &req, &info_hashes) {
+                return match sharding::forward(&data.config().sharding, &owner, &req, None).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let failure = ScrapeResponse::failure(e.text());
+                        let bencoded = bencode::encode_scrape_response(failure);
+                        HttpResponse::Ok().content_type("text/plain").body(bencoded)
+                    }
+                };
+            }
+
+            // A scrape without any info hashes asks for every torrent
+            let full_scrape =
+                parsed_req.info_hashes.is_empty() && data.config().bt.allow_full_scrape;
+            let mut files = if full_scrape {
+                handle_full_scrape(&data).await
+            } else {
+                handle_scrape(&data, parsed_req.info_hashes).await
+            };
+
+            // Counts from upstream trackers are attached separately,
+            // and only added to ours if asked to
+            {
+                let merge_counts = data.config().upstream.merge_counts;
+                let upstream = data.upstream.read().await;
+                for file in files.iter_mut() {
+                    file.upstream = InfoHash::from_bytes(&file.info_hash)
+                        .and_then(|h| upstream.get(&h))
+                        .copied();
+                    if let (true, Some(counts)) = (merge_counts, file.upstream) {
+                        file.complete = file.complete.saturating_add(counts.complete);
+                        file.incomplete = file.incomplete.saturating_add(counts.incomplete);
+                    }
+                }
+            }
+
+            // Full scrapes are bencoded as they're sent out, so that
+            // the whole body never has to be held in memory at once
+            if full_scrape {
+                let chunks = bencode::encode_full_scrape(files)
+                    .map(|chunk| Ok::<_, actix_web::Error>(web::Bytes::from(chunk)));
+                return HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .streaming(stream::iter(chunks));
+            }
+
+            let mut scrape_response = ScrapeResponse::new().unwrap();
+            for file in files {
+                scrape_response.add_file(file.info_hash.clone(), file);
+            }
+
+            let bencoded = bencode::encode_scrape_response(scrape_response);
+            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        }
+
+        Err(failure) => {
+            let bencoded = bencode::encode_scrape_response(failure);
+            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
+pub async fn get_stats(data: web::Data<State>) -> impl Responder {
+    let stats = if data.config().bt.snapshot_interval > 0 {
+        ReturnedStatistics::new(&data.snapshot.load().stats)
+    } else {
+        ReturnedStatistics::new(&*data.stats.read().await)
+    };
+    web::Json(stats)
+}
+
+#[cfg(feature = "actix")]
+pub async fn get_cheaters(data: web::Data<State>) -> impl Responder {
+    web::Json(data.cheaters.flagged())
+}
+
+#[cfg(feature = "actix")]
+pub async fn get_stats_history(data: web::Data<State>) -> impl Responder {
+    web::Json(data.stats_history.read().await.clone())
+}
+
+#[cfg(feature = "actix")]
+pub async fn get_version(data: web::Data<State>) -> impl Responder {
+    web::Json(BuildInfo::new(&data))
+}
+
+// Swarms broken down by country, once a GeoIP database is loaded
+#[cfg(all(feature = "actix", feature = "geoip"))]
+pub async fn get_countries(data: web::Data<State>) -> impl Responder {
+    match &data.countries {
+        Some(db) => {
+            HttpResponse::Ok().json(crate::geoip::country_counts(db, &data.peer_store).await)
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// Liveness probe; if the process can answer at all, it's alive
+#[cfg(feature = "actix")]
+pub async fn get_health() -> impl Responder {
+    HttpResponse::Ok().body("OK")
+}
+
+// Readiness probe. The stores are loaded before the server starts
+// listening, so the only thing left to check is the storage backend.
+#[cfg(feature = "actix")]
+pub async fn get_ready(data: web::Data<State>) -> impl Responder {
+    if let Some(backend) = &data.backend {
+        if let Err(e) = backend.ping().await {
+            error!("{} ({})", InternalError::StorageUnreachable.text(), e);
+            return HttpResponse::ServiceUnavailable()
+                .body(InternalError::StorageUnreachable.text());
+        }
+    }
+
+    HttpResponse::Ok().body("OK")
+}
+
+#[cfg(all(test, feature = "actix"))]
+mod tests {
+    use super::*;
+
+    use actix_service::Service;
+    use actix_web::{test, web, App, HttpResponse};
+
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+
+    use crate::bittorrent::{PeerId, Peerv4, UpstreamCounts};
+    use crate::config::Permaseed;
+    use crate::state::State;
+    use crate::storage::users::{User, UserRecords};
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    #[actix_rt::test]
+    async fn index_get_not_allowed() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape)),
+                )
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert!(resp.status().is_client_error());
+    }
+
+    #[actix_rt::test]
+    async fn announce_get_malformed() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape)),
+                )
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
+        )
+        .await;
+
+        let proper_resp = "d14:failure reason26:Malformed announce requeste".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?bad_stuff=123").to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn scrape_get_malformed() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape)),
+                )
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
+        )
+        .await;
+
+        let proper_resp = "d14:failure reason24:Malformed scrape requeste".as_bytes();
+        let req = test::TestRequest::with_uri("/scrape?bad_stuff=123").to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn scrape_get_success() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let torrent1 = Torrent::new(info_hash1, 10, 34, 7, 10000000);
+
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let torrent2 = Torrent::new(info_hash2, 25, 57, 19, 20000000);
+
+        {
+            let mut store = stores.torrent_store.torrents.write().await;
+            store.insert(torrent1.info_hash, torrent1);
+            store.insert(torrent2.info_hash, torrent2);
+        }
+
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape)),
+                )
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
+        )
+        .await;
+
+        let uri = "/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0\
+                   &info_hash=B2C3D4E5F6G7H8I9J0K1";
+
+        let proper_resp = "d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei10e10:downloadedi34e10:incompletei7ee20:B2C3D4E5F6G7H8I9J0K1d8:completei25e10:downloadedi57e10:incompletei19eeee".as_bytes();
+        let req = test::TestRequest::with_uri(uri).to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn scrape_merges_upstream_counts() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 1, 3, 2, 0));
+        let mut config = Config::default();
+        config.upstream.merge_counts = true;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(records)));
+        stores.upstream.write().await.insert(
+            info_hash,
+            UpstreamCounts {
+                complete: 10,
+                incomplete: 20,
+            },
+        );
+
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape)),
+                ),
+        )
+        .await;
+
+        // The upstream share is still flagged
+        let proper_resp = "d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei11e10:downloadedi3e10:incompletei22e8:upstreamd8:completei10e10:incompletei20eeeee".as_bytes();
+        let req =
+            test::TestRequest::with_uri("/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, proper_resp);
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0\
+                   &peer_id=-DE9824-143964258012&port=6881\
+                   &uploaded=0&downloaded=0&left=1000&event=started";
+        let req = test::TestRequest::with_uri(uri)
+            .peer_addr("8.8.4.4:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert!(resp.starts_with(b"d8:completei11e10:incompletei23e"));
+    }
+
+    #[actix_rt::test]
+    async fn scrape_full() {
+        let mut config = Config::default();
+        config.bt.allow_full_scrape = true;
+
+        let mut hidden = Torrent::new(InfoHash(*b"0000000000HIDDEN0000"), 1, 1, 1, 0);
+        hidden.hidden = true;
+        let mut records = TorrentRecords::new();
+        for torrent in [
+            Torrent::new(InfoHash(*b"B2C3D4E5F6G7H8I9J0K1"), 25, 57, 19, 0),
+            Torrent::new(InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"), 10, 34, 7, 0),
+            hidden,
+        ]
+        .iter()
+        {
+            records.insert(torrent.info_hash, torrent.clone());
+        }
+        let stores = web::Data::new(State::new(config, TorrentStore::new(records)));
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("scrape")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_scrape)),
+            ),
+        )
+        .await;
+
+        // Hidden torrents stay out of full scrapes too
+        let proper_resp = "d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei10e10:downloadedi34e10:incompletei7ee20:B2C3D4E5F6G7H8I9J0K1d8:completei25e10:downloadedi57e10:incompletei19eeee".as_bytes();
+        let req = test::TestRequest::with_uri("/scrape").to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+        assert_eq!(stores.stats.read().await.scrapes, 1);
+    }
+
+    #[actix_rt::test]
+    async fn announce_category_client_restriction() {
+        let mut config = Config::default();
+        config.categories.insert(
+            "music".to_string(),
+            CategoryPolicy {
+                allowed_clients: vec!["qB".to_string()],
+                ..Default::default()
+            },
+        );
+
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut torrent = Torrent::new(info_hash, 0, 0, 0, 0);
+        torrent.category = Some("music".to_string());
+
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, torrent);
+        let stores = web::Data::new(State::new(config, TorrentStore::new(records)));
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=727955456&numwant=30&compact=1&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn announce_numwant_zero_stats_only() {
+        let config = Config::default();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 1, 0, 0, 0));
+        let stores = web::Data::new(State::new(config, TorrentStore::new(records)));
+
+        let seeder = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"-TR2940-k8hj0wgej6ch"),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 51413,
+            uploaded: 0,
+            downloaded: 0,
+            last_announced: Instant::now(),
+        });
+        stores.peer_store.put_seeder(info_hash, seeder).await;
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        // The event is still processed, but no peers are returned
+        let proper_resp =
+            "d8:completei1e10:incompletei1e8:intervali1800e5:peers0:6:peers60:10:tracker_id0:e"
+                .as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=727955456&numwant=0&compact=1&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+        assert_eq!(stores.stats.read().await.succ_announces, 1);
+    }
+
+    #[actix_rt::test]
+    async fn announce_paused_partial_seed() {
+        let config = Config::default();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let stores = web::Data::new(State::new(config, TorrentStore::new(records)));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        for event in &["started", "paused"] {
+            let req = test::TestRequest::with_uri(&format!("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event={}", event))
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+
+        // The partial seed is neither a seeder nor a leecher, but still handed out
+        assert_eq!(
+            stores.torrent_store.get_announce_stats(info_hash).await,
+            (0, 0, 0)
+        );
+        assert_eq!(
+            stores.torrent_store.torrents.read().await[&info_hash].partial,
+            1
+        );
+        let (peers, _) = stores.peer_store.get_peers(info_hash, 50).await;
+        assert_eq!(peers.len(), 1);
+
+        // Resuming the download makes it a leecher again
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        assert_eq!(
+            stores.torrent_store.get_announce_stats(info_hash).await,
+            (0, 0, 1)
+        );
+        assert_eq!(stores.stats.read().await.total_leechers, 1);
+    }
+
+    #[actix_rt::test]
+    async fn announce_external_ip() {
+        let mut config = Config::default();
+        config.bt.announce_external_ip = true;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        // The observed address is reported, not the one the client claimed
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&ip=10.0.0.5&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        let expected = b"11:external ip4:\x7f\x00\x00\x01";
+        assert!(resp.windows(expected.len()).any(|w| w == expected));
+    }
+
+    #[test]
+    fn numwant_default_and_cap() {
+        let mut config = Config::default();
+        config.bt.default_numwant = 30;
+        config.bt.max_numwant = 100;
+        assert_eq!(numwant(&config, None, false), 30);
+        assert_eq!(numwant(&config, None, true), 30);
+        assert_eq!(numwant(&config, Some(0), false), 0);
+        assert_eq!(numwant(&config, Some(100_000), false), 100);
+
+        // Seeders asking for a particular number still get it
+        config.bt.seeder_numwant = 10;
+        assert_eq!(numwant(&config, None, true), 10);
+        assert_eq!(numwant(&config, None, false), 30);
+        assert_eq!(numwant(&config, Some(40), true), 40);
+
+        config.bt.default_numwant = 0;
+        config.bt.seeder_numwant = 0;
+        config.bt.max_numwant = 0;
+        assert_eq!(numwant(&config, None, true), DEFAULT_NUMWANT);
+        assert_eq!(numwant(&config, Some(100_000), false), 100_000);
+    }
+
+    #[test]
+    fn claimed_ip_only_from_trusted_sources() {
+        let remote: IpAddr = "8.8.4.4".parse().unwrap();
+        let resolve = |state: &State, claimed: &str| {
+            let query = format!("info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&ip={}", claimed);
+            let mut request = AnnounceRequest::new(&query, Some("8.8.4.4:51413")).unwrap();
+            resolve_claimed_ip(state, &mut request, remote);
+            request.peer.ip().to_string()
+        };
+
+        let state = State::new(Config::default(), TorrentStore::new(TorrentRecords::new()));
+        assert_eq!(resolve(&state, "1.1.1.1"), "1.1.1.1");
+        assert_eq!(resolve(&state, "10.0.0.5"), "8.8.4.4");
+        assert_eq!(resolve(&state, "::1"), "8.8.4.4");
+
+        let mut config = Config::default();
+        config.ip_param.trusted = vec!["192.0.2.0/24".to_string()];
+        let state = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        assert_eq!(resolve(&state, "1.1.1.1"), "8.8.4.4");
+        state.ip_param_trusted.replace(&["8.8.4.0/24".to_string()]);
+        assert_eq!(resolve(&state, "1.1.1.1"), "1.1.1.1");
+
+        let mut config = Config::default();
+        config.ip_param.honor = false;
+        let state = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        assert_eq!(resolve(&state, "1.1.1.1"), "8.8.4.4");
+    }
+
+    #[actix_rt::test]
+    async fn claimed_ip_not_trusted_from_forwarded_for() {
+        let mut config = Config::default();
+        config.bt.announce_external_ip = true;
+        config.ip_param.trusted = vec!["192.0.2.0/24".to_string()];
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        // Naming the same address in both doesn't make the client trusted,
+        // or get that address reported back as the one it connected from
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&ip=192.0.2.5&event=started")
+            .peer_addr("203.0.113.5:51413".parse().unwrap())
+            .header("X-Forwarded-For", "192.0.2.5")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let expected = b"11:external ip4:\xcb\x00\x71\x05";
+        assert!(resp.windows(expected.len()).any(|w| w == expected));
+
+        // Other peers are sent the address it connected from
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-000000000000&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+            .peer_addr("198.51.100.7:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let spoofed = b"\xc0\x00\x02\x05\x1a\xe1";
+        let observed = b"\xcb\x00\x71\x05\x1a\xe1";
+        assert!(!resp.windows(spoofed.len()).any(|w| w == spoofed));
+        assert!(resp.windows(observed.len()).any(|w| w == observed));
+    }
+
+    #[actix_rt::test]
+    async fn announce_private_passkeys() {
+        let mut config = Config::default();
+        config.private.enabled = true;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut users = UserRecords::new();
+        users.insert(
+            "0123456789abcdef0123456789abcdef".to_string(),
+            User {
+                id: 1,
+                passkey: "0123456789abcdef0123456789abcdef".to_string(),
+            },
+        );
+        stores.user_store.replace(users).await;
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce))
+                        .route("/{passkey}", web::get().to(parse_private_announce)),
+                )
+                .service(
+                    web::scope("scrape")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_scrape))
+                        .route("/{passkey}", web::get().to(parse_private_scrape)),
+                ),
+        )
+        .await;
+        let query = "?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started";
 
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
+        let req = test::TestRequest::with_uri(&format!("/announce{}", query))
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, "d14:failure reason16:Passkey requirede".as_bytes());
 
-                    // Associate all the requisite data together and
-                    // respond with the bencoded version of the data
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-
-                    let mut stats = data.stats.write().await;
-                    stats.add_leech();
-                    stats.succ_announce();
-
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
+        let req = test::TestRequest::with_uri(&format!("/announce/nobody{}", query))
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, "d14:failure reason15:Unknown passkeye".as_bytes());
 
-                // Stopped should be sent when a client stops seed or leeching
-                Event::Stopped => {
-                    // If the peer is present in one set, then it
-                    // cannot be present in the other.
-                    let mut stats = data.stats.write().await;
-
-                    if data
-                        .peer_store
-                        .remove_seeder(parsed_req.info_hash.clone(), parsed_req.peer.clone())
-                        .await
-                    {
-                        stats.sub_seed();
-                    } else {
-                        data.peer_store
-                            .remove_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
-                            .await;
-                        stats.sub_leech();
-                    }
+        let req = test::TestRequest::with_uri(&format!(
+            "/announce/0123456789abcdef0123456789abcdef{}",
+            query
+        ))
+        .peer_addr("127.0.0.1:51413".parse().unwrap())
+        .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let expected = b"8:intervali";
+        assert!(resp.windows(expected.len()).any(|w| w == expected));
 
-                    stats.succ_announce();
+        let req = test::TestRequest::with_uri("/scrape/nobody?info_hash=A1B2C3D4E5F6G7H8I9J0")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, "d14:failure reason15:Unknown passkeye".as_bytes());
+    }
 
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
+    #[actix_rt::test]
+    async fn announce_bans_impossible_uploads() {
+        let mut config = Config::default();
+        config.cheaters.max_upload_rate = 1000;
+        config.cheaters.action = CheaterAction::Ban;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
 
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
+        let mut responses = Vec::new();
+        for (uploaded, event) in &[(0, "started"), (1_000_000, ""), (0, "started")] {
+            let req = test::TestRequest::with_uri(&format!("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded={}&downloaded=0&left=1000&compact=1&event={}", uploaded, event))
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            responses.push(test::read_response(&mut app, req).await);
+        }
 
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
+        // Once banned, the address is turned away whatever it reports
+        let banned = "d14:failure reason41:Banned for reporting impossible transferse".as_bytes();
+        assert_ne!(responses[0], banned);
+        assert_eq!(responses[1], banned);
+        assert_eq!(responses[2], banned);
+        assert_eq!(stores.stats.read().await.cheaters_caught, 1);
+        assert_eq!(stores.cheaters.flagged()[0].upload_rate, 1_000_000);
+    }
 
-                // Completed should be sent when a peer receives 100%
-                // of the data associated with a particular torrent
-                Event::Completed => {
-                    data.peer_store
-                        .promote_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
-                        .await;
-                    data.torrent_store
-                        .new_seed(parsed_req.info_hash.clone())
-                        .await;
+    #[actix_rt::test]
+    async fn announce_duplicate_peer_ids() {
+        for policy in &[DuplicatePeerIds::Merge, DuplicatePeerIds::Reject] {
+            let mut config = Config::default();
+            config.bt.duplicate_peer_ids = *policy;
+            let stores =
+                web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+            let mut app = test::init_service(
+                App::new().service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                ),
+            )
+            .await;
 
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
+            let mut responses = Vec::new();
+            for addr in &["127.0.0.1:51413", "127.0.0.2:51413"] {
+                let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+                    .peer_addr(addr.parse().unwrap())
+                    .to_request();
+                responses.push(test::read_response(&mut app, req).await);
+            }
 
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
+            // Either way, only one address is left in the swarm
+            let peers = stores.peer_store.dump().await;
+            assert_eq!(peers.len(), 1);
+            assert_eq!(stores.stats.read().await.duplicate_peer_ids, 1);
 
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let mut stats = data.stats.write().await;
-                    stats.promote_leech();
-                    stats.succ_announce();
-
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
+            let rejected =
+                "d14:failure reason43:Peer ID already in use from another addresse".as_bytes();
+            if *policy == DuplicatePeerIds::Merge {
+                assert_ne!(responses[1], rejected);
+                assert_eq!(peers[0].1.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+            } else {
+                assert_eq!(responses[1], rejected);
+                assert_eq!(peers[0].1.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+            }
+        }
+    }
 
-                // None should only be sent if
-                // there is no change in snatch state
-                Event::None => {
-                    // This updates a peer if it is present in either swarm.
-                    // It is intended that a client correctly send its states.
-                    // If a client starts out with this event, it will never be added.
-                    data.peer_store
-                        .update_peer(parsed_req.info_hash.clone(), parsed_req.peer)
-                        .await;
+    #[actix_rt::test]
+    async fn rejected_duplicates_are_not_merged() {
+        let mut config = Config::default();
+        config.bt.duplicate_peer_ids = DuplicatePeerIds::Merge;
+        config.private.enabled = true;
+        config.private.max_peers_per_torrent = 1;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let passkey = "0123456789abcdef0123456789abcdef";
+        let mut users = UserRecords::new();
+        users.insert(
+            passkey.to_string(),
+            User {
+                id: 1,
+                passkey: passkey.to_string(),
+            },
+        );
+        stores.user_store.replace(users).await;
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("/{passkey}", web::get().to(parse_private_announce)),
+            ),
+        )
+        .await;
 
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
+        let mut responses = Vec::new();
+        for addr in &["127.0.0.1:51413", "127.0.0.2:51413"] {
+            let req = test::TestRequest::with_uri(&format!("/announce/{}?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started", passkey))
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            responses.push(test::read_response(&mut app, req).await);
+        }
 
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
+        // The second address is over the user's limit, so the
+        // first one stays in the swarm rather than being merged away
+        assert!(responses[1].starts_with(b"d14:failure reason"));
+        let peers = stores.peer_store.dump().await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].1.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(stores.stats.read().await.total_leechers, 1);
+    }
 
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    data.stats.write().await.succ_announce();
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
-            }
+    #[actix_rt::test]
+    async fn announce_rate_limited_per_passkey() {
+        let mut config = Config::default();
+        config.private.enabled = true;
+        config.rate_limit.passkey_requests_per_sec = 0.01;
+        config.rate_limit.passkey_burst = 1;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut users = UserRecords::new();
+        for (id, passkey) in &[
+            (1, "0123456789abcdef0123456789abcdef"),
+            (2, "fedcba9876543210fedcba9876543210"),
+        ] {
+            users.insert(
+                passkey.to_string(),
+                User {
+                    id: *id,
+                    passkey: passkey.to_string(),
+                },
+            );
         }
+        stores.user_store.replace(users).await;
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("/{passkey}", web::get().to(parse_private_announce)),
+            ),
+        )
+        .await;
 
-        // If the request is not parse-able, short-circuit and respond with failure
-        Err(failure) => {
-            let bencoded = bencode::encode_announce_response(failure);
-            data.stats.write().await.fail_announce();
-            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        // A second address doesn't get the user a second allowance,
+        // and other users behind the same address aren't held back
+        let mut responses = Vec::new();
+        for (passkey, addr) in &[
+            ("0123456789abcdef0123456789abcdef", "127.0.0.1:51413"),
+            ("0123456789abcdef0123456789abcdef", "127.0.0.2:51413"),
+            ("fedcba9876543210fedcba9876543210", "127.0.0.1:51414"),
+        ] {
+            let req = test::TestRequest::with_uri(&format!("/announce/{}?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started", passkey))
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            responses.push(test::read_response(&mut app, req).await);
         }
+
+        let limited = b"d14:failure reason17:Too many requests";
+        assert!(!responses[0].starts_with(limited));
+        assert!(responses[1].starts_with(limited));
+        assert!(!responses[2].starts_with(limited));
     }
-}
 
-pub async fn parse_scrape(data: web::Data<State>, req: HttpRequest) -> impl Responder {
-    let scrape_request = ScrapeRequest::new(req.query_string());
-    match scrape_request {
-        Ok(parsed_req) => {
-            let scrape_files = data.torrent_store.get_scrapes(parsed_req.info_hashes).await;
-            let mut scrape_response = ScrapeResponse::new().unwrap();
+    #[actix_rt::test]
+    async fn rejected_announces_hold_no_slots() {
+        let mut config = Config::default();
+        config.private.enabled = true;
+        config.private.max_peers = 2;
+        config.bt.duplicate_peer_ids = DuplicatePeerIds::Reject;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut users = UserRecords::new();
+        users.insert(
+            "0123456789abcdef0123456789abcdef".to_string(),
+            User {
+                id: 1,
+                passkey: "0123456789abcdef0123456789abcdef".to_string(),
+            },
+        );
+        stores.user_store.replace(users).await;
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("/{passkey}", web::get().to(parse_private_announce)),
+            ),
+        )
+        .await;
 
-            for file in scrape_files {
-                scrape_response.add_file(file.info_hash.clone(), file);
-            }
+        // The second announce is a duplicate, so only the first takes a slot
+        let mut responses = Vec::new();
+        for (peer_id, addr) in &[
+            ("-DE9824-143964258012", "127.0.0.1:51413"),
+            ("-DE9824-143964258012", "127.0.0.2:51413"),
+            ("-TR2940-143964258012", "127.0.0.3:51413"),
+        ] {
+            let req = test::TestRequest::with_uri(&format!("/announce/0123456789abcdef0123456789abcdef?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id={}&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started", peer_id))
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            responses.push(test::read_response(&mut app, req).await);
+        }
 
-            let bencoded = bencode::encode_scrape_response(scrape_response);
-            data.stats.write().await.incr_scrapes();
-            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        assert_eq!(
+            responses[1],
+            "d14:failure reason43:Peer ID already in use from another addresse".as_bytes()
+        );
+        assert!(responses[2].starts_with(b"d8:complete"));
+        assert_eq!(stores.peer_store.dump().await.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn announce_port_validation() {
+        let mut config = Config::default();
+        config.bt.min_port = 1024;
+        config.bt.denied_ports = vec![6881];
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let cases: &[(u16, &[u8])] = &[
+            (0, b"d14:failure reason26:Port 0 is not a valid porte"),
+            (
+                80,
+                b"d14:failure reason33:Port is below the allowed minimume",
+            ),
+            (6881, b"d14:failure reason19:Port is not allowede"),
+        ];
+        for (port, expected) in cases {
+            let uri = format!("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port={}&uploaded=0&downloaded=0&left=1000&compact=1&event=started", port);
+            let req = test::TestRequest::with_uri(&uri)
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            assert_eq!(test::read_response(&mut app, req).await, *expected);
         }
+        assert!(stores.peer_store.dump().await.is_empty());
 
-        Err(failure) => {
-            let bencoded = bencode::encode_scrape_response(failure);
-            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=51413&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        test::read_response(&mut app, req).await;
+        assert_eq!(stores.peer_store.dump().await.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn announce_updates_statistics() {
+        let stores = web::Data::new(State::new(
+            Config::default(),
+            TorrentStore::new(TorrentRecords::new()),
+        ));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let announces = [
+            ("127.0.0.1:51413", "started"),
+            ("127.0.0.2:51413", "started"),
+            ("127.0.0.1:51413", "completed"),
+            ("127.0.0.3:51413", "completed"),
+            ("127.0.0.1:51413", "stopped"),
+            ("127.0.0.4:51413", "stopped"),
+        ];
+        for (addr, event) in &announces {
+            let uri = format!("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=0&compact=1&event={}", event);
+            let req = test::TestRequest::with_uri(&uri)
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            test::read_response(&mut app, req).await;
         }
+
+        // Peers that were never seen leave the counts alone
+        let stats = stores.stats.read().await;
+        assert_eq!((stats.total_seeders, stats.total_leechers), (1, 1));
+        assert_eq!(stats.succ_announces, 6);
     }
-}
 
-pub async fn get_stats(data: web::Data<State>) -> impl Responder {
-    let global_stats = data.stats.read().await;
-    let stats = ReturnedStatistics::new(&global_stats);
-    web::Json(stats)
-}
+    #[actix_rt::test]
+    async fn announce_without_event_adds_peer() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let stores = web::Data::new(State::new(Config::default(), TorrentStore::new(records)));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        for (addr, left) in &[("127.0.0.1:51413", 0), ("127.0.0.2:51413", 1000)] {
+            let uri = format!("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left={}&compact=1", left);
+            let req = test::TestRequest::with_uri(&uri)
+                .peer_addr(addr.parse().unwrap())
+                .to_request();
+            test::read_response(&mut app, req).await;
+        }
 
-    use actix_service::Service;
-    use actix_web::{test, web, App, HttpResponse};
+        // Announcing again only updates the peers
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=0&compact=1")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        test::read_response(&mut app, req).await;
 
-    use crate::config::Config;
-    use crate::state::State;
-    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+        assert_eq!(stores.peer_store.dump().await.len(), 2);
+        assert_eq!(
+            stores.torrent_store.get_announce_stats(info_hash).await,
+            (1, 0, 1)
+        );
+        let stats = stores.stats.read().await;
+        assert_eq!((stats.total_seeders, stats.total_leechers), (1, 1));
+    }
 
     #[actix_rt::test]
-    async fn index_get_not_allowed() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
+    async fn announce_compact_only() {
+        let mut config = Config::default();
+        config.bt.compact_only = true;
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=0&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(
+            resp,
+            "d14:failure reason35:Announces must be in compact formate".as_bytes()
+        );
+
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert!(!resp.starts_with(b"d14:failure reason"));
+    }
+
+    #[actix_rt::test]
+    async fn announce_and_scrape_aliases() {
+        let mut config = Config::default();
+        config.network.announce_aliases = vec!["/announce.php".to_string()];
+        config.network.scrape_aliases = vec!["/s".to_string()];
+        let network = config.network.clone();
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let state = stores.get_ref().clone();
         let mut app = test::init_service(
             App::new()
-                .service(
-                    web::scope("announce")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_announce)),
-                )
-                .service(
-                    web::scope("scrape")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_scrape)),
-                )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .app_data(stores.clone())
+                .configure(|cfg| tracker_routes(cfg, &state, &network)),
         )
         .await;
 
-        let req = test::TestRequest::with_uri("/").to_request();
-        let resp = app.call(req).await.unwrap();
+        for path in &["/announce", "/announce.php"] {
+            let req = test::TestRequest::with_uri(&format!("{}?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started", path))
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert!(resp.starts_with(b"d8:complete"), "{}", path);
+        }
 
-        assert!(resp.status().is_client_error());
+        let req = test::TestRequest::with_uri("/s?info_hash=A1B2C3D4E5F6G7H8I9J0")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert!(resp.starts_with(b"d5:files"));
+
+        let req = test::TestRequest::with_uri("/announce.phpx").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
-    async fn announce_get_malformed() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
+    async fn scrape_with_client_approval() {
+        let mut config = Config::default();
+        config.client_approval.enabled = true;
+        config.client_approval.client_list = vec!["DE".to_string()];
+        let network = config.network.clone();
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let state = stores.get_ref().clone();
         let mut app = test::init_service(
             App::new()
-                .service(
-                    web::scope("announce")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_announce)),
-                )
-                .service(
-                    web::scope("scrape")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_scrape)),
-                )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .app_data(stores.clone())
+                .configure(|cfg| tracker_routes(cfg, &state, &network)),
         )
         .await;
 
-        let proper_resp = "d14:failure_reason26:Malformed announce requeste".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?bad_stuff=123").to_request();
+        let req = test::TestRequest::with_uri("/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
         let resp = test::read_response(&mut app, req).await;
+        assert!(resp.starts_with(b"d5:files"));
 
-        assert_eq!(resp, proper_resp);
+        // Announces are still held to the client list
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-TR2940-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, "d14:failure reason17:Unapproved cliente".as_bytes());
     }
 
     #[actix_rt::test]
-    async fn scrape_get_malformed() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
+    async fn announce_sent_to_owning_tracker() {
+        let mut config = Config::default();
+        config.sharding.nodes = vec![
+            "http://a.example".to_string(),
+            "http://b.example".to_string(),
+        ];
+        config.sharding.this_node = "http://a.example".to_string();
+        let network = config.network.clone();
+        let stores = web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+        let state = stores.get_ref().clone();
         let mut app = test::init_service(
             App::new()
-                .service(
+                .app_data(stores.clone())
+                .configure(|cfg| tracker_routes(cfg, &state, &network)),
+        )
+        .await;
+
+        let ring = state.hash_ring.as_ref().unwrap();
+        let hashes: Vec<String> = (0..100)
+            .map(|i| format!("{:02}B2C3D4E5F6G7H8I9J0", i))
+            .collect();
+        let owned_by = |owner| {
+            hashes
+                .iter()
+                .find(|h| ring.owner(&InfoHash::from_bytes(h.as_bytes()).unwrap()) == owner)
+                .unwrap()
+        };
+        let query = |info_hash: &str| {
+            format!("info_hash={}&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1", info_hash)
+        };
+
+        let elsewhere = query(owned_by(Some("http://b.example")));
+        let req = test::TestRequest::with_uri(&format!("/announce?{}", elsewhere))
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::TEMPORARY_REDIRECT
+        );
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            &format!("http://b.example/announce?{}", elsewhere)
+        );
+
+        // Requests the owner proxied back, and torrents owned here, stay here
+        let req = test::TestRequest::with_uri(&format!("/announce?{}", elsewhere))
+            .header(sharding::FORWARDED_HEADER, "1")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert!(resp.starts_with(b"d8:complete"));
+
+        let here = query(owned_by(None));
+        let req = test::TestRequest::with_uri(&format!("/announce?{}", here))
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert!(resp.starts_with(b"d8:complete"));
+    }
+
+    #[actix_rt::test]
+    async fn announce_min_interval() {
+        for (min_interval, expected) in &[(0, None), (900, Some(900)), (3600, Some(1800))] {
+            let mut config = Config::default();
+            config.bt.min_interval = *min_interval;
+            let stores =
+                web::Data::new(State::new(config, TorrentStore::new(TorrentRecords::new())));
+            let mut app = test::init_service(
+                App::new().service(
                     web::scope("announce")
                         .app_data(stores.clone())
                         .route("", web::get().to(parse_announce)),
-                )
-                .service(
-                    web::scope("scrape")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_scrape)),
-                )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
                 ),
+            )
+            .await;
+
+            let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=1000&compact=1&event=started")
+                .peer_addr("127.0.0.1:51413".parse().unwrap())
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            let body = String::from_utf8_lossy(&resp);
+
+            // Never longer than the interval itself
+            match expected {
+                Some(secs) => assert!(body.contains(&format!("12:min intervali{}e", secs))),
+                None => assert!(!body.contains("min interval")),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn announce_dictionary_peers() {
+        let config = Config::default();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 1, 0, 0, 0));
+        let stores = web::Data::new(State::new(config, TorrentStore::new(records)));
+
+        let seeder = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"-TR2940-k8hj0wgej6ch"),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 51413,
+            uploaded: 0,
+            downloaded: 0,
+            last_announced: Instant::now(),
+        });
+        stores.peer_store.put_seeder(info_hash, seeder).await;
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
         )
         .await;
 
-        let proper_resp = "d14:failure_reason24:Malformed scrape requeste".as_bytes();
-        let req = test::TestRequest::with_uri("/scrape?bad_stuff=123").to_request();
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=727955456&compact=0&event=started")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
         let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8_lossy(&resp);
 
-        assert_eq!(resp, proper_resp);
+        assert!(body.contains("d2:ip8:10.0.0.17:peer id20:-TR2940-k8hj0wgej6ch4:porti51413ee"));
+        assert!(!body.contains("peers6"));
+
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&uploaded=0&downloaded=0&left=727955456&no_peer_id=1")
+            .peer_addr("127.0.0.1:51413".parse().unwrap())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8_lossy(&resp);
+
+        assert!(body.contains("d2:ip8:10.0.0.14:porti51413ee"));
+        assert!(!body.contains("peer id"));
     }
 
     #[actix_rt::test]
-    async fn scrape_get_success() {
+    async fn announce_includes_permaseeds() {
+        let config = Config {
+            permaseeds: vec![
+                Permaseed {
+                    ip: "10.0.0.9".parse().unwrap(),
+                    port: 6881,
+                    torrents: vec![InfoHash(*b"A1B2C3D4E5F6G7H8I9J0")],
+                },
+                Permaseed {
+                    ip: "10.0.0.10".parse().unwrap(),
+                    port: 6881,
+                    torrents: vec![InfoHash(*b"B2C3D4E5F6G7H8I9J0K1")],
+                },
+            ],
+            ..Default::default()
+        };
+        let state = State::new(config, TorrentStore::new(TorrentRecords::new()));
+
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let response = announce_response(&state, info_hash, 50, 1800, PeerFormat::Compact).await;
+
+        assert_eq!(
+            response.peers,
+            vec![CompactPeerv4 {
+                ip: Ipv4Addr::new(10, 0, 0, 9),
+                port: 6881,
+            }]
+        );
+        assert_eq!(response.complete, 0);
+    }
+
+    #[actix_rt::test]
+    async fn announce_response_caches_hot_swarms() {
+        let mut config = Config::default();
+        config.bt.response_cache_ttl = 60;
+        config.bt.response_cache_min_peers = 1;
+
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 1, 0, 0, 0));
+        let state = State::new(config, TorrentStore::new(records));
+
+        let seeder = |port| {
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(*b"-TR2940-k8hj0wgej6ch"),
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+                port,
+                uploaded: 0,
+                downloaded: 0,
+                last_announced: Instant::now(),
+            })
+        };
+        state.peer_store.put_seeder(info_hash, seeder(51413)).await;
+        let first = announce_response(&state, info_hash, 50, 1800, PeerFormat::Compact).await;
+        assert!(first.encoded_peers.is_some());
+
+        // Later announces are served the cached peers, but current counts
+        state.peer_store.put_seeder(info_hash, seeder(51414)).await;
+        state.torrent_store.new_seed(info_hash).await;
+        let second = announce_response(&state, info_hash, 50, 1800, PeerFormat::Compact).await;
+        assert_eq!(second.encoded_peers, first.encoded_peers);
+        assert!(second.peers.is_empty());
+        assert!(bencode::encode_announce_response(second).starts_with(b"d8:completei2e"));
+
+        // A different numwant or peer format is sampled separately
+        let third = announce_response(&state, info_hash, 10, 1800, PeerFormat::Compact).await;
+        assert_eq!(third.peers.len(), 2);
+        let format = PeerFormat::Dictionary { peer_ids: true };
+        let fourth = announce_response(&state, info_hash, 50, 1800, format).await;
+        assert_eq!(fourth.peers.len(), 2);
+        assert_eq!(fourth.peer_ids.len(), 2);
+    }
+
+    #[test]
+    fn merge_peers_respects_numwant() {
+        let local = CompactPeerv4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6881,
+        };
+        let mut response =
+            AnnounceResponse::new(1800, 1, 0, vec![local.clone()], Vec::new()).unwrap();
+
+        let origin = vec![
+            local,
+            CompactPeerv4 {
+                ip: Ipv4Addr::new(10, 0, 0, 2),
+                port: 6881,
+            },
+            CompactPeerv4 {
+                ip: Ipv4Addr::new(10, 0, 0, 3),
+                port: 6881,
+            },
+        ];
+        merge_peers(&mut response, origin, Vec::new(), 2);
+
+        // Duplicates are skipped and the total is capped at numwant
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(response.peers[1].ip, Ipv4Addr::new(10, 0, 0, 2));
+    }
+
+    #[actix_rt::test]
+    async fn version_get_success() {
         let config = Config::default();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
         let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("version")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(get_version)),
+            ),
+        )
+        .await;
 
-        let info_hash1 = "A1B2C3D4E5F6G7H8I9J0".to_string();
-        let torrent1 = Torrent::new(info_hash1, 10, 34, 7, 10000000);
-
-        let info_hash2 = "B2C3D4E5F6G7H8I9J0K1".to_string();
-        let torrent2 = Torrent::new(info_hash2, 25, 57, 19, 20000000);
+        let req = test::TestRequest::with_uri("/version").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
 
-        {
-            let mut store = stores.torrent_store.torrents.write().await;
-            store.insert(torrent1.info_hash.clone(), torrent1);
-            store.insert(torrent2.info_hash.clone(), torrent2);
-        }
+        assert!(body.contains(&format!("\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))));
+        assert!(body.contains("\"backend\":\"memory\""));
+    }
 
+    #[actix_rt::test]
+    async fn probes_without_backend() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
         let mut app = test::init_service(
             App::new()
-                .service(
-                    web::scope("announce")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_announce)),
-                )
-                .service(
-                    web::scope("scrape")
-                        .app_data(stores.clone())
-                        .route("", web::get().to(parse_scrape)),
-                )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .app_data(stores.clone())
+                .service(web::scope("healthz").route("", web::get().to(get_health)))
+                .service(web::scope("readyz").route("", web::get().to(get_ready))),
         )
         .await;
 
-        let uri = "/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0\
-                   &info_hash=B2C3D4E5F6G7H8I9J0K1";
-
-        let proper_resp = "d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei10e10:downloadedi34e10:incompletei7ee20:B2C3D4E5F6G7H8I9J0K1d8:completei25e10:downloadedi57e10:incompletei19eeee".as_bytes();
-        let req = test::TestRequest::with_uri(uri).to_request();
-        let resp = test::read_response(&mut app, req).await;
-
-        assert_eq!(resp, proper_resp);
+        // Without a persistent backend there's nothing that can be unreachable
+        for uri in &["/healthz", "/readyz"] {
+            let req = test::TestRequest::with_uri(uri).to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert!(resp.status().is_success());
+        }
     }
 }
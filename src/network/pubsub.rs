@@ -0,0 +1,244 @@
+// Swarm sharing over Redis: trackers using the Redis backend publish the
+// changes their own announces make to the swarms on a channel every
+// second, and apply whatever the others publish there, so that several
+// of them behind a load balancer soon agree on every swarm. Changes are
+// applied the same way gossiped ones are. Peers that leave are removed
+// from the saved peers as well, rather than left there to expire.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ::redis::{Client, Connection, RedisResult};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::delay_for;
+
+use crate::errors::InternalError;
+use crate::network::gossip::{apply, PeerDelta};
+use crate::state::State;
+use crate::storage;
+
+const CHANNEL: &str = "tyto:swarms";
+
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Keeps messages to around 80 KB
+const DELTAS_PER_MESSAGE: usize = 1000;
+
+// How long to wait before subscribing again once the connection is lost
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+    // Picked at random on startup, so a tracker can tell
+    // when it has been sent its own messages
+    node: u64,
+    deltas: Vec<PeerDelta>,
+}
+
+#[derive(Clone)]
+pub struct SwarmChannel {
+    state: State,
+    client: Client,
+    // Publishing has a connection of its own, as subscribing takes one
+    // over. It's made when first needed, and again after failing.
+    conn: Arc<Mutex<Option<Connection>>>,
+    node: u64,
+}
+
+impl SwarmChannel {
+    pub fn new(state: State, path: &str) -> RedisResult<SwarmChannel> {
+        Ok(SwarmChannel {
+            state,
+            client: Client::open(path)?,
+            conn: Arc::new(Mutex::new(None)),
+            node: rand::random(),
+        })
+    }
+
+    pub async fn serve(self) {
+        actix_rt::spawn(self.clone().publish());
+
+        // Reading a subscription blocks, so it gets a thread of its own
+        let (send, mut received) = mpsc::unbounded_channel();
+        let client = self.client.clone();
+        thread::spawn(move || subscribe(client, send));
+
+        // Messages are handled one at a time, in the order they came in
+        while let Some(payload) = received.recv().await {
+            self.receive(&payload).await;
+        }
+    }
+
+    async fn publish(self) {
+        loop {
+            delay_for(PUBLISH_INTERVAL).await;
+
+            let deltas = self.state.swarm_events.take();
+            if deltas.is_empty() {
+                continue;
+            }
+
+            let departed: Vec<String> = deltas
+                .iter()
+                .filter(|delta| delta.role.is_none())
+                .map(|delta| {
+                    let addr = SocketAddr::new(delta.ip, delta.port);
+                    storage::redis::peer_key(&delta.info_hash, &delta.peer_id, addr)
+                })
+                .collect();
+            let messages = self.messages(deltas);
+
+            let (client, conn) = (self.client.clone(), self.conn.clone());
+            let published = actix_threadpool::run(move || -> RedisResult<()> {
+                let mut pipe = ::redis::pipe();
+                for message in messages {
+                    pipe.publish(CHANNEL, message).ignore();
+                }
+                if !departed.is_empty() {
+                    pipe.del(departed).ignore();
+                }
+
+                let mut conn = conn.lock().unwrap();
+                let mut connected = match conn.take() {
+                    Some(connected) => connected,
+                    None => client.get_connection()?,
+                };
+                pipe.query::<()>(&mut connected)?;
+                *conn = Some(connected);
+                Ok(())
+            })
+            .await;
+            if let Err(e) = published {
+                warn!("{} ({})", InternalError::SwarmPublish.text(), e);
+            }
+        }
+    }
+
+    fn messages(&self, deltas: Vec<PeerDelta>) -> Vec<Vec<u8>> {
+        deltas
+            .chunks(DELTAS_PER_MESSAGE)
+            .filter_map(|deltas| {
+                bincode::serialize(&Message {
+                    node: self.node,
+                    deltas: deltas.to_vec(),
+                })
+                .ok()
+            })
+            .collect()
+    }
+
+    // Subscribers are sent their own messages too, which are skipped
+    async fn receive(&self, payload: &[u8]) {
+        match bincode::deserialize::<Message>(payload) {
+            Ok(message) if message.node != self.node => {
+                for delta in message.deltas {
+                    apply(&self.state, delta).await;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Dropping malformed swarm message ({})", e),
+        }
+    }
+}
+
+// Runs until the tracker stops taking messages,
+// subscribing again whenever the connection is lost
+fn subscribe(client: Client, send: UnboundedSender<Vec<u8>>) {
+    loop {
+        match listen(&client, &send) {
+            Ok(()) => return,
+            Err(e) => warn!("{} ({})", InternalError::SwarmSubscribe.text(), e),
+        }
+        thread::sleep(RESUBSCRIBE_DELAY);
+    }
+}
+
+fn listen(client: &Client, send: &UnboundedSender<Vec<u8>>) -> RedisResult<()> {
+    let mut conn = client.get_connection()?;
+    let mut pubsub = conn.as_pubsub();
+    pubsub.subscribe(CHANNEL)?;
+
+    loop {
+        let payload: Vec<u8> = pubsub.get_message()?.get_payload()?;
+        if send.send(payload).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::{InfoHash, Peer, PeerId};
+    use crate::config::Config;
+    use crate::storage::{PeerRole, TorrentRecords, TorrentStore};
+
+    fn channel() -> SwarmChannel {
+        let mut config = Config::default();
+        config.storage.backend = "redis".to_string();
+        let state = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        SwarmChannel::new(state, "redis://127.0.0.1/").unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn swarm_messages_shared() {
+        let first = channel();
+        let second = channel();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = Peer::new(
+            PeerId(*b"-DE9824-143964258012"),
+            "192.0.2.1".parse().unwrap(),
+            6881,
+            0,
+            0,
+        );
+
+        first
+            .state
+            .swarm_events
+            .record(info_hash, peer.clone(), Some(PeerRole::Seeder));
+        let messages = first.messages(first.state.swarm_events.take());
+        for message in &messages {
+            first.receive(message).await;
+            second.receive(message).await;
+        }
+
+        // Nothing is applied twice on the tracker it came from
+        assert!(first
+            .state
+            .peer_store
+            .lookup(&info_hash, &peer)
+            .await
+            .is_none());
+        assert_eq!(
+            second
+                .state
+                .peer_store
+                .lookup(&info_hash, &peer)
+                .await
+                .unwrap()
+                .1,
+            PeerRole::Seeder
+        );
+        assert_eq!(second.state.stats.read().await.total_seeders, 1);
+
+        first
+            .state
+            .swarm_events
+            .record(info_hash, peer.clone(), None);
+        for message in &first.messages(first.state.swarm_events.take()) {
+            second.receive(message).await;
+        }
+        assert!(second
+            .state
+            .peer_store
+            .lookup(&info_hash, &peer)
+            .await
+            .is_none());
+        assert_eq!(second.state.stats.read().await.total_seeders, 0);
+    }
+}
@@ -0,0 +1,156 @@
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+
+// How often the janitor sweeps out buckets that have refilled, in secs
+pub const SWEEP_INTERVAL: u64 = 60;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+// A token bucket per client address (or passkey). Every request takes a
+// token, and tokens come back at a steady rate up to the burst size, so
+// clients can make a few requests in quick succession but not keep it up.
+// The rate and burst are passed in on every check so that reloads apply
+// at once.
+//
+// Shared limiters also count the tokens taken, so that they can be sent
+// to the rest of a cluster and taken from its members' buckets as well.
+// Clients then get one allowance across the cluster, rather than one for
+// every tracker behind the load balancer.
+#[derive(Debug, Clone)]
+pub struct RateLimiter<K = IpAddr> {
+    buckets: Arc<Mutex<HashMap<K, Bucket>>>,
+    taken: Option<Arc<Mutex<HashMap<K, u32>>>>,
+}
+
+impl<K: Hash + Eq> Default for RateLimiter<K> {
+    fn default() -> Self {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            taken: None,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new() -> RateLimiter<K> {
+        RateLimiter::default()
+    }
+
+    pub fn shared() -> RateLimiter<K> {
+        RateLimiter {
+            taken: Some(Arc::new(Mutex::new(HashMap::new()))),
+            ..RateLimiter::default()
+        }
+    }
+
+    // Returns how long the client has to wait if it has no tokens left
+    pub fn check(&self, key: K, rate: f64, burst: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = f64::from(burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.refill(now, rate, burst);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            if let Some(taken) = &self.taken {
+                *taken.lock().unwrap().entry(key).or_insert(0) += 1;
+            }
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    // The tokens taken since the last time, for the rest of the cluster
+    pub fn take_counts(&self) -> Vec<(K, u32)> {
+        match &self.taken {
+            Some(taken) => std::mem::take(&mut *taken.lock().unwrap())
+                .into_iter()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Takes the tokens another tracker took for the client. Buckets can
+    // go as far as a whole burst into debt, which the client has to wait
+    // out before it's let through again.
+    pub fn consume(&self, key: K, count: u32, rate: f64, burst: u32) {
+        let now = Instant::now();
+        let burst = f64::from(burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.refill(now, rate, burst);
+        bucket.tokens = (bucket.tokens - f64::from(count)).max(-burst);
+    }
+
+    // A full bucket is no different from a missing one, so they're
+    // dropped to keep clients that have gone quiet from piling up.
+    // Returns the number of buckets left.
+    pub fn sweep(&self, rate: f64, burst: u32) -> usize {
+        let now = Instant::now();
+        let burst = f64::from(burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| {
+            bucket.refill(now, rate, burst);
+            bucket.tokens < burst
+        });
+        buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_burst_then_refill() {
+        let limiter = RateLimiter::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.check(ip, 1.0, 2).is_ok());
+        assert!(limiter.check(ip, 1.0, 2).is_ok());
+        let wait = limiter.check(ip, 1.0, 2).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        // Every address has its own bucket
+        assert!(limiter.check(other, 1.0, 2).is_ok());
+
+        // and so does every passkey
+        let passkeys = RateLimiter::new();
+        assert!(passkeys.check("alice".to_string(), 1.0, 1).is_ok());
+        assert!(passkeys.check("alice".to_string(), 1.0, 1).is_err());
+        assert!(passkeys.check("bob".to_string(), 1.0, 1).is_ok());
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(limiter.check(ip, 100.0, 2).is_ok());
+
+        // Only buckets that haven't refilled are kept
+        assert!(limiter.check(other, 1.0, 2).is_ok());
+        assert_eq!(limiter.sweep(1.0, 2), 2);
+        assert_eq!(limiter.sweep(1_000_000.0, 2), 0);
+    }
+}
@@ -0,0 +1,389 @@
+// Primary/replica replication: the primary keeps a numbered log of the
+// changes its announces make to the swarms, and replicas poll its admin
+// route for whatever came after the last change they applied. A replica
+// that has fallen further behind than the log reaches, or that starts
+// following a different (or restarted) primary, is sent every peer instead.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+// Only the HTTP tracker follows a primary
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "actix")]
+use {crate::errors::InternalError, awc::ClientBuilder, std::time::Duration};
+
+use crate::bittorrent::{InfoHash, Peer};
+use crate::config::{Replication, ReplicationRole};
+use crate::network::gossip::{apply, PeerDelta};
+use crate::state::State;
+use crate::storage::PeerRole;
+
+// Replicas that are further behind than this catch up over several batches
+pub const MAX_BATCH_SIZE: usize = 10_000;
+
+// Full batches hold every peer, so they get plenty of time
+#[cfg(feature = "actix")]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Log {
+    // Picked at random when the log is created, so a replica
+    // can tell when its numbers are from another log
+    epoch: u64,
+    // The number of the oldest change still in the log
+    first: u64,
+    changes: VecDeque<(InfoHash, Peer, Option<PeerRole>)>,
+    capacity: usize,
+}
+
+#[derive(Clone)]
+pub struct ChangeLog {
+    log: Option<Arc<Mutex<Log>>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    pub epoch: u64,
+    // The number to ask for the changes after this batch from
+    pub next: u64,
+    // Set when the deltas hold every peer, rather than changes
+    pub full: bool,
+    pub deltas: Vec<PeerDelta>,
+}
+
+impl ChangeLog {
+    // A capacity of 0 means disabled
+    pub fn new(capacity: usize) -> ChangeLog {
+        if capacity == 0 {
+            return ChangeLog { log: None };
+        }
+
+        ChangeLog {
+            log: Some(Arc::new(Mutex::new(Log {
+                epoch: rand::random(),
+                first: 0,
+                changes: VecDeque::new(),
+                capacity,
+            }))),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.log.is_some()
+    }
+
+    pub fn record(&self, info_hash: InfoHash, peer: Peer, role: Option<PeerRole>) {
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap();
+            log.changes.push_back((info_hash, peer, role));
+            if log.changes.len() > log.capacity {
+                log.changes.pop_front();
+                log.first += 1;
+            }
+        }
+    }
+
+    // The changes numbered since and after, or None if any of them
+    // have already dropped out of the log or are from another log
+    fn since(&self, epoch: u64, since: u64) -> Option<ChangeBatch> {
+        let log = self.log.as_ref()?.lock().unwrap();
+        let end = log.first + log.changes.len() as u64;
+        if epoch != log.epoch || since < log.first || since > end {
+            return None;
+        }
+
+        let now = Instant::now();
+        let deltas: Vec<PeerDelta> = log
+            .changes
+            .iter()
+            .skip((since - log.first) as usize)
+            .take(MAX_BATCH_SIZE)
+            .map(|(info_hash, peer, role)| PeerDelta::new(*info_hash, peer, *role, now))
+            .collect();
+
+        Some(ChangeBatch {
+            epoch: log.epoch,
+            next: since + deltas.len() as u64,
+            full: false,
+            deltas,
+        })
+    }
+
+    // The log's epoch and the number the next change will get
+    fn position(&self) -> Option<(u64, u64)> {
+        let log = self.log.as_ref()?.lock().unwrap();
+        Some((log.epoch, log.first + log.changes.len() as u64))
+    }
+}
+
+// What a replica is sent when it asks for changes. Replicas asking for
+// the first time (with no position) are sent every peer. Returns None if
+// this tracker doesn't keep a change log.
+pub async fn changes(state: &State, position: Option<(u64, u64)>) -> Option<ChangeBatch> {
+    if let Some((epoch, since)) = position {
+        if let Some(batch) = state.change_log.since(epoch, since) {
+            return Some(batch);
+        }
+    }
+
+    // Changes made while the peers are dumped are sent again next time,
+    // which does no harm as only the newest announce of a peer is kept
+    let (epoch, next) = state.change_log.position()?;
+    let now = Instant::now();
+    let deltas = state
+        .peer_store
+        .dump()
+        .await
+        .into_iter()
+        .map(|(info_hash, peer, role)| PeerDelta::new(info_hash, &peer, Some(role), now))
+        .collect();
+
+    Some(ChangeBatch {
+        epoch,
+        next,
+        full: true,
+        deltas,
+    })
+}
+
+// Applies a batch from the primary. Every peer the replica has that
+// isn't in a full batch has left since, and is taken out.
+pub async fn apply_batch(state: &State, batch: ChangeBatch) {
+    let now = Instant::now();
+    if batch.full {
+        let kept: HashSet<(InfoHash, Peer)> = batch
+            .deltas
+            .iter()
+            .map(|delta| (delta.info_hash, delta.peer(now)))
+            .collect();
+
+        for (info_hash, peer, _) in state.peer_store.dump().await {
+            if !kept.contains(&(info_hash, peer.clone())) {
+                let departed = PeerDelta {
+                    age: 0,
+                    ..PeerDelta::new(info_hash, &peer, None, now)
+                };
+                apply(state, departed).await;
+            }
+        }
+    }
+
+    for delta in batch.deltas {
+        apply(state, delta).await;
+    }
+}
+
+// The primary a replica follows, until it's promoted
+#[derive(Clone)]
+pub struct PrimaryLink {
+    primary: Arc<RwLock<Option<String>>>,
+}
+
+impl PrimaryLink {
+    pub fn new(config: &Replication) -> PrimaryLink {
+        let primary = if config.role == ReplicationRole::Replica {
+            Some(config.primary.trim_end_matches('/').to_string())
+        } else {
+            None
+        };
+
+        PrimaryLink {
+            primary: Arc::new(RwLock::new(primary)),
+        }
+    }
+
+    pub fn primary(&self) -> Option<String> {
+        self.primary.read().unwrap().clone()
+    }
+
+    // Returns whether this tracker was a replica before
+    pub fn promote(&self) -> bool {
+        self.primary.write().unwrap().take().is_some()
+    }
+}
+
+// Keeps a replica's swarms in step with its primary's
+#[cfg(feature = "actix")]
+pub struct Replica {
+    state: State,
+    token: String,
+    poll_interval: Duration,
+}
+
+#[cfg(feature = "actix")]
+impl Replica {
+    pub fn new(state: State, config: &Replication) -> Replica {
+        Replica {
+            state,
+            token: config.token.clone(),
+            poll_interval: Duration::from_secs(config.poll_interval),
+        }
+    }
+
+    async fn fetch(&self, primary: &str, position: Option<(u64, u64)>) -> Option<ChangeBatch> {
+        let url = match position {
+            Some((epoch, since)) => {
+                format!("{}/admin/changes?epoch={}&since={}", primary, epoch, since)
+            }
+            None => format!("{}/admin/changes", primary),
+        };
+
+        // Full batches can be large, so the body limit is lifted
+        let client = ClientBuilder::new()
+            .timeout(FETCH_TIMEOUT)
+            .bearer_auth(&self.token)
+            .finish();
+        let mut response = client.get(url).send().await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let body = response.body().limit(usize::MAX).await.ok()?;
+        bincode::deserialize(&body).ok()
+    }
+
+    // Batches are asked for back to back while the replica is catching
+    // up, and every poll_interval once it has. Stops once it's promoted.
+    pub async fn follow(self) {
+        let mut position = None;
+        while let Some(primary) = self.state.primary.primary() {
+            let caught_up = match self.fetch(&primary, position).await {
+                Some(batch) => {
+                    let caught_up = batch.deltas.len() < MAX_BATCH_SIZE;
+                    position = Some((batch.epoch, batch.next));
+                    apply_batch(&self.state, batch).await;
+                    caught_up
+                }
+                None => {
+                    warn!("{} ({})", InternalError::ReplicationFetch.text(), &primary);
+                    true
+                }
+            };
+
+            if caught_up {
+                tokio::time::delay_for(self.poll_interval).await;
+            }
+        }
+
+        info!("Promoted to primary, no longer following changes");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::{AnnounceRequest, PeerFormat, PeerId};
+    use crate::config::Config;
+    use crate::network::handle_announce;
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    const INFO_HASH: InfoHash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+
+    fn tracker(role: ReplicationRole, log_size: usize) -> State {
+        let mut config = Config::default();
+        config.replication.role = role;
+        config.replication.primary = "http://primary.example".to_string();
+        config.replication.log_size = log_size;
+
+        let mut records = TorrentRecords::new();
+        records.insert(INFO_HASH, Torrent::new(INFO_HASH, 0, 0, 0, 0));
+        State::new(config, TorrentStore::new(records))
+    }
+
+    async fn announce(state: &State, peer_id: &str, left: u64, event: &str) {
+        let query = format!(
+            "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id={}&port=6881&uploaded=0&downloaded=0&left={}&event={}",
+            peer_id, left, event
+        );
+        let request = AnnounceRequest::new(&query, Some("8.8.4.4:51413")).unwrap();
+        let format = PeerFormat::of(&request);
+        handle_announce(state, request, format).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn replica_follows_change_log() {
+        let primary = tracker(ReplicationRole::Primary, 2);
+        let replica = tracker(ReplicationRole::Replica, 2);
+        assert_eq!(
+            replica.primary.primary().as_deref(),
+            Some("http://primary.example")
+        );
+
+        // A replica starts out with every peer
+        announce(&primary, "-DE9824-143964258012", 1000, "started").await;
+        let batch = changes(&primary, None).await.unwrap();
+        assert!(batch.full);
+        let mut position = Some((batch.epoch, batch.next));
+        apply_batch(&replica, batch).await;
+        assert_eq!(replica.peer_store.swarm_size(&INFO_HASH).await, (0, 1));
+
+        // and then only gets the changes since
+        announce(&primary, "-qB4250-143964258012", 1000, "started").await;
+        announce(&primary, "-qB4250-143964258012", 0, "completed").await;
+        let batch = changes(&primary, position).await.unwrap();
+        assert!(!batch.full);
+        assert_eq!(batch.deltas.len(), 2);
+        position = Some((batch.epoch, batch.next));
+        apply_batch(&replica, batch).await;
+        assert_eq!(replica.peer_store.swarm_size(&INFO_HASH).await, (1, 1));
+        assert_eq!(
+            replica.torrent_store.get_announce_stats(INFO_HASH).await,
+            primary.torrent_store.get_announce_stats(INFO_HASH).await
+        );
+
+        // Falling further behind than the log reaches means starting over
+        announce(&primary, "-DE9824-143964258012", 1000, "stopped").await;
+        announce(&primary, "-TR2940-143964258012", 1000, "started").await;
+        announce(&primary, "-UT3530-143964258012", 1000, "started").await;
+        let batch = changes(&primary, position).await.unwrap();
+        assert!(batch.full);
+        apply_batch(&replica, batch).await;
+        assert_eq!(replica.peer_store.swarm_size(&INFO_HASH).await, (1, 2));
+        let stats = replica.stats.read().await;
+        assert_eq!((stats.total_seeders, stats.total_leechers), (1, 2));
+        drop(stats);
+
+        // Once promoted, a replica stops following and keeps a log of its own
+        assert!(replica.primary.promote());
+        assert!(!replica.primary.promote());
+        assert!(replica.primary.primary().is_none());
+        announce(&replica, "-DE9824-143964258012", 1000, "started").await;
+        assert_eq!(changes(&replica, None).await.unwrap().deltas.len(), 4);
+        assert!(changes(&tracker(ReplicationRole::Standalone, 2), None)
+            .await
+            .is_none());
+    }
+
+    #[actix_rt::test]
+    async fn replica_counts_seeders_restarting() {
+        let replica = tracker(ReplicationRole::Replica, 2);
+        let peer = Peer::new(
+            PeerId(*b"-DE9824-143964258012"),
+            "8.8.4.4".parse().unwrap(),
+            6881,
+            0,
+            0,
+        );
+        let batch = |role, age| ChangeBatch {
+            epoch: 0,
+            next: 0,
+            full: false,
+            deltas: vec![PeerDelta {
+                age,
+                ..PeerDelta::new(INFO_HASH, &peer, Some(role), Instant::now())
+            }],
+        };
+
+        // A seeder that starts over is a leecher again, as an announce would have it
+        apply_batch(&replica, batch(PeerRole::Seeder, 2_000)).await;
+        apply_batch(&replica, batch(PeerRole::Leecher, 1_000)).await;
+        assert_eq!(replica.peer_store.swarm_size(&INFO_HASH).await, (0, 1));
+        assert_eq!(
+            replica.torrent_store.get_announce_stats(INFO_HASH).await,
+            (1, 0, 1)
+        );
+        let stats = replica.stats.read().await;
+        assert_eq!((stats.total_seeders, stats.total_leechers), (0, 1));
+    }
+}
@@ -0,0 +1,214 @@
+// Sharding: each tracker in the ring owns the info hashes that land in its
+// part of a consistent hash ring, and only keeps swarms for those. Adding
+// or removing a tracker only moves the torrents in the parts it takes
+// over or gives up. Requests for torrents owned by another tracker are
+// redirected or proxied there.
+
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use crate::bittorrent::InfoHash;
+use crate::config::Sharding;
+
+// Requests are only redirected or proxied by the HTTP tracker
+#[cfg(feature = "actix")]
+use {
+    crate::config::ShardForward,
+    crate::errors::ClientError,
+    crate::network::upstream::forward_url,
+    actix_web::{http::header, HttpRequest, HttpResponse},
+    awc::ClientBuilder,
+    std::net::IpAddr,
+    std::time::Duration,
+};
+
+// Each tracker takes this many points on the ring, which
+// keeps the share of torrents each one owns close to even
+const POINTS_PER_NODE: u32 = 160;
+
+// Set on proxied requests, which are always handled where they arrive so
+// that trackers configured with different rings can't pass them around
+pub const FORWARDED_HEADER: &str = "x-tyto-forwarded";
+
+#[derive(Clone)]
+pub struct HashRing {
+    // Sorted by position, with the index of the node each belongs to
+    points: Arc<Vec<(u64, usize)>>,
+    nodes: Arc<Vec<String>>,
+    this_node: usize,
+}
+
+impl HashRing {
+    // Returns None when sharding is disabled. The points are derived from
+    // the nodes' URLs with DefaultHasher::new(), which always uses the same
+    // keys, so every tracker built from the same release draws the same ring.
+    pub fn new(config: &Sharding) -> Option<HashRing> {
+        let this_node = config
+            .nodes
+            .iter()
+            .position(|node| *node == config.this_node)?;
+        let nodes: Vec<String> = config
+            .nodes
+            .iter()
+            .map(|node| node.trim_end_matches('/').to_string())
+            .collect();
+
+        let mut points = Vec::with_capacity(nodes.len() * POINTS_PER_NODE as usize);
+        for (index, node) in nodes.iter().enumerate() {
+            for point in 0..POINTS_PER_NODE {
+                let mut hasher = DefaultHasher::new();
+                node.hash(&mut hasher);
+                point.hash(&mut hasher);
+                points.push((hasher.finish(), index));
+            }
+        }
+        points.sort_unstable();
+
+        Some(HashRing {
+            points: Arc::new(points),
+            nodes: Arc::new(nodes),
+            this_node,
+        })
+    }
+
+    // Info hashes are already uniformly distributed, so their first bytes
+    // place them on the ring. They belong to the next point at or after that.
+    fn node_for(&self, info_hash: &InfoHash) -> usize {
+        let position = u64::from_be_bytes(info_hash.0[..8].try_into().unwrap());
+        let next = match self
+            .points
+            .binary_search_by(|(point, _)| point.cmp(&position))
+        {
+            Ok(next) | Err(next) => next,
+        };
+        self.points[next % self.points.len()].1
+    }
+
+    // The base URL of the tracker owning the torrent, or None if it's this one
+    pub fn owner(&self, info_hash: &InfoHash) -> Option<&str> {
+        let node = self.node_for(info_hash);
+        if node == self.this_node {
+            None
+        } else {
+            Some(&self.nodes[node])
+        }
+    }
+
+    // The tracker owning all of the torrents, if it's another one. Requests
+    // that span several trackers are handled where they arrive.
+    pub fn sole_owner(&self, info_hashes: &[InfoHash]) -> Option<&str> {
+        let (first, rest) = info_hashes.split_first()?;
+        let node = self.node_for(first);
+        if node == self.this_node || rest.iter().any(|h| self.node_for(h) != node) {
+            return None;
+        }
+        Some(&self.nodes[node])
+    }
+}
+
+// Sends the request on to the owning tracker at the same path
+#[cfg(feature = "actix")]
+pub async fn forward(
+    config: &Sharding,
+    owner: &str,
+    req: &HttpRequest,
+    client_ip: Option<IpAddr>,
+) -> Result<HttpResponse, ClientError> {
+    let base = format!("{}{}", owner, req.path());
+    if config.forward == ShardForward::Redirect {
+        let url = format!("{}?{}", base, req.query_string());
+        return Ok(HttpResponse::TemporaryRedirect()
+            .header(header::LOCATION, url)
+            .finish());
+    }
+
+    // Proxied announces carry the client's address as the ip parameter,
+    // so the owner has to trust this tracker with it (see ip_param.trusted)
+    let url = match client_ip {
+        Some(ip) => forward_url(&base, req.query_string(), ip),
+        None => format!("{}?{}", base, req.query_string()),
+    };
+
+    let client = ClientBuilder::new()
+        .timeout(Duration::new(config.timeout, 0))
+        .finish();
+    let mut response = client
+        .get(url)
+        .header(FORWARDED_HEADER, "1")
+        .send()
+        .await
+        .map_err(|_| ClientError::OwnerUnreachable)?;
+    let body = response
+        .body()
+        .await
+        .map_err(|_| ClientError::OwnerUnreachable)?;
+
+    Ok(HttpResponse::Ok().content_type("text/plain").body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(nodes: &[&str], this_node: &str) -> HashRing {
+        let config = Sharding {
+            nodes: nodes.iter().map(|n| n.to_string()).collect(),
+            this_node: this_node.to_string(),
+            ..Sharding::default()
+        };
+        HashRing::new(&config).unwrap()
+    }
+
+    fn info_hash(i: u32) -> InfoHash {
+        let mut hasher = DefaultHasher::new();
+        i.hash(&mut hasher);
+        let mut bytes = [0u8; 20];
+        bytes[..8].copy_from_slice(&hasher.finish().to_be_bytes());
+        InfoHash(bytes)
+    }
+
+    #[test]
+    fn hash_ring_ownership() {
+        let nodes = ["http://a.example", "http://b.example", "http://c.example/"];
+        let rings: Vec<HashRing> = nodes.iter().map(|node| ring(&nodes, node)).collect();
+        assert!(HashRing::new(&Sharding::default()).is_none());
+
+        // Every torrent has exactly one owner, and the others agree on it
+        let mut owned = [0; 3];
+        for i in 0..3000 {
+            let owners: Vec<usize> = (0..3)
+                .filter(|&n| rings[n].owner(&info_hash(i)).is_none())
+                .collect();
+            assert_eq!(owners.len(), 1);
+            owned[owners[0]] += 1;
+
+            let url = nodes[owners[0]].trim_end_matches('/');
+            for (n, ring) in rings.iter().enumerate() {
+                if n != owners[0] {
+                    assert_eq!(ring.owner(&info_hash(i)), Some(url));
+                }
+            }
+        }
+        assert!(owned.iter().all(|&count| count > 700), "{:?}", owned);
+
+        // Without c, only c's torrents move
+        let smaller = ring(&nodes[..2], nodes[0]);
+        for i in 0..3000 {
+            let node = rings[0].node_for(&info_hash(i));
+            if node != 2 {
+                assert_eq!(smaller.node_for(&info_hash(i)), node);
+            }
+        }
+
+        let b_owned: Vec<InfoHash> = (0..3000)
+            .map(info_hash)
+            .filter(|h| rings[0].owner(h) == Some("http://b.example"))
+            .take(3)
+            .collect();
+        assert_eq!(rings[0].sole_owner(&b_owned), Some("http://b.example"));
+        assert_eq!(rings[1].sole_owner(&b_owned), None);
+        assert_eq!(rings[0].sole_owner(&[]), None);
+    }
+}
@@ -0,0 +1,463 @@
+// BEP 15: UDP Tracker Protocol. Announces and scrapes go through the same
+// handling as their HTTP counterparts, so UDP and HTTP clients share one
+// set of swarms; only the wire format differs.
+
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use bytes::BufMut;
+use hashbrown::HashMap;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+use crate::bittorrent::{AnnounceRequest, InfoHash, Peer, PeerFormat, PeerId};
+use crate::errors::{ClientError, InternalError};
+use crate::network::{handle_announce, handle_scrape, resolve_claimed_ip};
+use crate::state::State;
+use crate::util::{unix_secs, Event};
+
+// Magic constant that every connect request starts with
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// Connection IDs are issued per window and accepted for the current
+// and previous one, so they stay valid for one to two minutes
+const CONNECTION_ID_WINDOW: u64 = 60;
+
+// Large enough for a full scrape plus any BEP 41 extensions
+const RECEIVE_BUFFER_SIZE: usize = 2048;
+
+// Announces have a fixed layout, so anything shorter is malformed
+const ANNOUNCE_REQUEST_SIZE: usize = 98;
+
+// A scrape response for this many torrents still fits in a single datagram
+const MAX_SCRAPE_TORRENTS: usize = 74;
+
+// Connection IDs aren't stored anywhere; they are derived from the client's
+// address and the current time window using a key that only this process
+// knows, and checked by deriving them again.
+#[derive(Clone)]
+pub struct ConnectionIds {
+    keys: RandomState,
+}
+
+impl Default for ConnectionIds {
+    fn default() -> Self {
+        ConnectionIds::new()
+    }
+}
+
+impl ConnectionIds {
+    pub fn new() -> ConnectionIds {
+        ConnectionIds {
+            keys: RandomState::new(),
+        }
+    }
+
+    fn derive(&self, addr: &SocketAddr, window: u64) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        addr.hash(&mut hasher);
+        window.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn issue(&self, addr: &SocketAddr, now: u64) -> u64 {
+        self.derive(addr, now / CONNECTION_ID_WINDOW)
+    }
+
+    pub fn is_valid(&self, id: u64, addr: &SocketAddr, now: u64) -> bool {
+        let window = now / CONNECTION_ID_WINDOW;
+        id == self.derive(addr, window) || id == self.derive(addr, window.saturating_sub(1))
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(packet[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(packet[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(packet: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(packet[offset..offset + 8].try_into().unwrap())
+}
+
+// Sockets bound to a v6 wildcard see v4 clients as mapped addresses
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => match v6.to_ipv4() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        _ => ip,
+    }
+}
+
+fn error_response(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut response = Vec::with_capacity(8 + message.len());
+    response.put_u32(ACTION_ERROR);
+    response.put_u32(transaction_id);
+    response.put_slice(message.as_bytes());
+    response
+}
+
+fn parse_announce(packet: &[u8], src: SocketAddr) -> Result<AnnounceRequest, ClientError> {
+    if packet.len() < ANNOUNCE_REQUEST_SIZE {
+        return Err(ClientError::MalformedAnnounce);
+    }
+
+    let info_hash = InfoHash::from_bytes(&packet[16..36]).ok_or(ClientError::MalformedAnnounce)?;
+    let peer_id = PeerId::from_bytes(&packet[36..56]).ok_or(ClientError::MalformedAnnounce)?;
+    let downloaded = read_u64(packet, 56);
+    let left = read_u64(packet, 64);
+    let uploaded = read_u64(packet, 72);
+    let event = match read_u32(packet, 80) {
+        0 => Event::None,
+        1 => Event::Completed,
+        2 => Event::Started,
+        3 => Event::Stopped,
+        _ => return Err(ClientError::MalformedAnnounce),
+    };
+    let key = read_u32(packet, 88);
+    let port = read_u16(packet, 96);
+
+    // Like the ip parameter over HTTP, a client may name its own address
+    let ip = match read_u32(packet, 84) {
+        0 => canonical_ip(src.ip()),
+        addr => IpAddr::V4(Ipv4Addr::from(addr)),
+    };
+
+    // A negative numwant asks for the default
+    let numwant = match read_u32(packet, 92) as i32 {
+        n if n < 0 => None,
+        n => Some(n as u32),
+    };
+
+    Ok(AnnounceRequest {
+        info_hash,
+        peer: Peer::new(peer_id, ip, port, uploaded, downloaded),
+        port,
+        uploaded,
+        downloaded,
+        left,
+        compact: true,
+        no_peer_id: true,
+        event,
+        ip: Some(ip),
+        numwant,
+        key: Some(format!("{:08x}", key)),
+        trackerid: None,
+        passkey: None,
+    })
+}
+
+#[derive(Clone)]
+pub struct UdpTracker {
+    state: State,
+    connection_ids: ConnectionIds,
+}
+
+impl UdpTracker {
+    pub fn new(state: State) -> UdpTracker {
+        UdpTracker {
+            connection_ids: ConnectionIds::new(),
+            state,
+        }
+    }
+
+    pub async fn serve(self, socket: UdpSocket) {
+        let (mut recv, send) = socket.split();
+        let send = Arc::new(Mutex::new(send));
+        let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+
+        loop {
+            let (len, src) = match recv.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("{} ({})", InternalError::UdpSocket.text(), e);
+                    continue;
+                }
+            };
+
+            // Each request is handled in its own task so that one waiting
+            // on the stores doesn't hold up everything behind it
+            let packet = buf[..len].to_vec();
+            let tracker = self.clone();
+            let send = send.clone();
+            actix_rt::spawn(async move {
+                if let Some(response) = tracker.handle_packet(&packet, src).await {
+                    if let Err(e) = send.lock().await.send_to(&response, &src).await {
+                        warn!("{} ({})", InternalError::UdpSocket.text(), e);
+                    }
+                }
+            });
+        }
+    }
+
+    // Returns the datagram to send back, if any. Packets too short to
+    // carry a transaction ID can't be answered and are dropped.
+    pub async fn handle_packet(&self, packet: &[u8], src: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 || !self.state.ip_filter.permits(src.ip()) {
+            return None;
+        }
+
+        let connection_id = read_u64(packet, 0);
+        let action = read_u32(packet, 8);
+        let transaction_id = read_u32(packet, 12);
+        let now = unix_secs(SystemTime::now());
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+
+            let mut response = Vec::with_capacity(16);
+            response.put_u32(ACTION_CONNECT);
+            response.put_u32(transaction_id);
+            response.put_u64(self.connection_ids.issue(&src, now));
+            return Some(response);
+        }
+
+        if !self.connection_ids.is_valid(connection_id, &src, now) {
+            return Some(error_response(
+                transaction_id,
+                &ClientError::InvalidConnectionId.text(),
+            ));
+        }
+
+        let response = match action {
+            ACTION_ANNOUNCE => {
+                let started = Instant::now();
+                let response = self.announce(packet, src, transaction_id).await;
+                let mut stats = self.state.stats.write().await;
+                stats.announce_latency.record(started.elapsed());
+                response
+            }
+            ACTION_SCRAPE => self.scrape(packet, transaction_id).await,
+            _ => error_response(transaction_id, "Unknown action"),
+        };
+        Some(response)
+    }
+
+    async fn announce(&self, packet: &[u8], src: SocketAddr, transaction_id: u32) -> Vec<u8> {
+        // There's no redirecting UDP clients to the primary
+        if self.state.primary.primary().is_some() {
+            return error_response(transaction_id, &ClientError::NotPrimary.text());
+        }
+
+        let mut parsed_req = match parse_announce(packet, src) {
+            Ok(parsed_req) => parsed_req,
+            Err(e) => {
+                self.state.stats.write().await.fail_announce();
+                return error_response(transaction_id, &e.text());
+            }
+        };
+
+        resolve_claimed_ip(&self.state, &mut parsed_req, canonical_ip(src.ip()));
+
+        // Client and torrent approval are applied here the same way
+        // the HTTP middleware applies them to announces
+        let rejection = self.state.client_approval.check(&parsed_req.peer.peer_id());
+        if let Some(failure) = rejection {
+            return error_response(transaction_id, &failure.failure_reason.unwrap_or_default());
+        }
+
+        if !self.state.torrent_approval.approves(&parsed_req.info_hash) {
+            return error_response(transaction_id, &ClientError::UnapprovedTorrent.text());
+        }
+
+        // Clients only get peers of the address family they announced over
+        let v6 = parsed_req.peer.ip().is_ipv6();
+        let format = PeerFormat::Datagram { v6 };
+        match handle_announce(&self.state, parsed_req, format).await {
+            Ok((response, _)) => {
+                let peers = match &response.encoded_peers {
+                    Some(encoded) => Cow::Borrowed(&encoded[..]),
+                    None if v6 => Cow::Owned(response.peersv6_as_compact()),
+                    None => Cow::Owned(response.peersv4_as_compact()),
+                };
+
+                let mut datagram = Vec::with_capacity(20 + peers.len());
+                datagram.put_u32(ACTION_ANNOUNCE);
+                datagram.put_u32(transaction_id);
+                datagram.put_u32(response.interval);
+                datagram.put_u32(response.incomplete);
+                datagram.put_u32(response.complete);
+                datagram.put_slice(&peers);
+                datagram
+            }
+            Err(failure) => {
+                error_response(transaction_id, &failure.failure_reason.unwrap_or_default())
+            }
+        }
+    }
+
+    async fn scrape(&self, packet: &[u8], transaction_id: u32) -> Vec<u8> {
+        // There's nowhere in the packet for a passkey
+        if self.state.config().private.enabled {
+            return error_response(transaction_id, &ClientError::MissingPasskey.text());
+        }
+
+        let hashes: Vec<&[u8]> = packet[16..].chunks(20).collect();
+        if hashes.is_empty()
+            || hashes.len() > MAX_SCRAPE_TORRENTS
+            || hashes.iter().any(|h| h.len() != 20)
+        {
+            return error_response(transaction_id, &ClientError::MalformedScrape.text());
+        }
+
+        // Every requested torrent gets an entry in the response, in order
+        let info_hashes: Vec<Vec<u8>> = hashes.iter().map(|h| h.to_vec()).collect();
+        let files: HashMap<Vec<u8>, (u32, u32, u32)> =
+            handle_scrape(&self.state, info_hashes.clone())
+                .await
+                .into_iter()
+                .map(|f| (f.info_hash, (f.complete, f.downloaded, f.incomplete)))
+                .collect();
+
+        let mut datagram = Vec::with_capacity(8 + 12 * info_hashes.len());
+        datagram.put_u32(ACTION_SCRAPE);
+        datagram.put_u32(transaction_id);
+        for info_hash in &info_hashes {
+            let (complete, downloaded, incomplete) =
+                files.get(info_hash).copied().unwrap_or((0, 0, 0));
+            datagram.put_u32(complete);
+            datagram.put_u32(downloaded);
+            datagram.put_u32(incomplete);
+        }
+        datagram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    const INFO_HASH: InfoHash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+
+    fn tracker() -> UdpTracker {
+        let mut records = TorrentRecords::new();
+        records.insert(INFO_HASH, Torrent::new(INFO_HASH, 3, 10, 1, 0));
+        let state = State::new(Config::default(), TorrentStore::new(records));
+        UdpTracker::new(state)
+    }
+
+    async fn connect(tracker: &UdpTracker, src: SocketAddr) -> u64 {
+        let mut packet = Vec::new();
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(7);
+
+        let response = tracker.handle_packet(&packet, src).await.unwrap();
+        assert_eq!(response.len(), 16);
+        assert_eq!(read_u32(&response, 0), ACTION_CONNECT);
+        assert_eq!(read_u32(&response, 4), 7);
+        read_u64(&response, 8)
+    }
+
+    fn announce_packet(connection_id: u64, peer_id: &[u8], event: u32, port: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_ANNOUNCE);
+        packet.put_u32(9);
+        packet.put_slice(&INFO_HASH.0);
+        packet.put_slice(peer_id);
+        packet.put_u64(0);
+        packet.put_u64(1000);
+        packet.put_u64(0);
+        packet.put_u32(event);
+        packet.put_u32(0);
+        packet.put_u32(0);
+        packet.put_i32(-1);
+        packet.put_u16(port);
+        packet
+    }
+
+    #[actix_rt::test]
+    async fn udp_connection_id_validation() {
+        let ids = ConnectionIds::new();
+        let addr: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:6881".parse().unwrap();
+
+        let id = ids.issue(&addr, 1000);
+        assert!(ids.is_valid(id, &addr, 1000));
+        assert!(ids.is_valid(id, &addr, 1000 + CONNECTION_ID_WINDOW));
+        assert!(!ids.is_valid(id, &addr, 1000 + 2 * CONNECTION_ID_WINDOW));
+        assert!(!ids.is_valid(id, &other, 1000));
+
+        // Requests with a made-up connection ID get an error back
+        let tracker = tracker();
+        let response = tracker
+            .handle_packet(&announce_packet(42, b"-DE9824-143964258012", 2, 6881), addr)
+            .await
+            .unwrap();
+        assert_eq!(read_u32(&response, 0), ACTION_ERROR);
+        assert_eq!(
+            &response[8..],
+            ClientError::InvalidConnectionId.text().as_bytes()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn udp_announce_shares_swarm() {
+        let tracker = tracker();
+        let first: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let second: SocketAddr = "192.0.2.2:6882".parse().unwrap();
+
+        let id = connect(&tracker, first).await;
+        let packet = announce_packet(id, b"-DE9824-143964258012", 2, 6881);
+        let response = tracker.handle_packet(&packet, first).await.unwrap();
+        assert_eq!(read_u32(&response, 0), ACTION_ANNOUNCE);
+        assert_eq!(read_u32(&response, 4), 9);
+
+        let id = connect(&tracker, second).await;
+        let packet = announce_packet(id, b"-qB4250-143964258012", 2, 6882);
+        let response = tracker.handle_packet(&packet, second).await.unwrap();
+
+        // The first peer is handed out to the second in compact form
+        assert_eq!(read_u32(&response, 8), 1800);
+        assert_eq!(response.len(), 20 + 2 * 6);
+        assert!(response[20..]
+            .chunks(6)
+            .any(|peer| peer == [192, 0, 2, 1, 0x1a, 0xe1]));
+
+        let peers = tracker.state.peer_store.get_peers(INFO_HASH, 50).await;
+        assert_eq!(peers.0.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn udp_scrape() {
+        let tracker = tracker();
+        let src: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let id = connect(&tracker, src).await;
+
+        let mut packet = Vec::new();
+        packet.put_u64(id);
+        packet.put_u32(ACTION_SCRAPE);
+        packet.put_u32(11);
+        packet.put_slice(&INFO_HASH.0);
+        packet.put_slice(&[0xff; 20]);
+
+        let response = tracker.handle_packet(&packet, src).await.unwrap();
+        assert_eq!(read_u32(&response, 0), ACTION_SCRAPE);
+        assert_eq!(read_u32(&response, 4), 11);
+        assert_eq!(response.len(), 8 + 2 * 12);
+        assert_eq!(read_u32(&response, 8), 3);
+        assert_eq!(read_u32(&response, 12), 10);
+        assert_eq!(read_u32(&response, 16), 1);
+        assert_eq!(&response[20..], &[0; 12][..]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::net::IpAddr;
+
+use hashbrown::HashMap;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+
+use crate::bittorrent::{InfoHash, UpstreamCounts};
+
+// Upstream trackers are only reached over awc
+#[cfg(feature = "actix")]
+use {
+    crate::bencode,
+    crate::bittorrent::{CompactPeerv4, CompactPeerv6},
+    crate::config::{Retracker, Upstream},
+    crate::errors::InternalError,
+    awc::ClientBuilder,
+    std::time::Duration,
+};
+
+pub type UpstreamRecords = HashMap<InfoHash, UpstreamCounts>;
+
+// Most trackers cap how many info hashes a single scrape may ask for
+#[cfg(feature = "actix")]
+const SCRAPE_BATCH_SIZE: usize = 64;
+
+// Upstream URLs may already carry a query string (e.g. a passkey)
+pub fn scrape_url(base: &str, info_hashes: &[InfoHash]) -> String {
+    let mut url = base.to_string();
+    let mut separator = if base.contains('?') { '&' } else { '?' };
+
+    for info_hash in info_hashes {
+        url.push(separator);
+        url.push_str("info_hash=");
+        url.push_str(&percent_encode(&info_hash.0, NON_ALPHANUMERIC).to_string());
+        separator = '&';
+    }
+
+    url
+}
+
+// Scrapes every configured upstream tracker for the given torrents and
+// sums up what they report. Trackers that fail to answer are skipped.
+#[cfg(feature = "actix")]
+pub async fn scrape_all(config: &Upstream, info_hashes: Vec<InfoHash>) -> UpstreamRecords {
+    let mut records = UpstreamRecords::new();
+    let client = ClientBuilder::new()
+        .timeout(Duration::new(config.timeout, 0))
+        .finish();
+
+    for tracker in &config.trackers {
+        for batch in info_hashes.chunks(SCRAPE_BATCH_SIZE) {
+            let body = match client.get(scrape_url(tracker, batch)).send().await {
+                Ok(mut response) => response.body().await.ok(),
+                Err(_) => None,
+            };
+
+            match body.and_then(|b| bencode::decode_scrape_counts(&b).ok()) {
+                Some(files) => {
+                    for (info_hash, counts) in files {
+                        let entry = records.entry(info_hash).or_default();
+                        entry.complete += counts.complete;
+                        entry.incomplete += counts.incomplete;
+                    }
+                }
+                None => {
+                    warn!("{} ({})", InternalError::UpstreamScrape.text(), tracker);
+                    break;
+                }
+            }
+        }
+    }
+
+    records
+}
+
+// The client's own query is passed along as-is, with its address added
+// so that the origin tracker hands it out rather than our own address
+pub fn forward_url(base: &str, query: &str, ip: IpAddr) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!("{}{}{}&ip={}", base, separator, query, ip)
+}
+
+// Forwards an announce to the origin tracker and returns the peers it
+// knows about. Failures are only logged, as local peers are still served.
+#[cfg(feature = "actix")]
+pub async fn forward_announce(
+    config: &Retracker,
+    query: &str,
+    ip: IpAddr,
+) -> Option<(Vec<CompactPeerv4>, Vec<CompactPeerv6>)> {
+    let client = ClientBuilder::new()
+        .timeout(Duration::new(config.timeout, 0))
+        .finish();
+
+    let body = match client
+        .get(forward_url(&config.announce_url, query, ip))
+        .send()
+        .await
+    {
+        Ok(mut response) => response.body().await.ok(),
+        Err(_) => None,
+    };
+
+    let peers = body.and_then(|b| bencode::decode_announce_peers(&b).ok());
+    if peers.is_none() {
+        warn!(
+            "{} ({})",
+            InternalError::UpstreamAnnounce.text(),
+            &config.announce_url
+        );
+    }
+
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_forward_url() {
+        let query = "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881";
+
+        assert_eq!(
+            forward_url("http://origin.example/announce", query, "10.0.0.12".parse().unwrap()),
+            "http://origin.example/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=6881&ip=10.0.0.12"
+        );
+    }
+
+    #[test]
+    fn upstream_scrape_url() {
+        let info_hashes = vec![
+            InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"),
+            InfoHash(*b"ab cdefghij\xff\x00\x01klmnop"),
+        ];
+
+        assert_eq!(
+            scrape_url("http://tracker.example/scrape", &info_hashes),
+            "http://tracker.example/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0&info_hash=ab%20cdefghij%FF%00%01klmnop"
+        );
+        assert_eq!(
+            scrape_url(
+                "http://tracker.example/abc123/scrape?x=1",
+                &info_hashes[..1]
+            ),
+            "http://tracker.example/abc123/scrape?x=1&info_hash=A1B2C3D4E5F6G7H8I9J0"
+        );
+    }
+}
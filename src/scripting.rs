@@ -0,0 +1,176 @@
+// Operator-supplied announce policy, written in Lua. The script defines
+// an `announce(request, torrent)` function which is called for every
+// announce, and which may return nothing to let the announce through
+// unchanged, or a table with any of `deny`, `interval` and `warning` set.
+// Lua support is behind the `scripting` feature so that trackers without
+// bespoke rules don't have to build an interpreter.
+
+use std::net::IpAddr;
+
+#[cfg(feature = "scripting")]
+use std::fs;
+#[cfg(feature = "scripting")]
+use std::sync::{Arc, Mutex};
+
+// What the script decided to do with an announce
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Decision {
+    pub deny: Option<String>,
+    pub interval: Option<u32>,
+    pub warning: Option<String>,
+}
+
+// Everything a script gets to see about an announce. The info hash
+// is handed over as hex, while the peer ID keeps its raw bytes.
+#[derive(Debug, Clone)]
+pub struct AnnounceContext<'a> {
+    pub info_hash: String,
+    pub peer_id: &'a [u8],
+    pub ip: IpAddr,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: &'static str,
+    pub numwant: Option<u32>,
+    pub category: Option<String>,
+    pub complete: u32,
+    pub incomplete: u32,
+    pub snatches: u32,
+}
+
+// The interpreter can only be entered by one announce at a time
+#[cfg(feature = "scripting")]
+#[derive(Clone)]
+pub struct AnnouncePolicy {
+    lua: Arc<Mutex<rlua::Lua>>,
+}
+
+#[cfg(feature = "scripting")]
+impl AnnouncePolicy {
+    pub fn load(path: &str) -> Result<AnnouncePolicy, String> {
+        let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        AnnouncePolicy::from_source(path, &source)
+    }
+
+    pub fn from_source(name: &str, source: &str) -> Result<AnnouncePolicy, String> {
+        let lua = rlua::Lua::new();
+        lua.context(|ctx| -> rlua::Result<()> {
+            ctx.load(source).set_name(name)?.exec()?;
+
+            // A script without the hook is a mistake, not a no-op
+            let _: rlua::Function = ctx.globals().get("announce")?;
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+
+        Ok(AnnouncePolicy {
+            lua: Arc::new(Mutex::new(lua)),
+        })
+    }
+
+    pub fn evaluate(&self, announce: &AnnounceContext) -> Result<Decision, String> {
+        let lua = self.lua.lock().map_err(|e| e.to_string())?;
+
+        lua.context(|ctx| -> rlua::Result<Decision> {
+            let request = ctx.create_table()?;
+            request.set("info_hash", announce.info_hash.as_str())?;
+            request.set("peer_id", ctx.create_string(announce.peer_id)?)?;
+            request.set("ip", announce.ip.to_string())?;
+            request.set("port", announce.port)?;
+            request.set("uploaded", announce.uploaded)?;
+            request.set("downloaded", announce.downloaded)?;
+            request.set("left", announce.left)?;
+            request.set("event", announce.event)?;
+            request.set("numwant", announce.numwant)?;
+
+            let torrent = ctx.create_table()?;
+            torrent.set("category", announce.category.clone())?;
+            torrent.set("complete", announce.complete)?;
+            torrent.set("incomplete", announce.incomplete)?;
+            torrent.set("downloaded", announce.snatches)?;
+
+            let hook: rlua::Function = ctx.globals().get("announce")?;
+            match hook.call::<_, Option<rlua::Table>>((request, torrent))? {
+                Some(result) => Ok(Decision {
+                    deny: result.get("deny")?,
+                    interval: result.get("interval")?,
+                    warning: result.get("warning")?,
+                }),
+                None => Ok(Decision::default()),
+            }
+        })
+        .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+#[derive(Clone)]
+pub struct AnnouncePolicy;
+
+#[cfg(not(feature = "scripting"))]
+impl AnnouncePolicy {
+    pub fn load(_path: &str) -> Result<AnnouncePolicy, String> {
+        Err("tyto was built without the scripting feature".to_string())
+    }
+
+    pub fn evaluate(&self, _announce: &AnnounceContext) -> Result<Decision, String> {
+        Ok(Decision::default())
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+
+    fn context(left: u64) -> AnnounceContext<'static> {
+        AnnounceContext {
+            info_hash: "4131423243334434453546364737483849394a30".to_string(),
+            peer_id: b"-DE9824-143964258012",
+            ip: "192.0.2.7".parse().unwrap(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: "started",
+            numwant: None,
+            category: Some("freeleech".to_string()),
+            complete: 3,
+            incomplete: 1,
+            snatches: 10,
+        }
+    }
+
+    #[test]
+    fn policy_decisions() {
+        let source = r#"
+            function announce(request, torrent)
+                if request.left == 0 and torrent.category == "freeleech" then
+                    return { interval = 3600, warning = "Seeding freeleech" }
+                elseif request.left > 1000 then
+                    return { deny = "Too much left" }
+                end
+            end
+        "#;
+        let policy = AnnouncePolicy::from_source("test", source).unwrap();
+
+        assert_eq!(
+            policy.evaluate(&context(0)).unwrap(),
+            Decision {
+                deny: None,
+                interval: Some(3600),
+                warning: Some("Seeding freeleech".to_string()),
+            }
+        );
+        assert_eq!(
+            policy.evaluate(&context(5000)).unwrap().deny,
+            Some("Too much left".to_string())
+        );
+        assert_eq!(policy.evaluate(&context(10)).unwrap(), Decision::default());
+    }
+
+    #[test]
+    fn policy_without_hook() {
+        assert!(AnnouncePolicy::from_source("test", "x = 1").is_err());
+    }
+}
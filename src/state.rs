@@ -1,25 +1,167 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-use crate::config::Config;
-use crate::statistics::GlobalStatistics;
+use crate::blocklist::{Blocklist, SharedRanges};
+use crate::config::{Config, ReplicationRole, SharedConfig};
+use crate::errors::InternalError;
+#[cfg(feature = "geoip")]
+use crate::geoip::CountryDb;
+use crate::network::cheaters::Cheaters;
+use crate::network::dnsbl::Dnsbl;
+use crate::network::gossip::GossipLog;
+use crate::network::middleware::{ClientApproval, IpFilter, TorrentApproval};
+use crate::network::rate_limit::RateLimiter;
+use crate::network::replication::{ChangeLog, PrimaryLink};
+use crate::network::sharding::HashRing;
+use crate::network::upstream::UpstreamRecords;
+use crate::scripting::AnnouncePolicy;
+use crate::statistics::{ArchivedStatistics, GlobalStatistics};
+use crate::storage::backend::Backend;
+use crate::storage::history::AnnounceHistory;
+use crate::storage::leaderboard::Leaderboard;
+use crate::storage::response_cache::ResponseCache;
+use crate::storage::snapshot::Snapshot;
+use crate::storage::users::{Freeleech, UserStore};
 use crate::storage::{PeerStore, TorrentStore};
 
 #[derive(Clone)]
 pub struct State {
-    pub config: Config,
+    pub announce_history: AnnounceHistory,
+    pub announce_policy: Option<AnnouncePolicy>,
+    pub backend: Option<Backend>,
+    pub blocklists: Arc<RwLock<Vec<Blocklist>>>,
+    // Changes for replicas to follow
+    pub change_log: ChangeLog,
+    pub cheaters: Cheaters,
+    pub client_approval: ClientApproval,
+    config: SharedConfig,
+    // Where the config was loaded from, so it can be reloaded
+    pub config_path: String,
+    // Set when a GeoIP database is configured
+    #[cfg(feature = "geoip")]
+    pub countries: Option<Arc<CountryDb>>,
+    pub dnsbl: Dnsbl,
+    pub freeleech: Freeleech,
+    // Set when the info hash space is shared among several trackers
+    pub hash_ring: Option<HashRing>,
+    // Swarm changes waiting to be sent to the rest of the cluster
+    pub gossip: GossipLog,
+    pub ip_filter: IpFilter,
+    // Clients whose ip parameter is taken, if not all of them
+    pub ip_param_trusted: SharedRanges,
+    pub leaderboard: Leaderboard,
+    pub listeners: Vec<SocketAddr>,
     pub peer_store: PeerStore,
+    // Set while this tracker is a replica
+    pub primary: PrimaryLink,
+    pub rate_limiter: RateLimiter,
+    pub passkey_rate_limiter: RateLimiter<String>,
+    pub response_cache: ResponseCache,
+    pub snapshot: Snapshot,
     pub stats: Arc<RwLock<GlobalStatistics>>,
+    pub stats_history: Arc<RwLock<Vec<ArchivedStatistics>>>,
+    // Swarm changes waiting to be published to the trackers sharing Redis
+    pub swarm_events: GossipLog,
+    pub torrent_approval: TorrentApproval,
     pub torrent_store: TorrentStore,
+    // Proxies whose forwarded client addresses are taken
+    pub trusted_proxies: SharedRanges,
+    pub upstream: Arc<RwLock<UpstreamRecords>>,
+    pub user_store: UserStore,
 }
 
 impl State {
     pub fn new(config: Config, torrent_store: TorrentStore) -> State {
+        let peer_store = PeerStore::with_shards(config.bt.peer_shards);
+        let peer_store = if config.bt.swarm_writers {
+            peer_store.with_writers()
+        } else {
+            peer_store
+        };
+        let response_cache = ResponseCache::new(
+            config.bt.response_cache_min_peers,
+            config.bt.response_cache_ttl,
+        );
+        // Replicas keep a log too, for after they've been promoted
+        let log_size = if config.replication.role == ReplicationRole::Standalone {
+            0
+        } else {
+            config.replication.log_size
+        };
+        let primary = PrimaryLink::new(&config.replication);
+        // Clusters share their rate limits over gossip
+        let rate_limiter = if config.cluster.bind.is_empty() {
+            RateLimiter::new()
+        } else {
+            RateLimiter::shared()
+        };
+        let trusted_proxies = SharedRanges::parse(&config.network.trusted_proxies);
+        let swarm_events =
+            GossipLog::new(cfg!(feature = "redis") && config.storage.backend == "redis");
         State {
-            config,
-            peer_store: PeerStore::new(),
+            announce_history: AnnounceHistory::new(config.bt.announce_history),
+            announce_policy: None,
+            backend: None,
+            blocklists: Arc::new(RwLock::new(Vec::new())),
+            change_log: ChangeLog::new(log_size),
+            cheaters: Cheaters::new(),
+            client_approval: ClientApproval::from_config(&config.client_approval),
+            torrent_approval: TorrentApproval::from_config(
+                &config.torrent_approval,
+                torrent_store.clone(),
+            ),
+            dnsbl: Dnsbl::new(&config.dnsbl),
+            freeleech: Freeleech::from_config(&config.private),
+            hash_ring: HashRing::new(&config.sharding),
+            gossip: GossipLog::new(!config.cluster.bind.is_empty()),
+            ip_filter: IpFilter::from_config(&config.ip_filter, trusted_proxies.clone()),
+            ip_param_trusted: SharedRanges::parse(&config.ip_param.trusted),
+            config: SharedConfig::new(config),
+            config_path: String::new(),
+            #[cfg(feature = "geoip")]
+            countries: None,
+            leaderboard: Leaderboard::new(),
+            listeners: Vec::new(),
+            peer_store,
+            primary,
+            rate_limiter,
+            passkey_rate_limiter: RateLimiter::new(),
+            response_cache,
+            snapshot: Snapshot::new(),
             stats: Arc::new(RwLock::new(GlobalStatistics::new())),
+            stats_history: Arc::new(RwLock::new(Vec::new())),
+            swarm_events,
             torrent_store,
+            trusted_proxies,
+            upstream: Arc::new(RwLock::new(UpstreamRecords::new())),
+            user_store: UserStore::default(),
         }
     }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.get()
+    }
+
+    // Rereads the config file and applies it to everything that reads
+    // its settings as it goes: announce intervals and policies, client
+    // and torrent lists, the IP filter, trusted proxies, freeleech, and
+    // the janitor's jobs, which pick up new intervals from their next run
+    // on. Listeners and storage stay as they were started. If the file
+    // can't be read, the running config is kept.
+    pub fn reload_config(&self) -> Result<(), InternalError> {
+        let config =
+            Config::from_file(&self.config_path).map_err(|_| InternalError::ConfigReload)?;
+
+        self.client_approval.reload(&config.client_approval);
+        self.torrent_approval.reload(&config.torrent_approval);
+        self.freeleech.reload(&config.private);
+        self.ip_filter.reload(&config.ip_filter);
+        self.ip_param_trusted.replace(&config.ip_param.trusted);
+        self.trusted_proxies
+            .replace(&config.network.trusted_proxies);
+        self.config.replace(config);
+
+        Ok(())
+    }
 }
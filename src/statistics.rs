@@ -1,6 +1,331 @@
-use std::time::Instant;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::bittorrent::PeerId;
+use crate::util::unix_secs;
+
+// Counters as they stood at the start of the current archival period
+#[derive(Clone)]
+struct PeriodStart {
+    time: SystemTime,
+    announce_requests: u32,
+    succ_announces: u32,
+    scrapes: u32,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+// Activity over a single rollover period, which is
+// persisted and served through the statistics history
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedStatistics {
+    pub period_start: u64,
+    pub period_end: u64,
+    pub announce_requests: u32,
+    pub succ_announces: u32,
+    pub scrapes: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// Bucket i counts durations of under 2^i microseconds (and at least
+// half that), and the last bucket anything from about 4 seconds up
+const LATENCY_BUCKETS: usize = 24;
+
+// How long announces take to handle, from parsing the request to
+// encoding the response. Only the buckets are kept, so percentiles
+// are rounded up to the next power of two microseconds.
+#[derive(Clone, Default)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LatencyPercentiles {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    // In microseconds, or 0 if nothing has been recorded yet
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let rank = ((self.count as f64) * p / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 1 << i;
+            }
+        }
+        1 << (LATENCY_BUCKETS - 1)
+    }
+
+    pub fn percentiles(&self) -> LatencyPercentiles {
+        LatencyPercentiles {
+            p50: self.percentile(50.0),
+            p95: self.percentile(95.0),
+            p99: self.percentile(99.0),
+        }
+    }
+}
+
+// Requests are counted per second for as long as the longest window
+const RATE_WINDOW: u64 = 900;
+
+#[derive(Clone, Copy, Default)]
+struct RateBucket {
+    second: u64,
+    announces: u32,
+    scrapes: u32,
+}
+
+// Requests per second, averaged over the last 1, 5 and 15 minutes
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RequestRate {
+    pub last_1m: f64,
+    pub last_5m: f64,
+    pub last_15m: f64,
+}
+
+// Announces and scrapes over sliding windows, kept as a ring of per-second
+// counts. A bucket left over from an earlier lap of the ring is emptied
+// before it's counted in again.
+#[derive(Clone)]
+pub struct RequestRates {
+    start: Instant,
+    buckets: Vec<RateBucket>,
+}
+
+impl RequestRates {
+    fn new() -> RequestRates {
+        RequestRates {
+            start: Instant::now(),
+            buckets: vec![RateBucket::default(); RATE_WINDOW as usize],
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn bucket(&mut self, second: u64) -> &mut RateBucket {
+        let bucket = &mut self.buckets[(second % RATE_WINDOW) as usize];
+        if bucket.second != second {
+            *bucket = RateBucket {
+                second,
+                ..RateBucket::default()
+            };
+        }
+        bucket
+    }
+
+    fn record_at(&mut self, second: u64, announce: bool) {
+        let bucket = self.bucket(second);
+        if announce {
+            bucket.announces += 1;
+        } else {
+            bucket.scrapes += 1;
+        }
+    }
+
+    pub fn record_announce(&mut self) {
+        self.record_at(self.now(), true);
+    }
+
+    pub fn record_scrape(&mut self) {
+        self.record_at(self.now(), false);
+    }
+
+    // Until the tracker has been up for a whole window, the
+    // rate is averaged over however long it has been up
+    fn rate_at(&self, second: u64, announces: bool) -> RequestRate {
+        let rate = |window: u64| {
+            let total: u64 = self
+                .buckets
+                .iter()
+                .filter(|b| b.second <= second && second - b.second < window)
+                .map(|b| u64::from(if announces { b.announces } else { b.scrapes }))
+                .sum();
+            total as f64 / window.min(second + 1) as f64
+        };
+
+        RequestRate {
+            last_1m: rate(60),
+            last_5m: rate(300),
+            last_15m: rate(RATE_WINDOW),
+        }
+    }
+
+    pub fn announce_rate(&self) -> RequestRate {
+        self.rate_at(self.now(), true)
+    }
+
+    pub fn scrape_rate(&self) -> RequestRate {
+        self.rate_at(self.now(), false)
+    }
+}
+
+// The first HLL_PRECISION bits of a hash pick one of the registers
+const HLL_PRECISION: u32 = 12;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+// Estimates how many distinct values were added in a few kilobytes, to
+// within about 2%, without keeping any of the values themselves. Every
+// register holds the longest run of leading zeros seen among the hashes
+// that were assigned to it.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+
+    pub fn insert<T: Hash>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        // A set bit after the index bits caps the run
+        let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|r| 1.0 / f64::from(1u32 << r))
+            .sum();
+        let raw = alpha * m * m / sum;
+
+        // Small counts leave many registers empty, which is
+        // a better estimate than the harmonic mean
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct UniqueCounts {
+    pub peer_ids: u64,
+    pub ips: u64,
+}
+
+// Distinct peer IDs and addresses that announced on each UTC day. Today's
+// counts start over at midnight, and the previous day's are kept.
+#[derive(Clone)]
+pub struct DailyUniques {
+    day: u64,
+    peer_ids: HyperLogLog,
+    ips: HyperLogLog,
+    yesterday: Option<UniqueCounts>,
+}
+
+impl DailyUniques {
+    fn new() -> DailyUniques {
+        DailyUniques {
+            day: unix_secs(SystemTime::now()) / 86400,
+            peer_ids: HyperLogLog::new(),
+            ips: HyperLogLog::new(),
+            yesterday: None,
+        }
+    }
+
+    fn roll_to(&mut self, day: u64) {
+        if day == self.day {
+            return;
+        }
+
+        // Nothing was seen on the days in between
+        self.yesterday = if day == self.day + 1 {
+            Some(self.today())
+        } else {
+            Some(UniqueCounts {
+                peer_ids: 0,
+                ips: 0,
+            })
+        };
+        self.day = day;
+        self.peer_ids = HyperLogLog::new();
+        self.ips = HyperLogLog::new();
+    }
+
+    fn record_at(&mut self, day: u64, peer_id: &PeerId, ip: &IpAddr) {
+        self.roll_to(day);
+        self.peer_ids.insert(peer_id);
+        self.ips.insert(ip);
+    }
+
+    pub fn record(&mut self, peer_id: &PeerId, ip: &IpAddr) {
+        self.record_at(unix_secs(SystemTime::now()) / 86400, peer_id, ip);
+    }
+
+    pub fn today(&self) -> UniqueCounts {
+        UniqueCounts {
+            peer_ids: self.peer_ids.estimate(),
+            ips: self.ips.estimate(),
+        }
+    }
+
+    pub fn yesterday(&self) -> Option<UniqueCounts> {
+        self.yesterday
+    }
+}
+
+// Peers coming and going. A steady swarm has joins and departures in
+// step, while clients failing en masse show up as reaps without stops.
+// Peers stopping under a delayed-stop strategy are counted as reaped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Churn {
+    pub joined: u32,
+    pub departed: u32,
+    pub promoted: u32,
+    pub reaped: u32,
+}
+
+impl Churn {
+    fn since(&self, earlier: &Churn) -> Churn {
+        Churn {
+            joined: self.joined - earlier.joined,
+            departed: self.departed - earlier.departed,
+            promoted: self.promoted - earlier.promoted,
+            reaped: self.reaped - earlier.reaped,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct GlobalStatistics {
@@ -10,6 +335,35 @@ pub struct GlobalStatistics {
     pub announce_requests: u32,
     pub succ_announces: u32,
     pub scrapes: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub upstream_seeders: u32,
+    pub upstream_leechers: u32,
+    pub dnsbl_hits: u32,
+    pub blocklist_hits: HashMap<String, u32>,
+    pub swarms_evicted: u32,
+    pub peers_evicted: u32,
+    pub swarms_reaped: u32,
+    pub peers_reaped: u32,
+    // How long the last reap took, and the slowest shard in it
+    pub reap_millis: u64,
+    pub slowest_shard_reap_millis: u64,
+    pub cheaters_caught: u32,
+    pub duplicate_peer_ids: u32,
+    pub announce_latency: LatencyHistogram,
+    pub request_rates: RequestRates,
+    pub uniques: DailyUniques,
+    // Running totals, and what changed between the last two reaps
+    pub churn: Churn,
+    pub last_churn: Churn,
+    churn_mark: Churn,
+    period: PeriodStart,
+}
+
+impl Default for GlobalStatistics {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GlobalStatistics {
@@ -21,9 +375,63 @@ impl GlobalStatistics {
             announce_requests: 0,
             succ_announces: 0,
             scrapes: 0,
+            uploaded: 0,
+            downloaded: 0,
+            upstream_seeders: 0,
+            upstream_leechers: 0,
+            dnsbl_hits: 0,
+            blocklist_hits: HashMap::new(),
+            swarms_evicted: 0,
+            peers_evicted: 0,
+            swarms_reaped: 0,
+            peers_reaped: 0,
+            reap_millis: 0,
+            slowest_shard_reap_millis: 0,
+            cheaters_caught: 0,
+            duplicate_peer_ids: 0,
+            announce_latency: LatencyHistogram::default(),
+            request_rates: RequestRates::new(),
+            uniques: DailyUniques::new(),
+            churn: Churn::default(),
+            last_churn: Churn::default(),
+            churn_mark: Churn::default(),
+            period: PeriodStart {
+                time: SystemTime::now(),
+                announce_requests: 0,
+                succ_announces: 0,
+                scrapes: 0,
+                uploaded: 0,
+                downloaded: 0,
+            },
         }
     }
 
+    // Closes out the current period and starts a new one. The running
+    // totals are left alone so that the live statistics are unaffected.
+    pub fn rollover(&mut self) -> ArchivedStatistics {
+        let now = SystemTime::now();
+        let archived = ArchivedStatistics {
+            period_start: unix_secs(self.period.time),
+            period_end: unix_secs(now),
+            announce_requests: self.announce_requests - self.period.announce_requests,
+            succ_announces: self.succ_announces - self.period.succ_announces,
+            scrapes: self.scrapes - self.period.scrapes,
+            uploaded: self.uploaded - self.period.uploaded,
+            downloaded: self.downloaded - self.period.downloaded,
+        };
+
+        self.period = PeriodStart {
+            time: now,
+            announce_requests: self.announce_requests,
+            succ_announces: self.succ_announces,
+            scrapes: self.scrapes,
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+        };
+
+        archived
+    }
+
     pub fn uptime(&self) -> u64 {
         self.start_time.elapsed().as_secs()
     }
@@ -31,10 +439,12 @@ impl GlobalStatistics {
     pub fn succ_announce(&mut self) {
         self.announce_requests += 1;
         self.succ_announces += 1;
+        self.request_rates.record_announce();
     }
 
     pub fn fail_announce(&mut self) {
         self.announce_requests += 1;
+        self.request_rates.record_announce();
     }
 
     pub fn num_fails(&self) -> u32 {
@@ -43,33 +453,71 @@ impl GlobalStatistics {
 
     pub fn incr_scrapes(&mut self) {
         self.scrapes += 1;
+        self.request_rates.record_scrape();
+    }
+
+    pub fn add_traffic(&mut self, uploaded: u64, downloaded: u64) {
+        self.uploaded = self.uploaded.saturating_add(uploaded);
+        self.downloaded = self.downloaded.saturating_add(downloaded);
+    }
+
+    pub fn blocklist_hit(&mut self, name: &str) {
+        *self.blocklist_hits.entry(name.to_string()).or_insert(0) += 1;
     }
 
     pub fn add_seed(&mut self) {
         self.total_seeders += 1;
+        self.churn.joined += 1;
     }
 
     pub fn add_leech(&mut self) {
         self.total_leechers += 1;
+        self.churn.joined += 1;
     }
 
     pub fn sub_seed(&mut self) {
-        self.total_leechers = self.total_seeders.saturating_sub(1);
+        self.total_seeders = self.total_seeders.saturating_sub(1);
+        self.churn.departed += 1;
     }
 
     pub fn sub_leech(&mut self) {
         self.total_leechers = self.total_leechers.saturating_sub(1);
+        self.churn.departed += 1;
     }
 
     pub fn promote_leech(&mut self) {
         self.total_leechers = self.total_leechers.saturating_sub(1);
         self.total_seeders += 1;
+        self.churn.promoted += 1;
     }
 
     pub fn cleared_peers(&mut self, seeders_cleared: u32, leechers_cleared: u32) {
         self.total_seeders = self.total_seeders.saturating_sub(seeders_cleared);
         self.total_leechers = self.total_leechers.saturating_sub(leechers_cleared);
     }
+
+    // Every reap also closes the interval that churn is reported over
+    pub fn reaped(&mut self, swarms: u32, seeders: u32, leechers: u32) {
+        self.swarms_reaped += swarms;
+        self.peers_reaped += seeders + leechers;
+        self.churn.reaped += seeders + leechers;
+        self.cleared_peers(seeders, leechers);
+
+        self.last_churn = self.churn.since(&self.churn_mark);
+        self.churn_mark = self.churn;
+    }
+
+    pub fn reap_timing(&mut self, elapsed: Duration, slowest_shard: Duration) {
+        self.reap_millis = elapsed.as_millis() as u64;
+        self.slowest_shard_reap_millis = slowest_shard.as_millis() as u64;
+    }
+
+    // Evicted peers are gone from their swarms just like reaped ones
+    pub fn evicted(&mut self, swarms: u32, seeders: u32, leechers: u32) {
+        self.swarms_evicted += swarms;
+        self.peers_evicted += seeders + leechers;
+        self.cleared_peers(seeders, leechers);
+    }
 }
 
 // This is a separate struct that will be returned through
@@ -83,6 +531,30 @@ pub struct ReturnedStatistics {
     pub announce_requests: u32,
     pub succ_announces: u32,
     pub scrapes: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub upstream_seeders: u32,
+    pub upstream_leechers: u32,
+    pub dnsbl_hits: u32,
+    pub blocklist_hits: HashMap<String, u32>,
+    pub swarms_evicted: u32,
+    pub peers_evicted: u32,
+    pub swarms_reaped: u32,
+    pub peers_reaped: u32,
+    pub reap_millis: u64,
+    pub slowest_shard_reap_millis: u64,
+    pub cheaters_caught: u32,
+    pub duplicate_peer_ids: u32,
+    // In microseconds
+    pub announce_latency: LatencyPercentiles,
+    pub announce_rate: RequestRate,
+    pub scrape_rate: RequestRate,
+    // Estimates for the current day (UTC) so far, and all of the one before
+    pub uniques_today: UniqueCounts,
+    pub uniques_yesterday: Option<UniqueCounts>,
+    pub churn: Churn,
+    // Between the last two reaps
+    pub churn_last_interval: Churn,
 }
 
 impl ReturnedStatistics {
@@ -94,6 +566,217 @@ impl ReturnedStatistics {
             announce_requests: stats.announce_requests,
             succ_announces: stats.succ_announces,
             scrapes: stats.scrapes,
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            upstream_seeders: stats.upstream_seeders,
+            upstream_leechers: stats.upstream_leechers,
+            dnsbl_hits: stats.dnsbl_hits,
+            blocklist_hits: stats.blocklist_hits.clone(),
+            swarms_evicted: stats.swarms_evicted,
+            peers_evicted: stats.peers_evicted,
+            swarms_reaped: stats.swarms_reaped,
+            peers_reaped: stats.peers_reaped,
+            reap_millis: stats.reap_millis,
+            slowest_shard_reap_millis: stats.slowest_shard_reap_millis,
+            cheaters_caught: stats.cheaters_caught,
+            duplicate_peer_ids: stats.duplicate_peer_ids,
+            announce_latency: stats.announce_latency.percentiles(),
+            announce_rate: stats.request_rates.announce_rate(),
+            scrape_rate: stats.request_rates.scrape_rate(),
+            uniques_today: stats.uniques.today(),
+            uniques_yesterday: stats.uniques.yesterday(),
+            churn: stats.churn,
+            churn_last_interval: stats.last_churn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statistics_rollover() {
+        let mut stats = GlobalStatistics::new();
+        stats.succ_announce();
+        stats.succ_announce();
+        stats.fail_announce();
+        stats.incr_scrapes();
+        stats.add_traffic(1000, 500);
+
+        let first = stats.rollover();
+        assert_eq!(first.announce_requests, 3);
+        assert_eq!(first.succ_announces, 2);
+        assert_eq!(first.scrapes, 1);
+        assert_eq!(first.uploaded, 1000);
+        assert_eq!(first.downloaded, 500);
+
+        stats.succ_announce();
+
+        // Only activity since the last rollover is archived,
+        // while the running totals keep counting
+        let second = stats.rollover();
+        assert_eq!(second.announce_requests, 1);
+        assert_eq!(second.scrapes, 0);
+        assert_eq!(second.period_start, first.period_end);
+        assert_eq!(stats.announce_requests, 4);
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), 0);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(100));
         }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(3));
+        }
+        histogram.record(Duration::from_secs(60));
+
+        assert_eq!(
+            histogram.percentiles(),
+            LatencyPercentiles {
+                p50: 128,
+                p95: 4096,
+                p99: 4096,
+            }
+        );
+        assert_eq!(histogram.percentile(100.0), 1 << 23);
+    }
+
+    #[test]
+    fn statistics_peer_counts() {
+        let mut stats = GlobalStatistics::new();
+        stats.add_leech();
+        stats.add_leech();
+        stats.promote_leech();
+        stats.add_seed();
+        stats.sub_seed();
+        stats.sub_leech();
+
+        assert_eq!(stats.total_seeders, 1);
+        assert_eq!(stats.total_leechers, 0);
+    }
+
+    #[test]
+    fn request_rates_over_windows() {
+        let mut rates = RequestRates::new();
+        for second in 0..600 {
+            rates.record_at(second, true);
+        }
+        for _ in 0..30 {
+            rates.record_at(599, false);
+        }
+
+        let announces = rates.rate_at(599, true);
+        assert_eq!(announces.last_1m, 1.0);
+        assert_eq!(announces.last_5m, 1.0);
+        // Averaged over the ten minutes the tracker has been up
+        assert_eq!(announces.last_15m, 1.0);
+        assert_eq!(rates.rate_at(599, false).last_1m, 0.5);
+
+        // Quiet seconds bring the rates down, and the
+        // ring's old buckets don't count once overwritten
+        let later = rates.rate_at(659, true);
+        assert_eq!(later.last_1m, 0.0);
+        assert_eq!(later.last_5m, 0.8);
+        rates.record_at(1500, true);
+        assert_eq!(rates.rate_at(1500, true).last_15m, 1.0 / 900.0);
+    }
+
+    #[test]
+    fn hyperloglog_estimates() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0);
+
+        for i in 0..10u32 {
+            hll.insert(&i);
+            hll.insert(&i);
+        }
+        assert_eq!(hll.estimate(), 10);
+
+        for i in 0..100_000u32 {
+            hll.insert(&i);
+        }
+        let estimate = hll.estimate() as f64;
+        assert!((estimate - 100_000.0).abs() < 5_000.0, "{}", estimate);
+    }
+
+    #[test]
+    fn uniques_per_day() {
+        let mut uniques = DailyUniques::new();
+        let day = uniques.day;
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        uniques.record_at(day, &PeerId(*b"-DE9824-143964258012"), &ip);
+        uniques.record_at(day, &PeerId(*b"-DE9824-143964258013"), &ip);
+        assert_eq!(
+            uniques.today(),
+            UniqueCounts {
+                peer_ids: 2,
+                ips: 1
+            }
+        );
+        assert_eq!(uniques.yesterday(), None);
+
+        uniques.record_at(day + 1, &PeerId(*b"-DE9824-143964258012"), &ip);
+        assert_eq!(
+            uniques.today(),
+            UniqueCounts {
+                peer_ids: 1,
+                ips: 1
+            }
+        );
+        assert_eq!(
+            uniques.yesterday(),
+            Some(UniqueCounts {
+                peer_ids: 2,
+                ips: 1
+            })
+        );
+
+        uniques.roll_to(day + 3);
+        assert_eq!(
+            uniques.yesterday(),
+            Some(UniqueCounts {
+                peer_ids: 0,
+                ips: 0
+            })
+        );
+    }
+
+    #[test]
+    fn churn_per_reap_interval() {
+        let mut stats = GlobalStatistics::new();
+        stats.add_leech();
+        stats.add_leech();
+        stats.add_seed();
+        stats.promote_leech();
+        stats.sub_seed();
+        stats.reaped(0, 0, 1);
+
+        let first = Churn {
+            joined: 3,
+            departed: 1,
+            promoted: 1,
+            reaped: 1,
+        };
+        assert_eq!(stats.last_churn, first);
+
+        stats.add_leech();
+        stats.reaped(1, 1, 1);
+        assert_eq!(
+            stats.last_churn,
+            Churn {
+                joined: 1,
+                departed: 0,
+                promoted: 0,
+                reaped: 2,
+            }
+        );
+        assert_eq!(stats.churn.joined, 4);
+        assert_eq!(stats.churn.reaped, 3);
     }
 }
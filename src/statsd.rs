@@ -0,0 +1,246 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Statsd;
+use crate::statistics::GlobalStatistics;
+
+// Metrics are batched into datagrams no larger than this,
+// which fits within the usual MTU after the IP and UDP headers
+const MAX_PACKET_SIZE: usize = 1432;
+
+// Running totals as they stood at the previous push, so that
+// counters can be sent as the change since then
+#[derive(Clone, Copy, Default)]
+struct Counters {
+    announce_requests: u64,
+    succ_announces: u64,
+    scrapes: u64,
+    uploaded: u64,
+    downloaded: u64,
+    swarms_reaped: u64,
+    peers_reaped: u64,
+    peers_joined: u64,
+    peers_departed: u64,
+    peers_promoted: u64,
+    swarms_evicted: u64,
+    peers_evicted: u64,
+    cheaters_caught: u64,
+    dnsbl_hits: u64,
+}
+
+impl Counters {
+    fn from_stats(stats: &GlobalStatistics) -> Counters {
+        Counters {
+            announce_requests: stats.announce_requests.into(),
+            succ_announces: stats.succ_announces.into(),
+            scrapes: stats.scrapes.into(),
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            swarms_reaped: stats.swarms_reaped.into(),
+            peers_reaped: stats.peers_reaped.into(),
+            peers_joined: stats.churn.joined.into(),
+            peers_departed: stats.churn.departed.into(),
+            peers_promoted: stats.churn.promoted.into(),
+            swarms_evicted: stats.swarms_evicted.into(),
+            peers_evicted: stats.peers_evicted.into(),
+            cheaters_caught: stats.cheaters_caught.into(),
+            dnsbl_hits: stats.dnsbl_hits.into(),
+        }
+    }
+}
+
+// Pushes the statistics to a StatsD (or DogStatsD) server over UDP
+#[derive(Clone)]
+pub struct Exporter {
+    socket: Arc<UdpSocket>,
+    prefix: String,
+    tags: Vec<String>,
+    last: Arc<Mutex<Counters>>,
+}
+
+impl Exporter {
+    // The address is only resolved once, when the exporter is created
+    pub fn new(config: &Statsd) -> io::Result<Exporter> {
+        let target = config
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Exporter {
+            socket: Arc::new(socket),
+            prefix: config.prefix.clone(),
+            tags: config.tags.clone(),
+            last: Arc::new(Mutex::new(Counters::default())),
+        })
+    }
+
+    pub fn push(&self, stats: &GlobalStatistics) -> io::Result<()> {
+        let current = Counters::from_stats(stats);
+        let previous = std::mem::replace(&mut *self.last.lock().unwrap(), current);
+        let lines = format_metrics(&self.prefix, &self.tags, stats, &previous);
+
+        for packet in packets(&lines) {
+            self.socket.send(packet.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn metric(prefix: &str, tags: &str, name: &str, value: u64, kind: &str) -> String {
+    if prefix.is_empty() {
+        format!("{}:{}|{}{}", name, value, kind, tags)
+    } else {
+        format!("{}.{}:{}|{}{}", prefix, name, value, kind, tags)
+    }
+}
+
+// Counters are sent as the change since the previous push,
+// and gauges as they stand
+fn format_metrics(
+    prefix: &str,
+    tags: &[String],
+    stats: &GlobalStatistics,
+    previous: &Counters,
+) -> Vec<String> {
+    let tags = if tags.is_empty() {
+        "".to_string()
+    } else {
+        format!("|#{}", tags.join(","))
+    };
+    let current = Counters::from_stats(stats);
+    let latency = stats.announce_latency.percentiles();
+
+    let counters = [
+        (
+            "announces",
+            current.announce_requests,
+            previous.announce_requests,
+        ),
+        (
+            "announces.succeeded",
+            current.succ_announces,
+            previous.succ_announces,
+        ),
+        (
+            "announces.failed",
+            current.announce_requests - current.succ_announces,
+            previous.announce_requests - previous.succ_announces,
+        ),
+        ("scrapes", current.scrapes, previous.scrapes),
+        ("bytes.uploaded", current.uploaded, previous.uploaded),
+        ("bytes.downloaded", current.downloaded, previous.downloaded),
+        (
+            "reaper.swarms",
+            current.swarms_reaped,
+            previous.swarms_reaped,
+        ),
+        ("reaper.peers", current.peers_reaped, previous.peers_reaped),
+        ("peers.joined", current.peers_joined, previous.peers_joined),
+        (
+            "peers.departed",
+            current.peers_departed,
+            previous.peers_departed,
+        ),
+        (
+            "peers.promoted",
+            current.peers_promoted,
+            previous.peers_promoted,
+        ),
+        (
+            "evicted.swarms",
+            current.swarms_evicted,
+            previous.swarms_evicted,
+        ),
+        (
+            "evicted.peers",
+            current.peers_evicted,
+            previous.peers_evicted,
+        ),
+        (
+            "cheaters",
+            current.cheaters_caught,
+            previous.cheaters_caught,
+        ),
+        ("dnsbl.hits", current.dnsbl_hits, previous.dnsbl_hits),
+    ];
+    let gauges = [
+        ("seeders", u64::from(stats.total_seeders)),
+        ("leechers", u64::from(stats.total_leechers)),
+        ("announce.latency.p50", latency.p50),
+        ("announce.latency.p95", latency.p95),
+        ("announce.latency.p99", latency.p99),
+        ("reaper.duration_ms", stats.reap_millis),
+        ("reaper.slowest_shard_ms", stats.slowest_shard_reap_millis),
+    ];
+
+    let mut lines = Vec::new();
+    for (name, current, previous) in counters.iter() {
+        let delta = current.saturating_sub(*previous);
+        lines.push(metric(prefix, &tags, name, delta, "c"));
+    }
+    for (name, value) in gauges.iter() {
+        lines.push(metric(prefix, &tags, name, *value, "g"));
+    }
+    lines
+}
+
+// StatsD servers take several metrics per datagram, one per line
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_SIZE => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statsd_metrics() {
+        let mut stats = GlobalStatistics::new();
+        stats.succ_announce();
+        stats.fail_announce();
+        stats.add_seed();
+        stats.reaped(1, 0, 2);
+
+        let first = format_metrics("tyto", &[], &stats, &Counters::default());
+        assert!(first.contains(&"tyto.announces:2|c".to_string()));
+        assert!(first.contains(&"tyto.announces.failed:1|c".to_string()));
+        assert!(first.contains(&"tyto.reaper.peers:2|c".to_string()));
+        assert!(first.contains(&"tyto.peers.joined:1|c".to_string()));
+        assert!(first.contains(&"tyto.seeders:1|g".to_string()));
+
+        // Only what happened since the previous push is counted again
+        let previous = Counters::from_stats(&stats);
+        stats.succ_announce();
+        let tags = vec!["env:prod".to_string(), "region:eu".to_string()];
+        let second = format_metrics("", &tags, &stats, &previous);
+        assert!(second.contains(&"announces:1|c|#env:prod,region:eu".to_string()));
+        assert!(second.contains(&"announces.failed:0|c|#env:prod,region:eu".to_string()));
+        assert!(second.contains(&"seeders:1|g|#env:prod,region:eu".to_string()));
+
+        let lines: Vec<String> = (0..200).map(|i| format!("tyto.metric{}:1|c", i)).collect();
+        let packets = packets(&lines);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_SIZE));
+        assert_eq!(packets.join("\n"), lines.join("\n"));
+    }
+}
@@ -0,0 +1,339 @@
+// Built without any database feature, only the memory backend's arms are
+// left, and those ignore what they're given
+#![cfg_attr(
+    not(any(
+        feature = "mysql",
+        feature = "postgres",
+        feature = "sqlite",
+        feature = "redis"
+    )),
+    allow(unused_variables)
+)]
+
+#[cfg(any(feature = "mysql", feature = "sqlite", feature = "redis"))]
+use std::fmt;
+
+use crate::config;
+use crate::statistics::ArchivedStatistics;
+#[cfg(any(
+    feature = "mysql",
+    feature = "postgres",
+    feature = "sqlite",
+    feature = "redis"
+))]
+use crate::storage;
+use crate::storage::peer_snapshot::SavedPeer;
+use crate::storage::users::{Snatch, UserRecords, UserTraffic};
+use crate::storage::{Torrent, TorrentRecords};
+
+// The database that torrent records and the statistics history are kept
+// in, chosen by storage.backend. Every backend is an optional feature,
+// with MySQL on by default, so deployments only build the drivers they use.
+// The memory backend is always there: nothing is loaded or kept, so every
+// torrent starts out unknown and counts are lost on restart.
+#[derive(Clone)]
+pub enum Backend {
+    Memory,
+    #[cfg(feature = "mysql")]
+    Mysql(::mysql::Pool),
+    #[cfg(feature = "postgres")]
+    Postgres(sqlx::PgPool),
+    #[cfg(feature = "sqlite")]
+    Sqlite(storage::sqlite::Database),
+    #[cfg(feature = "redis")]
+    Redis(storage::redis::Database),
+}
+
+// The MySQL, SQLite and Redis drivers block, so their calls are run on the
+// blocking thread pool rather than on the executor serving announces.
+// The pool has a fixed number of threads, so a backlog of database work
+// queues up there instead of stalling requests.
+#[cfg(any(feature = "mysql", feature = "sqlite", feature = "redis"))]
+async fn blocking<F, T, E>(f: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: fmt::Debug + Send + 'static,
+{
+    actix_threadpool::run(f).await.map_err(|e| e.to_string())
+}
+
+impl Backend {
+    pub async fn connect(config: &config::Storage) -> Result<Backend, String> {
+        match config.backend.as_str() {
+            "memory" => Ok(Backend::Memory),
+            #[cfg(feature = "mysql")]
+            "mysql" => ::mysql::Pool::new(&config.path)
+                .map(Backend::Mysql)
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "postgres")]
+            "postgres" => sqlx::PgPool::connect(&config.path)
+                .await
+                .map(Backend::Postgres)
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => storage::sqlite::open(&config.path)
+                .map(Backend::Sqlite)
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "redis")]
+            "redis" => storage::redis::open(&config.path)
+                .map(Backend::Redis)
+                .map_err(|e| e.to_string()),
+            other => Err(format!("Unsupported storage backend '{}'", other)),
+        }
+    }
+
+    pub async fn get_torrents(&self) -> Result<TorrentRecords, String> {
+        match self {
+            Backend::Memory => Ok(TorrentRecords::new()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::get_torrents(pool)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::get_torrents(pool)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::get_torrents(&db)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::get_torrents(&db)).await
+            }
+        }
+    }
+
+    pub async fn flush_torrents(&self, torrents: Vec<Torrent>) -> Result<(), String> {
+        match self {
+            Backend::Memory => Ok(()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::flush_torrents(pool, torrents)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::flush_torrents(pool, torrents)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::flush_torrents(&db, torrents)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::flush_torrents(&db, torrents)).await
+            }
+        }
+    }
+
+    pub async fn get_stats_history(&self) -> Result<Vec<ArchivedStatistics>, String> {
+        match self {
+            Backend::Memory => Ok(Vec::new()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::get_stats_history(pool)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::get_stats_history(pool)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::get_stats_history(&db)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::get_stats_history(&db)).await
+            }
+        }
+    }
+
+    pub async fn archive_stats(&self, archived: &ArchivedStatistics) -> Result<(), String> {
+        match self {
+            Backend::Memory => Ok(()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let (pool, archived) = (pool.clone(), archived.clone());
+                blocking(move || storage::mysql::archive_stats(pool, &archived)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::archive_stats(pool, archived)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let (db, archived) = (db.clone(), archived.clone());
+                blocking(move || storage::sqlite::archive_stats(&db, &archived)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let (db, archived) = (db.clone(), archived.clone());
+                blocking(move || storage::redis::archive_stats(&db, &archived)).await
+            }
+        }
+    }
+
+    pub async fn get_peers(&self) -> Result<Vec<SavedPeer>, String> {
+        match self {
+            Backend::Memory => Ok(Vec::new()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::get_peers(pool)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::get_peers(pool)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::get_peers(&db)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::get_peers(&db)).await
+            }
+        }
+    }
+
+    // Redis expires saved peers once they'd have timed out, which
+    // the other backends leave to the tracker loading them
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    pub async fn save_peers(&self, peers: Vec<SavedPeer>, peer_timeout: u64) -> Result<(), String> {
+        match self {
+            Backend::Memory => Ok(()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::save_peers(pool, peers)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::save_peers(pool, peers)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::save_peers(&db, peers)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::save_peers(&db, peers, peer_timeout)).await
+            }
+        }
+    }
+
+    pub async fn get_users(&self) -> Result<UserRecords, String> {
+        match self {
+            Backend::Memory => Ok(UserRecords::new()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::get_users(pool)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::get_users(pool)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::get_users(&db)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::get_users(&db)).await
+            }
+        }
+    }
+
+    pub async fn flush_user_traffic(&self, traffic: Vec<(u32, UserTraffic)>) -> Result<(), String> {
+        match self {
+            Backend::Memory => Ok(()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::flush_user_traffic(pool, traffic)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::flush_user_traffic(pool, traffic)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::flush_user_traffic(&db, traffic)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::flush_user_traffic(&db, traffic)).await
+            }
+        }
+    }
+
+    pub async fn save_snatches(&self, snatches: Vec<Snatch>) -> Result<(), String> {
+        match self {
+            Backend::Memory => Ok(()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::save_snatches(pool, snatches)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::save_snatches(pool, snatches)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::save_snatches(&db, snatches)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::save_snatches(&db, snatches)).await
+            }
+        }
+    }
+
+    // Used by the readiness probe to check the database can still be reached
+    pub async fn ping(&self) -> Result<(), String> {
+        match self {
+            Backend::Memory => Ok(()),
+            #[cfg(feature = "mysql")]
+            Backend::Mysql(pool) => {
+                let pool = pool.clone();
+                blocking(move || storage::mysql::ping(pool)).await
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => storage::postgres::ping(pool)
+                .await
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let db = db.clone();
+                blocking(move || storage::sqlite::ping(&db)).await
+            }
+            #[cfg(feature = "redis")]
+            Backend::Redis(db) => {
+                let db = db.clone();
+                blocking(move || storage::redis::ping(&db)).await
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use hashbrown::{HashMap, HashSet};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::bittorrent::{InfoHash, PeerId};
+
+// A single announce as the tracker saw it, kept around for debugging
+#[derive(Clone, Debug, Serialize)]
+pub struct AnnounceRecord {
+    pub timestamp: u64,
+    pub event: &'static str,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+// Peers are identified by the swarm they are in and their peer ID
+pub type PeerKey = (InfoHash, PeerId);
+
+// Keeps the last few announces of every peer in a bounded ring so that
+// questions about a particular client can be answered from tracker data.
+// A capacity of zero disables recording entirely.
+#[derive(Debug, Clone)]
+pub struct AnnounceHistory {
+    capacity: usize,
+    records: Arc<RwLock<HashMap<PeerKey, VecDeque<AnnounceRecord>>>>,
+}
+
+impl AnnounceHistory {
+    pub fn new(capacity: usize) -> AnnounceHistory {
+        AnnounceHistory {
+            capacity,
+            records: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub async fn record(&self, info_hash: InfoHash, peer_id: PeerId, record: AnnounceRecord) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut records = self.records.write().await;
+        let ring = records
+            .entry((info_hash, peer_id))
+            .or_insert_with(VecDeque::new);
+
+        if ring.len() == self.capacity {
+            ring.pop_front();
+        }
+        ring.push_back(record);
+    }
+
+    // Returns the peer's announces from oldest to newest
+    pub async fn get(&self, info_hash: InfoHash, peer_id: PeerId) -> Vec<AnnounceRecord> {
+        let records = self.records.read().await;
+        records
+            .get(&(info_hash, peer_id))
+            .map(|ring| ring.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Drops the history of any peer that is no longer in a swarm
+    pub async fn retain_peers(&self, live: &HashSet<PeerKey>) {
+        self.records
+            .write()
+            .await
+            .retain(|key, _| live.contains(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn record(timestamp: u64) -> AnnounceRecord {
+        AnnounceRecord {
+            timestamp,
+            event: "",
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 6881,
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_history_bounded() {
+        let history = AnnounceHistory::new(3);
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer_id = PeerId(*b"-DE9824-143964258012");
+
+        for timestamp in 0..5 {
+            history.record(info_hash, peer_id, record(timestamp)).await;
+        }
+
+        let timestamps: Vec<u64> = history
+            .get(info_hash, peer_id)
+            .await
+            .iter()
+            .map(|r| r.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![2, 3, 4]);
+
+        history.retain_peers(&HashSet::new()).await;
+        assert!(history.get(info_hash, peer_id).await.is_empty());
+    }
+}
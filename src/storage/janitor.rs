@@ -1,116 +1,498 @@
-use crate::bittorrent::Peer;
+use crate::bittorrent::InfoHash;
+use crate::blocklist::Blocklist;
+use crate::config::Config;
 use crate::errors::InternalError;
+use crate::network::rate_limit;
+#[cfg(feature = "actix")]
+use crate::network::upstream;
 use crate::state::State;
+#[cfg(feature = "metrics")]
+use crate::statsd;
 use crate::storage;
+use crate::storage::backend::Backend;
+use crate::storage::history::PeerKey;
+use crate::storage::peer_snapshot;
+use crate::storage::{PeerRecords, PeerStore};
 
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
-use actix_web::web;
-use mysql::*;
+use futures::stream::{self, StreamExt};
+use hashbrown::HashSet;
+
+// How often a job the config has switched off
+// checks whether a reload has switched it back on
+const DISABLED_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+type Job = fn(&mut Janitor, &mut Context<Janitor>);
+
+// How many secs apart a job runs, or None if it's switched off
+type Period = fn(&Config) -> Option<u64>;
+
+// What reaping a shard cleared away, and how long it took
+struct ShardReap {
+    shard: usize,
+    seeds_cleared: usize,
+    leeches_cleared: usize,
+    swarms_dropped: u32,
+    live_peers: HashSet<PeerKey>,
+    elapsed: Duration,
+}
+
+impl ShardReap {
+    fn new(shard: usize) -> ShardReap {
+        ShardReap {
+            shard,
+            seeds_cleared: 0,
+            leeches_cleared: 0,
+            swarms_dropped: 0,
+            live_peers: HashSet::new(),
+            elapsed: Duration::from_secs(0),
+        }
+    }
+}
+
+// Reaps some of a shard's swarms, adding what was cleared to reaped
+fn reap_swarms(
+    records: &mut PeerRecords,
+    info_hashes: &[InfoHash],
+    config: &Config,
+    track_history: bool,
+    reaped: &mut ShardReap,
+) {
+    let mut emptied = Vec::new();
+    for info_hash in info_hashes {
+        let swarm = match records.get_mut(info_hash) {
+            Some(swarm) => swarm,
+            None => continue,
+        };
+        let seeds_1 = swarm.seeders.len();
+        let leeches_1 = swarm.leechers.len() + swarm.partial_seeds.len();
+
+        // Peers are cleared according to the strategy in effect for this torrent
+        let strategy = config.bt.reap_strategy(info_hash);
+        let timeout = Duration::new(config.bt.peer_timeout(strategy), 0);
+
+        swarm.reap(timeout);
+
+        // Partial seeds are still counted as leechers in the statistics
+        reaped.seeds_cleared += seeds_1 - swarm.seeders.len();
+        reaped.leeches_cleared += leeches_1 - swarm.leechers.len() - swarm.partial_seeds.len();
+
+        if track_history {
+            for peer in swarm.peers() {
+                reaped.live_peers.insert((*info_hash, peer.peer_id()));
+            }
+        }
+
+        if swarm.is_empty() && !swarm.is_kept() {
+            emptied.push(*info_hash);
+        }
+    }
+
+    // Swarms without any peers left are dropped so that torrents nobody
+    // announces anymore don't take up memory, unless they're being kept
+    for info_hash in emptied {
+        records.remove(&info_hash);
+        reaped.swarms_dropped += 1;
+    }
+}
+
+// With a chunk size set, the shard's lock is given up after every chunk
+// of swarms, and the next chunk waits for the chunk interval. Chunks are
+// reaped by the shard's writer in single-writer mode.
+async fn reap_shard(
+    index: usize,
+    peer_store: &PeerStore,
+    config: &Arc<Config>,
+    track_history: bool,
+) -> ShardReap {
+    let started = Instant::now();
+    let mut reaped = ShardReap::new(index);
+
+    let shard = peer_store.shards().nth(index).unwrap();
+    let info_hashes: Vec<InfoHash> = shard.read().await.keys().copied().collect();
+    let chunk_size = match config.bt.reap_chunk_size {
+        0 => info_hashes.len().max(1),
+        n => n,
+    };
+    let chunk_interval = Duration::from_millis(config.bt.reap_chunk_interval.max(1));
+
+    for (i, chunk) in info_hashes.chunks(chunk_size).enumerate() {
+        if i > 0 {
+            tokio::time::delay_for(chunk_interval).await;
+        }
+
+        let (chunk, config) = (chunk.to_vec(), config.clone());
+        let partial = peer_store
+            .write_shard(index, None, move |records| {
+                let mut partial = ShardReap::new(index);
+                reap_swarms(records, &chunk, &config, track_history, &mut partial);
+                partial
+            })
+            .await;
+        reaped.seeds_cleared += partial.seeds_cleared;
+        reaped.leeches_cleared += partial.leeches_cleared;
+        reaped.swarms_dropped += partial.swarms_dropped;
+        reaped.live_peers.extend(partial.live_peers);
+    }
+
+    reaped.elapsed = started.elapsed();
+    reaped
+}
 
 #[derive(Clone)]
 pub struct Janitor {
-    reap_interval: Duration,
-    peer_timeout: Duration,
-    flush_interval: Duration,
-    state: web::Data<State>,
-    pool: Pool,
+    state: State,
+    backend: Backend,
+    flushing: Arc<AtomicBool>, // Set while a flush is being written
+    reaping: Arc<AtomicBool>,  // Set while a reap pass is under way
+    #[cfg(feature = "metrics")]
+    statsd: Option<statsd::Exporter>,
 }
 
 impl Janitor {
-    pub fn new(state: web::Data<State>, pool: Pool) -> Janitor {
+    pub fn new(state: State, backend: Backend) -> Janitor {
+        #[cfg(feature = "metrics")]
+        let statsd = {
+            let config = state.config();
+            if config.statsd.address.is_empty() {
+                None
+            } else {
+                statsd::Exporter::new(&config.statsd)
+                    .map_err(|e| error!("{} ({})", InternalError::StatsdConnect.text(), e))
+                    .ok()
+            }
+        };
+
         Janitor {
-            reap_interval: Duration::new(state.config.bt.reap_interval, 0),
-            peer_timeout: Duration::new(state.config.bt.peer_timeout, 0),
-            flush_interval: Duration::new(state.config.bt.flush_interval, 0),
             state,
-            pool,
+            backend,
+            flushing: Arc::new(AtomicBool::new(false)),
+            reaping: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "metrics")]
+            statsd,
         }
     }
 
+    // Runs the job every period, going by the config as it stands before
+    // each run so that reloads apply from the next run on. A period of 0
+    // switches the job off just as None does.
+    fn schedule(&self, ctx: &mut Context<Self>, period: Period, job: Job) {
+        let delay = match period(&self.state.config()) {
+            Some(secs) if secs > 0 => Duration::new(secs, 0),
+            _ => DISABLED_CHECK_INTERVAL,
+        };
+
+        ctx.run_later(delay, move |janitor, ctx| {
+            if matches!(period(&janitor.state.config()), Some(secs) if secs > 0) {
+                job(janitor, ctx);
+            }
+            janitor.schedule(ctx, period, job);
+        });
+    }
+
     // Had to clone self to avoid wacky lifetime error
     fn clear_peers(&mut self, ctx: &mut Context<Self>) {
+        // A pass spread out over chunks can outlast the reap interval
+        if self.reaping.swap(true, Ordering::SeqCst) {
+            warn!("Previous reap is still running, skipping this one.");
+            return;
+        }
+
         let self2 = self.clone();
         ctx.spawn(actix::fut::wrap_future(async move {
+            let config = self2.state.config();
             info!("Clearing away stale peers...");
 
+            // Peers that survive reaping keep their announce history
+            let track_history = self2.state.announce_history.is_enabled();
+
+            // Up to reap_concurrency shards are reaped at once, each locked
+            // only while it's being reaped, so announces to the other shards
+            // carry on meanwhile
+            let started = Instant::now();
+            let concurrency = config.bt.reap_concurrency.max(1);
+            let peer_store = &self2.state.peer_store;
+            let mut reaps = stream::iter(0..peer_store.shards().count())
+                .map(|i| reap_shard(i, peer_store, &config, track_history))
+                .buffer_unordered(concurrency);
+
             let mut seeds_cleared = 0;
             let mut leeches_cleared = 0;
+            let mut swarms_dropped = 0;
+            let mut live_peers = HashSet::new();
+            let mut slowest_shard = Duration::from_secs(0);
+            while let Some(reaped) = reaps.next().await {
+                debug!(
+                    "Reaped shard {} in {} ms: cleared {} seeders and {} leechers.",
+                    reaped.shard,
+                    reaped.elapsed.as_millis(),
+                    reaped.seeds_cleared,
+                    reaped.leeches_cleared
+                );
+                seeds_cleared += reaped.seeds_cleared;
+                leeches_cleared += reaped.leeches_cleared;
+                swarms_dropped += reaped.swarms_dropped;
+                live_peers.extend(reaped.live_peers);
+                slowest_shard = slowest_shard.max(reaped.elapsed);
+            }
 
-            let info_hashes: Vec<String> = self2
-                .state
-                .peer_store
-                .records
-                .read()
-                .await
-                .iter()
-                .map(|(info_hash, _)| info_hash.clone())
-                .collect();
+            if track_history {
+                self2.state.announce_history.retain_peers(&live_peers).await;
+            }
+
+            // Make sure that stats are up-to-date
+            // TODO: Getting E0495 all over this thing
+            {
+                let mut stats = self2.state.stats.write().await;
+                stats.reaped(
+                    swarms_dropped,
+                    seeds_cleared as u32,
+                    leeches_cleared as u32,
+                );
+                stats.reap_timing(started.elapsed(), slowest_shard);
+            }
 
-            for info_hash in info_hashes {
-                if let Some(swarm) = self2
+            // Keep the swarms within their limits once the stale peers are gone
+            let (max_swarms, max_peers) = (config.bt.max_swarms, config.bt.max_peers);
+            if max_swarms > 0 || max_peers > 0 {
+                let evicted = self2
                     .state
                     .peer_store
-                    .records
-                    .write()
-                    .await
-                    .get_mut(&info_hash)
-                {
-                    let seeds_1 = swarm.seeders.len();
-                    let leeches_1 = swarm.leechers.len();
+                    .evict_lru(max_swarms, max_peers)
+                    .await;
 
-                    swarm.seeders.retain(|peer| match peer {
-                        Peer::V4(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                        Peer::V6(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                    });
-                    swarm.leechers.retain(|peer| match peer {
-                        Peer::V4(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                        Peer::V6(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                    });
+                // Partial seeds are still counted as leechers in the statistics
+                let (mut seeders, mut leechers) = (0, 0);
+                for swarm in &evicted {
+                    self2.state.torrent_store.remove_peers(swarm).await;
+                    seeders += swarm.seeders;
+                    leechers += swarm.leechers + swarm.partial_seeds;
+                }
 
-                    seeds_cleared += seeds_1 - swarm.seeders.len();
-                    leeches_cleared += leeches_1 - swarm.leechers.len();
+                if !evicted.is_empty() {
+                    let swarms = evicted.len() as u32;
+                    self2
+                        .state
+                        .stats
+                        .write()
+                        .await
+                        .evicted(swarms, seeders, leechers);
+                    warn!(
+                        "Evicted {} idle swarms ({} peers) to stay within limits.",
+                        swarms,
+                        seeders + leechers
+                    );
                 }
             }
 
-            // Make sure that stats are up-to-date
-            // TODO: Getting E0495 all over this thing
-            self2
-                .state
-                .stats
-                .write()
-                .await
-                .cleared_peers(seeds_cleared as u32, leeches_cleared as u32);
-
             info!(
-                "Cleared {} seeders and {} leechers.",
-                seeds_cleared, leeches_cleared
+                "Cleared {} seeders and {} leechers, and dropped {} empty swarms in {} ms (slowest shard {} ms).",
+                seeds_cleared,
+                leeches_cleared,
+                swarms_dropped,
+                started.elapsed().as_millis(),
+                slowest_shard.as_millis()
             );
+            self2.reaping.store(false, Ordering::SeqCst);
         }));
     }
 
     // Had to clone self to avoid wacky lifetime error
     fn flush(&mut self, ctx: &mut Context<Self>) {
+        // A slow database shouldn't have flushes piling up behind each other
+        if self.flushing.swap(true, Ordering::SeqCst) {
+            warn!("Previous flush is still running, skipping this one.");
+            return;
+        }
+
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let config = self2.state.config();
+            info!("Flushing changed torrents to database...");
+
+            // Only torrents that changed since the last flush are written
+            let torrents = self2.state.torrent_store.take_dirty().await;
+            let info_hashes: Vec<InfoHash> = torrents.iter().map(|t| t.info_hash).collect();
+            let num_torrents = torrents.len();
+
+            match self2.backend.flush_torrents(torrents).await {
+                Ok(_) => info!("Flushed {} torrents.", num_torrents),
+                Err(e) => {
+                    error!("{} ({})", InternalError::StorageTorrentFlush.text(), e);
+                    self2.state.torrent_store.restore_dirty(info_hashes);
+                }
+            }
+
+            // Users' traffic is only known when announces carry passkeys
+            if config.private.enabled {
+                let traffic = self2.state.user_store.take_traffic();
+                let num_users = traffic.len();
+                match self2.backend.flush_user_traffic(traffic.clone()).await {
+                    Ok(_) => info!("Flushed traffic for {} users.", num_users),
+                    Err(e) => {
+                        error!("{} ({})", InternalError::StorageUserFlush.text(), e);
+                        self2.state.user_store.restore_traffic(traffic);
+                    }
+                }
+
+                let snatches = self2.state.user_store.take_snatches();
+                let num_snatches = snatches.len();
+                match self2.backend.save_snatches(snatches.clone()).await {
+                    Ok(_) => info!("Saved {} snatches.", num_snatches),
+                    Err(e) => {
+                        error!("{} ({})", InternalError::StorageSnatchSave.text(), e);
+                        self2.state.user_store.restore_snatches(snatches);
+                    }
+                }
+            }
+
+            if config.storage.persists_peers() {
+                let peers = peer_snapshot::to_saved(self2.state.peer_store.dump().await);
+                let num_peers = peers.len();
+                match self2
+                    .backend
+                    .save_peers(peers, config.bt.peer_timeout)
+                    .await
+                {
+                    Ok(_) => info!("Saved {} peers to database.", num_peers),
+                    Err(e) => error!("{} ({})", InternalError::StoragePeerSave.text(), e),
+                }
+            }
+
+            self2.flushing.store(false, Ordering::SeqCst);
+        }));
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    fn save_peers(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let config = self2.state.config();
+            let path = &config.peer_snapshot.path;
+            match storage::peer_snapshot::save(path, &self2.state.peer_store).await {
+                Ok(num_peers) => debug!("Saved {} peers to {}.", num_peers, path),
+                Err(e) => error!("{} ({})", InternalError::StoragePeerSave.text(), e),
+            }
+        }));
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    fn take_snapshot(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let torrents = self2.state.torrent_store.records().await;
+            let stats = self2.state.stats.read().await.clone();
+            let num_torrents = torrents.len();
+
+            self2.state.snapshot.replace(torrents, stats);
+            debug!("Took snapshot of {} torrents.", num_torrents);
+        }));
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    fn rollover_stats(&mut self, ctx: &mut Context<Self>) {
         let self2 = self.clone();
         ctx.spawn(actix::fut::wrap_future(async move {
-            info!("Flushing torrents to database...");
+            let archived = self2.state.stats.write().await.rollover();
+
+            match self2.backend.archive_stats(&archived).await {
+                Ok(_) => info!(
+                    "Archived statistics: {} announces, {} scrapes.",
+                    archived.announce_requests, archived.scrapes
+                ),
+                _ => error!("{}", InternalError::StorageStatsArchive.text()),
+            }
 
-            let torrents: Vec<storage::Torrent> = self2
+            self2.state.stats_history.write().await.push(archived);
+        }));
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    #[cfg(feature = "metrics")]
+    fn push_metrics(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            if let Some(exporter) = &self2.statsd {
+                let stats = self2.state.stats.read().await;
+                if let Err(e) = exporter.push(&stats) {
+                    error!("{} ({})", InternalError::StatsdSend.text(), e);
+                }
+            }
+        }));
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    #[cfg(feature = "actix")]
+    fn scrape_upstream(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let config = self2.state.config();
+            info!("Scraping upstream trackers...");
+
+            let info_hashes: Vec<InfoHash> = self2
                 .state
                 .torrent_store
                 .torrents
                 .read()
                 .await
-                .iter()
-                .map(|(_, torrent)| torrent.clone())
+                .keys()
+                .copied()
                 .collect();
 
-            let num_torrents = torrents.len();
+            let records = upstream::scrape_all(&config.upstream, info_hashes).await;
+            let (seeders, leechers) = records
+                .values()
+                .fold((0, 0), |(s, l), c| (s + c.complete, l + c.incomplete));
 
-            let _result = storage::mysql::flush_torrents(self2.pool, torrents);
+            {
+                let mut stats = self2.state.stats.write().await;
+                stats.upstream_seeders = seeders;
+                stats.upstream_leechers = leechers;
+            }
 
-            info!("Flushed {} torrents.", num_torrents);
+            info!("Scraped {} torrents from upstream trackers.", records.len());
+            *self2.state.upstream.write().await = records;
+        }));
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    fn refresh_blocklists(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let config = self2.state.config();
+            let mut blocklists = Vec::new();
+
+            for source in &config.blocklists.sources {
+                match Blocklist::load(source).await {
+                    Ok(blocklist) => {
+                        info!(
+                            "Loaded blocklist {} with {} ranges.",
+                            &blocklist.name,
+                            blocklist.ranges.len()
+                        );
+                        blocklists.push(blocklist);
+                    }
+                    Err(e) => error!(
+                        "{} ({}: {})",
+                        InternalError::BlocklistLoad.text(),
+                        &source.name,
+                        e
+                    ),
+                }
+            }
+
+            // A list that failed to load keeps its previous ranges
+            let mut current = self2.state.blocklists.write().await;
+            for old in current.drain(..) {
+                if !blocklists.iter().any(|b| b.name == old.name) {
+                    blocklists.push(old);
+                }
+            }
+            *current = blocklists;
         }));
     }
 
@@ -119,22 +501,62 @@ impl Janitor {
         ctx.spawn(actix::fut::wrap_future(async move {
             info!("Fetching new torrents from database...");
 
-            match storage::mysql::get_torrents(self2.pool) {
+            match self2.backend.get_torrents().await {
                 Ok(db_torrents) => {
-                    let mut diff = 0;
-                    let mut torrent_store = self2.state.torrent_store.torrents.write().await;
-                    for (info_hash, torrent) in db_torrents.iter() {
-                        if !torrent_store.contains_key(&info_hash[..].to_string()) {
-                            torrent_store.insert(info_hash.clone(), torrent.clone());
-                            diff += 1;
-                        }
-                    }
+                    let diff = self2
+                        .state
+                        .torrent_store
+                        .register(db_torrents.into_iter().map(|(_, torrent)| torrent))
+                        .await;
                     info!("Added new {} torrents from database.", diff);
                 }
                 _ => error!("{}", InternalError::StorageTorrentFetchNew.text()),
             }
         }));
     }
+
+    // List files are small, so they're simply reread in place
+    fn refresh_client_list(&mut self, _ctx: &mut Context<Self>) {
+        let config = self.state.config();
+        self.state.client_approval.reload(&config.client_approval);
+    }
+
+    fn refresh_torrent_list(&mut self, _ctx: &mut Context<Self>) {
+        let config = self.state.config();
+        self.state.torrent_approval.reload(&config.torrent_approval);
+    }
+
+    fn sweep_rate_limits(&mut self, _ctx: &mut Context<Self>) {
+        let limit = &self.state.config().rate_limit;
+        if limit.requests_per_sec > 0.0 {
+            let kept = self
+                .state
+                .rate_limiter
+                .sweep(limit.requests_per_sec, limit.burst);
+            debug!("Kept rate limits for {} addresses.", kept);
+        }
+        if limit.passkey_requests_per_sec > 0.0 {
+            let kept = self
+                .state
+                .passkey_rate_limiter
+                .sweep(limit.passkey_requests_per_sec, limit.passkey_burst);
+            debug!("Kept rate limits for {} passkeys.", kept);
+        }
+    }
+
+    // Had to clone self to avoid wacky lifetime error
+    fn fetch_users(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            match self2.backend.get_users().await {
+                Ok(users) => {
+                    debug!("Reloaded {} users from database.", users.len());
+                    self2.state.user_store.replace(users).await;
+                }
+                Err(e) => error!("{} ({})", InternalError::StorageUserLoad.text(), e),
+            }
+        }));
+    }
 }
 
 impl Actor for Janitor {
@@ -142,20 +564,215 @@ impl Actor for Janitor {
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         info!("Janitor is now on duty...");
+        let config = self.state.config();
 
         // This will go through all of the swarms and remove
         // any peers that have not announced in a defined time
-        ctx.run_interval(self.reap_interval, Self::clear_peers);
+        self.schedule(ctx, |c| Some(c.bt.reap_interval), Self::clear_peers);
 
         // This will flush all torrent data to the database
         // to ensure that stats are up-to-date
-        ctx.run_interval(self.flush_interval, Self::flush);
+        self.schedule(ctx, |c| Some(c.bt.flush_interval), Self::flush);
+
+        // This will refresh the read-only copy of the
+        // torrent stats that scrapes are served from
+        if config.bt.snapshot_interval > 0 {
+            self.take_snapshot(ctx);
+        }
+        self.schedule(ctx, |c| Some(c.bt.snapshot_interval), Self::take_snapshot);
+
+        // This will save the swarms so that
+        // they survive a restart of the tracker
+        self.schedule(
+            ctx,
+            |c| {
+                if c.peer_snapshot.path.is_empty() {
+                    None
+                } else {
+                    Some(c.peer_snapshot.interval)
+                }
+            },
+            Self::save_peers,
+        );
+
+        // This will archive the statistics for each period
+        // so that operators have a history of tracker activity
+        self.schedule(
+            ctx,
+            |c| c.bt.stats_rollover.interval(),
+            Self::rollover_stats,
+        );
+
+        // This will push the statistics to StatsD for
+        // operators that monitor the tracker that way
+        #[cfg(feature = "metrics")]
+        if self.statsd.is_some() {
+            self.schedule(ctx, |c| Some(c.statsd.interval), Self::push_metrics);
+        }
+
+        // This will refresh the seeder and leecher
+        // counts reported by upstream trackers
+        #[cfg(feature = "actix")]
+        {
+            if !config.upstream.trackers.is_empty() {
+                self.scrape_upstream(ctx);
+            }
+            self.schedule(
+                ctx,
+                |c| {
+                    if c.upstream.trackers.is_empty() {
+                        None
+                    } else {
+                        Some(c.upstream.interval)
+                    }
+                },
+                Self::scrape_upstream,
+            );
+        }
+
+        // This will reload the address blocklists from their sources
+        if !config.blocklists.sources.is_empty() {
+            self.refresh_blocklists(ctx);
+        }
+        self.schedule(
+            ctx,
+            |c| {
+                if c.blocklists.sources.is_empty() {
+                    None
+                } else {
+                    Some(c.blocklists.refresh_interval)
+                }
+            },
+            Self::refresh_blocklists,
+        );
+
+        // This will pick up changes to the approval list files
+        self.schedule(
+            ctx,
+            |c| {
+                if c.client_approval.list_file.is_empty() {
+                    None
+                } else {
+                    Some(c.client_approval.refresh_interval)
+                }
+            },
+            Self::refresh_client_list,
+        );
+        self.schedule(
+            ctx,
+            |c| {
+                if c.torrent_approval.list_file.is_empty() {
+                    None
+                } else {
+                    Some(c.torrent_approval.refresh_interval)
+                }
+            },
+            Self::refresh_torrent_list,
+        );
+
+        // This will forget clients that have stayed
+        // under the rate limit for long enough
+        self.schedule(
+            ctx,
+            |c| {
+                if c.rate_limit.requests_per_sec > 0.0
+                    || c.rate_limit.passkey_requests_per_sec > 0.0
+                {
+                    Some(rate_limit::SWEEP_INTERVAL)
+                } else {
+                    None
+                }
+            },
+            Self::sweep_rate_limits,
+        );
+
+        // This will pick up users that the site software
+        // has added or disabled since the last refresh
+        self.schedule(
+            ctx,
+            |c| {
+                if c.private.enabled {
+                    Some(c.private.refresh_interval)
+                } else {
+                    None
+                }
+            },
+            Self::fetch_users,
+        );
 
         // This will pull any new torrents from the database
         // and add them to the torrent store
-        ctx.run_interval(
-            Duration::new(self.state.config.bt.announce_rate, 0),
-            Self::fetch_new_torrents,
-        );
+        self.schedule(ctx, |c| Some(c.bt.announce_rate), Self::fetch_new_torrents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::{Peer, PeerId};
+
+    #[actix_rt::test]
+    async fn reap_shards_in_chunks() {
+        let mut config = Config::default();
+        config.bt.reap_chunk_size = 1;
+        let config = Arc::new(config);
+        let peer_store = PeerStore::with_shards(2);
+
+        let peer = |peer_id: &[u8; 20], port| {
+            Peer::new(PeerId(*peer_id), "127.0.0.1".parse().unwrap(), port, 0, 0)
+        };
+        for info_hash in &[*b"A1B2C3D4E5F6G7H8I9J0", *b"B2C3D4E5F6G7H8I9J0K1"] {
+            let info_hash = InfoHash(*info_hash);
+            peer_store
+                .put_seeder(info_hash, peer(b"-DE9824-143964258012", 6881))
+                .await;
+            peer_store
+                .put_leecher(info_hash, peer(b"-qB4250-143964258012", 6882))
+                .await;
+        }
+
+        let mut stale = peer(b"-TR2940-143964258012", 6883);
+        stale.set_last_announced(Instant::now() - Duration::new(config.bt.peer_timeout + 1, 0));
+        peer_store
+            .put_seeder(InfoHash(*b"C3D4E5F6G7H8I9J0K1L2"), stale)
+            .await;
+
+        let mut seeds_cleared = 0;
+        let mut swarms_dropped = 0;
+        let mut live_peers = HashSet::new();
+        for i in 0..2 {
+            let reaped = reap_shard(i, &peer_store, &config, true).await;
+            assert_eq!(reaped.shard, i);
+            seeds_cleared += reaped.seeds_cleared;
+            swarms_dropped += reaped.swarms_dropped;
+            live_peers.extend(reaped.live_peers);
+        }
+
+        // Only the stale swarm goes, however the swarms were split up
+        assert_eq!((seeds_cleared, swarms_dropped), (1, 1));
+        assert_eq!(live_peers.len(), 4);
+        assert_eq!(peer_store.dump().await.len(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn reap_keeps_created_swarms() {
+        let config = Arc::new(Config::default());
+        let peer_store = PeerStore::new();
+        let kept = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let expired = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+
+        let created = peer_store
+            .create_swarms(vec![kept], Duration::from_secs(60))
+            .await;
+        assert_eq!(created, 1);
+        peer_store
+            .create_swarms(vec![kept, expired], Duration::from_secs(0))
+            .await;
+
+        // Only the swarm still within its keep time survives being empty
+        let reaped = reap_shard(0, &peer_store, &config, false).await;
+        assert_eq!(reaped.swarms_dropped, 1);
+        assert!(peer_store.shard(&kept).read().await.contains_key(&kept));
     }
 }
@@ -0,0 +1,146 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+
+use crate::bittorrent::InfoHash;
+
+// Announces are counted over windows of this length
+const WINDOW: Duration = Duration::from_secs(60);
+
+struct Windows {
+    started: Instant,
+    current: HashMap<InfoHash, u32>,
+    previous: HashMap<InfoHash, u32>,
+}
+
+impl Windows {
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed < WINDOW {
+            return;
+        }
+
+        // A window without any announces leaves nothing to carry over
+        self.previous = if elapsed < WINDOW * 2 {
+            std::mem::take(&mut self.current)
+        } else {
+            self.current.clear();
+            HashMap::new()
+        };
+        self.started = now - Duration::from_nanos((elapsed.as_nanos() % WINDOW.as_nanos()) as u64);
+    }
+
+    // Announces over the last minute, estimated by taking the part of the
+    // previous window that still falls within it along with the current one
+    fn recent(&self, now: Instant, info_hash: &InfoHash) -> f64 {
+        let into_window =
+            now.saturating_duration_since(self.started).as_secs_f64() / WINDOW.as_secs_f64();
+        let previous = self.previous.get(info_hash).copied().unwrap_or(0);
+        let current = self.current.get(info_hash).copied().unwrap_or(0);
+        f64::from(previous) * (1.0 - into_window.min(1.0)) + f64::from(current)
+    }
+}
+
+// Keeps the number of recent announces to each torrent, so the busiest
+// ones can be found without going through every swarm. Only torrents
+// announced to in the last two minutes take up any room.
+#[derive(Clone)]
+pub struct Leaderboard {
+    windows: Arc<Mutex<Windows>>,
+}
+
+impl Leaderboard {
+    pub fn new() -> Leaderboard {
+        Leaderboard {
+            windows: Arc::new(Mutex::new(Windows {
+                started: Instant::now(),
+                current: HashMap::new(),
+                previous: HashMap::new(),
+            })),
+        }
+    }
+
+    fn record_at(&self, info_hash: InfoHash, now: Instant) {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(now);
+        *windows.current.entry(info_hash).or_insert(0) += 1;
+    }
+
+    pub fn record(&self, info_hash: InfoHash) {
+        self.record_at(info_hash, Instant::now());
+    }
+
+    fn recent_at(&self, info_hash: &InfoHash, now: Instant) -> f64 {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(now);
+        windows.recent(now, info_hash)
+    }
+
+    pub fn recent(&self, info_hash: &InfoHash) -> f64 {
+        self.recent_at(info_hash, Instant::now())
+    }
+
+    fn top_at(&self, limit: usize, now: Instant) -> Vec<(InfoHash, f64)> {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(now);
+
+        let mut top: Vec<(InfoHash, f64)> = windows
+            .previous
+            .keys()
+            .chain(windows.current.keys())
+            .map(|info_hash| (*info_hash, windows.recent(now, info_hash)))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+        top.dedup_by_key(|(info_hash, _)| *info_hash);
+        top.retain(|(_, recent)| *recent > 0.0);
+        top.truncate(limit);
+        top
+    }
+
+    // The torrents announced to most over the last minute, busiest first
+    pub fn top(&self, limit: usize) -> Vec<(InfoHash, f64)> {
+        self.top_at(limit, Instant::now())
+    }
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        Leaderboard::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaderboard_ranks_recent_announces() {
+        let leaderboard = Leaderboard::new();
+        let busy = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let quiet = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let start = leaderboard.windows.lock().unwrap().started;
+
+        for _ in 0..4 {
+            leaderboard.record_at(busy, start);
+        }
+        leaderboard.record_at(quiet, start);
+        assert_eq!(
+            leaderboard.top_at(10, start),
+            vec![(busy, 4.0), (quiet, 1.0)]
+        );
+        assert_eq!(leaderboard.top_at(1, start), vec![(busy, 4.0)]);
+
+        // Halfway into the next window, half of the previous one still counts
+        let later = start + WINDOW + WINDOW / 2;
+        leaderboard.record_at(quiet, later);
+        assert_eq!(leaderboard.recent_at(&busy, later), 2.0);
+        assert_eq!(
+            leaderboard.top_at(10, later),
+            vec![(busy, 2.0), (quiet, 1.5)]
+        );
+
+        // Torrents nobody announces to drop off altogether
+        assert!(leaderboard.top_at(10, start + WINDOW * 4).is_empty());
+    }
+}
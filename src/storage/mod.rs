@@ -1,136 +1,472 @@
+pub mod backend;
+pub mod history;
 pub mod janitor;
+pub mod leaderboard;
+#[cfg(feature = "mysql")]
 pub mod mysql;
-
-use std::sync::Arc;
-
+pub mod peer_snapshot;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod response_cache;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod users;
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{self, Arc};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
 use hashbrown::{HashMap, HashSet};
-use rand::seq::SliceRandom;
+use rand::seq::index;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-
-use crate::bittorrent::ScrapeFile;
-use crate::bittorrent::{CompactPeer, CompactPeerv4, CompactPeerv6, Peer};
-
-#[derive(Debug, Clone)]
-struct PeerList(Vec<CompactPeer>);
-
-// Wasn't a huge fan of this, but couldn't do it using FromIterator
-impl PeerList {
-    fn new() -> PeerList {
-        PeerList(Vec::new())
-    }
+use tokio::sync::mpsc::{self, error::SendError, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, RwLock};
 
-    fn make_random(&mut self, numwant: u32) {
-        // If the total amount of peers is less than numwant,
-        // just return the entire list of peers
-        if self.0.len() > numwant as usize {
-            // Otherwise, choose a random sampling and send it
-            let mut rng = &mut rand::thread_rng();
-            self.0.shuffle(&mut rng);
-            self.0.truncate(numwant as usize);
-        }
-    }
-}
+use crate::bittorrent::{CompactPeerv4, CompactPeerv6, Peer, PeerId};
+use crate::bittorrent::{InfoHash, ScrapeFile};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Torrent {
-    pub info_hash: String,
+    pub info_hash: InfoHash,
     pub complete: u32,   // Number of seeders
     pub downloaded: u32, // Amount of Event::Complete as been received
     pub incomplete: u32, // Number of leechers
-    pub balance: u32,    // Total traffic for this torrent
+    #[serde(default)]
+    pub partial: u32, // Number of partial seeds (BEP 21)
+    pub balance: u64,    // Total traffic for this torrent
+    #[serde(default)]
+    pub bytes_uploaded: u64, // Uploaded by its peers, out of the balance
+    #[serde(default)]
+    pub bytes_downloaded: u64, // Downloaded by its peers, out of the balance
+    #[serde(default)]
+    pub category: Option<String>, // Set when the torrent is registered
+    #[serde(default)]
+    pub hidden: bool, // Left out of scrapes, but can still be announced
 }
 
 impl Torrent {
     pub fn new(
-        info_hash: String,
+        info_hash: InfoHash,
         complete: u32,
         downloaded: u32,
         incomplete: u32,
-        balance: u32,
+        balance: u64,
     ) -> Torrent {
         Torrent {
             info_hash,
             complete,
             downloaded,
             incomplete,
+            partial: 0,
             balance,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            category: None,
+            hidden: false,
+        }
+    }
+}
+
+pub type TorrentRecords = HashMap<InfoHash, Torrent>;
+
+// Used when re-seeding tracker state in bulk, where
+// each peer's role in the swarm is known up front.
+#[derive(Debug, Clone)]
+pub struct ImportedPeer {
+    pub info_hash: InfoHash,
+    pub peer: Peer,
+    pub seeder: bool,
+}
+
+// A swarm dropped to keep within the limits, and the peers it held
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvictedSwarm {
+    pub info_hash: InfoHash,
+    pub seeders: u32,
+    pub leechers: u32,
+    pub partial_seeds: u32,
+}
+
+// Shared between the live store and read-only snapshots
+pub fn scrapes_from(torrents: &TorrentRecords, info_hashes: Vec<Vec<u8>>) -> Vec<ScrapeFile> {
+    let mut scrapes = Vec::new();
+
+    // Hidden torrents are omitted just like unknown ones
+    // so that they can't be enumerated through scrapes. Files
+    // are reported under the hash the client asked for, which
+    // may be the full form of a v2 hash.
+    for info_hash in info_hashes {
+        let torrent = InfoHash::from_bytes(&info_hash).and_then(|h| torrents.get(&h));
+        if let Some(t) = torrent.filter(|t| !t.hidden) {
+            scrapes.push(ScrapeFile {
+                info_hash,
+                complete: t.complete,
+                downloaded: t.downloaded,
+                incomplete: t.incomplete,
+                name: None,
+                upstream: None,
+            });
         }
     }
+
+    scrapes
+}
+
+// Every visible torrent, in the order their keys have to be bencoded
+pub fn all_scrapes_from(torrents: &TorrentRecords) -> Vec<ScrapeFile> {
+    let mut scrapes: Vec<ScrapeFile> = torrents
+        .values()
+        .filter(|t| !t.hidden)
+        .map(|t| ScrapeFile {
+            info_hash: t.info_hash.0.to_vec(),
+            complete: t.complete,
+            downloaded: t.downloaded,
+            incomplete: t.incomplete,
+            name: None,
+            upstream: None,
+        })
+        .collect();
+
+    scrapes.sort_unstable_by(|a, b| a.info_hash.cmp(&b.info_hash));
+    scrapes
 }
 
-pub type TorrentRecords = HashMap<String, Torrent>;
+impl Default for TorrentStore {
+    fn default() -> TorrentStore {
+        TorrentStore::new(TorrentRecords::new())
+    }
+}
 
 // TorrentStore needs to be wrapped in a RwLock or other exclusion
 // primitive in order to prevent data races. This is further wrapped
 // in an atomic reference counter in order to make it thread-safe.
+//
+// With a RocksDB tier, only the torrents in use are held in torrents.
+// The rest are read through from the tier when they're asked for, and
+// the ones used least lately go back to it once there are too many.
 #[derive(Debug, Clone)]
 pub struct TorrentStore {
     pub torrents: Arc<RwLock<TorrentRecords>>,
+    // A synchronous view of which torrents are registered, kept so
+    // that request middleware can check it without awaiting a lock
+    registered: Arc<sync::RwLock<HashSet<InfoHash>>>,
+    // Torrents changed since they were last flushed to the database
+    dirty: Arc<sync::Mutex<HashSet<InfoHash>>>,
+    #[cfg(feature = "rocksdb")]
+    tier: Option<rocksdb::TorrentTier>,
 }
 
 impl TorrentStore {
     pub fn new(torrent_records: TorrentRecords) -> TorrentStore {
+        let registered = torrent_records.keys().copied().collect();
         TorrentStore {
             torrents: Arc::new(RwLock::new(torrent_records)),
+            registered: Arc::new(sync::RwLock::new(registered)),
+            dirty: Arc::new(sync::Mutex::new(HashSet::new())),
+            #[cfg(feature = "rocksdb")]
+            tier: None,
         }
     }
 
-    pub fn default() -> TorrentStore {
-        TorrentStore {
-            torrents: Arc::new(RwLock::new(TorrentRecords::new())),
+    // Every torrent starts out in the tier, and is only held in memory once used
+    #[cfg(feature = "rocksdb")]
+    pub fn with_tier(
+        torrent_records: TorrentRecords,
+        tier: rocksdb::TorrentTier,
+    ) -> Result<TorrentStore, String> {
+        tier.put_all(torrent_records.values())?;
+        let mut store = TorrentStore::new(TorrentRecords::new());
+        store.tier = Some(tier);
+        Ok(store)
+    }
+
+    #[cfg(feature = "rocksdb")]
+    async fn load(&self, info_hashes: &[InfoHash]) {
+        let tier = match &self.tier {
+            Some(tier) => tier,
+            None => return,
+        };
+        tier.touch(info_hashes);
+
+        let loaded: Vec<Torrent> = {
+            let torrents = self.torrents.read().await;
+            info_hashes
+                .iter()
+                .filter(|info_hash| !torrents.contains_key(*info_hash))
+                .filter_map(|info_hash| tier.get(info_hash))
+                .collect()
+        };
+        if loaded.is_empty() {
+            return;
         }
+
+        let mut torrents = self.torrents.write().await;
+        let mut registered = self.registered.write().unwrap();
+        for torrent in loaded {
+            registered.insert(torrent.info_hash);
+            torrents.entry(torrent.info_hash).or_insert(torrent);
+        }
+        for info_hash in tier.evict(&mut torrents) {
+            registered.remove(&info_hash);
+        }
+    }
+
+    #[cfg(not(feature = "rocksdb"))]
+    async fn load(&self, _: &[InfoHash]) {}
+
+    // Torrents that are only in the tier, rather than in memory
+    #[cfg(feature = "rocksdb")]
+    fn evicted(&self, info_hash: &InfoHash) -> Option<Torrent> {
+        self.tier.as_ref().and_then(|tier| tier.get(info_hash))
+    }
+
+    #[cfg(not(feature = "rocksdb"))]
+    fn evicted(&self, _: &InfoHash) -> Option<Torrent> {
+        None
+    }
+
+    // Every torrent, whether it's in memory or only in the tier
+    pub async fn records(&self) -> TorrentRecords {
+        #[cfg(feature = "rocksdb")]
+        let mut records = match &self.tier {
+            Some(tier) => tier.records(),
+            None => TorrentRecords::new(),
+        };
+        #[cfg(not(feature = "rocksdb"))]
+        let mut records = TorrentRecords::new();
+
+        for (info_hash, torrent) in self.torrents.read().await.iter() {
+            records.insert(*info_hash, torrent.clone());
+        }
+        records
+    }
+
+    pub fn is_registered(&self, info_hash: &InfoHash) -> bool {
+        self.registered.read().unwrap().contains(info_hash) || self.evicted(info_hash).is_some()
     }
 
-    pub async fn get_scrapes(&self, info_hashes: Vec<String>) -> Vec<ScrapeFile> {
+    fn mark_dirty(&self, info_hash: InfoHash) {
+        self.dirty.lock().unwrap().insert(info_hash);
+    }
+
+    // Hands out the torrents changed since the last call, and forgets
+    // that they changed. Flushes that fail should give them back through
+    // restore_dirty so that they're written next time.
+    pub async fn take_dirty(&self) -> Vec<Torrent> {
         let torrents = self.torrents.read().await;
-        let mut scrapes = Vec::new();
-
-        for info_hash in info_hashes {
-            if let Some(t) = torrents.get(&info_hash) {
-                scrapes.push(ScrapeFile {
-                    info_hash: info_hash.clone(),
-                    complete: t.complete,
-                    downloaded: t.downloaded,
-                    incomplete: t.incomplete,
-                    name: None,
-                });
-            }
+        let dirty: Vec<InfoHash> = self.dirty.lock().unwrap().drain().collect();
+        dirty
+            .iter()
+            .filter_map(|info_hash| {
+                torrents
+                    .get(info_hash)
+                    .cloned()
+                    .or_else(|| self.evicted(info_hash))
+            })
+            .collect()
+    }
+
+    pub fn restore_dirty(&self, info_hashes: impl IntoIterator<Item = InfoHash>) {
+        self.dirty.lock().unwrap().extend(info_hashes);
+    }
+
+    pub async fn get_scrapes(&self, info_hashes: Vec<Vec<u8>>) -> Vec<ScrapeFile> {
+        let requested: Vec<InfoHash> = info_hashes
+            .iter()
+            .filter_map(|info_hash| InfoHash::from_bytes(info_hash))
+            .collect();
+        self.load(&requested).await;
+
+        let torrents = self.torrents.read().await;
+        scrapes_from(&torrents, info_hashes)
+    }
+
+    pub async fn get_all_scrapes(&self) -> Vec<ScrapeFile> {
+        #[cfg(feature = "rocksdb")]
+        if self.tier.is_some() {
+            return all_scrapes_from(&self.records().await);
         }
 
-        scrapes
+        let torrents = self.torrents.read().await;
+        all_scrapes_from(&torrents)
     }
 
-    // Announces only require complete and incomplete
-    pub async fn get_announce_stats(&self, info_hash: String) -> (u32, u32) {
+    // Announces only require complete, incomplete, and optionally downloaded
+    pub async fn get_announce_stats(&self, info_hash: InfoHash) -> (u32, u32, u32) {
+        self.load(&[info_hash]).await;
         let torrents = self.torrents.read().await;
-        let mut complete: u32 = 0;
-        let mut incomplete: u32 = 0;
 
-        if let Some(t) = torrents.get(&info_hash) {
-            complete = t.complete;
-            incomplete = t.incomplete;
+        match torrents.get(&info_hash) {
+            Some(t) => (t.complete, t.downloaded, t.incomplete),
+            None => (0, 0, 0),
         }
+    }
 
-        (complete, incomplete)
+    pub async fn get_category(&self, info_hash: &InfoHash) -> Option<String> {
+        self.load(&[*info_hash]).await;
+        let torrents = self.torrents.read().await;
+        torrents.get(info_hash).and_then(|t| t.category.clone())
     }
 
-    pub async fn new_seed(&self, info_hash: String) {
+    pub async fn new_seed(&self, info_hash: InfoHash) {
+        self.load(&[info_hash]).await;
         let mut torrents = self.torrents.write().await;
         if let Some(t) = torrents.get_mut(&info_hash) {
             t.complete += 1;
+            t.downloaded += 1;
             t.incomplete = t.incomplete.saturating_sub(1);
+            self.mark_dirty(info_hash);
+        }
+    }
+
+    // A seeder that joined without having been seen to complete
+    pub async fn add_seed(&self, info_hash: InfoHash) {
+        self.load(&[info_hash]).await;
+        let mut torrents = self.torrents.write().await;
+        if let Some(t) = torrents.get_mut(&info_hash) {
+            t.complete += 1;
+            self.mark_dirty(info_hash);
         }
     }
 
-    pub async fn new_leech(&self, info_hash: String) {
+    pub async fn new_leech(&self, info_hash: InfoHash) {
+        self.load(&[info_hash]).await;
         let mut torrents = self.torrents.write().await;
         if let Some(t) = torrents.get_mut(&info_hash) {
             t.incomplete += 1;
+            self.mark_dirty(info_hash);
+        }
+    }
+
+    // Partial seeds aren't counted as leechers, as they aren't downloading
+    pub async fn new_partial_seed(&self, info_hash: InfoHash, was_leecher: bool) {
+        self.load(&[info_hash]).await;
+        let mut torrents = self.torrents.write().await;
+        if let Some(t) = torrents.get_mut(&info_hash) {
+            t.partial += 1;
+            if was_leecher {
+                t.incomplete = t.incomplete.saturating_sub(1);
+            }
+            self.mark_dirty(info_hash);
+        }
+    }
+
+    pub async fn resume_partial_seed(&self, info_hash: InfoHash) {
+        self.load(&[info_hash]).await;
+        let mut torrents = self.torrents.write().await;
+        if let Some(t) = torrents.get_mut(&info_hash) {
+            t.partial = t.partial.saturating_sub(1);
+            self.mark_dirty(info_hash);
         }
     }
 
-    /*pub fn undo_snatch(&self, info_hash: String) {
+    // Takes peers out of the counts that left without announcing a stop,
+    // as those in evicted swarms do
+    pub async fn remove_peers(&self, swarm: &EvictedSwarm) {
+        self.load(&[swarm.info_hash]).await;
+        let mut torrents = self.torrents.write().await;
+        if let Some(t) = torrents.get_mut(&swarm.info_hash) {
+            t.complete = t.complete.saturating_sub(swarm.seeders);
+            t.incomplete = t.incomplete.saturating_sub(swarm.leechers);
+            t.partial = t.partial.saturating_sub(swarm.partial_seeds);
+            self.mark_dirty(swarm.info_hash);
+        }
+    }
+
+    pub async fn add_traffic(&self, info_hash: &InfoHash, uploaded: u64, downloaded: u64) {
+        self.load(&[*info_hash]).await;
+        let mut torrents = self.torrents.write().await;
+        if let Some(t) = torrents.get_mut(info_hash) {
+            t.balance = t
+                .balance
+                .saturating_add(uploaded.saturating_add(downloaded));
+            t.bytes_uploaded = t.bytes_uploaded.saturating_add(uploaded);
+            t.bytes_downloaded = t.bytes_downloaded.saturating_add(downloaded);
+            self.mark_dirty(*info_hash);
+        }
+    }
+
+    // Inserts or replaces many torrents while only taking the lock once
+    pub async fn bulk_insert(&self, torrents: Vec<Torrent>) -> usize {
+        let num_torrents = torrents.len();
+        let mut store = self.torrents.write().await;
+        let mut registered = self.registered.write().unwrap();
+        let mut dirty = self.dirty.lock().unwrap();
+        for torrent in torrents {
+            registered.insert(torrent.info_hash);
+            dirty.insert(torrent.info_hash);
+            store.insert(torrent.info_hash, torrent);
+        }
+        num_torrents
+    }
+
+    // Adds torrents that were registered elsewhere, such as directly in
+    // the database, leaving the ones already here untouched. They are
+    // already stored, so they aren't marked dirty. Returns the number added.
+    pub async fn register(&self, torrents: impl IntoIterator<Item = Torrent>) -> usize {
+        let torrents: Vec<Torrent> = torrents.into_iter().collect();
+        let info_hashes: Vec<InfoHash> = torrents.iter().map(|t| t.info_hash).collect();
+        self.load(&info_hashes).await;
+
+        let mut added = 0;
+        let mut store = self.torrents.write().await;
+        let mut registered = self.registered.write().unwrap();
+        for torrent in torrents {
+            if !store.contains_key(&torrent.info_hash) {
+                registered.insert(torrent.info_hash);
+                store.insert(torrent.info_hash, torrent);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    // Folds in torrents from another tracker. Unknown torrents are added
+    // as they are, and known ones take the larger of each count, so that
+    // merging the same state twice changes nothing. Their category and
+    // visibility stay as set here. Returns the number of torrents added.
+    pub async fn merge(&self, torrents: Vec<Torrent>) -> usize {
+        let info_hashes: Vec<InfoHash> = torrents.iter().map(|t| t.info_hash).collect();
+        self.load(&info_hashes).await;
+
+        let mut added = 0;
+        let mut store = self.torrents.write().await;
+        let mut registered = self.registered.write().unwrap();
+        let mut dirty = self.dirty.lock().unwrap();
+        for torrent in torrents {
+            dirty.insert(torrent.info_hash);
+            match store.get_mut(&torrent.info_hash) {
+                Some(t) => {
+                    t.complete = t.complete.max(torrent.complete);
+                    t.downloaded = t.downloaded.max(torrent.downloaded);
+                    t.incomplete = t.incomplete.max(torrent.incomplete);
+                    t.partial = t.partial.max(torrent.partial);
+                    t.balance = t.balance.max(torrent.balance);
+                    t.bytes_uploaded = t.bytes_uploaded.max(torrent.bytes_uploaded);
+                    t.bytes_downloaded = t.bytes_downloaded.max(torrent.bytes_downloaded);
+                    if t.category.is_none() {
+                        t.category = torrent.category;
+                    }
+                }
+                None => {
+                    registered.insert(torrent.info_hash);
+                    store.insert(torrent.info_hash, torrent);
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    /*pub fn undo_snatch(&self, info_hash: InfoHash) {
         let mut torrents = self.torrents.write();
         if let Some(t) = torrents.get_mut(&info_hash) {
             t.incomplete = t.incomplete.saturating_sub(1);
@@ -138,10 +474,163 @@ impl TorrentStore {
     }*/
 }
 
+// Where a peer currently sits in its swarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeerRole {
+    Seeder,
+    Leecher,
+    PartialSeed,
+}
+
+// How roles are written to the database
+impl PeerRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PeerRole::Seeder => "seeder",
+            PeerRole::Leecher => "leecher",
+            PeerRole::PartialSeed => "partial_seed",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<PeerRole> {
+        match role {
+            "seeder" => Some(PeerRole::Seeder),
+            "leecher" => Some(PeerRole::Leecher),
+            "partial_seed" => Some(PeerRole::PartialSeed),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Swarm {
     pub seeders: HashSet<Peer>,
     pub leechers: HashSet<Peer>,
+    // BEP 21: peers that have all they want of a torrent without
+    // having all of it. They still upload, but no longer download.
+    pub partial_seeds: HashSet<Peer>,
+    // Every peer in the swarm in compact form, kept up to date as peers
+    // join and leave so that announces can sample it directly
+    compact: CompactPeers,
+    // Swarms created ahead of their peers, as by a scrape import, are
+    // kept even while empty until then
+    kept_until: Option<Instant>,
+}
+
+// Peers are keyed by ID and address, as a peer's identity is both
+type PeerKey = (PeerId, SocketAddr);
+
+fn peer_key(peer: &Peer) -> PeerKey {
+    (peer.peer_id(), SocketAddr::new(peer.ip(), peer.port()))
+}
+
+// Every compact peer list changed gets a version no other list has had,
+// so that a snapshot of one can tell whether it's still current
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Default)]
+struct CompactList {
+    v4: Vec<(PeerId, CompactPeerv4)>,
+    v6: Vec<(PeerId, CompactPeerv6)>,
+    version: u64,
+}
+
+impl CompactList {
+    fn changed(&mut self) {
+        self.version = NEXT_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Picks up to numwant peers at random from both address families.
+    // Only the chosen peers are copied, however large the swarm.
+    fn sample(&self, numwant: u32) -> (Vec<CompactPeerv4>, Vec<CompactPeerv6>) {
+        let total = self.v4.len() + self.v6.len();
+        let amount = total.min(numwant as usize);
+
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for i in index::sample(&mut rand::thread_rng(), total, amount).into_iter() {
+            match self.v4.get(i) {
+                Some((_, p)) => peers.push(p.clone()),
+                None => peers6.push(self.v6[i - self.v4.len()].1.clone()),
+            }
+        }
+
+        (peers, peers6)
+    }
+
+    fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CompactPeers {
+    list: CompactList,
+    // Position of each peer in v4 or v6, so it can be swapped out in O(1)
+    positions: HashMap<PeerKey, usize>,
+}
+
+impl CompactPeers {
+    fn insert(&mut self, peer: &Peer) {
+        let key = peer_key(peer);
+        if self.positions.contains_key(&key) {
+            return;
+        }
+
+        let list = &mut self.list;
+        let position = match peer {
+            Peer::V4(p) => {
+                list.v4.push((
+                    p.peer_id,
+                    CompactPeerv4 {
+                        ip: p.ip,
+                        port: p.port,
+                    },
+                ));
+                list.v4.len() - 1
+            }
+            Peer::V6(p) => {
+                list.v6.push((
+                    p.peer_id,
+                    CompactPeerv6 {
+                        ip: p.ip,
+                        port: p.port,
+                    },
+                ));
+                list.v6.len() - 1
+            }
+        };
+        list.changed();
+        self.positions.insert(key, position);
+    }
+
+    fn remove(&mut self, peer: &Peer) {
+        let position = match self.positions.remove(&peer_key(peer)) {
+            Some(position) => position,
+            None => return,
+        };
+
+        // The last peer takes the removed one's place
+        let list = &mut self.list;
+        let moved = match peer {
+            Peer::V4(_) => {
+                list.v4.swap_remove(position);
+                list.v4
+                    .get(position)
+                    .map(|(id, c)| (*id, SocketAddr::new(c.ip.into(), c.port)))
+            }
+            Peer::V6(_) => {
+                list.v6.swap_remove(position);
+                list.v6
+                    .get(position)
+                    .map(|(id, c)| (*id, SocketAddr::new(c.ip.into(), c.port)))
+            }
+        };
+        list.changed();
+        if let Some(key) = moved {
+            self.positions.insert(key, position);
+        }
+    }
 }
 
 // Swarm actually holds the peers for each torrent. The structure
@@ -152,190 +641,889 @@ impl Swarm {
         Swarm {
             seeders: HashSet::new(),
             leechers: HashSet::new(),
+            partial_seeds: HashSet::new(),
+            compact: CompactPeers::default(),
+            kept_until: None,
+        }
+    }
+
+    // Brings the compact peers in line with the sets after
+    // a peer has been added to or removed from any of them
+    fn sync_compact(&mut self, peer: &Peer) {
+        if self.seeders.contains(peer)
+            || self.leechers.contains(peer)
+            || self.partial_seeds.contains(peer)
+        {
+            self.compact.insert(peer);
+        } else {
+            self.compact.remove(peer);
         }
     }
 
+    // Peers already in the swarm are replaced rather than kept, like
+    // they are by every other announce, so that their announce time
+    // and transfer counts are always those of their latest announce
     fn add_seeder(&mut self, peer: Peer) {
-        self.seeders.insert(peer);
+        self.compact.insert(&peer);
+        self.seeders.replace(peer);
     }
 
-    fn add_leecher(&mut self, peer: Peer) {
-        self.leechers.insert(peer);
+    // Returns whether the peer was a partial seed that started downloading again
+    fn add_leecher(&mut self, peer: Peer) -> bool {
+        self.compact.insert(&peer);
+        let resumed = self.partial_seeds.remove(&peer);
+        self.leechers.replace(peer);
+        resumed
+    }
+
+    // Seeders that send a paused event stay seeders, and anyone else
+    // becomes a partial seed. Returns the role the peer had before.
+    fn pause(&mut self, peer: Peer) -> Option<PeerRole> {
+        self.compact.insert(&peer);
+        if self.seeders.contains(&peer) {
+            self.seeders.replace(peer);
+            return Some(PeerRole::Seeder);
+        }
+
+        let previous = if self.leechers.remove(&peer) {
+            Some(PeerRole::Leecher)
+        } else if self.partial_seeds.contains(&peer) {
+            Some(PeerRole::PartialSeed)
+        } else {
+            None
+        };
+        self.partial_seeds.replace(peer);
+        previous
     }
 
     // The update methods ensure that peers that
     // continue to announce have accurate announce times
     // in order to prevent errant peer reaping
-    fn update_seeder(&mut self, peer: Peer) {
+    fn update_seeder(&mut self, peer: Peer) -> bool {
         if self.seeders.contains(&peer) {
             self.seeders.replace(peer);
+            return true;
         }
+        false
     }
 
-    fn update_leecher(&mut self, peer: Peer) {
+    fn update_leecher(&mut self, peer: Peer) -> bool {
         if self.leechers.contains(&peer) {
             self.leechers.replace(peer);
+            return true;
+        }
+        false
+    }
+
+    fn update_partial_seed(&mut self, peer: Peer) -> bool {
+        if self.partial_seeds.contains(&peer) {
+            self.partial_seeds.replace(peer);
+            return true;
         }
+        false
+    }
+
+    // The peer as it's stored, along with the role it holds
+    fn lookup(&self, peer: &Peer) -> Option<(&Peer, PeerRole)> {
+        if let Some(p) = self.seeders.get(peer) {
+            Some((p, PeerRole::Seeder))
+        } else if let Some(p) = self.leechers.get(peer) {
+            Some((p, PeerRole::Leecher))
+        } else {
+            self.partial_seeds
+                .get(peer)
+                .map(|p| (p, PeerRole::PartialSeed))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeders.is_empty() && self.leechers.is_empty() && self.partial_seeds.is_empty()
+    }
+
+    // When any of its peers last announced, if it has any
+    pub fn last_announced(&self) -> Option<Instant> {
+        self.peers().map(|p| p.last_announced()).max()
+    }
+
+    pub fn is_kept(&self) -> bool {
+        self.kept_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    // All peers, regardless of their role
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.seeders
+            .iter()
+            .chain(self.leechers.iter())
+            .chain(self.partial_seeds.iter())
     }
 
     fn remove_seeder(&mut self, peer: Peer) -> bool {
-        self.seeders.remove(&peer)
+        let removed = self.seeders.remove(&peer);
+        self.sync_compact(&peer);
+        removed
     }
 
+    // Partial seeds are removed as leechers, which is what they announced as
     fn remove_leecher(&mut self, peer: Peer) -> bool {
-        self.leechers.remove(&peer)
+        let removed = self.leechers.remove(&peer) | self.partial_seeds.remove(&peer);
+        self.sync_compact(&peer);
+        removed
     }
 
-    fn promote_leecher(&mut self, peer: Peer) {
-        match self.leechers.take(&peer) {
-            Some(leecher) => {
-                self.seeders.insert(leecher);
-            }
-            None => {
-                self.seeders.insert(peer);
-            }
-        };
+    // Clears away peers that haven't announced within the timeout
+    pub fn reap(&mut self, timeout: Duration) {
+        let mut stale = Vec::new();
+        for set in [
+            &mut self.seeders,
+            &mut self.leechers,
+            &mut self.partial_seeds,
+        ]
+        .iter_mut()
+        {
+            set.retain(|peer| {
+                let live = peer.last_announced().elapsed() < timeout;
+                if !live {
+                    stale.push(peer.clone());
+                }
+                live
+            });
+        }
+
+        for peer in stale {
+            self.sync_compact(&peer);
+        }
+    }
+
+    // The incoming peer replaces any stored one so that
+    // its announce time and traffic counters are kept current
+    // Returns whether the peer was counted as a leecher before
+    fn promote_leecher(&mut self, peer: Peer) -> bool {
+        self.compact.insert(&peer);
+        let was_leecher = self.leechers.remove(&peer) | self.partial_seeds.remove(&peer);
+        self.seeders.replace(peer);
+        was_leecher
     }
 }
 
-type PeerRecords = HashMap<String, Swarm>;
+pub type PeerRecords = HashMap<InfoHash, Swarm>;
+
+// A change to a shard's swarms, which hands back whatever
+// replies to its caller once the change can be seen
+type ShardJob = Box<dyn FnOnce(&mut PeerRecords) -> Reply + Send>;
+type Reply = Box<dyn FnOnce() + Send>;
+
+struct WriteRequest {
+    // The swarm changed, or None if any of them may have
+    info_hash: Option<InfoHash>,
+    job: ShardJob,
+}
+
+// What announces sample peers from in single-writer mode. A swarm's list
+// is swapped out whole when peers join or leave it, and the map of them
+// only when swarms come or go, so reading either never takes a lock.
+type SwarmSnapshots = HashMap<InfoHash, Arc<ArcSwap<CompactList>>>;
+
+// How many queued changes a writer makes before giving up the lock
+const WRITE_BATCH_SIZE: usize = 1024;
+
+struct ShardWriter {
+    requests: UnboundedSender<WriteRequest>,
+    snapshots: Arc<ArcSwap<SwarmSnapshots>>,
+}
 
 // PeerStore needs to be wrapped in a RwLock or other exclusion
-// primitive in order to prevent data races. This is further wrapped
-// in an atomic reference counter in order to make it thread-safe.
-#[derive(Debug, Clone)]
+// primitive in order to prevent data races. Swarms are split across
+// shards chosen by info hash, each behind its own lock, so announces
+// for torrents in different shards never wait on each other. The
+// shards are further wrapped in an atomic reference counter in order
+// to make them thread-safe.
+//
+// In single-writer mode, every change to a shard is sent to a task of
+// its own instead, which makes them in order, taking the lock once for
+// each batch of them (see swarm_writers).
+#[derive(Clone)]
 pub struct PeerStore {
-    pub records: Arc<RwLock<PeerRecords>>,
+    shards: Arc<Vec<RwLock<PeerRecords>>>,
+    writers: Option<Arc<Vec<ShardWriter>>>,
+}
+
+impl fmt::Debug for PeerStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PeerStore")
+            .field("shards", &self.shards.len())
+            .field("writers", &self.writers.is_some())
+            .finish()
+    }
+}
+
+// Makes the changes sent for one shard, one batch at a time, then
+// publishes snapshots of the swarms whose peers changed. Callers only
+// hear back once the snapshots are up to date.
+async fn write_shard_jobs(
+    shards: Arc<Vec<RwLock<PeerRecords>>>,
+    index: usize,
+    mut requests: UnboundedReceiver<WriteRequest>,
+    snapshots: Arc<ArcSwap<SwarmSnapshots>>,
+) {
+    while let Some(first) = requests.recv().await {
+        let mut records = shards[index].write().await;
+        let mut touched = Some(HashSet::new());
+        let mut replies = Vec::new();
+
+        let mut next = Some(first);
+        while let Some(WriteRequest { info_hash, job }) = next {
+            replies.push(job(&mut records));
+            match (info_hash, touched.as_mut()) {
+                (Some(info_hash), Some(touched)) => {
+                    touched.insert(info_hash);
+                }
+                _ => touched = None,
+            }
+            next = if replies.len() < WRITE_BATCH_SIZE {
+                requests.try_recv().ok()
+            } else {
+                None
+            };
+        }
+
+        publish_snapshots(&records, &snapshots, touched);
+        drop(records);
+        for reply in replies {
+            reply();
+        }
+    }
+}
+
+// None brings every swarm's snapshot up to date
+fn publish_snapshots(
+    records: &PeerRecords,
+    snapshots: &ArcSwap<SwarmSnapshots>,
+    touched: Option<HashSet<InfoHash>>,
+) {
+    let current = snapshots.load_full();
+    let touched =
+        touched.unwrap_or_else(|| records.keys().chain(current.keys()).copied().collect());
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for info_hash in touched {
+        match (records.get(&info_hash), current.get(&info_hash)) {
+            (Some(sw), Some(snapshot)) => {
+                if snapshot.load().version != sw.compact.list.version {
+                    snapshot.store(Arc::new(sw.compact.list.clone()));
+                }
+            }
+            (Some(sw), None) => added.push((info_hash, sw.compact.list.clone())),
+            (None, Some(_)) => removed.push(info_hash),
+            (None, None) => {}
+        }
+    }
+    if added.is_empty() && removed.is_empty() {
+        return;
+    }
+
+    let mut next = SwarmSnapshots::clone(&current);
+    for info_hash in removed {
+        next.remove(&info_hash);
+    }
+    for (info_hash, list) in added {
+        next.insert(info_hash, Arc::new(ArcSwap::from_pointee(list)));
+    }
+    snapshots.store(Arc::new(next));
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PeerStore {
     pub fn new() -> PeerStore {
+        PeerStore::with_shards(1)
+    }
+
+    pub fn with_shards(num_shards: usize) -> PeerStore {
         PeerStore {
-            records: Arc::new(RwLock::new(PeerRecords::new())),
+            shards: Arc::new(
+                (0..num_shards.max(1))
+                    .map(|_| RwLock::new(PeerRecords::new()))
+                    .collect(),
+            ),
+            writers: None,
         }
     }
 
-    pub async fn put_seeder(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        match store.get_mut(&info_hash) {
-            Some(sw) => {
-                sw.add_seeder(peer);
+    // Switches to single-writer mode, starting a writer for each shard
+    // on the current runtime. They run until every copy of the store
+    // has been dropped.
+    pub fn with_writers(mut self) -> PeerStore {
+        let writers = (0..self.shards.len())
+            .map(|index| {
+                let (requests, received) = mpsc::unbounded_channel();
+                let snapshots = Arc::new(ArcSwap::from_pointee(SwarmSnapshots::new()));
+                actix_rt::spawn(write_shard_jobs(
+                    self.shards.clone(),
+                    index,
+                    received,
+                    snapshots.clone(),
+                ));
+                ShardWriter {
+                    requests,
+                    snapshots,
+                }
+            })
+            .collect();
+        self.writers = Some(Arc::new(writers));
+        self
+    }
+
+    // Info hashes are already uniformly distributed,
+    // so their first bytes are enough to pick a shard
+    fn shard_index(&self, info_hash: &InfoHash) -> usize {
+        let prefix = u32::from_be_bytes([
+            info_hash.0[0],
+            info_hash.0[1],
+            info_hash.0[2],
+            info_hash.0[3],
+        ]);
+        prefix as usize % self.shards.len()
+    }
+
+    pub fn shard(&self, info_hash: &InfoHash) -> &RwLock<PeerRecords> {
+        &self.shards[self.shard_index(info_hash)]
+    }
+
+    pub fn shards(&self) -> impl Iterator<Item = &RwLock<PeerRecords>> {
+        self.shards.iter()
+    }
+
+    // Makes a change to the swarm, and returns what the change did
+    async fn write<T, F>(&self, info_hash: InfoHash, change: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PeerRecords) -> T + Send + 'static,
+    {
+        self.write_shard(self.shard_index(&info_hash), Some(info_hash), change)
+            .await
+    }
+
+    // Changes are made under the shard's lock, or sent to its writer
+    // in single-writer mode. Those for any number of the shard's swarms
+    // are made with no info hash.
+    async fn write_shard<T, F>(&self, index: usize, info_hash: Option<InfoHash>, change: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PeerRecords) -> T + Send + 'static,
+    {
+        let writer = match &self.writers {
+            Some(writers) => &writers[index],
+            None => return change(&mut *self.shards[index].write().await),
+        };
+
+        let (reply, result) = oneshot::channel();
+        let job: ShardJob = Box::new(move |records| {
+            let changed = change(records);
+            Box::new(move || {
+                let _ = reply.send(changed);
+            })
+        });
+
+        // Writers only stop with the runtime, but in case one has,
+        // the change is made here instead
+        if let Err(SendError(request)) = writer.requests.send(WriteRequest { info_hash, job }) {
+            (request.job)(&mut *self.shards[index].write().await)();
+        }
+        result.await.expect("Swarm writer dropped a change")
+    }
+
+    // Splits peers by the shard their swarm lives in,
+    // so that bulk operations take each lock only once
+    fn by_shard<T>(&self, items: Vec<T>, info_hash: impl Fn(&T) -> InfoHash) -> Vec<Vec<T>> {
+        let mut buckets: Vec<Vec<T>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+        for item in items {
+            buckets[self.shard_index(&info_hash(&item))].push(item);
+        }
+        buckets
+    }
+
+    pub async fn put_seeder(&self, info_hash: InfoHash, peer: Peer) {
+        self.write(info_hash, move |store| {
+            store
+                .entry(info_hash)
+                .or_insert_with(Swarm::new)
+                .add_seeder(peer)
+        })
+        .await
+    }
+
+    pub async fn remove_seeder(&self, info_hash: InfoHash, peer: Peer) -> bool {
+        self.write(info_hash, move |store| {
+            let mut result = false;
+            if let Some(sw) = store.get_mut(&info_hash) {
+                result = sw.remove_seeder(peer);
+                // Swarms are dropped with their last peer so they don't pile up
+                if sw.is_empty() {
+                    store.remove(&info_hash);
+                }
+            }
+            result
+        })
+        .await
+    }
+
+    // Returns whether the peer was a partial seed that started downloading again
+    pub async fn put_leecher(&self, info_hash: InfoHash, peer: Peer) -> bool {
+        self.write(info_hash, move |store| {
+            store
+                .entry(info_hash)
+                .or_insert_with(Swarm::new)
+                .add_leecher(peer)
+        })
+        .await
+    }
+
+    pub async fn pause_peer(&self, info_hash: InfoHash, peer: Peer) -> Option<PeerRole> {
+        self.write(info_hash, move |store| {
+            store
+                .entry(info_hash)
+                .or_insert_with(Swarm::new)
+                .pause(peer)
+        })
+        .await
+    }
+
+    pub async fn remove_leecher(&self, info_hash: InfoHash, peer: Peer) -> bool {
+        self.write(info_hash, move |store| {
+            let mut result = false;
+            if let Some(sw) = store.get_mut(&info_hash) {
+                result = sw.remove_leecher(peer);
+                // Swarms are dropped with their last peer so they don't pile up
+                if sw.is_empty() {
+                    store.remove(&info_hash);
+                }
+            }
+            result
+        })
+        .await
+    }
+
+    // Instead of removing a peer outright, its last announce is backdated
+    // so that the reaper clears it once the delay has passed. Returns
+    // whether the peer was present in the swarm.
+    pub async fn expire_peer(
+        &self,
+        info_hash: InfoHash,
+        peer: Peer,
+        timeout: Duration,
+        delay: Duration,
+    ) -> bool {
+        let backdated = Instant::now()
+            .checked_sub(timeout.checked_sub(delay).unwrap_or_default())
+            .unwrap_or_else(Instant::now);
+
+        self.write(info_hash, move |store| {
+            if let Some(sw) = store.get_mut(&info_hash) {
+                for set in [&mut sw.seeders, &mut sw.leechers, &mut sw.partial_seeds].iter_mut() {
+                    if let Some(mut p) = set.take(&peer) {
+                        p.set_last_announced(backdated);
+                        set.insert(p);
+                        return true;
+                    }
+                }
+            }
+            false
+        })
+        .await
+    }
+
+    pub async fn promote_leecher(&self, info_hash: InfoHash, peer: Peer) -> bool {
+        self.write(info_hash, move |store| {
+            store
+                .entry(info_hash)
+                .or_insert_with(Swarm::new)
+                .promote_leecher(peer)
+        })
+        .await
+    }
+
+    // Returns whether the peer was in the swarm. If it wasn't,
+    // it's added as a seeder or a leecher, as the case may be.
+    pub async fn update_peer(&self, info_hash: InfoHash, peer: Peer, seeding: bool) -> bool {
+        self.write(info_hash, move |store| {
+            let sw = store.entry(info_hash).or_insert_with(Swarm::new);
+            if sw.update_seeder(peer.clone())
+                || sw.update_leecher(peer.clone())
+                || sw.update_partial_seed(peer.clone())
+            {
+                return true;
             }
-            None => {
-                let mut sw = Swarm::new();
+
+            if seeding {
                 sw.add_seeder(peer);
-                store.insert(info_hash, sw);
+            } else {
+                sw.add_leecher(peer);
+            }
+            false
+        })
+        .await
+    }
+
+    // The peer as it was at its last announce, and the role it holds
+    pub async fn lookup(&self, info_hash: &InfoHash, peer: &Peer) -> Option<(Peer, PeerRole)> {
+        let store = self.shard(info_hash).read().await;
+        store
+            .get(info_hash)
+            .and_then(|sw| sw.lookup(peer))
+            .map(|(p, role)| (p.clone(), role))
+    }
+
+    // Puts the peer in the given role, or takes it out of the swarm for
+    // None. Changes made elsewhere can arrive out of order, so a peer that
+    // announced more recently than the incoming one is left as it is, in
+    // which case None is returned. Otherwise, returns the role it had before.
+    pub async fn set_role(
+        &self,
+        info_hash: InfoHash,
+        peer: Peer,
+        role: Option<PeerRole>,
+    ) -> Option<Option<PeerRole>> {
+        self.write(info_hash, move |store| {
+            let sw = store.entry(info_hash).or_insert_with(Swarm::new);
+            let previous = match sw.lookup(&peer) {
+                Some((stored, _)) if stored.last_announced() > peer.last_announced() => {
+                    return None
+                }
+                Some((_, previous)) => Some(previous),
+                None => None,
+            };
+
+            sw.seeders.remove(&peer);
+            sw.leechers.remove(&peer);
+            sw.partial_seeds.remove(&peer);
+            match role {
+                Some(PeerRole::Seeder) => sw.seeders.insert(peer.clone()),
+                Some(PeerRole::Leecher) => sw.leechers.insert(peer.clone()),
+                Some(PeerRole::PartialSeed) => sw.partial_seeds.insert(peer.clone()),
+                None => false,
+            };
+            sw.sync_compact(&peer);
+
+            // Swarms are dropped with their last peer so they don't pile up
+            if sw.is_empty() {
+                store.remove(&info_hash);
+            }
+            Some(previous)
+        })
+        .await
+    }
+
+    // Other peers in the swarm with the same ID, but at a different address
+    pub async fn duplicates(&self, info_hash: &InfoHash, peer: &Peer) -> Vec<Peer> {
+        let store = self.shard(info_hash).read().await;
+        let peer_id = peer.peer_id();
+        match store.get(info_hash) {
+            Some(sw) => sw
+                .peers()
+                .filter(|p| p.peer_id() == peer_id && *p != peer)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // The peer as it was at its last announce, if it's in the swarm
+    pub async fn previous(&self, info_hash: &InfoHash, peer: &Peer) -> Option<Peer> {
+        let store = self.shard(info_hash).read().await;
+        store.get(info_hash).and_then(|sw| {
+            sw.seeders
+                .get(peer)
+                .or_else(|| sw.leechers.get(peer))
+                .or_else(|| sw.partial_seeds.get(peer))
+                .cloned()
+        })
+    }
+
+    // Computes how much a peer has transferred since its last announce.
+    // Counters that went backwards mean the client started a new session,
+    // in which case everything it reports is new traffic.
+    pub async fn traffic_delta(&self, info_hash: &InfoHash, peer: &Peer) -> (u64, u64) {
+        match self.previous(info_hash, peer).await {
+            Some(prev) => {
+                let (prev_up, prev_down) = prev.traffic();
+                let (up, down) = peer.traffic();
+                (
+                    up.checked_sub(prev_up).unwrap_or(up),
+                    down.checked_sub(prev_down).unwrap_or(down),
+                )
             }
+            None => (0, 0),
         }
     }
 
-    pub async fn remove_seeder(&self, info_hash: String, peer: Peer) -> bool {
-        let mut result = false;
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            result = sw.remove_seeder(peer);
+    // Inserts many peers while only taking each shard's lock once. Peers
+    // that are already present are left alone as live announces are fresher
+    // than imported state. Returns the number of seeders and leechers added.
+    pub async fn bulk_insert(&self, peers: Vec<ImportedPeer>) -> (u32, u32) {
+        let mut seeds_added = 0;
+        let mut leeches_added = 0;
+
+        let buckets = self.by_shard(peers, |imported| imported.info_hash);
+        for (index, bucket) in buckets.into_iter().enumerate() {
+            let (seeds, leeches) = self
+                .write_shard(index, None, move |store| {
+                    let (mut seeds, mut leeches) = (0, 0);
+                    for imported in bucket {
+                        let sw = store.entry(imported.info_hash).or_insert_with(Swarm::new);
+                        if sw.seeders.contains(&imported.peer)
+                            || sw.leechers.contains(&imported.peer)
+                            || sw.partial_seeds.contains(&imported.peer)
+                        {
+                            continue;
+                        }
+
+                        if imported.seeder {
+                            sw.add_seeder(imported.peer);
+                            seeds += 1;
+                        } else {
+                            sw.add_leecher(imported.peer);
+                            leeches += 1;
+                        }
+                    }
+                    (seeds, leeches)
+                })
+                .await;
+            seeds_added += seeds;
+            leeches_added += leeches;
         }
-        result
+
+        (seeds_added, leeches_added)
     }
 
-    pub async fn put_leecher(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        match store.get_mut(&info_hash) {
-            Some(sw) => {
-                sw.add_leecher(peer);
+    // Creates an empty swarm for each torrent that doesn't have one yet,
+    // which reaping leaves alone for keep_for so that it's still there
+    // when the clients arrive. Returns the number of swarms created.
+    pub async fn create_swarms(&self, info_hashes: Vec<InfoHash>, keep_for: Duration) -> usize {
+        let kept_until = Instant::now() + keep_for;
+        let mut created = 0;
+
+        let buckets = self.by_shard(info_hashes, |info_hash| *info_hash);
+        for (index, bucket) in buckets.into_iter().enumerate() {
+            created += self
+                .write_shard(index, None, move |store| {
+                    let mut created = 0;
+                    for info_hash in bucket {
+                        if !store.contains_key(&info_hash) {
+                            let mut sw = Swarm::new();
+                            sw.kept_until = Some(kept_until);
+                            store.insert(info_hash, sw);
+                            created += 1;
+                        }
+                    }
+                    created
+                })
+                .await;
+        }
+
+        created
+    }
+
+    // Drops the swarms that have gone longest without an announce until
+    // there are no more than max_swarms swarms and max_peers peers, where
+    // 0 means no limit. Swarms announced to while the victims were being
+    // picked are spared. Returns the swarms removed and what was in them.
+    pub async fn evict_lru(&self, max_swarms: usize, max_peers: usize) -> Vec<EvictedSwarm> {
+        let mut swarms = Vec::new();
+        let mut total_peers = 0;
+        for shard in self.shards() {
+            for (info_hash, sw) in shard.read().await.iter() {
+                let num_peers = sw.peers().count();
+                total_peers += num_peers;
+                swarms.push((sw.last_announced(), *info_hash, num_peers));
             }
-            None => {
-                let mut sw = Swarm::new();
-                sw.add_leecher(peer);
-                store.insert(info_hash, sw);
+        }
+
+        let over_swarms = max_swarms > 0 && swarms.len() > max_swarms;
+        let over_peers = max_peers > 0 && total_peers > max_peers;
+        if !over_swarms && !over_peers {
+            return Vec::new();
+        }
+
+        // Oldest first; empty swarms have no announce and go before all others
+        swarms.sort_unstable();
+        let mut remaining_swarms = swarms.len();
+        let mut victims = Vec::new();
+        for (last_announced, info_hash, num_peers) in swarms {
+            let swarms_ok = max_swarms == 0 || remaining_swarms <= max_swarms;
+            let peers_ok = max_peers == 0 || total_peers <= max_peers;
+            if swarms_ok && peers_ok {
+                break;
             }
+            victims.push((info_hash, last_announced));
+            remaining_swarms -= 1;
+            total_peers -= num_peers;
         }
-    }
 
-    pub async fn remove_leecher(&self, info_hash: String, peer: Peer) -> bool {
-        let mut result = false;
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            result = sw.remove_leecher(peer);
+        let mut evicted = Vec::new();
+        let buckets = self.by_shard(victims, |(info_hash, _)| *info_hash);
+        for (index, bucket) in buckets.into_iter().enumerate() {
+            let removed = self
+                .write_shard(index, None, move |store| {
+                    let mut removed = Vec::new();
+                    for (info_hash, last_announced) in bucket {
+                        match store.get(&info_hash) {
+                            Some(sw) if sw.last_announced() <= last_announced => {}
+                            _ => continue,
+                        }
+                        let sw = store.remove(&info_hash).unwrap();
+                        removed.push(EvictedSwarm {
+                            info_hash,
+                            seeders: sw.seeders.len() as u32,
+                            leechers: sw.leechers.len() as u32,
+                            partial_seeds: sw.partial_seeds.len() as u32,
+                        });
+                    }
+                    removed
+                })
+                .await;
+            evicted.extend(removed);
         }
-        result
+
+        evicted
     }
 
-    pub async fn promote_leecher(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            sw.promote_leecher(peer);
+    // Every peer with its role, for saving the swarms elsewhere
+    pub async fn dump(&self) -> Vec<(InfoHash, Peer, PeerRole)> {
+        let mut peers = Vec::new();
+        for shard in self.shards() {
+            let store = shard.read().await;
+            for (info_hash, sw) in store.iter() {
+                let roles = [
+                    (&sw.seeders, PeerRole::Seeder),
+                    (&sw.leechers, PeerRole::Leecher),
+                    (&sw.partial_seeds, PeerRole::PartialSeed),
+                ];
+                for (set, role) in roles.iter() {
+                    peers.extend(set.iter().map(|p| (*info_hash, p.clone(), *role)));
+                }
+            }
         }
+        peers
     }
 
-    pub async fn update_peer(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            sw.update_seeder(peer.clone());
-            sw.update_leecher(peer);
+    // Puts back peers from dump(), leaving alone any that have already
+    // announced since, and returns the number of peers restored
+    pub async fn restore(&self, peers: Vec<(InfoHash, Peer, PeerRole)>) -> usize {
+        let mut restored = 0;
+
+        let buckets = self.by_shard(peers, |(info_hash, _, _)| *info_hash);
+        for (index, bucket) in buckets.into_iter().enumerate() {
+            restored += self
+                .write_shard(index, None, move |store| {
+                    let mut restored = 0;
+                    for (info_hash, peer, role) in bucket {
+                        let sw = store.entry(info_hash).or_insert_with(Swarm::new);
+                        if sw.seeders.contains(&peer)
+                            || sw.leechers.contains(&peer)
+                            || sw.partial_seeds.contains(&peer)
+                        {
+                            continue;
+                        }
+
+                        match role {
+                            PeerRole::Seeder => sw.add_seeder(peer),
+                            PeerRole::Leecher => {
+                                sw.add_leecher(peer);
+                            }
+                            PeerRole::PartialSeed => {
+                                sw.pause(peer);
+                            }
+                        }
+                        restored += 1;
+                    }
+                    restored
+                })
+                .await;
         }
+
+        restored
     }
 
-    // Returns a randomized vector of peers to be returned to client
+    // Returns a randomized vector of peers to be returned to client.
+    // Partial seeds are handed out too, as they still upload. There are
+    // no guarantees on the presence of either protocol version in the list.
     pub async fn get_peers(
         &self,
-        info_hash: String,
+        info_hash: InfoHash,
         numwant: u32,
     ) -> (Vec<CompactPeerv4>, Vec<CompactPeerv6>) {
-        let mut peer_list = PeerList::new();
+        let (peers, peers6, _) = self.sample_peers(info_hash, numwant).await;
+        (peers, peers6)
+    }
 
-        let store = self.records.read().await;
-        if let Some(sw) = store.get(&info_hash) {
-            let seeds: Vec<CompactPeer> = sw
-                .seeders
-                .iter()
-                .map(|p| match p {
-                    Peer::V4(p) => CompactPeer::V4(CompactPeerv4 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                    Peer::V6(p) => CompactPeer::V6(CompactPeerv6 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                })
-                .collect();
-            let leeches: Vec<CompactPeer> = sw
-                .leechers
-                .iter()
-                .map(|p| match p {
-                    Peer::V4(p) => CompactPeer::V4(CompactPeerv4 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                    Peer::V6(p) => CompactPeer::V6(CompactPeerv6 {
-                        ip: p.ip,
-                        port: p.port,
-                    }),
-                })
-                .collect();
-            peer_list.0.extend(seeds);
-            peer_list.0.extend(leeches);
+    // Same as get_peers, but also returns the size of the swarm sampled from
+    pub async fn sample_peers(
+        &self,
+        info_hash: InfoHash,
+        numwant: u32,
+    ) -> (Vec<CompactPeerv4>, Vec<CompactPeerv6>, usize) {
+        // Single-writer mode samples the snapshots without taking a lock
+        if let Some(writers) = &self.writers {
+            let snapshots = writers[self.shard_index(&info_hash)].snapshots.load();
+            return match snapshots.get(&info_hash) {
+                Some(snapshot) => {
+                    let list = snapshot.load();
+                    let (peers, peers6) = list.sample(numwant);
+                    (peers, peers6, list.len())
+                }
+                None => (Vec::new(), Vec::new(), 0),
+            };
         }
 
-        // Randomized bunch of seeders and leechers
-        peer_list.make_random(numwant);
+        let store = self.shard(&info_hash).read().await;
+        match store.get(&info_hash) {
+            Some(sw) => {
+                let (peers, peers6) = sw.compact.list.sample(numwant);
+                (peers, peers6, sw.compact.list.len())
+            }
+            None => (Vec::new(), Vec::new(), 0),
+        }
+    }
 
-        let mut peers = Vec::new();
-        let mut peers6 = Vec::new();
+    // Seeders and leechers in a swarm, with partial seeds as leechers
+    pub async fn swarm_size(&self, info_hash: &InfoHash) -> (u32, u32) {
+        let store = self.shard(info_hash).read().await;
+        store.get(info_hash).map_or((0, 0), |sw| {
+            let leechers = sw.leechers.len() + sw.partial_seeds.len();
+            (sw.seeders.len() as u32, leechers as u32)
+        })
+    }
 
-        // Separate peers by protocol version. There are no
-        // guarantees on the presence of either in the list.
-        // It's entirely possible (but unlikely) to have peers
-        // of only one protocol type.
-        for peer in peer_list.0.drain(..) {
-            match peer {
-                CompactPeer::V4(p) => peers.push(p),
-                CompactPeer::V6(p) => peers6.push(p),
+    // The largest swarms, largest first, as counted by swarm_size
+    pub async fn largest_swarms(&self, limit: usize) -> Vec<(InfoHash, u32, u32)> {
+        let mut swarms = Vec::new();
+        for shard in self.shards() {
+            for (info_hash, sw) in shard.read().await.iter() {
+                let leechers = sw.leechers.len() + sw.partial_seeds.len();
+                swarms.push((*info_hash, sw.seeders.len() as u32, leechers as u32));
             }
         }
 
-        (peers, peers6)
+        swarms.sort_unstable_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(&b.0)));
+        swarms.truncate(limit);
+        swarms
+    }
+
+    // Peer IDs are only needed by clients asking for dictionary peers
+    pub async fn get_peer_ids(&self, info_hash: InfoHash) -> HashMap<SocketAddr, PeerId> {
+        let store = self.shard(&info_hash).read().await;
+        match store.get(&info_hash) {
+            Some(sw) => sw
+                .peers()
+                .map(|p| (SocketAddr::new(p.ip(), p.port()), p.peer_id()))
+                .collect(),
+            None => HashMap::new(),
+        }
     }
 }
 
@@ -345,261 +1533,755 @@ mod tests {
     use std::net::Ipv4Addr;
     use std::time::Instant;
 
-    use crate::bittorrent::{Peer, Peerv4};
+    use crate::bittorrent::{Peer, PeerId, Peerv4};
 
     use super::*;
 
+    #[tokio::test]
+    async fn memory_torrent_storage_hidden_scrape() {
+        let visible = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let hidden = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+
+        let mut hidden_torrent = Torrent::new(hidden, 3, 5, 1, 0);
+        hidden_torrent.hidden = true;
+
+        let mut records = TorrentRecords::new();
+        records.insert(visible, Torrent::new(visible, 10, 34, 7, 0));
+        records.insert(hidden, hidden_torrent);
+        let torrent_store = TorrentStore::new(records);
+
+        let scrapes = torrent_store
+            .get_scrapes(vec![visible.0.to_vec(), hidden.0.to_vec()])
+            .await;
+        assert_eq!(scrapes.len(), 1);
+        assert_eq!(scrapes[0].info_hash, visible.0);
+
+        // Announces are unaffected
+        assert_eq!(torrent_store.get_announce_stats(hidden).await, (3, 5, 1));
+    }
+
+    #[tokio::test]
+    async fn memory_torrent_storage_v2_scrape() {
+        let truncated = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let full = b"A1B2C3D4E5F6G7H8I9J0K1L2M3N4O5P6".to_vec();
+
+        let mut records = TorrentRecords::new();
+        records.insert(truncated, Torrent::new(truncated, 10, 34, 7, 0));
+        let torrent_store = TorrentStore::new(records);
+
+        let scrapes = torrent_store.get_scrapes(vec![full.clone()]).await;
+        assert_eq!(scrapes.len(), 1);
+        assert_eq!(scrapes[0].info_hash, full);
+        assert_eq!(scrapes[0].complete, 10);
+    }
+
+    #[tokio::test]
+    async fn memory_torrent_storage_register() {
+        let known = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let new = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let mut records = TorrentRecords::new();
+        records.insert(known, Torrent::new(known, 10, 34, 7, 0));
+        let torrent_store = TorrentStore::new(records);
+
+        let added = torrent_store
+            .register(vec![
+                Torrent::new(known, 0, 0, 0, 0),
+                Torrent::new(new, 0, 0, 0, 0),
+            ])
+            .await;
+
+        assert_eq!(added, 1);
+        assert!(torrent_store.is_registered(&new));
+        assert_eq!(torrent_store.get_announce_stats(known).await, (10, 34, 7));
+        assert!(torrent_store.take_dirty().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_torrent_storage_new_seed() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 10, 34, 7, 0));
+        let torrent_store = TorrentStore::new(records);
+
+        torrent_store.new_seed(info_hash).await;
+
+        assert_eq!(
+            torrent_store.get_announce_stats(info_hash).await,
+            (11, 35, 6)
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_torrent_storage_dirty_tracking() {
+        let changed = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let untouched = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let mut records = TorrentRecords::new();
+        records.insert(changed, Torrent::new(changed, 10, 34, 7, 0));
+        records.insert(untouched, Torrent::new(untouched, 3, 5, 1, 0));
+        let torrent_store = TorrentStore::new(records);
+
+        assert!(torrent_store.take_dirty().await.is_empty());
+
+        torrent_store.new_leech(changed).await;
+        torrent_store.add_traffic(&changed, 60, 40).await;
+
+        let dirty = torrent_store.take_dirty().await;
+        assert_eq!(dirty.len(), 1);
+        assert_eq!(dirty[0].info_hash, changed);
+        assert_eq!(dirty[0].balance, 100);
+        assert_eq!(
+            (dirty[0].bytes_uploaded, dirty[0].bytes_downloaded),
+            (60, 40)
+        );
+        assert!(torrent_store.take_dirty().await.is_empty());
+
+        // A failed flush puts them back for the next one
+        torrent_store.restore_dirty(vec![changed]);
+        assert_eq!(torrent_store.take_dirty().await.len(), 1);
+
+        // Partial seeds coming and going are flushed too
+        torrent_store.new_partial_seed(changed, false).await;
+        assert_eq!(torrent_store.take_dirty().await[0].partial, 1);
+        torrent_store.resume_partial_seed(changed).await;
+        assert_eq!(torrent_store.take_dirty().await[0].partial, 0);
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[tokio::test]
+    async fn tiered_torrent_storage_reads_through() {
+        let path = std::env::temp_dir().join("tyto_tier_test");
+        let tier = super::rocksdb::TorrentTier::open(&path.to_string_lossy(), 10).unwrap();
+        let info_hashes: Vec<InfoHash> = (0..20).map(|i| InfoHash([i; 20])).collect();
+        let records = info_hashes
+            .iter()
+            .map(|info_hash| (*info_hash, Torrent::new(*info_hash, 1, 2, 3, 0)))
+            .collect();
+        let torrent_store = TorrentStore::with_tier(records, tier).unwrap();
+
+        // Nothing is held in memory until it's used
+        assert!(torrent_store.torrents.read().await.is_empty());
+        assert!(torrent_store.is_registered(&info_hashes[0]));
+
+        for info_hash in &info_hashes {
+            torrent_store.new_leech(*info_hash).await;
+        }
+
+        // Torrents moved back to the tier keep their changes
+        assert!(torrent_store.torrents.read().await.len() <= 10);
+        assert_eq!(
+            torrent_store.get_announce_stats(info_hashes[0]).await,
+            (1, 2, 4)
+        );
+        assert_eq!(torrent_store.take_dirty().await.len(), 20);
+        assert_eq!(torrent_store.get_all_scrapes().await.len(), 20);
+    }
+
     #[tokio::test]
     async fn memory_peer_storage_put_seeder_new_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer),
-            true
-        );
+        peer_store.put_seeder(info_hash, peer.clone()).await;
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .unwrap()
+            .seeders
+            .contains(&peer));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_put_seeder_prior_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer1 = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer1).await;
+        peer_store.put_seeder(info_hash, peer1).await;
 
         let peer2 = Peer::V4(Peerv4 {
-            peer_id: "TSRQPONMLKJIHGFEDCBA".to_string(),
+            peer_id: PeerId(*b"TSRQPONMLKJIHGFEDCBA"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6881,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store
-            .put_seeder(info_hash.clone(), peer2.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer2),
-            true
-        );
+        peer_store.put_seeder(info_hash, peer2.clone()).await;
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .unwrap()
+            .seeders
+            .contains(&peer2));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_put_leecher_new_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer),
-            true
-        );
+        peer_store.put_leecher(info_hash, peer.clone()).await;
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .unwrap()
+            .leechers
+            .contains(&peer));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_put_leecher_prior_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer1 = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer1).await;
+        peer_store.put_seeder(info_hash, peer1).await;
 
         let peer2 = Peer::V4(Peerv4 {
-            peer_id: "TSRQPONMLKJIHGFEDCBA".to_string(),
+            peer_id: PeerId(*b"TSRQPONMLKJIHGFEDCBA"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6881,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer2.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer2),
-            true
-        );
+        peer_store.put_leecher(info_hash, peer2.clone()).await;
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .unwrap()
+            .leechers
+            .contains(&peer2));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_remove_seeder() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
+        peer_store.put_seeder(info_hash, peer.clone()).await;
 
-        let _ = peer_store
-            .remove_seeder(info_hash.clone(), peer.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer),
-            false
-        );
+        let _ = peer_store.remove_seeder(info_hash, peer.clone()).await;
+        // The swarm goes away along with its last peer
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .is_none());
     }
 
     #[tokio::test]
     async fn memory_peer_storage_remove_leecher() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
-            .await;
+        peer_store.put_leecher(info_hash, peer.clone()).await;
 
-        let _ = peer_store
-            .remove_leecher(info_hash.clone(), peer.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer),
-            false
-        );
+        let _ = peer_store.remove_leecher(info_hash, peer.clone()).await;
+        // The swarm goes away along with its last peer
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .is_none());
     }
 
     #[tokio::test]
     async fn memory_peer_storage_promote_leecher() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
+        peer_store.put_leecher(info_hash, peer.clone()).await;
+        peer_store.promote_leecher(info_hash, peer.clone()).await;
+
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .unwrap()
+            .seeders
+            .contains(&peer));
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_bulk_insert() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer1 = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            uploaded: 0,
+            downloaded: 0,
+            last_announced: Instant::now(),
+        });
+        let peer2 = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"TSRQPONMLKJIHGFEDCBA"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            last_announced: Instant::now(),
+        });
+
+        // An already present peer should not be counted twice
+        peer_store.put_leecher(info_hash, peer1.clone()).await;
+
+        let imported = vec![
+            ImportedPeer {
+                info_hash,
+                peer: peer1.clone(),
+                seeder: true,
+            },
+            ImportedPeer {
+                info_hash,
+                peer: peer2.clone(),
+                seeder: true,
+            },
+        ];
+
+        assert_eq!(peer_store.bulk_insert(imported).await, (1, 0));
+
+        let store = peer_store.shard(&info_hash).read().await;
+        let swarm = store.get(&info_hash).unwrap();
+        assert!(swarm.leechers.contains(&peer1));
+        assert!(swarm.seeders.contains(&peer2));
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_compact_peers() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peers: Vec<Peer> = (0..4u16)
+            .map(|i| {
+                Peer::new(
+                    PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+                    if i % 2 == 0 {
+                        "192.0.2.1".parse().unwrap()
+                    } else {
+                        "2001:db8::1".parse().unwrap()
+                    },
+                    6881 + i,
+                    0,
+                    0,
+                )
+            })
+            .collect();
+
+        for peer in peers.iter() {
+            peer_store.put_leecher(info_hash, peer.clone()).await;
+        }
         peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
+            .promote_leecher(info_hash, peers[1].clone())
             .await;
+        peer_store.remove_leecher(info_hash, peers[0].clone()).await;
+
+        // Promotions keep a peer, removals drop it
+        let (v4, v6) = peer_store.get_peers(info_hash, 50).await;
+        assert_eq!(v4.len() + v6.len(), 3);
+        assert!(!v4.iter().any(|p| p.port == 6881));
+
+        let (v4, v6) = peer_store.get_peers(info_hash, 2).await;
+        assert_eq!(v4.len() + v6.len(), 2);
+
+        // The reaper keeps the compact peers in step with the sets
         peer_store
-            .promote_leecher(info_hash.clone(), peer.clone())
-            .await;
+            .shard(&info_hash)
+            .write()
+            .await
+            .get_mut(&info_hash)
+            .unwrap()
+            .reap(Duration::from_secs(0));
+        assert_eq!(peer_store.get_peers(info_hash, 50).await, (vec![], vec![]));
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_evict_lru() {
+        let peer_store = PeerStore::with_shards(4);
+        let info_hashes = [
+            InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"),
+            InfoHash(*b"B2C3D4E5F6G7H8I9J0K1"),
+            InfoHash(*b"C3D4E5F6G7H8I9J0K1L2"),
+        ];
+
+        // The first torrent was announced to longest ago
+        for (age, info_hash) in info_hashes
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (3 - i as u64, h))
+        {
+            let mut peer = Peer::new(
+                PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+                "192.0.2.1".parse().unwrap(),
+                6881,
+                0,
+                0,
+            );
+            peer.set_last_announced(Instant::now() - Duration::from_secs(age * 60));
+            peer_store.put_seeder(*info_hash, peer).await;
+        }
 
+        assert!(peer_store.evict_lru(0, 0).await.is_empty());
+        let evicted = peer_store.evict_lru(2, 0).await;
         assert_eq!(
+            evicted,
+            vec![EvictedSwarm {
+                info_hash: info_hashes[0],
+                seeders: 1,
+                leechers: 0,
+                partial_seeds: 0,
+            }]
+        );
+        assert!(peer_store
+            .shard(&info_hashes[0])
+            .read()
+            .await
+            .get(&info_hashes[0])
+            .is_none());
+
+        assert_eq!(
+            peer_store.evict_lru(0, 1).await[0].info_hash,
+            info_hashes[1]
+        );
+        assert_eq!(peer_store.dump().await[0].0, info_hashes[2]);
+
+        // The evicted peers come out of the torrent's counts
+        let mut records = TorrentRecords::new();
+        records.insert(info_hashes[0], Torrent::new(info_hashes[0], 3, 5, 2, 0));
+        let torrent_store = TorrentStore::new(records);
+        torrent_store.remove_peers(&evicted[0]).await;
+        assert_eq!(
+            torrent_store.get_announce_stats(info_hashes[0]).await,
+            (2, 5, 2)
+        );
+        assert_eq!(torrent_store.take_dirty().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_sharded() {
+        let peer_store = PeerStore::with_shards(4);
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let peer = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            uploaded: 0,
+            downloaded: 0,
+            last_announced: Instant::now(),
+        });
+
+        let imported = [info_hash1, info_hash2]
+            .iter()
+            .map(|info_hash| ImportedPeer {
+                info_hash: *info_hash,
+                peer: peer.clone(),
+                seeder: true,
+            })
+            .collect();
+        assert_eq!(peer_store.bulk_insert(imported).await, (2, 0));
+
+        // Each swarm lives in the shard its info hash picks, and nowhere else
+        assert!(!std::ptr::eq(
+            peer_store.shard(&info_hash1),
+            peer_store.shard(&info_hash2)
+        ));
+        for info_hash in [info_hash1, info_hash2].iter() {
+            let mut holding = 0;
+            for shard in peer_store.shards() {
+                holding += shard.read().await.contains_key(info_hash) as usize;
+            }
+            assert_eq!(holding, 1);
+        }
+        assert_eq!(peer_store.dump().await.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn single_writer_peer_storage() {
+        let peer_store = PeerStore::with_shards(2).with_writers();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = |port| {
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+                ip: Ipv4Addr::LOCALHOST,
+                port,
+                uploaded: 0,
+                downloaded: 0,
+                last_announced: Instant::now(),
+            })
+        };
+
+        // Changes sent at once are made in one batch, and each
+        // caller still gets back what its own change did
+        let resumed = futures::future::join_all(
+            (6881..6891).map(|port| peer_store.put_leecher(info_hash, peer(port))),
+        )
+        .await;
+        assert!(resumed.iter().all(|resumed| !resumed));
+        assert!(peer_store.promote_leecher(info_hash, peer(6881)).await);
+        assert_eq!(peer_store.swarm_size(&info_hash).await, (1, 9));
+
+        // Snapshots are current by the time a change returns
+        assert_eq!(peer_store.sample_peers(info_hash, 50).await.2, 10);
+        assert!(peer_store.remove_leecher(info_hash, peer(6882)).await);
+        let (peers, _, size) = peer_store.sample_peers(info_hash, 50).await;
+        assert_eq!(size, 9);
+        assert!(!peers.iter().any(|p| p.port == 6882));
+
+        // So are those of swarms changed in bulk
+        let stale = peer_store.dump().await;
+        let evicted = peer_store.evict_lru(0, 1).await;
+        assert_eq!((evicted[0].seeders, evicted[0].leechers), (1, 8));
+        assert_eq!(peer_store.sample_peers(info_hash, 50).await.2, 0);
+        assert_eq!(peer_store.restore(stale).await, 9);
+        assert_eq!(peer_store.sample_peers(info_hash, 50).await.2, 9);
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_expire_peer() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            uploaded: 0,
+            downloaded: 0,
+            last_announced: Instant::now(),
+        });
+
+        peer_store.put_seeder(info_hash, peer.clone()).await;
+
+        let timeout = Duration::from_secs(5);
+        let delay = Duration::from_secs(2);
+        assert!(
             peer_store
-                .records
-                .read()
+                .expire_peer(info_hash, peer.clone(), timeout, delay)
                 .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer),
-            true
         );
+
+        let store = peer_store.shard(&info_hash).read().await;
+        let expiring = store.get(&info_hash).unwrap().seeders.get(&peer).unwrap();
+        assert!(expiring.last_announced().elapsed() >= timeout - delay);
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_traffic_delta() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            uploaded: 1000,
+            downloaded: 5000,
+            last_announced: Instant::now(),
+        });
+
+        // Unknown peers have no baseline to compare against
+        assert_eq!(peer_store.traffic_delta(&info_hash, &peer).await, (0, 0));
+
+        peer_store.put_leecher(info_hash, peer.clone()).await;
+
+        let mut later = peer.clone();
+        if let Peer::V4(p) = &mut later {
+            p.uploaded = 1500;
+            p.downloaded = 9000;
+        }
+        assert_eq!(
+            peer_store.traffic_delta(&info_hash, &later).await,
+            (500, 4000)
+        );
+
+        // A client that restarted its session reports smaller totals
+        let mut restarted = peer.clone();
+        if let Peer::V4(p) = &mut restarted {
+            p.uploaded = 200;
+            p.downloaded = 300;
+        }
+        assert_eq!(
+            peer_store.traffic_delta(&info_hash, &restarted).await,
+            (200, 300)
+        );
+    }
+
+    #[tokio::test]
+    async fn every_announce_refreshes_peers() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = |age| {
+            let mut peer = Peer::new(
+                PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+                Ipv4Addr::LOCALHOST.into(),
+                6893,
+                0,
+                0,
+            );
+            peer.set_last_announced(Instant::now() - Duration::from_secs(age));
+            peer
+        };
+
+        peer_store.put_leecher(info_hash, peer(100)).await;
+        peer_store.put_leecher(info_hash, peer(0)).await;
+        let peers = peer_store.dump().await;
+        assert!(peers[0].1.last_announced().elapsed() < Duration::from_secs(100));
+
+        // Completing a torrent adds a peer that wasn't seen starting it
+        let other = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        peer_store.promote_leecher(other, peer(0)).await;
+        let peers = peer_store.dump().await;
+        assert!(peers.contains(&(other, peer(0), PeerRole::Seeder)));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_update_peer() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
-            .await;
+        peer_store.put_leecher(info_hash, peer.clone()).await;
 
         let peer2 = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
+            uploaded: 0,
+            downloaded: 0,
             last_announced: Instant::now(),
         });
 
-        peer_store
-            .update_peer(info_hash.clone(), peer2.clone())
-            .await;
+        assert!(
+            peer_store
+                .update_peer(info_hash, peer2.clone(), false)
+                .await
+        );
 
+        assert!(peer_store
+            .shard(&info_hash)
+            .read()
+            .await
+            .get(&info_hash)
+            .unwrap()
+            .leechers
+            .contains(&peer2));
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_set_role() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = |age| {
+            let mut peer = Peer::new(
+                PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+                Ipv4Addr::LOCALHOST.into(),
+                6893,
+                0,
+                0,
+            );
+            peer.set_last_announced(Instant::now() - Duration::from_secs(age));
+            peer
+        };
+
+        let set = |role| peer_store.set_role(info_hash, peer(10), role);
+        assert_eq!(set(Some(PeerRole::Leecher)).await, Some(None));
+        assert_eq!(
+            set(Some(PeerRole::Seeder)).await,
+            Some(Some(PeerRole::Leecher))
+        );
+        assert_eq!(
+            peer_store.lookup(&info_hash, &peer(0)).await.unwrap().1,
+            PeerRole::Seeder
+        );
+        assert_eq!(peer_store.get_peers(info_hash, 50).await.0.len(), 1);
+
+        // An older change than what's stored is ignored
         assert_eq!(
             peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer2),
-            true
+                .set_role(info_hash, peer(20), Some(PeerRole::Leecher))
+                .await,
+            None
+        );
+        assert_eq!(peer_store.swarm_size(&info_hash).await, (1, 0));
+
+        assert_eq!(
+            peer_store.set_role(info_hash, peer(0), None).await,
+            Some(Some(PeerRole::Seeder))
         );
+        assert!(peer_store.lookup(&info_hash, &peer(0)).await.is_none());
+        assert!(peer_store.shard(&info_hash).read().await.is_empty());
     }
 }
@@ -1,4 +1,8 @@
+use crate::bittorrent::InfoHash;
+use crate::statistics::ArchivedStatistics;
 use crate::storage;
+use crate::storage::peer_snapshot::{self, SavedPeer};
+use crate::storage::users::{Snatch, User, UserRecords, UserTraffic};
 use mysql::prelude::*;
 use mysql::*;
 
@@ -8,18 +12,44 @@ pub fn get_torrents(pool: Pool) -> Result<storage::TorrentRecords> {
     let mut torrents = storage::TorrentRecords::new();
 
     let selected_torrents = conn.query_map(
-        "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
-        |(info_hash, complete, downloaded, incomplete, balance)| storage::Torrent {
-            info_hash,
-            complete,
-            downloaded,
-            incomplete,
-            balance,
-        },
+        "SELECT info_hash, complete, downloaded, incomplete, balance, bytes_uploaded, bytes_downloaded, category, hidden FROM torrents",
+        |row: (String, u32, u32, u32, u64, u64, u64, Option<String>, bool)| row,
     )?;
 
-    for sel in selected_torrents {
-        torrents.insert(sel.info_hash.clone(), sel);
+    // Info hashes are stored as hex, so rows that don't hold one are skipped
+    for (
+        hex,
+        complete,
+        downloaded,
+        incomplete,
+        balance,
+        bytes_uploaded,
+        bytes_downloaded,
+        category,
+        hidden,
+    ) in selected_torrents
+    {
+        match hex.parse::<InfoHash>() {
+            Ok(info_hash) => {
+                torrents.insert(
+                    info_hash,
+                    storage::Torrent {
+                        info_hash,
+                        complete,
+                        downloaded,
+                        incomplete,
+                        // Partial seeds only last as long as their peers do
+                        partial: 0,
+                        balance,
+                        bytes_uploaded,
+                        bytes_downloaded,
+                        category,
+                        hidden,
+                    },
+                );
+            }
+            Err(_) => warn!("Skipping torrent with malformed info hash {}", hex),
+        }
     }
 
     Ok(torrents)
@@ -31,22 +61,187 @@ pub fn flush_torrents(pool: Pool, torrents: Vec<storage::Torrent>) -> Result<()>
 
     let params = torrents.iter().map(|torrent| {
         params! {
-            "info_hash" => &torrent.info_hash,
+            "info_hash" => torrent.info_hash.to_hex(),
             "complete" => torrent.complete,
             "downloaded" => torrent.downloaded,
             "incomplete" => torrent.incomplete,
             "balance" => torrent.balance,
+            "bytes_uploaded" => torrent.bytes_uploaded,
+            "bytes_downloaded" => torrent.bytes_downloaded,
+            "category" => &torrent.category,
         }
     });
 
     conn.exec_batch(
-        r"INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
-                    VALUES (:info_hash, :complete, :downloaded, :incomplete, :balance)
+        r"INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance, bytes_uploaded, bytes_downloaded, category)
+                    VALUES (:info_hash, :complete, :downloaded, :incomplete, :balance, :bytes_uploaded, :bytes_downloaded, :category)
                     ON DUPLICATE KEY UPDATE 
                         complete=:complete, 
                         downloaded=:downloaded, 
                         incomplete=:incomplete, 
-                        balance=:balance",
+                        balance=:balance,
+                        bytes_uploaded=:bytes_uploaded,
+                        bytes_downloaded=:bytes_downloaded",
+        params,
+    )?;
+
+    Ok(())
+}
+
+pub fn get_stats_history(pool: Pool) -> Result<Vec<ArchivedStatistics>> {
+    let mut conn = pool.get_conn()?;
+
+    conn.query_map(
+        "SELECT period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded
+            FROM stats_history ORDER BY period_start",
+        |(period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded)| {
+            ArchivedStatistics {
+                period_start,
+                period_end,
+                announce_requests,
+                succ_announces,
+                scrapes,
+                uploaded,
+                downloaded,
+            }
+        },
+    )
+}
+
+pub fn archive_stats(pool: Pool, archived: &ArchivedStatistics) -> Result<()> {
+    let mut conn = pool.get_conn()?;
+
+    conn.exec_drop(
+        r"INSERT INTO stats_history (period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded)
+                    VALUES (:period_start, :period_end, :announce_requests, :succ_announces, :scrapes, :uploaded, :downloaded)",
+        params! {
+            "period_start" => archived.period_start,
+            "period_end" => archived.period_end,
+            "announce_requests" => archived.announce_requests,
+            "succ_announces" => archived.succ_announces,
+            "scrapes" => archived.scrapes,
+            "uploaded" => archived.uploaded,
+            "downloaded" => archived.downloaded,
+        },
+    )
+}
+
+pub fn get_peers(pool: Pool) -> Result<Vec<SavedPeer>> {
+    let mut conn = pool.get_conn()?;
+
+    let rows = conn.query_map(
+        "SELECT info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced FROM peers",
+        |row: (String, String, String, u16, u64, u64, String, u64)| row,
+    )?;
+
+    let mut peers = Vec::new();
+    for (info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced) in rows {
+        match peer_snapshot::parse_columns(&info_hash, &peer_id, &ip, &role) {
+            Some((info_hash, peer_id, ip, role)) => peers.push(SavedPeer {
+                info_hash,
+                peer_id,
+                ip,
+                port,
+                uploaded,
+                downloaded,
+                role,
+                last_announced,
+            }),
+            None => warn!("Skipping malformed saved peer for torrent {}", info_hash),
+        }
+    }
+
+    Ok(peers)
+}
+
+pub fn save_peers(pool: Pool, peers: Vec<SavedPeer>) -> Result<()> {
+    let mut conn = pool.get_conn()?;
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+
+    // The saved peers replace whatever was saved before
+    tx.query_drop("DELETE FROM peers")?;
+
+    let params = peers.iter().map(|peer| {
+        params! {
+            "info_hash" => peer.info_hash.to_hex(),
+            "peer_id" => peer.peer_id.to_hex(),
+            "ip" => peer.ip.to_string(),
+            "port" => peer.port,
+            "uploaded" => peer.uploaded,
+            "downloaded" => peer.downloaded,
+            "role" => peer.role.as_str(),
+            "last_announced" => peer.last_announced,
+        }
+    });
+
+    tx.exec_batch(
+        r"INSERT INTO peers (info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced)
+                    VALUES (:info_hash, :peer_id, :ip, :port, :uploaded, :downloaded, :role, :last_announced)",
+        params,
+    )?;
+
+    tx.commit()
+}
+
+pub fn ping(pool: Pool) -> Result<()> {
+    let mut conn = pool.get_conn()?;
+    conn.query_drop("SELECT 1")
+}
+
+pub fn get_users(pool: Pool) -> Result<UserRecords> {
+    let mut conn = pool.get_conn()?;
+
+    let users = conn.query_map(
+        "SELECT id, passkey FROM users",
+        |(id, passkey): (u32, String)| User { id, passkey },
+    )?;
+
+    Ok(users
+        .into_iter()
+        .map(|user| (user.passkey.clone(), user))
+        .collect())
+}
+
+// Traffic is added to what's in the database rather than overwriting it,
+// so that the site software can adjust users' totals in the meantime
+pub fn flush_user_traffic(pool: Pool, traffic: Vec<(u32, UserTraffic)>) -> Result<()> {
+    let mut conn = pool.get_conn()?;
+
+    let params = traffic.iter().map(|(id, user_traffic)| {
+        params! {
+            "id" => id,
+            "uploaded" => user_traffic.uploaded,
+            "downloaded" => user_traffic.downloaded,
+        }
+    });
+
+    conn.exec_batch(
+        r"UPDATE users SET
+                        uploaded = uploaded + :uploaded,
+                        downloaded = downloaded + :downloaded
+                    WHERE id = :id",
+        params,
+    )?;
+
+    Ok(())
+}
+
+pub fn save_snatches(pool: Pool, snatches: Vec<Snatch>) -> Result<()> {
+    let mut conn = pool.get_conn()?;
+
+    let params = snatches.iter().map(|snatch| {
+        params! {
+            "user_id" => snatch.user_id,
+            "info_hash" => snatch.info_hash.to_hex(),
+            "completed_at" => snatch.completed_at,
+            "uploaded" => snatch.uploaded,
+            "downloaded" => snatch.downloaded,
+        }
+    });
+
+    conn.exec_batch(
+        r"INSERT INTO snatches (user_id, info_hash, completed_at, uploaded, downloaded)
+                    VALUES (:user_id, :info_hash, :completed_at, :uploaded, :downloaded)",
         params,
     )?;
 
@@ -0,0 +1,168 @@
+use std::fs;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bittorrent::{InfoHash, Peer, PeerId};
+use crate::storage::{PeerRole, PeerStore};
+
+// Instants can't outlive the process, so peers are saved with the
+// wall-clock time of their last announce. The same form is used whether
+// they're written to a local file or to the database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPeer {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub role: PeerRole,
+    pub last_announced: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn to_saved(peers: Vec<(InfoHash, Peer, PeerRole)>) -> Vec<SavedPeer> {
+    let now = Instant::now();
+    let unix_now = unix_now();
+
+    peers
+        .into_iter()
+        .map(|(info_hash, peer, role)| {
+            let (uploaded, downloaded) = peer.traffic();
+            let age = now.duration_since(peer.last_announced()).as_secs();
+            SavedPeer {
+                info_hash,
+                peer_id: peer.peer_id(),
+                ip: peer.ip(),
+                port: peer.port(),
+                uploaded,
+                downloaded,
+                role,
+                last_announced: unix_now.saturating_sub(age),
+            }
+        })
+        .collect()
+}
+
+// Peers that would have timed out while the tracker was down are dropped
+pub fn from_saved(peers: Vec<SavedPeer>, peer_timeout: u64) -> Vec<(InfoHash, Peer, PeerRole)> {
+    let now = Instant::now();
+    let unix_now = unix_now();
+
+    peers
+        .into_iter()
+        .filter_map(|p| {
+            let age = unix_now.saturating_sub(p.last_announced);
+            if age >= peer_timeout {
+                return None;
+            }
+
+            let mut peer = Peer::new(p.peer_id, p.ip, p.port, p.uploaded, p.downloaded);
+            peer.set_last_announced(now.checked_sub(Duration::from_secs(age)).unwrap_or(now));
+            Some((p.info_hash, peer, p.role))
+        })
+        .collect()
+}
+
+// Databases keep keys, addresses and roles as text. Returns None for
+// rows where any of them can't be parsed back.
+pub fn parse_columns(
+    info_hash: &str,
+    peer_id: &str,
+    ip: &str,
+    role: &str,
+) -> Option<(InfoHash, PeerId, IpAddr, PeerRole)> {
+    Some((
+        info_hash.parse().ok()?,
+        peer_id.parse().ok()?,
+        ip.parse().ok()?,
+        PeerRole::parse(role)?,
+    ))
+}
+
+fn encode(peers: Vec<(InfoHash, Peer, PeerRole)>) -> Result<Vec<u8>, String> {
+    bincode::serialize(&to_saved(peers)).map_err(|e| e.to_string())
+}
+
+fn decode(bytes: &[u8], peer_timeout: u64) -> Result<Vec<(InfoHash, Peer, PeerRole)>, String> {
+    let saved: Vec<SavedPeer> = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+    Ok(from_saved(saved, peer_timeout))
+}
+
+// The file is written next to its final location and then renamed over
+// it, so a crash mid-write never leaves a truncated snapshot behind
+pub async fn save(path: &str, peer_store: &PeerStore) -> Result<usize, String> {
+    let peers = peer_store.dump().await;
+    let num_peers = peers.len();
+    let bytes = encode(peers)?;
+
+    let path = path.to_string();
+    let tmp_path = format!("{}.tmp", path);
+    actix_threadpool::run(move || {
+        fs::write(&tmp_path, bytes).and_then(|_| fs::rename(&tmp_path, &path))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(num_peers)
+}
+
+// A missing file isn't an error, as there's nothing to restore on first start
+pub async fn load(path: &str, peer_store: &PeerStore, peer_timeout: u64) -> Result<usize, String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let peers = decode(&bytes, peer_timeout)?;
+    Ok(peer_store.restore(peers).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn peer_snapshot_round_trip() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let seeder = Peer::new(
+            PeerId(*b"-DE9824-143964258012"),
+            "192.0.2.1".parse().unwrap(),
+            6881,
+            100,
+            0,
+        );
+        let mut stale = Peer::new(
+            PeerId(*b"-DE9824-143964258013"),
+            "2001:db8::1".parse().unwrap(),
+            6882,
+            0,
+            50,
+        );
+        stale.set_last_announced(Instant::now() - Duration::from_secs(600));
+
+        let peers = vec![
+            (info_hash, seeder.clone(), PeerRole::Seeder),
+            (info_hash, stale, PeerRole::PartialSeed),
+        ];
+        let bytes = encode(peers).unwrap();
+
+        // Only the peer that announced recently survives a 300 sec timeout
+        let store = PeerStore::new();
+        assert_eq!(store.restore(decode(&bytes, 300).unwrap()).await, 1);
+        let records = store.shard(&info_hash).read().await;
+        let restored = records[&info_hash].seeders.get(&seeder).unwrap();
+        assert_eq!(restored.traffic(), (100, 0));
+
+        assert_eq!(decode(&bytes, 7200).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,246 @@
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{Error, Row};
+
+use crate::bittorrent::InfoHash;
+use crate::statistics::ArchivedStatistics;
+use crate::storage;
+use crate::storage::peer_snapshot::{self, SavedPeer};
+use crate::storage::users::{Snatch, User, UserRecords, UserTraffic};
+
+// Postgres has no unsigned integers, so counters are stored in the
+// signed type of the same width and converted on the way in and out.
+// The schema is the same as MySQL's otherwise (see schema_postgres.sql).
+
+fn torrent_from_row(row: &PgRow, info_hash: InfoHash) -> Result<storage::Torrent, Error> {
+    Ok(storage::Torrent {
+        info_hash,
+        complete: row.try_get::<i32, _>("complete")? as u32,
+        downloaded: row.try_get::<i32, _>("downloaded")? as u32,
+        incomplete: row.try_get::<i32, _>("incomplete")? as u32,
+        // Partial seeds only last as long as their peers do
+        partial: 0,
+        balance: row.try_get::<i64, _>("balance")? as u64,
+        bytes_uploaded: row.try_get::<i64, _>("bytes_uploaded")? as u64,
+        bytes_downloaded: row.try_get::<i64, _>("bytes_downloaded")? as u64,
+        category: row.try_get("category")?,
+        hidden: row.try_get("hidden")?,
+    })
+}
+
+pub async fn get_torrents(pool: &PgPool) -> Result<storage::TorrentRecords, Error> {
+    let rows = sqlx::query(
+        "SELECT info_hash, complete, downloaded, incomplete, balance, bytes_uploaded, bytes_downloaded, category, hidden FROM torrents",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut torrents = storage::TorrentRecords::new();
+
+    // Info hashes are stored as hex, so rows that don't hold one are skipped
+    for row in rows {
+        let hex: String = row.try_get("info_hash")?;
+        match hex.parse::<InfoHash>() {
+            Ok(info_hash) => {
+                torrents.insert(info_hash, torrent_from_row(&row, info_hash)?);
+            }
+            Err(_) => warn!("Skipping torrent with malformed info hash {}", hex),
+        }
+    }
+
+    Ok(torrents)
+}
+
+pub async fn flush_torrents(pool: &PgPool, torrents: Vec<storage::Torrent>) -> Result<(), Error> {
+    // All torrents are written in one transaction, as with MySQL's batch
+    let mut tx = pool.begin().await?;
+
+    for torrent in torrents {
+        sqlx::query(
+            "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance, bytes_uploaded, bytes_downloaded, category)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT (info_hash) DO UPDATE SET
+                    complete = $2,
+                    downloaded = $3,
+                    incomplete = $4,
+                    balance = $5,
+                    bytes_uploaded = $6,
+                    bytes_downloaded = $7",
+        )
+        .bind(torrent.info_hash.to_hex())
+        .bind(torrent.complete as i32)
+        .bind(torrent.downloaded as i32)
+        .bind(torrent.incomplete as i32)
+        .bind(torrent.balance as i64)
+        .bind(torrent.bytes_uploaded as i64)
+        .bind(torrent.bytes_downloaded as i64)
+        .bind(torrent.category)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+pub async fn get_stats_history(pool: &PgPool) -> Result<Vec<ArchivedStatistics>, Error> {
+    let rows = sqlx::query(
+        "SELECT period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded
+            FROM stats_history ORDER BY period_start",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(ArchivedStatistics {
+                period_start: row.try_get::<i64, _>("period_start")? as u64,
+                period_end: row.try_get::<i64, _>("period_end")? as u64,
+                announce_requests: row.try_get::<i32, _>("announce_requests")? as u32,
+                succ_announces: row.try_get::<i32, _>("succ_announces")? as u32,
+                scrapes: row.try_get::<i32, _>("scrapes")? as u32,
+                uploaded: row.try_get::<i64, _>("uploaded")? as u64,
+                downloaded: row.try_get::<i64, _>("downloaded")? as u64,
+            })
+        })
+        .collect()
+}
+
+pub async fn archive_stats(pool: &PgPool, archived: &ArchivedStatistics) -> Result<(), Error> {
+    sqlx::query(
+        "INSERT INTO stats_history (period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+    )
+    .bind(archived.period_start as i64)
+    .bind(archived.period_end as i64)
+    .bind(archived.announce_requests as i32)
+    .bind(archived.succ_announces as i32)
+    .bind(archived.scrapes as i32)
+    .bind(archived.uploaded as i64)
+    .bind(archived.downloaded as i64)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_peers(pool: &PgPool) -> Result<Vec<SavedPeer>, Error> {
+    let rows = sqlx::query(
+        "SELECT info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced FROM peers",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut peers = Vec::new();
+    for row in rows {
+        let info_hash: String = row.try_get("info_hash")?;
+        let peer_id: String = row.try_get("peer_id")?;
+        let ip: String = row.try_get("ip")?;
+        let role: String = row.try_get("role")?;
+
+        match peer_snapshot::parse_columns(&info_hash, &peer_id, &ip, &role) {
+            Some((info_hash, peer_id, ip, role)) => peers.push(SavedPeer {
+                info_hash,
+                peer_id,
+                ip,
+                port: row.try_get::<i32, _>("port")? as u16,
+                uploaded: row.try_get::<i64, _>("uploaded")? as u64,
+                downloaded: row.try_get::<i64, _>("downloaded")? as u64,
+                role,
+                last_announced: row.try_get::<i64, _>("last_announced")? as u64,
+            }),
+            None => warn!("Skipping malformed saved peer for torrent {}", info_hash),
+        }
+    }
+
+    Ok(peers)
+}
+
+pub async fn save_peers(pool: &PgPool, peers: Vec<SavedPeer>) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    // The saved peers replace whatever was saved before
+    sqlx::query("DELETE FROM peers").execute(&mut tx).await?;
+
+    for peer in peers {
+        sqlx::query(
+            "INSERT INTO peers (info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(peer.info_hash.to_hex())
+        .bind(peer.peer_id.to_hex())
+        .bind(peer.ip.to_string())
+        .bind(peer.port as i32)
+        .bind(peer.uploaded as i64)
+        .bind(peer.downloaded as i64)
+        .bind(peer.role.as_str())
+        .bind(peer.last_announced as i64)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+pub async fn ping(pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
+
+pub async fn get_users(pool: &PgPool) -> Result<UserRecords, Error> {
+    let rows = sqlx::query("SELECT id, passkey FROM users")
+        .fetch_all(pool)
+        .await?;
+
+    let mut users = UserRecords::new();
+    for row in rows {
+        let user = User {
+            id: row.try_get::<i32, _>("id")? as u32,
+            passkey: row.try_get("passkey")?,
+        };
+        users.insert(user.passkey.clone(), user);
+    }
+
+    Ok(users)
+}
+
+pub async fn flush_user_traffic(
+    pool: &PgPool,
+    traffic: Vec<(u32, UserTraffic)>,
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    for (id, user_traffic) in traffic {
+        sqlx::query(
+            "UPDATE users SET
+                uploaded = uploaded + $2,
+                downloaded = downloaded + $3
+            WHERE id = $1",
+        )
+        .bind(id as i32)
+        .bind(user_traffic.uploaded as i64)
+        .bind(user_traffic.downloaded as i64)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+pub async fn save_snatches(pool: &PgPool, snatches: Vec<Snatch>) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    for snatch in snatches {
+        sqlx::query(
+            "INSERT INTO snatches (user_id, info_hash, completed_at, uploaded, downloaded)
+                VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(snatch.user_id as i32)
+        .bind(snatch.info_hash.to_hex())
+        .bind(snatch.completed_at as i64)
+        .bind(snatch.uploaded as i64)
+        .bind(snatch.downloaded as i64)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use ::redis::{Commands, Connection, ErrorKind, RedisResult};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::bittorrent::{InfoHash, PeerId};
+use crate::statistics::ArchivedStatistics;
+use crate::storage;
+use crate::storage::peer_snapshot::SavedPeer;
+use crate::storage::users::{Snatch, User, UserRecords, UserTraffic};
+use crate::util::unix_secs;
+
+// Redis has no tables, so each kind of record lives under its own key
+// prefix. Torrents are hashes, so that the site software can set the
+// category or hidden field of one without touching its counts. Peers
+// each get a key of their own, prefixed by the info hash, which expires
+// when the peer would have timed out; trackers sharing the database
+// load every one that's still there when they start.
+const TORRENT_PREFIX: &str = "tyto:torrent:";
+const PEER_PREFIX: &str = "tyto:peer:";
+const USER_PREFIX: &str = "tyto:user:";
+const USERS: &str = "tyto:users";
+const SNATCHES: &str = "tyto:snatches";
+const STATS_HISTORY: &str = "tyto:stats_history";
+
+// Keys are read and written this many at a time
+const BATCH_SIZE: usize = 1000;
+
+// A connection can only be used by one caller at a time
+pub type Database = Arc<Mutex<Connection>>;
+
+pub fn open(path: &str) -> RedisResult<Database> {
+    let conn = ::redis::Client::open(path)?.get_connection()?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+fn to_json<T: Serialize>(record: &T) -> RedisResult<String> {
+    serde_json::to_string(record).map_err(|e| {
+        (
+            ErrorKind::TypeError,
+            "Could not encode record",
+            e.to_string(),
+        )
+            .into()
+    })
+}
+
+// SCAN rather than KEYS, so Redis keeps serving everyone else meanwhile
+fn scan(conn: &mut Connection, prefix: &str) -> RedisResult<Vec<String>> {
+    let keys = conn.scan_match(format!("{}*", prefix))?.collect();
+    Ok(keys)
+}
+
+pub fn peer_key(info_hash: &InfoHash, peer_id: &PeerId, addr: SocketAddr) -> String {
+    format!(
+        "{}{}:{}:{}",
+        PEER_PREFIX,
+        info_hash.to_hex(),
+        peer_id.to_hex(),
+        addr
+    )
+}
+
+// Fields the site software hasn't set are taken to be zero
+fn torrent_from_fields(info_hash: InfoHash, fields: &HashMap<String, String>) -> storage::Torrent {
+    let count = |name: &str| {
+        fields
+            .get(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    };
+
+    storage::Torrent {
+        info_hash,
+        complete: count("complete") as u32,
+        downloaded: count("downloaded") as u32,
+        incomplete: count("incomplete") as u32,
+        // Partial seeds only last as long as their peers do
+        partial: 0,
+        balance: count("balance"),
+        bytes_uploaded: count("bytes_uploaded"),
+        bytes_downloaded: count("bytes_downloaded"),
+        category: fields.get("category").cloned(),
+        hidden: matches!(
+            fields.get("hidden").map(String::as_str),
+            Some("1") | Some("true")
+        ),
+    }
+}
+
+pub fn get_torrents(db: &Database) -> RedisResult<storage::TorrentRecords> {
+    let mut conn = db.lock().unwrap();
+    let keys = scan(&mut conn, TORRENT_PREFIX)?;
+
+    let mut torrents = storage::TorrentRecords::new();
+
+    // Info hashes are kept as hex, so keys that don't end in one are skipped
+    for batch in keys.chunks(BATCH_SIZE) {
+        let mut pipe = ::redis::pipe();
+        for key in batch {
+            pipe.hgetall(key);
+        }
+        let records: Vec<HashMap<String, String>> = pipe.query(&mut *conn)?;
+
+        for (key, fields) in batch.iter().zip(records) {
+            let hex = &key[TORRENT_PREFIX.len()..];
+            match hex.parse::<InfoHash>() {
+                Ok(info_hash) => {
+                    torrents.insert(info_hash, torrent_from_fields(info_hash, &fields));
+                }
+                Err(_) => warn!("Skipping torrent with malformed info hash {}", hex),
+            }
+        }
+    }
+
+    Ok(torrents)
+}
+
+// Like the SQL backends, a category is only written for new torrents
+pub fn flush_torrents(db: &Database, torrents: Vec<storage::Torrent>) -> RedisResult<()> {
+    let mut conn = db.lock().unwrap();
+
+    for batch in torrents.chunks(BATCH_SIZE) {
+        let mut pipe = ::redis::pipe();
+        for torrent in batch {
+            let key = format!("{}{}", TORRENT_PREFIX, torrent.info_hash.to_hex());
+            pipe.hset_multiple(
+                &key,
+                &[
+                    ("complete", torrent.complete as u64),
+                    ("downloaded", torrent.downloaded as u64),
+                    ("incomplete", torrent.incomplete as u64),
+                    ("balance", torrent.balance),
+                    ("bytes_uploaded", torrent.bytes_uploaded),
+                    ("bytes_downloaded", torrent.bytes_downloaded),
+                ],
+            )
+            .ignore();
+            if let Some(category) = &torrent.category {
+                pipe.hset_nx(&key, "category", category).ignore();
+            }
+        }
+        pipe.query::<()>(&mut *conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_stats_history(db: &Database) -> RedisResult<Vec<ArchivedStatistics>> {
+    let mut conn = db.lock().unwrap();
+    let periods: Vec<String> = conn.lrange(STATS_HISTORY, 0, -1)?;
+
+    Ok(periods
+        .iter()
+        .filter_map(|period| match serde_json::from_str(period) {
+            Ok(archived) => Some(archived),
+            Err(_) => {
+                warn!("Skipping malformed statistics period {}", period);
+                None
+            }
+        })
+        .collect())
+}
+
+pub fn archive_stats(db: &Database, archived: &ArchivedStatistics) -> RedisResult<()> {
+    let mut conn = db.lock().unwrap();
+    conn.rpush(STATS_HISTORY, to_json(archived)?)
+}
+
+pub fn get_peers(db: &Database) -> RedisResult<Vec<SavedPeer>> {
+    let mut conn = db.lock().unwrap();
+    let keys = scan(&mut conn, PEER_PREFIX)?;
+
+    let mut peers = Vec::new();
+    for batch in keys.chunks(BATCH_SIZE) {
+        // Peers can expire between the scan and here
+        let saved: Vec<Option<String>> = ::redis::cmd("MGET").arg(batch).query(&mut *conn)?;
+
+        for (key, peer) in batch.iter().zip(saved) {
+            match peer.map(|peer| serde_json::from_str(&peer)) {
+                Some(Ok(peer)) => peers.push(peer),
+                Some(Err(_)) => warn!("Skipping malformed saved peer {}", key),
+                None => {}
+            }
+        }
+    }
+
+    Ok(peers)
+}
+
+// Saved peers aren't removed, they just expire once they'd have timed
+// out, so that trackers sharing the database don't drop each other's
+pub fn save_peers(db: &Database, peers: Vec<SavedPeer>, peer_timeout: u64) -> RedisResult<()> {
+    let mut conn = db.lock().unwrap();
+    let now = unix_secs(SystemTime::now());
+
+    for batch in peers.chunks(BATCH_SIZE) {
+        let mut pipe = ::redis::pipe();
+        for peer in batch {
+            let age = now.saturating_sub(peer.last_announced);
+            if age < peer_timeout {
+                let addr = SocketAddr::new(peer.ip, peer.port);
+                pipe.set_ex(
+                    peer_key(&peer.info_hash, &peer.peer_id, addr),
+                    to_json(peer)?,
+                    (peer_timeout - age) as usize,
+                )
+                .ignore();
+            }
+        }
+        pipe.query::<()>(&mut *conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn ping(db: &Database) -> RedisResult<()> {
+    let mut conn = db.lock().unwrap();
+    ::redis::cmd("PING").query::<String>(&mut *conn).map(|_| ())
+}
+
+// Users are a single hash of passkeys to user IDs
+pub fn get_users(db: &Database) -> RedisResult<UserRecords> {
+    let mut conn = db.lock().unwrap();
+    let ids: HashMap<String, String> = conn.hgetall(USERS)?;
+
+    let mut users = UserRecords::new();
+    for (passkey, id) in ids {
+        match id.parse() {
+            Ok(id) => {
+                users.insert(passkey.clone(), User { id, passkey });
+            }
+            Err(_) => warn!("Skipping user with malformed ID {}", id),
+        }
+    }
+
+    Ok(users)
+}
+
+pub fn flush_user_traffic(db: &Database, traffic: Vec<(u32, UserTraffic)>) -> RedisResult<()> {
+    let mut conn = db.lock().unwrap();
+
+    let mut pipe = ::redis::pipe();
+    for (id, user_traffic) in traffic {
+        let key = format!("{}{}", USER_PREFIX, id);
+        pipe.hincr(&key, "uploaded", user_traffic.uploaded)
+            .ignore()
+            .hincr(&key, "downloaded", user_traffic.downloaded)
+            .ignore();
+    }
+    pipe.query(&mut *conn)
+}
+
+pub fn save_snatches(db: &Database, snatches: Vec<Snatch>) -> RedisResult<()> {
+    if snatches.is_empty() {
+        return Ok(());
+    }
+
+    let records = snatches
+        .iter()
+        .map(|snatch| {
+            to_json(&json!({
+                "user_id": snatch.user_id,
+                "info_hash": snatch.info_hash.to_hex(),
+                "completed_at": snatch.completed_at,
+                "uploaded": snatch.uploaded,
+                "downloaded": snatch.downloaded,
+            }))
+        })
+        .collect::<RedisResult<Vec<String>>>()?;
+
+    let mut conn = db.lock().unwrap();
+    conn.rpush(SNATCHES, records)
+}
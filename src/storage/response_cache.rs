@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use tokio::sync::RwLock;
+
+use crate::bittorrent::{InfoHash, PeerFormat};
+
+// Responses are cached per torrent, numwant and peer format, as clients
+// asking for different amounts of peers, or for them laid out another
+// way, can't be handed the same bytes
+type CacheKey = (InfoHash, u32, PeerFormat);
+
+#[derive(Debug, Clone)]
+struct CachedPeers {
+    created: Instant,
+    encoded: Arc<[u8]>,
+}
+
+// The hottest swarms see thousands of announces within a few seconds,
+// each of which would otherwise lock the swarm, sample it anew and
+// encode the peers. For swarms of at least min_peers peers, the encoded
+// peers are reused for ttl instead, and only the rest of the response
+// (complete, incomplete, interval) is written per request, as it's
+// cheap to look up. A ttl of zero disables caching entirely.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    min_peers: usize,
+    ttl: Duration,
+    entries: Arc<RwLock<HashMap<CacheKey, CachedPeers>>>,
+}
+
+impl ResponseCache {
+    pub fn new(min_peers: usize, ttl: u64) -> ResponseCache {
+        ResponseCache {
+            min_peers,
+            ttl: Duration::from_secs(ttl),
+            entries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ttl > Duration::from_secs(0)
+    }
+
+    // Only swarms at or above the size threshold are cached
+    pub fn caches(&self, swarm_size: usize) -> bool {
+        self.is_enabled() && swarm_size >= self.min_peers
+    }
+
+    pub async fn get(
+        &self,
+        info_hash: InfoHash,
+        numwant: u32,
+        format: PeerFormat,
+    ) -> Option<Arc<[u8]>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let entries = self.entries.read().await;
+        match entries.get(&(info_hash, numwant, format)) {
+            Some(cached) if cached.created.elapsed() < self.ttl => Some(cached.encoded.clone()),
+            _ => None,
+        }
+    }
+
+    // Expired entries are cleared out whenever a new one goes in, so the
+    // cache never holds more than the swarms that were hot within the
+    // last ttl
+    pub async fn insert(
+        &self,
+        info_hash: InfoHash,
+        numwant: u32,
+        format: PeerFormat,
+        encoded: Arc<[u8]>,
+    ) {
+        let ttl = self.ttl;
+        let mut entries = self.entries.write().await;
+        entries.retain(|_, cached| cached.created.elapsed() < ttl);
+        entries.insert(
+            (info_hash, numwant, format),
+            CachedPeers {
+                created: Instant::now(),
+                encoded,
+            },
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use ::rocksdb::{IteratorMode, Options, WriteBatch, DB};
+use hashbrown::HashSet;
+
+use crate::bittorrent::InfoHash;
+use crate::storage::{Torrent, TorrentRecords};
+
+// Every torrent is kept on disk in RocksDB, keyed by info hash, so only
+// the ones in use need to be held in memory. The tier is a cache of the
+// storage backend rather than a store of its own, so it's rebuilt from
+// the torrents loaded from there on every start.
+//
+// Lookups are quick enough to be made in place, rather than being sent
+// off to the blocking thread pool like database queries are.
+#[derive(Clone)]
+pub struct TorrentTier {
+    db: Arc<DB>,
+    // How many torrents may be held in memory
+    capacity: usize,
+    // Torrents used since the last eviction, which are the last to go
+    used: Arc<Mutex<HashSet<InfoHash>>>,
+}
+
+impl fmt::Debug for TorrentTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TorrentTier")
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl TorrentTier {
+    pub fn open(path: &str, capacity: usize) -> Result<TorrentTier, String> {
+        DB::destroy(&Options::default(), path).map_err(|e| e.to_string())?;
+        let db = DB::open_default(path).map_err(|e| e.to_string())?;
+
+        Ok(TorrentTier {
+            db: Arc::new(db),
+            capacity,
+            used: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    pub fn get(&self, info_hash: &InfoHash) -> Option<Torrent> {
+        match self.db.get(info_hash.0) {
+            Ok(Some(bytes)) => match bincode::deserialize(&bytes) {
+                Ok(torrent) => Some(torrent),
+                Err(e) => {
+                    warn!(
+                        "Skipping malformed torrent {} in tier ({})",
+                        info_hash.to_hex(),
+                        e
+                    );
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                warn!(
+                    "Could not read torrent {} from tier ({})",
+                    info_hash.to_hex(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    pub fn put_all<'a>(&self, torrents: impl Iterator<Item = &'a Torrent>) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        for torrent in torrents {
+            let bytes = bincode::serialize(torrent).map_err(|e| e.to_string())?;
+            batch.put(torrent.info_hash.0, bytes);
+        }
+        self.db.write(batch).map_err(|e| e.to_string())
+    }
+
+    pub fn records(&self) -> TorrentRecords {
+        self.db
+            .iterator(IteratorMode::Start)
+            .filter_map(|(_, bytes)| bincode::deserialize::<Torrent>(&bytes).ok())
+            .map(|torrent| (torrent.info_hash, torrent))
+            .collect()
+    }
+
+    pub fn touch(&self, info_hashes: &[InfoHash]) {
+        self.used
+            .lock()
+            .unwrap()
+            .extend(info_hashes.iter().copied());
+    }
+
+    // Once there are more than capacity torrents in memory, moves a tenth
+    // of them out, starting with those that haven't been used since the
+    // last eviction. Returns the info hashes of the ones moved.
+    pub fn evict(&self, torrents: &mut TorrentRecords) -> Vec<InfoHash> {
+        if torrents.len() <= self.capacity {
+            return Vec::new();
+        }
+
+        let mut used = self.used.lock().unwrap();
+        let excess = torrents.len() - (self.capacity - self.capacity / 10);
+        let mut evicted: Vec<InfoHash> = torrents
+            .keys()
+            .filter(|info_hash| !used.contains(*info_hash))
+            .take(excess)
+            .copied()
+            .collect();
+        if evicted.len() < excess {
+            let more: Vec<InfoHash> = torrents
+                .keys()
+                .filter(|info_hash| used.contains(*info_hash))
+                .take(excess - evicted.len())
+                .copied()
+                .collect();
+            evicted.extend(more);
+        }
+        used.clear();
+
+        // Torrents that can't be written back are kept, rather than lost
+        if let Err(e) = self.put_all(evicted.iter().filter_map(|h| torrents.get(h))) {
+            warn!("Could not move torrents to tier ({})", e);
+            return Vec::new();
+        }
+        for info_hash in &evicted {
+            torrents.remove(info_hash);
+        }
+        evicted
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+
+use crate::bittorrent::ScrapeFile;
+use crate::statistics::GlobalStatistics;
+use crate::storage::{all_scrapes_from, scrapes_from, TorrentRecords};
+
+// An immutable copy of per-torrent stats and global statistics.
+pub struct SnapshotData {
+    pub torrents: TorrentRecords,
+    pub stats: GlobalStatistics,
+    pub taken: Instant,
+}
+
+// Scrapes and statistics can be served from a periodically refreshed
+// snapshot instead of the live stores. The snapshot is swapped out whole,
+// so readers never take any of the live stores' locks.
+#[derive(Clone)]
+pub struct Snapshot {
+    current: Arc<ArcSwap<SnapshotData>>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot {
+            current: Arc::new(ArcSwap::from_pointee(SnapshotData {
+                torrents: TorrentRecords::new(),
+                stats: GlobalStatistics::new(),
+                taken: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn load(&self) -> Arc<SnapshotData> {
+        self.current.load_full()
+    }
+
+    pub fn replace(&self, torrents: TorrentRecords, stats: GlobalStatistics) {
+        self.current.store(Arc::new(SnapshotData {
+            torrents,
+            stats,
+            taken: Instant::now(),
+        }));
+    }
+
+    pub fn get_scrapes(&self, info_hashes: Vec<Vec<u8>>) -> Vec<ScrapeFile> {
+        scrapes_from(&self.load().torrents, info_hashes)
+    }
+
+    pub fn get_all_scrapes(&self) -> Vec<ScrapeFile> {
+        all_scrapes_from(&self.load().torrents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::InfoHash;
+    use crate::storage::{Torrent, TorrentStore};
+
+    #[tokio::test]
+    async fn snapshot_isolated_from_live_store() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut records = TorrentRecords::new();
+        records.insert(info_hash, Torrent::new(info_hash, 10, 34, 7, 0));
+
+        let torrent_store = TorrentStore::new(records);
+        let snapshot = Snapshot::new();
+        snapshot.replace(
+            torrent_store.torrents.read().await.clone(),
+            GlobalStatistics::new(),
+        );
+
+        torrent_store.new_leech(info_hash).await;
+
+        let scrapes = snapshot.get_scrapes(vec![info_hash.0.to_vec()]);
+        assert_eq!(scrapes[0].incomplete, 7);
+        assert_eq!(
+            torrent_store.get_scrapes(vec![info_hash.0.to_vec()]).await[0].incomplete,
+            8
+        );
+    }
+}
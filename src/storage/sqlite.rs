@@ -0,0 +1,311 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, Result, Row};
+
+use crate::bittorrent::InfoHash;
+use crate::statistics::ArchivedStatistics;
+use crate::storage;
+use crate::storage::peer_snapshot::{self, SavedPeer};
+use crate::storage::users::{Snatch, User, UserRecords, UserTraffic};
+
+// SQLite keeps everything in a single file next to the tracker, so the
+// tables are created on open rather than through a separate schema file.
+// Integers are stored as SQLite's signed 64-bit type and converted back.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS torrents (
+        info_hash TEXT NOT NULL PRIMARY KEY,
+        complete INTEGER NOT NULL,
+        downloaded INTEGER NOT NULL,
+        incomplete INTEGER NOT NULL,
+        balance INTEGER NOT NULL,
+        bytes_uploaded INTEGER NOT NULL DEFAULT 0,
+        bytes_downloaded INTEGER NOT NULL DEFAULT 0,
+        category TEXT NULL,
+        hidden BOOLEAN NOT NULL DEFAULT FALSE
+    );
+    CREATE TABLE IF NOT EXISTS stats_history (
+        period_start INTEGER NOT NULL PRIMARY KEY,
+        period_end INTEGER NOT NULL,
+        announce_requests INTEGER NOT NULL,
+        succ_announces INTEGER NOT NULL,
+        scrapes INTEGER NOT NULL,
+        uploaded INTEGER NOT NULL,
+        downloaded INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS peers (
+        info_hash TEXT NOT NULL,
+        peer_id TEXT NOT NULL,
+        ip TEXT NOT NULL,
+        port INTEGER NOT NULL,
+        uploaded INTEGER NOT NULL,
+        downloaded INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        last_announced INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER NOT NULL PRIMARY KEY,
+        passkey TEXT NOT NULL UNIQUE,
+        uploaded INTEGER NOT NULL DEFAULT 0,
+        downloaded INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS snatches (
+        user_id INTEGER NOT NULL,
+        info_hash TEXT NOT NULL,
+        completed_at INTEGER NOT NULL,
+        uploaded INTEGER NOT NULL,
+        downloaded INTEGER NOT NULL
+    );";
+
+// A connection can only be used by one caller at a time
+pub type Database = Arc<Mutex<Connection>>;
+
+pub fn open(path: &str) -> Result<Database> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+fn torrent_from_row(row: &Row, info_hash: InfoHash) -> Result<storage::Torrent> {
+    Ok(storage::Torrent {
+        info_hash,
+        complete: row.get::<_, i64>(1)? as u32,
+        downloaded: row.get::<_, i64>(2)? as u32,
+        incomplete: row.get::<_, i64>(3)? as u32,
+        // Partial seeds only last as long as their peers do
+        partial: 0,
+        balance: row.get::<_, i64>(4)? as u64,
+        bytes_uploaded: row.get::<_, i64>(5)? as u64,
+        bytes_downloaded: row.get::<_, i64>(6)? as u64,
+        category: row.get(7)?,
+        hidden: row.get(8)?,
+    })
+}
+
+pub fn get_torrents(db: &Database) -> Result<storage::TorrentRecords> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT info_hash, complete, downloaded, incomplete, balance, bytes_uploaded, bytes_downloaded, category, hidden FROM torrents",
+    )?;
+    let mut rows = stmt.query(params![])?;
+
+    let mut torrents = storage::TorrentRecords::new();
+
+    // Info hashes are stored as hex, so rows that don't hold one are skipped
+    while let Some(row) = rows.next()? {
+        let hex: String = row.get(0)?;
+        match hex.parse::<InfoHash>() {
+            Ok(info_hash) => {
+                torrents.insert(info_hash, torrent_from_row(row, info_hash)?);
+            }
+            Err(_) => warn!("Skipping torrent with malformed info hash {}", hex),
+        }
+    }
+
+    Ok(torrents)
+}
+
+pub fn flush_torrents(db: &Database, torrents: Vec<storage::Torrent>) -> Result<()> {
+    let mut conn = db.lock().unwrap();
+
+    // One transaction for the whole flush; SQLite is far slower otherwise
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance, bytes_uploaded, bytes_downloaded, category)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (info_hash) DO UPDATE SET
+                    complete = ?2,
+                    downloaded = ?3,
+                    incomplete = ?4,
+                    balance = ?5,
+                    bytes_uploaded = ?6,
+                    bytes_downloaded = ?7",
+        )?;
+
+        for torrent in torrents {
+            stmt.execute(params![
+                torrent.info_hash.to_hex(),
+                torrent.complete as i64,
+                torrent.downloaded as i64,
+                torrent.incomplete as i64,
+                torrent.balance as i64,
+                torrent.bytes_uploaded as i64,
+                torrent.bytes_downloaded as i64,
+                torrent.category,
+            ])?;
+        }
+    }
+
+    tx.commit()
+}
+
+pub fn get_stats_history(db: &Database) -> Result<Vec<ArchivedStatistics>> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded
+            FROM stats_history ORDER BY period_start",
+    )?;
+
+    let history = stmt.query_map(params![], |row| {
+        Ok(ArchivedStatistics {
+            period_start: row.get::<_, i64>(0)? as u64,
+            period_end: row.get::<_, i64>(1)? as u64,
+            announce_requests: row.get::<_, i64>(2)? as u32,
+            succ_announces: row.get::<_, i64>(3)? as u32,
+            scrapes: row.get::<_, i64>(4)? as u32,
+            uploaded: row.get::<_, i64>(5)? as u64,
+            downloaded: row.get::<_, i64>(6)? as u64,
+        })
+    })?;
+
+    history.collect()
+}
+
+pub fn archive_stats(db: &Database, archived: &ArchivedStatistics) -> Result<()> {
+    let conn = db.lock().unwrap();
+
+    conn.execute(
+        "INSERT INTO stats_history (period_start, period_end, announce_requests, succ_announces, scrapes, uploaded, downloaded)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            archived.period_start as i64,
+            archived.period_end as i64,
+            archived.announce_requests as i64,
+            archived.succ_announces as i64,
+            archived.scrapes as i64,
+            archived.uploaded as i64,
+            archived.downloaded as i64,
+        ],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_peers(db: &Database) -> Result<Vec<SavedPeer>> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced FROM peers",
+    )?;
+    let mut rows = stmt.query(params![])?;
+
+    let mut peers = Vec::new();
+    while let Some(row) = rows.next()? {
+        let info_hash: String = row.get(0)?;
+        let peer_id: String = row.get(1)?;
+        let ip: String = row.get(2)?;
+        let role: String = row.get(6)?;
+
+        match peer_snapshot::parse_columns(&info_hash, &peer_id, &ip, &role) {
+            Some((info_hash, peer_id, ip, role)) => peers.push(SavedPeer {
+                info_hash,
+                peer_id,
+                ip,
+                port: row.get::<_, i64>(3)? as u16,
+                uploaded: row.get::<_, i64>(4)? as u64,
+                downloaded: row.get::<_, i64>(5)? as u64,
+                role,
+                last_announced: row.get::<_, i64>(7)? as u64,
+            }),
+            None => warn!("Skipping malformed saved peer for torrent {}", info_hash),
+        }
+    }
+
+    Ok(peers)
+}
+
+pub fn save_peers(db: &Database, peers: Vec<SavedPeer>) -> Result<()> {
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction()?;
+    {
+        // The saved peers replace whatever was saved before
+        tx.execute("DELETE FROM peers", params![])?;
+
+        let mut stmt = tx.prepare(
+            "INSERT INTO peers (info_hash, peer_id, ip, port, uploaded, downloaded, role, last_announced)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+
+        for peer in peers {
+            stmt.execute(params![
+                peer.info_hash.to_hex(),
+                peer.peer_id.to_hex(),
+                peer.ip.to_string(),
+                peer.port as i64,
+                peer.uploaded as i64,
+                peer.downloaded as i64,
+                peer.role.as_str(),
+                peer.last_announced as i64,
+            ])?;
+        }
+    }
+
+    tx.commit()
+}
+
+pub fn ping(db: &Database) -> Result<()> {
+    db.lock().unwrap().execute_batch("SELECT 1")
+}
+
+pub fn get_users(db: &Database) -> Result<UserRecords> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT id, passkey FROM users")?;
+
+    let users = stmt.query_map(params![], |row| {
+        Ok(User {
+            id: row.get::<_, i64>(0)? as u32,
+            passkey: row.get(1)?,
+        })
+    })?;
+
+    users
+        .map(|user| user.map(|user| (user.passkey.clone(), user)))
+        .collect()
+}
+
+pub fn flush_user_traffic(db: &Database, traffic: Vec<(u32, UserTraffic)>) -> Result<()> {
+    let mut conn = db.lock().unwrap();
+
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "UPDATE users SET
+                uploaded = uploaded + ?2,
+                downloaded = downloaded + ?3
+            WHERE id = ?1",
+        )?;
+
+        for (id, user_traffic) in traffic {
+            stmt.execute(params![
+                id as i64,
+                user_traffic.uploaded as i64,
+                user_traffic.downloaded as i64,
+            ])?;
+        }
+    }
+
+    tx.commit()
+}
+
+pub fn save_snatches(db: &Database, snatches: Vec<Snatch>) -> Result<()> {
+    let mut conn = db.lock().unwrap();
+
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO snatches (user_id, info_hash, completed_at, uploaded, downloaded)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+
+        for snatch in snatches {
+            stmt.execute(params![
+                snatch.user_id as i64,
+                snatch.info_hash.to_hex(),
+                snatch.completed_at as i64,
+                snatch.uploaded as i64,
+                snatch.downloaded as i64,
+            ])?;
+        }
+    }
+
+    tx.commit()
+}
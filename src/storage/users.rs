@@ -0,0 +1,387 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{self, Arc};
+use std::time::{Duration, Instant, SystemTime};
+
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::bittorrent::{InfoHash, Peer, PeerId};
+use crate::config::Private;
+use crate::util::unix_secs;
+
+// Passkeys are the secret part of a user's announce URL
+pub type Passkey = String;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: u32,
+    pub passkey: Passkey,
+}
+
+pub type UserRecords = HashMap<Passkey, User>;
+
+// Bytes a user has transferred since the last flush
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UserTraffic {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl UserTraffic {
+    fn add(&mut self, other: UserTraffic) {
+        self.uploaded = self.uploaded.saturating_add(other.uploaded);
+        self.downloaded = self.downloaded.saturating_add(other.downloaded);
+    }
+}
+
+// A user finishing a torrent, along with what their client reported
+// having transferred by then, for the site's snatch lists and auditing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snatch {
+    pub user_id: u32,
+    pub info_hash: InfoHash,
+    pub completed_at: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// One of a user's peers, identified the same way the swarms identify them
+type Slot = (InfoHash, PeerId, IpAddr, u16);
+
+fn slot(info_hash: InfoHash, peer: &Peer) -> Slot {
+    (info_hash, peer.peer_id(), peer.ip(), peer.port())
+}
+
+// The accounts of a private tracker, keyed by passkey. Accounts are
+// managed by the site software in the database, and are periodically
+// reloaded from there so new and disabled users take effect.
+//
+// Traffic is kept apart from the accounts, keyed by user ID, so that
+// reloading the accounts doesn't lose anything that isn't flushed yet.
+//
+// Passkeys can also be revoked through the admin API, which cuts them
+// off at once rather than at the next reload.
+#[derive(Debug, Clone)]
+pub struct UserStore {
+    pub users: Arc<RwLock<UserRecords>>,
+    revoked: Arc<sync::RwLock<HashSet<Passkey>>>,
+    traffic: Arc<sync::Mutex<HashMap<u32, UserTraffic>>>,
+    snatches: Arc<sync::Mutex<Vec<Snatch>>>,
+    // When each of a user's peers last announced
+    slots: Arc<sync::Mutex<HashMap<u32, HashMap<Slot, Instant>>>>,
+}
+
+impl UserStore {
+    pub fn new(user_records: UserRecords) -> UserStore {
+        UserStore {
+            users: Arc::new(RwLock::new(user_records)),
+            revoked: Arc::new(sync::RwLock::new(HashSet::new())),
+            traffic: Arc::new(sync::Mutex::new(HashMap::new())),
+            snatches: Arc::new(sync::Mutex::new(Vec::new())),
+            slots: Arc::new(sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn get(&self, passkey: &str) -> Option<User> {
+        self.users.read().await.get(passkey).cloned()
+    }
+
+    // Revocations are only needed until the reloaded accounts catch up
+    // with them, so any for passkeys that are gone by then are dropped
+    pub async fn replace(&self, user_records: UserRecords) {
+        self.revoked
+            .write()
+            .unwrap()
+            .retain(|passkey| user_records.contains_key(passkey));
+        *self.users.write().await = user_records;
+    }
+
+    pub fn is_revoked(&self, passkey: &str) -> bool {
+        self.revoked.read().unwrap().contains(passkey)
+    }
+
+    pub fn revoke(&self, passkey: Passkey) {
+        self.revoked.write().unwrap().insert(passkey);
+    }
+
+    pub fn reinstate(&self, passkey: &str) {
+        self.revoked.write().unwrap().remove(passkey);
+    }
+
+    pub fn revoked(&self) -> Vec<Passkey> {
+        self.revoked.read().unwrap().iter().cloned().collect()
+    }
+
+    // Credits the owner of a passkey with what one of their peers transferred
+    pub async fn add_traffic(&self, passkey: &str, traffic: UserTraffic) {
+        if let Some(user) = self.users.read().await.get(passkey) {
+            let mut pending = self.traffic.lock().unwrap();
+            pending.entry(user.id).or_default().add(traffic);
+        }
+    }
+
+    // Takes the traffic accumulated since the last flush. If writing it
+    // fails, it should be handed back through restore_traffic.
+    pub fn take_traffic(&self) -> Vec<(u32, UserTraffic)> {
+        self.traffic.lock().unwrap().drain().collect()
+    }
+
+    pub fn restore_traffic(&self, traffic: impl IntoIterator<Item = (u32, UserTraffic)>) {
+        let mut pending = self.traffic.lock().unwrap();
+        for (id, user_traffic) in traffic {
+            pending.entry(id).or_default().add(user_traffic);
+        }
+    }
+
+    pub async fn record_snatch(
+        &self,
+        passkey: &str,
+        info_hash: InfoHash,
+        uploaded: u64,
+        downloaded: u64,
+    ) {
+        if let Some(user) = self.users.read().await.get(passkey) {
+            self.snatches.lock().unwrap().push(Snatch {
+                user_id: user.id,
+                info_hash,
+                completed_at: unix_secs(SystemTime::now()),
+                uploaded,
+                downloaded,
+            });
+        }
+    }
+
+    // Snatches are handed over the same way as traffic
+    pub fn take_snatches(&self) -> Vec<Snatch> {
+        self.snatches.lock().unwrap().drain(..).collect()
+    }
+
+    pub fn restore_snatches(&self, snatches: Vec<Snatch>) {
+        self.snatches.lock().unwrap().extend(snatches);
+    }
+
+    // Takes up one of the user's peer slots, unless that would exceed
+    // either limit (0 means no limit). Peers already holding a slot keep
+    // it. Slots that haven't been announced on within timeout are freed,
+    // as their peers will have been reaped from the swarms by then.
+    pub async fn claim_slot(
+        &self,
+        passkey: &str,
+        info_hash: InfoHash,
+        peer: &Peer,
+        limits: (u32, u32),
+        timeout: Duration,
+    ) -> bool {
+        let user_id = match self.users.read().await.get(passkey) {
+            Some(user) => user.id,
+            None => return true,
+        };
+        let (max_per_torrent, max_total) = limits;
+        let now = Instant::now();
+        let key = slot(info_hash, peer);
+
+        let mut slots = self.slots.lock().unwrap();
+        let user_slots = slots.entry(user_id).or_default();
+        user_slots.retain(|_, last_seen| now.duration_since(*last_seen) < timeout);
+
+        if !user_slots.contains_key(&key) {
+            let on_torrent = user_slots.keys().filter(|s| s.0 == info_hash).count();
+            if (max_per_torrent > 0 && on_torrent >= max_per_torrent as usize)
+                || (max_total > 0 && user_slots.len() >= max_total as usize)
+            {
+                return false;
+            }
+        }
+
+        user_slots.insert(key, now);
+        true
+    }
+
+    pub async fn release_slot(&self, passkey: &str, info_hash: InfoHash, peer: &Peer) {
+        if let Some(user) = self.users.read().await.get(passkey) {
+            let mut slots = self.slots.lock().unwrap();
+            if let Some(user_slots) = slots.get_mut(&user.id) {
+                user_slots.remove(&slot(info_hash, peer));
+                if user_slots.is_empty() {
+                    slots.remove(&user.id);
+                }
+            }
+        }
+    }
+}
+
+impl Default for UserStore {
+    fn default() -> Self {
+        UserStore::new(UserRecords::new())
+    }
+}
+
+// Which torrents are freeleech, if not all of them. It starts out as the
+// config says, can be changed through the admin API, and is put back to
+// what the config says when it's reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct Freeleech {
+    global: Arc<AtomicBool>,
+    torrents: Arc<sync::RwLock<HashSet<InfoHash>>>,
+}
+
+impl Freeleech {
+    pub fn from_config(config: &Private) -> Freeleech {
+        let freeleech = Freeleech::default();
+        freeleech.reload(config);
+        freeleech
+    }
+
+    pub fn reload(&self, config: &Private) {
+        self.global.store(config.freeleech, Ordering::Relaxed);
+        *self.torrents.write().unwrap() = config.freeleech_torrents.iter().copied().collect();
+    }
+
+    pub fn is_free(&self, info_hash: &InfoHash) -> bool {
+        self.global.load(Ordering::Relaxed) || self.torrents.read().unwrap().contains(info_hash)
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.global.load(Ordering::Relaxed)
+    }
+
+    pub fn set_global(&self, free: bool) {
+        self.global.store(free, Ordering::Relaxed);
+    }
+
+    pub fn set_torrent(&self, info_hash: InfoHash, free: bool) {
+        let mut torrents = self.torrents.write().unwrap();
+        if free {
+            torrents.insert(info_hash);
+        } else {
+            torrents.remove(&info_hash);
+        }
+    }
+
+    pub fn torrents(&self) -> Vec<InfoHash> {
+        self.torrents.read().unwrap().iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u32, passkey: &str) -> (Passkey, User) {
+        let passkey = passkey.to_string();
+        (passkey.clone(), User { id, passkey })
+    }
+
+    #[actix_rt::test]
+    async fn user_traffic_survives_reload() {
+        let user_store = UserStore::new(vec![user(1, "alice")].into_iter().collect());
+        let traffic = UserTraffic {
+            uploaded: 100,
+            downloaded: 50,
+        };
+
+        user_store.add_traffic("alice", traffic).await;
+        user_store.add_traffic("nobody", traffic).await;
+        user_store
+            .replace(vec![user(1, "alice"), user(2, "bob")].into_iter().collect())
+            .await;
+        user_store.add_traffic("alice", traffic).await;
+
+        let taken = user_store.take_traffic();
+        assert_eq!(
+            taken,
+            vec![(
+                1,
+                UserTraffic {
+                    uploaded: 200,
+                    downloaded: 100,
+                }
+            )]
+        );
+        assert!(user_store.take_traffic().is_empty());
+
+        // A failed flush is written next time along with anything newer
+        user_store.restore_traffic(taken);
+        user_store.add_traffic("alice", traffic).await;
+        assert_eq!(user_store.take_traffic()[0].1.uploaded, 300);
+    }
+
+    #[actix_rt::test]
+    async fn peer_slots_limited_per_user() {
+        let user_store = UserStore::new(vec![user(1, "alice")].into_iter().collect());
+        let first = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let second = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let peer = |port| {
+            Peer::new(
+                PeerId(*b"-DE9824-143964258012"),
+                "127.0.0.1".parse().unwrap(),
+                port,
+                0,
+                0,
+            )
+        };
+        let timeout = Duration::from_secs(60);
+
+        assert!(
+            user_store
+                .claim_slot("alice", first, &peer(1), (1, 2), timeout)
+                .await
+        );
+        // Announcing again from the same peer needs no new slot
+        assert!(
+            user_store
+                .claim_slot("alice", first, &peer(1), (1, 2), timeout)
+                .await
+        );
+        assert!(
+            !user_store
+                .claim_slot("alice", first, &peer(2), (1, 2), timeout)
+                .await
+        );
+        assert!(
+            user_store
+                .claim_slot("alice", second, &peer(2), (1, 2), timeout)
+                .await
+        );
+        assert!(
+            !user_store
+                .claim_slot("alice", second, &peer(3), (0, 2), timeout)
+                .await
+        );
+
+        user_store.release_slot("alice", first, &peer(1)).await;
+        assert!(
+            user_store
+                .claim_slot("alice", first, &peer(2), (1, 2), timeout)
+                .await
+        );
+
+        // Peers that stopped announcing give their slots back
+        assert!(
+            user_store
+                .claim_slot("alice", first, &peer(3), (1, 2), Duration::new(0, 0))
+                .await
+        );
+    }
+
+    #[actix_rt::test]
+    async fn snatches_only_for_known_users() {
+        let user_store = UserStore::new(vec![user(1, "alice")].into_iter().collect());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+
+        user_store.record_snatch("alice", info_hash, 10, 1000).await;
+        user_store
+            .record_snatch("nobody", info_hash, 10, 1000)
+            .await;
+
+        let snatches = user_store.take_snatches();
+        assert_eq!(snatches.len(), 1);
+        assert_eq!(snatches[0].user_id, 1);
+        assert_eq!(snatches[0].downloaded, 1000);
+
+        user_store.restore_snatches(snatches);
+        assert_eq!(user_store.take_snatches().len(), 1);
+    }
+}
@@ -1,18 +1,24 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use crate::errors::ClientError;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     Started,
     Stopped,
     Completed,
+    // BEP 21: sent by partial seeds
+    Paused,
     None,
 }
 
-pub fn string_to_event(s: String) -> Result<Event, ClientError> {
-    match s.as_ref() {
+pub fn string_to_event(s: &str) -> Result<Event, ClientError> {
+    match s {
         "started" => Ok(Event::Started),
         "stopped" => Ok(Event::Stopped),
         "completed" => Ok(Event::Completed),
+        "paused" => Ok(Event::Paused),
         "" => Ok(Event::None),
         _ => Err(ClientError::MalformedAnnounce),
     }
@@ -23,25 +29,74 @@ pub fn event_to_string(event: Event) -> &'static str {
         Event::Started => "started",
         Event::Stopped => "stopped",
         Event::Completed => "completed",
+        Event::Paused => "paused",
         _ => "",
     }
 }
 
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn is_bogon_v4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_unspecified()
+        || ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // "This network", shared address space, IETF protocol
+        // assignments, benchmarking, and reserved for future use
+        || octets[0] == 0
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        || (octets[0] == 198 && octets[1] & 0xfe == 18)
+        || octets[0] >= 240
+}
+
+// Addresses that no peer on the internet could connect to
+pub fn is_bogon(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_bogon_v4(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_bogon_v4(Ipv4Addr::new(a, b, c, d));
+            }
+
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, link-local, and documentation
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{event_to_string, string_to_event, Event};
+    use super::{event_to_string, is_bogon, string_to_event, Event};
 
     #[test]
     fn event_string_to_event_good() {
         let s = "started".to_string();
-        assert_eq!(string_to_event(s).unwrap(), Event::Started);
+        assert_eq!(string_to_event(&s).unwrap(), Event::Started);
+        assert_eq!(string_to_event("paused").unwrap(), Event::Paused);
     }
 
     #[test]
     fn event_string_to_event_garbage() {
         let s = "garbage".to_string();
         assert!(
-            string_to_event(s).is_err(),
+            string_to_event(&s).is_err(),
             "String 'garbage' should result in error"
         );
     }
@@ -51,4 +106,30 @@ mod tests {
         let event = Event::Completed;
         assert_eq!(event_to_string(event), "completed");
     }
+
+    #[test]
+    fn bogon_addresses() {
+        for ip in &[
+            "10.1.2.3",
+            "127.0.0.1",
+            "100.64.0.1",
+            "192.168.1.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_bogon(ip.parse().unwrap()), "{} is a bogon", ip);
+        }
+        for ip in &[
+            "1.1.1.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:1.1.1.1",
+        ] {
+            assert!(!is_bogon(ip.parse().unwrap()), "{} is not a bogon", ip);
+        }
+    }
 }